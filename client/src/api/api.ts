@@ -24,6 +24,8 @@ export function logout(): Promise<{}> {
 export type CurrentUserOutput = {
     /** Returns undefined if we aren't logged in */
     username: string | null
+    /** Can this user manage other users? */
+    admin: boolean
 }
 export function current_user(): Promise<CurrentUserOutput> {
     return client("current_user")
@@ -33,6 +35,10 @@ export type UpsertUserInput = {
     /** Will modify the current user if name not given */
     username?: string
     password: string
+    /** Needed to change your own password */
+    current_password?: string
+    /** Only admins can set this; left as it was if not given */
+    admin?: boolean
 }
 export function upsert_user(opts: UpsertUserInput): Promise<{}> {
     return client("upsert_user", opts)
@@ -45,13 +51,51 @@ export function delete_user(opts: DeleteUserInput): Promise<{}> {
     return client("delete_user", opts)
 }
 
+export type AddTokenInput = {
+    name: string
+}
+export type AddTokenOutput = {
+    id: string
+    name: string
+    /** This is the only time that the token is handed back */
+    token: string
+    /** ISO date string */
+    created: string
+}
+export function add_token(opts: AddTokenInput): Promise<AddTokenOutput> {
+    return client("add_token", opts)
+}
+
+export type TokensOutput = Token[]
+export type Token = {
+    id: string
+    username: string
+    name: string
+    /** ISO date string */
+    created: string
+}
+export function tokens(): Promise<TokensOutput> {
+    return client("tokens")
+}
+
+export type RevokeTokenInput = {
+    id: string
+}
+export function revoke_token(opts: RevokeTokenInput): Promise<{}> {
+    return client("revoke_token", opts)
+}
+
 export type UpsertGroupInput = {
     id?: string
     name: string
 }
+export type Role = "viewer" | "editor" | "owner"
+
 export type GroupOutput = {
     id: string
     name: string
+    /** The role that the current user has in the group */
+    role: Role
 }
 export function upsert_group(opts: UpsertGroupInput): Promise<GroupOutput> {
     return client("upsert_group", opts)
@@ -64,21 +108,72 @@ export function delete_group(opts: DeleteGroupInput): Promise<{}> {
     return client("delete_group", opts)
 }
 
+/** Look at things as they were at some point in the past: an ISO date string, or a number of events to replay */
+export type AsOf = string | number
+
 export type GetGroupInput = {
     id: string
+    as_of?: AsOf
 }
 export function get_group(opts: GetGroupInput): Promise<GroupOutput> {
     return client("get_group", opts)
 }
 
+export type MembersInput = {
+    group_id: string
+    as_of?: AsOf
+}
+export type MembersOutput = Member[]
+export type Member = {
+    username: string
+    role: Role
+}
+export function members(opts: MembersInput): Promise<MembersOutput> {
+    return client("members", opts)
+}
+
+export type UpsertMemberInput = {
+    group_id: string
+    username: string
+    role: Role
+}
+export function upsert_member(opts: UpsertMemberInput): Promise<{}> {
+    return client("upsert_member", opts)
+}
+
+export type DeleteMemberInput = {
+    group_id: string
+    /** Leaves the group if not given */
+    username?: string
+}
+export function delete_member(opts: DeleteMemberInput): Promise<{}> {
+    return client("delete_member", opts)
+}
+
+export type Ranking = "higher_is_better" | "lower_is_better"
+
+export type ValueKind
+    = { kind: "integer", unit?: string | null }
+    /** Stored multiplied by 10^precision */
+    | { kind: "decimal", precision: number, unit?: string | null }
+    /** Displayed to `precision` decimal places of seconds */
+    | { kind: "duration", precision: number }
+
 export type UpsertScorableInput = {
     id?: string
     group_id: string
     name: string
+    /** Left as it was if not given (new scorables rank higher scores first) */
+    ranking?: Ranking
+    /** Left as it was if not given (new scorables have integer values) */
+    value_kind?: ValueKind
 }
 export type ScorableOutput = {
     id: string
+    group_id: string
     name: string
+    ranking: Ranking
+    value_kind: ValueKind
 }
 export function upsert_scorable(opts: UpsertScorableInput): Promise<ScorableOutput> {
     return client("upsert_scorable", opts)
@@ -86,6 +181,7 @@ export function upsert_scorable(opts: UpsertScorableInput): Promise<ScorableOutp
 
 export type GetScorableInput = {
     id: string
+    as_of?: AsOf
 }
 export function get_scorable(opts: GetScorableInput): Promise<ScorableOutput> {
     return client("get_scorable", opts)
@@ -102,12 +198,28 @@ export type UpsertScoreInput = {
     id?: string
     scorable_id: string
     username?: string
-    value: number
+    /** Anybody else to credit the score to, for team scores (up to 4 users in all) */
+    teammates?: string[]
+    /** A number, or a string like "1:23.4" for durations */
+    value: number | string
     /** ISO date string */
     date?: string
 }
 export type UpsertScoreOutput = {
     id: string
+    /** Set if the score is now the best against its scorable */
+    top_score: ScoreRecord | null
+    /** Each user that the score is credited to for whom it's now the best that they have against its scorable */
+    personal_bests: (ScoreRecord & { username: string })[]
+}
+export type ScoreRecord = {
+    /** Who held the record before, and with what; null if there were no scores to beat */
+    previous: {
+        username: string
+        /** Durations are given in seconds */
+        value: number
+        display: string
+    } | null
 }
 export function upsert_score(opts: UpsertScoreInput): Promise<UpsertScoreOutput> {
     return client("upsert_score", opts)
@@ -120,39 +232,344 @@ export function delete_score(opts: DeleteScoreInput): Promise<{}> {
     return client("delete_score", opts)
 }
 
-export type GroupsOutput = Group[]
+export type UpsertMatchInput = {
+    id?: string
+    scorable_id: string
+    participants: string[]
+    /** Who won the match; null or missing if it was a draw */
+    winner?: string | null
+    /** ISO date string */
+    date?: string
+}
+export function upsert_match(opts: UpsertMatchInput): Promise<{ id: string }> {
+    return client("upsert_match", opts)
+}
+
+export type DeleteMatchInput = {
+    id: string
+}
+export function delete_match(opts: DeleteMatchInput): Promise<{}> {
+    return client("delete_match", opts)
+}
+
+export type MatchesInput = {
+    scorable_id: string
+    /** Only matches that this user took part in */
+    username?: string
+    as_of?: AsOf
+}
+export type Match = {
+    id: string
+    participants: string[]
+    /** Who won the match; null if it was a draw */
+    winner: string | null
+    /** ISO date string */
+    date: string
+}
+/** Newest first */
+export function matches(opts: MatchesInput): Promise<Match[]> {
+    return client("matches", opts)
+}
+
+export type RatingsInput = {
+    scorable_id: string
+    as_of?: AsOf
+}
+export type Rating = {
+    username: string
+    rating: number
+    wins: number
+    draws: number
+    losses: number
+}
+/** Highest rated first */
+export function ratings(opts: RatingsInput): Promise<Rating[]> {
+    return client("ratings", opts)
+}
+
+export type RatingHistoryInput = {
+    scorable_id: string
+    username: string
+    as_of?: AsOf
+}
+export type RatingChange = {
+    match_id: string
+    /** ISO date string */
+    date: string
+    /** The rating after the match */
+    rating: number
+    /** How much the rating went up (or down) by */
+    change: number
+}
+/** Oldest first */
+export function rating_history(opts: RatingHistoryInput): Promise<RatingChange[]> {
+    return client("rating_history", opts)
+}
+
+/** How a group's scores are split up into seasons, by their date */
+export type SeasonsDefinition =
+    /** A season every period (in UTC), starting with the one that `from` (an ISO date string) falls in */
+    | { kind: "recurring", period: "weekly" | "monthly" | "quarterly" | "yearly", from: string }
+    /** Seasons with names and dates (ISO date strings) of their own, which mustn't overlap */
+    | { kind: "named", seasons: { name: string, start: string, end: string }[] }
+export type SetSeasonsInput = {
+    group_id: string
+    /** null to stop splitting the group's scores up into seasons */
+    seasons: SeasonsDefinition | null
+}
+export function set_seasons(opts: SetSeasonsInput): Promise<{}> {
+    return client("set_seasons", opts)
+}
+
+export type SeasonsInput = {
+    group_id: string
+    as_of?: AsOf
+}
+export type SeasonsOutput = {
+    definition: SeasonsDefinition | null
+    /** Oldest first */
+    seasons: Season[]
+}
+export type Season = {
+    name: string
+    /** ISO date string */
+    start: string
+    /** ISO date string; scores on or after this are in the next season */
+    end: string
+    /** Is this the season that's running now? */
+    current: boolean
+    finished: boolean
+}
+export function seasons(opts: SeasonsInput): Promise<SeasonsOutput> {
+    return client("seasons", opts)
+}
+
+export type SeasonLeaderboardInput = {
+    scorable_id: string
+    /** The name of the season; the one running now if not given */
+    season?: string
+    limit?: number
+    /** Rank each member of a team separately, rather than teams as a whole */
+    by_member?: boolean
+    as_of?: AsOf
+}
+export type SeasonLeaderboardOutput = {
+    season: Season
+    /** The best score that each user (or team) has in the season, best first */
+    scores: Score[]
+}
+export function season_leaderboard(opts: SeasonLeaderboardInput): Promise<SeasonLeaderboardOutput> {
+    return client("season_leaderboard", opts)
+}
+
+export type SeasonArchiveInput = {
+    group_id: string
+    as_of?: AsOf
+}
+export type ArchivedSeason = {
+    name: string
+    /** ISO date strings */
+    start: string
+    end: string
+    archived: string
+    winners: SeasonWinner[]
+}
+export type SeasonWinner = {
+    scorable_id: string
+    username: string
+    teammates: string[]
+    /** Durations are given in seconds */
+    value: number
+    /** The value formatted for display, including any unit */
+    display: string
+}
+/** Most recent first */
+export function season_archive(opts: SeasonArchiveInput): Promise<ArchivedSeason[]> {
+    return client("season_archive", opts)
+}
+
+/** Listings are paged if page or page_size is given */
+export type PageInput = {
+    /** A page token handed back with a previous page */
+    page?: string
+    /** 50 if not given */
+    page_size?: number
+}
+export type Page<T> = {
+    items: T[]
+    /** How many there are across every page */
+    total: number
+    /** Page tokens for the pages either side of this one, or null if there's nothing more that way */
+    prev: string | null
+    next: string | null
+}
+
+export type GroupsInput = PageInput & {
+    as_of?: AsOf
+}
+export type GroupsOutput = Group[] | Page<Group>
 export type Group = {
     id: string
     name: string
+    /** The role that the current user has in the group */
+    role: Role
 }
-export function groups(): Promise<GroupsOutput> {
-    return client("groups")
+export function groups(opts: GroupsInput = {}): Promise<GroupsOutput> {
+    const params = Object.entries(opts)
+        .filter(([_, value]) => value !== undefined)
+        .map(([key, value]) => `${key}=${encodeURIComponent(value as string)}`)
+    return params.length === 0
+        ? client("groups")
+        : client(`groups?${params.join("&")}`)
 }
 
-export type ScorablesInGroupInput = {
+export type ScorablesInGroupInput = PageInput & {
     group_id: string
+    as_of?: AsOf
 }
-export type ScorablesInGroupOutput = Scorable[]
+export type ScorablesInGroupOutput = Scorable[] | Page<Scorable>
 export type Scorable = {
     id: string
+    group_id: string
     name: string
+    ranking: Ranking
+    value_kind: ValueKind
 }
 export function scorables_in_group(opts: ScorablesInGroupInput): Promise<ScorablesInGroupOutput> {
     return client("scorables_in_group", opts)
 }
 
-export type ScoresInput = {
+export type ScoresInput = PageInput & {
     scorable_id: string
     limit?: number
-}
-export type ScoresOutput = Score[]
+    /** Only scores on or after this ISO date string */
+    from?: string
+    /** Only scores before this ISO date string */
+    to?: string
+    /** Only scores belonging to these users */
+    usernames?: string[]
+    /** Only the best score that each user (or team) has */
+    best_per_user?: boolean
+    /** Rank each member of a team separately, rather than teams as a whole */
+    by_member?: boolean
+    as_of?: AsOf
+}
+export type ScoresOutput = Score[] | Page<Score>
 export type Score = {
     id: string
     username: string
+    teammates: string[]
+    /** Durations are given in seconds */
     value: number
+    /** The value formatted for display, including any unit */
+    display: string
     /** ISO date string */
     date: string
 }
 export function scores(opts: ScoresInput): Promise<ScoresOutput> {
     return client("scores", opts)
-}
\ No newline at end of file
+}
+
+export type ExportScoresCsvInput = {
+    /** Every score in this group... */
+    group_id?: string
+    /** ...or just the scores against this scorable */
+    scorable_id?: string
+}
+export type StatsInput = {
+    scorable_id: string
+    /** Only scores on or after this ISO date string */
+    from?: string
+    /** Only scores before this ISO date string */
+    to?: string
+    /** Only scores belonging to these users */
+    usernames?: string[]
+    /** Give stats for each member of a team separately, rather than teams as a whole */
+    by_member?: boolean
+    as_of?: AsOf
+}
+export type StatsOutput = {
+    count: number
+    /** These are null if there are no scores */
+    best: Stat | null
+    worst: Stat | null
+    mean: Stat | null
+    median: Stat | null
+    std_dev: Stat | null
+    percentiles: (Stat & { percentile: number })[]
+    /** How each user (or team) has done, best first */
+    users: UserStats[]
+}
+export type Stat = {
+    /** Durations are given in seconds */
+    value: number
+    /** The value (rounded) formatted for display, including any unit */
+    display: string
+}
+export type UserStats = {
+    username: string
+    /** The rest of the team, if these are a team's stats */
+    teammates: string[]
+    best: Stat
+    mean: Stat
+    attempts: number
+}
+export function stats(opts: StatsInput): Promise<StatsOutput> {
+    return client("stats", opts)
+}
+
+/** A URL to download scores from as CSV (with scorable, username, value, date and rank columns) */
+export function export_scores_csv_url(opts: ExportScoresCsvInput): string {
+    const params = Object.entries(opts)
+        .filter(([_, value]) => value !== undefined)
+        .map(([key, value]) => `${key}=${encodeURIComponent(value as string)}`)
+    return `/api/export/scores.csv?${params.join("&")}`
+}
+export type HistoryInput = {
+    /** Only changes made by this user */
+    by?: string
+    /** Only changes made in this group */
+    group_id?: string
+    /** Only changes made to this scorable (or its scores) */
+    scorable_id?: string
+    limit?: number
+}
+export type HistoryOutput = HistoryEntry[]
+export type HistoryEntry = {
+    /** ISO date string; null for changes from before history was recorded */
+    at: string | null
+    /** null for changes made from the command line */
+    by: string | null
+    group_id: string | null
+    scorable_id: string | null
+    /** What changed, for instance { ty: "DeleteScore", id: "..." } */
+    change: { ty: string, [key: string]: string }
+}
+export function history(opts: HistoryInput): Promise<HistoryOutput> {
+    return client("history", opts)
+}
+export type Item = { kind: "group" | "scorable" | "score", id: string }
+export type TrashInput = {
+    /** Only things deleted from this group */
+    group_id?: string
+}
+export type TrashOutput = Deleted[]
+export type Deleted = {
+    item: Item
+    /** The group that the item is (or lives in) */
+    group_id: string
+    /** The name of the group or scorable, or the user that the score belongs to */
+    name: string
+    /** ISO date string */
+    deleted_at: string
+}
+export function trash(opts: TrashInput): Promise<TrashOutput> {
+    return client("trash", opts)
+}
+export type RestoreInput = {
+    item: Item
+}
+export type RestoreOutput = {}
+export function restore(opts: RestoreInput): Promise<RestoreOutput> {
+    return client("restore", opts)
+}
@@ -20,19 +20,37 @@ export const on_last_changed = (fn: () => void) => {
     })
 }
 
-// Periodically check for changes, and increment the
-// last_changed counter if any happen:
-let last_date: undefined | string = undefined
-setInterval(() => {
-    if (!subscriptions) return
-    api.last_changed().then(({ date: new_date }) => {
-        if (!last_date) {
-            last_date = new_date
-        }
-        if (last_date != new_date) {
-            last_changed.update(n => n+1)
-        }
-    }).catch((e) => {
-        console.error(`Failed to get last_changed`)
+// Increment the last_changed counter whenever the server tells us about a change.
+// If the browser doesn't support server-sent events, poll for changes instead.
+if (typeof EventSource !== "undefined") {
+    let has_errored = false
+    const events = new EventSource("/api/events")
+    // "lagged" means we missed some changes, so treat it like a change too:
+    const on_change = () => {
+        if (subscriptions) last_changed.update(n => n+1)
+    }
+    events.addEventListener("change", on_change)
+    events.addEventListener("lagged", on_change)
+    events.addEventListener("error", () => { has_errored = true })
+    // We may have missed changes while reconnecting:
+    events.addEventListener("open", () => {
+        if (has_errored) on_change()
+        has_errored = false
     })
-}, 1000)
+} else {
+    let last_date: undefined | string = undefined
+    setInterval(() => {
+        if (!subscriptions) return
+        api.last_changed().then(({ date: new_date }) => {
+            if (!last_date) {
+                last_date = new_date
+            }
+            if (last_date != new_date) {
+                last_date = new_date
+                last_changed.update(n => n+1)
+            }
+        }).catch((e) => {
+            console.error(`Failed to get last_changed`)
+        })
+    }, 1000)
+}
@@ -1,9 +1,13 @@
 //! Rocket API routes to provide access to the backend.
 
-use crate::store_interface::{ HashedPassword, GroupId, ScorableId, ScoreId, Group, Scorable, Score };
+use crate::store_interface::{ Store, AsOf, HashedPassword, HashedToken, GroupId, ScorableId, ScoreId, MatchId, TokenId, Group, Scorable, Score, Token, Member, Ranking, ValueKind, Role, StoreError, Item, Deleted, HistoryEntry, HistoryFilter, ScoreFilter, Page, PageRequest, Cursor, CursorKey, Record, Seasons, Season };
 use crate::user::{ self, User };
 use crate::http_result::{ HttpError, HttpResult };
 use crate::state;
+use crate::change_stream::ChangeStream;
+use crate::csv_stream::CsvStream;
+use crate::client_ip::ClientIp;
+use std::sync::Arc;
 use serde::{ Serialize, Deserialize };
 use rocket_contrib::json::Json;
 use rocket::{State, http::CookieJar};
@@ -13,22 +17,43 @@ use chrono::{ DateTime, Utc };
 pub fn routes() -> Vec<rocket::Route> {
     routes![
         last_changed,
+        events,
         login,
         logout,
         current_user,
         upsert_user,
         delete_user,
+        add_token,
+        tokens,
+        revoke_token,
         upsert_group,
         delete_group,
         get_group,
+        members,
+        upsert_member,
+        delete_member,
         upsert_scorable,
         delete_scorable,
         get_scorable,
         upsert_score,
         delete_score,
+        upsert_match,
+        delete_match,
+        matches,
+        ratings,
+        rating_history,
+        set_seasons,
+        seasons,
+        season_leaderboard,
+        season_archive,
         groups,
         scorables_in_group,
         scores,
+        stats,
+        export_scores_csv,
+        history,
+        trash,
+        restore,
     ]
 }
 
@@ -45,6 +70,14 @@ async fn last_changed(_user: User, state: State<'_, state::State>) -> HttpResult
 }
 
 
+// A stream of server-sent events describing each change that the user can see
+// as it happens. `last_changed` is kept around for clients that poll instead.
+#[get("/events")]
+async fn events(user: User, state: State<'_, state::State>) -> ChangeStream {
+    ChangeStream::new(state.store.clone(), user.name, state.store.subscribe(), state.shutting_down.clone())
+}
+
+
 #[derive(Deserialize)]
 struct LoginInput {
     username: String,
@@ -55,9 +88,18 @@ struct LoginInput {
 struct Empty {}
 
 #[post("/login", data = "<body>")]
-async fn login(state: State<'_, state::State>, cookies: &CookieJar<'_>, body: Json<LoginInput>) -> HttpResult<Json<Empty>> {
-    let is_valid = state.store.check_user(&body.username, &body.password).await?;
+async fn login(state: State<'_, state::State>, client_ip: ClientIp, cookies: &CookieJar<'_>, body: Json<LoginInput>) -> HttpResult<Json<Empty>> {
+    if let Err(wait) = state.login_limiter.attempt(client_ip.0, &body.username) {
+        return Err(too_many_attempts(wait))
+    }
+    let is_valid = match state.store.check_user(&body.username, &body.password).await {
+        Ok(is_valid) => is_valid,
+        // Don't give away which users exist:
+        Err(StoreError::UserNotFound(_)) => false,
+        Err(e) => return Err(e.into())
+    };
     if is_valid {
+        state.login_limiter.record_success(client_ip.0, &body.username);
         user::add_user_cookie(cookies, body.username.to_string());
         Ok(Json(Empty {}))
     } else {
@@ -75,30 +117,56 @@ async fn logout(cookies: &CookieJar<'_>) -> HttpResult<Json<Empty>> {
 
 #[derive(Serialize)]
 struct CurrentUserOutput {
-    username: Option<String>
+    username: Option<String>,
+    admin: bool
 }
 
 #[get("/current_user")]
-async fn current_user(user: Option<User>) -> HttpResult<Json<CurrentUserOutput>> {
-    Ok(Json(CurrentUserOutput { username: user.map(|u| u.name) }))
+async fn current_user(user: Option<User>, state: State<'_, state::State>) -> HttpResult<Json<CurrentUserOutput>> {
+    let admin = match &user {
+        Some(user) => state.store.is_admin(&user.name).await?,
+        None => false
+    };
+    Ok(Json(CurrentUserOutput { username: user.map(|u| u.name), admin }))
 }
 
 
 #[derive(Deserialize)]
 struct UpsertUserInput {
+    /// Will modify the current user if not given.
     username: Option<String>,
-    password: String
+    password: String,
+    /// Needed to change your own password.
+    current_password: Option<String>,
+    /// Left as it was if not given.
+    admin: Option<bool>
 }
 
 #[post("/upsert_user", data = "<body>")]
-async fn upsert_user(user: User, state: State<'_, state::State>, body: Json<UpsertUserInput>) -> HttpResult<Json<Empty>> {
+async fn upsert_user(user: User, state: State<'_, state::State>, client_ip: ClientIp, body: Json<UpsertUserInput>) -> HttpResult<Json<Empty>> {
     let new_user = body.into_inner();
     let username = new_user.username.unwrap_or_else(|| user.name.to_owned());
+    // Anybody can change their own password (given the current one),
+    // but only admins can manage other users or who is an admin:
+    if username != user.name || new_user.admin.is_some() {
+        require_admin(&state, &user).await?;
+    }
+    if username == user.name {
+        // This is another way to guess passwords, so it's throttled like logging in is:
+        if let Err(wait) = state.login_limiter.attempt(client_ip.0, &user.name) {
+            return Err(too_many_attempts(wait))
+        }
+        let current_password = new_user.current_password.unwrap_or_default();
+        if !state.store.check_user(&user.name, &current_password).await? {
+            return Err(HttpError::new(401, "Current password is incorrect"))
+        }
+        state.login_limiter.record_success(client_ip.0, &user.name);
+    }
     let plain_password = new_user.password;
     let hashed_password = tokio::task::spawn_blocking(move || HashedPassword::from_plain_password(&plain_password))
         .await
         .map_err(|_| HttpError::server_error("Failed to join thread after hashing password"))?;
-    state.store.upsert_user(username, hashed_password).await?;
+    state.store.upsert_user(username, hashed_password, new_user.admin, Some(&user.name)).await?;
     Ok(Json(Empty {}))
 }
 
@@ -109,8 +177,58 @@ struct DeleteUserInput {
 }
 
 #[post("/delete_user", data = "<body>")]
-async fn delete_user(_user: User, state: State<'_, state::State>, body: Json<DeleteUserInput>) -> HttpResult<Json<Empty>> {
-    state.store.delete_user(&body.username).await?;
+async fn delete_user(user: User, state: State<'_, state::State>, body: Json<DeleteUserInput>) -> HttpResult<Json<Empty>> {
+    require_admin(&state, &user).await?;
+    state.store.delete_user(&body.username, Some(&user.name)).await?;
+    Ok(Json(Empty {}))
+}
+
+
+#[derive(Deserialize)]
+struct AddTokenInput {
+    name: String
+}
+
+#[derive(Serialize)]
+struct AddTokenOutput {
+    id: TokenId,
+    name: String,
+    /// This is the only time that the token is handed back.
+    token: String,
+    created: DateTime<Utc>
+}
+
+#[post("/add_token", data = "<body>")]
+async fn add_token(user: User, state: State<'_, state::State>, body: Json<AddTokenInput>) -> HttpResult<Json<AddTokenOutput>> {
+    let name = body.into_inner().name;
+    let id = TokenId::new();
+    let secret = crate::tokens::generate_secret();
+    let created = Utc::now();
+    state.store.upsert_token(id, user.name.clone(), name.clone(), HashedToken::from_plain_secret(&secret), created, Some(&user.name)).await?;
+    Ok(Json(AddTokenOutput { id, name, token: crate::tokens::to_token(&id, &secret), created }))
+}
+
+
+#[get("/tokens")]
+async fn tokens(user: User, state: State<'_, state::State>) -> HttpResult<Json<Vec<Token>>> {
+    let tokens = state.store.tokens(&user.name).await?;
+    Ok(Json(tokens))
+}
+
+
+#[derive(Deserialize)]
+struct RevokeTokenInput {
+    id: TokenId
+}
+
+#[post("/revoke_token", data = "<body>")]
+async fn revoke_token(user: User, state: State<'_, state::State>, body: Json<RevokeTokenInput>) -> HttpResult<Json<Empty>> {
+    // Users can only revoke their own tokens:
+    let tokens = state.store.tokens(&user.name).await?;
+    if !tokens.iter().any(|t| t.id == body.id) {
+        return Err(StoreError::TokenNotFound(body.id).into())
+    }
+    state.store.delete_token(&body.id, Some(&user.name)).await?;
     Ok(Json(Empty {}))
 }
 
@@ -124,27 +242,44 @@ struct UpsertGroupInput {
 #[derive(Serialize)]
 struct GroupOutput {
     id: GroupId,
-    name: String
+    name: String,
+    /// The role that the current user has in the group.
+    role: Role
 }
 
 #[post("/upsert_group", data = "<body>")]
-async fn upsert_group(_user: User, state: State<'_, state::State>, body: Json<UpsertGroupInput>) -> HttpResult<Json<GroupOutput>> {
+async fn upsert_group(user: User, state: State<'_, state::State>, body: Json<UpsertGroupInput>) -> HttpResult<Json<GroupOutput>> {
     let group = body.into_inner();
     let id = group.id.unwrap_or_else(GroupId::new);
-    state.store.upsert_group(id, group.name.clone()).await?;
-    Ok(Json(GroupOutput { id, name: group.name }))
+    require_not_deleted(&state, Item::Group(id)).await?;
+    let is_new = match state.store.get_group(&id).await {
+        Ok(_) => false,
+        Err(StoreError::GroupNotFound(_)) => true,
+        Err(e) => return Err(e.into())
+    };
+    if is_new {
+        // Whoever creates a group owns it:
+        state.store.upsert_group(id, group.name.clone(), Some(&user.name)).await?;
+        state.store.upsert_member(id, user.name.clone(), Role::Owner, Some(&user.name)).await?;
+    } else {
+        require_role(&state, &user, &id, Role::Owner).await?;
+        state.store.upsert_group(id, group.name.clone(), Some(&user.name)).await?;
+    }
+    Ok(Json(GroupOutput { id, name: group.name, role: Role::Owner }))
 }
 
 
 #[derive(Deserialize)]
 struct GetGroupInput {
-    id: GroupId
+    id: GroupId,
+    as_of: Option<AsOf>
 }
 
 #[post("/get_group", data = "<body>")]
-async fn get_group(_user: User, state: State<'_, state::State>, body: Json<GetGroupInput>) -> HttpResult<Json<GroupOutput>> {
-    let group = state.store.get_group(&body.id).await?;
-    Ok(Json(GroupOutput { id: group.id, name: group.name }))
+async fn get_group(user: User, state: State<'_, state::State>, body: Json<GetGroupInput>) -> HttpResult<Json<GroupOutput>> {
+    let role = require_role(&state, &user, &body.id, Role::Viewer).await?;
+    let group = store_as_of(&state, body.as_of).await?.get_group(&body.id).await?;
+    Ok(Json(GroupOutput { id: group.id, name: group.name, role }))
 }
 
 
@@ -154,8 +289,65 @@ struct DeleteGroupInput {
 }
 
 #[post("/delete_group", data = "<body>")]
-async fn delete_group(_user: User, state: State<'_, state::State>, body: Json<DeleteGroupInput>) -> HttpResult<Json<Empty>> {
-    state.store.delete_group(&body.id).await?;
+async fn delete_group(user: User, state: State<'_, state::State>, body: Json<DeleteGroupInput>) -> HttpResult<Json<Empty>> {
+    require_role(&state, &user, &body.id, Role::Owner).await?;
+    state.store.delete_group(&body.id, Some(&user.name)).await?;
+    Ok(Json(Empty {}))
+}
+
+
+#[derive(Deserialize)]
+struct MembersInput {
+    group_id: GroupId,
+    as_of: Option<AsOf>
+}
+
+#[post("/members", data = "<body>")]
+async fn members(user: User, state: State<'_, state::State>, body: Json<MembersInput>) -> HttpResult<Json<Vec<Member>>> {
+    require_role(&state, &user, &body.group_id, Role::Viewer).await?;
+    let members = store_as_of(&state, body.as_of).await?.members(&body.group_id).await?;
+    Ok(Json(members))
+}
+
+
+#[derive(Deserialize)]
+struct UpsertMemberInput {
+    group_id: GroupId,
+    username: String,
+    role: Role
+}
+
+#[post("/upsert_member", data = "<body>")]
+async fn upsert_member(user: User, state: State<'_, state::State>, body: Json<UpsertMemberInput>) -> HttpResult<Json<Empty>> {
+    let member = body.into_inner();
+    require_role(&state, &user, &member.group_id, Role::Owner).await?;
+    // Groups without members are open to everybody. Adding a member closes the group
+    // off, so make sure that whoever does so remains an owner of it:
+    let is_owning_it = member.username == user.name && member.role == Role::Owner;
+    if state.store.members(&member.group_id).await?.is_empty() && !is_owning_it {
+        state.store.upsert_member(member.group_id, user.name.clone(), Role::Owner, Some(&user.name)).await?;
+    }
+    state.store.upsert_member(member.group_id, member.username, member.role, Some(&user.name)).await?;
+    Ok(Json(Empty {}))
+}
+
+
+#[derive(Deserialize)]
+struct DeleteMemberInput {
+    group_id: GroupId,
+    /// Leave the group if not given.
+    username: Option<String>
+}
+
+#[post("/delete_member", data = "<body>")]
+async fn delete_member(user: User, state: State<'_, state::State>, body: Json<DeleteMemberInput>) -> HttpResult<Json<Empty>> {
+    let member = body.into_inner();
+    let username = member.username.unwrap_or_else(|| user.name.to_owned());
+    // Anybody can leave a group, but only owners can remove other people:
+    if username != user.name {
+        require_role(&state, &user, &member.group_id, Role::Owner).await?;
+    }
+    state.store.delete_member(&member.group_id, &username, Some(&user.name)).await?;
     Ok(Json(Empty {}))
 }
 
@@ -164,21 +356,39 @@ async fn delete_group(_user: User, state: State<'_, state::State>, body: Json<De
 struct UpsertScorableInput {
     id: Option<ScorableId>,
     group_id: GroupId,
-    name: String
+    name: String,
+    ranking: Option<Ranking>,
+    value_kind: Option<ValueKind>
 }
 
 #[derive(Serialize)]
 struct ScorableOutput {
     id: ScorableId,
-    name: String
+    group_id: GroupId,
+    name: String,
+    ranking: Ranking,
+    value_kind: ValueKind
 }
 
 #[post("/upsert_scorable", data = "<body>")]
-async fn upsert_scorable(_user: User, state: State<'_, state::State>, body: Json<UpsertScorableInput>) -> HttpResult<Json<ScorableOutput>> {
+async fn upsert_scorable(user: User, state: State<'_, state::State>, body: Json<UpsertScorableInput>) -> HttpResult<Json<ScorableOutput>> {
     let scorable = body.into_inner();
     let id = scorable.id.unwrap_or_else(ScorableId::new);
-    state.store.upsert_scorable(id, scorable.group_id, scorable.name.clone()).await?;
-    Ok(Json(ScorableOutput { id, name: scorable.name }))
+    if let Some(value_kind) = &scorable.value_kind {
+        value_kind.validate().map_err(|e| HttpError::new(400, e))?;
+    }
+    require_role(&state, &user, &scorable.group_id, Role::Editor).await?;
+    require_not_deleted(&state, Item::Scorable(id)).await?;
+    // If the scorable already exists, it may be moving from another group:
+    match state.store.get_scorable(&id).await {
+        Ok(existing) => { require_role(&state, &user, &existing.group_id, Role::Editor).await?; },
+        Err(StoreError::ScorableNotFound(_)) => {},
+        Err(e) => return Err(e.into())
+    }
+    state.store.upsert_scorable(id, scorable.group_id, scorable.name, scorable.ranking, scorable.value_kind, Some(&user.name)).await?;
+    // Some things may have been left as they were, so look up what we ended up with:
+    let scorable = state.store.get_scorable(&id).await?;
+    Ok(Json(ScorableOutput { id, group_id: scorable.group_id, name: scorable.name, ranking: scorable.ranking, value_kind: scorable.value_kind }))
 }
 
 
@@ -188,21 +398,25 @@ struct DeleteScorableInput {
 }
 
 #[post("/delete_scorable", data = "<body>")]
-async fn delete_scorable(_user: User, state: State<'_, state::State>, body: Json<DeleteScorableInput>) -> HttpResult<Json<Empty>> {
-    state.store.delete_scorable(&body.id).await?;
+async fn delete_scorable(user: User, state: State<'_, state::State>, body: Json<DeleteScorableInput>) -> HttpResult<Json<Empty>> {
+    require_scorable_role(&state, &user, &body.id, Role::Editor).await?;
+    state.store.delete_scorable(&body.id, Some(&user.name)).await?;
     Ok(Json(Empty {}))
 }
 
 
 #[derive(Deserialize)]
 struct GetScorableInput {
-    id: ScorableId
+    id: ScorableId,
+    as_of: Option<AsOf>
 }
 
 #[post("/get_scorable", data = "<body>")]
-async fn get_scorable(_user: User, state: State<'_, state::State>, body: Json<GetScorableInput>) -> HttpResult<Json<ScorableOutput>> {
-    let scorable = state.store.get_scorable(&body.id).await?;
-    Ok(Json(ScorableOutput { id: scorable.id, name: scorable.name }))
+async fn get_scorable(user: User, state: State<'_, state::State>, body: Json<GetScorableInput>) -> HttpResult<Json<ScorableOutput>> {
+    let store = store_as_of(&state, body.as_of).await?;
+    let scorable = store.get_scorable(&body.id).await?;
+    require_role(&state, &user, &scorable.group_id, Role::Viewer).await?;
+    Ok(Json(ScorableOutput { id: scorable.id, group_id: scorable.group_id, name: scorable.name, ranking: scorable.ranking, value_kind: scorable.value_kind }))
 }
 
 
@@ -211,13 +425,44 @@ struct UpsertScoreInput {
     id: Option<ScoreId>,
     scorable_id: ScorableId,
     username: Option<String>,
-    value: i64,
+    /// Anybody else to credit the score to, for team scores.
+    #[serde(default)]
+    teammates: Vec<String>,
+    /// A number or string, depending on the scorable's kind of value.
+    value: serde_json::Value,
     date: Option<DateTime<Utc>>
 }
 
 #[derive(Serialize)]
 struct UpsertScoreOutput {
-    id: ScoreId
+    id: ScoreId,
+    /// Set if the score is now the best against its scorable.
+    top_score: Option<RecordOutput>,
+    /// Each user that the score is credited to for whom it's now the best that they
+    /// have against its scorable.
+    personal_bests: Vec<PersonalBestOutput>
+}
+
+#[derive(Serialize)]
+struct PersonalBestOutput {
+    username: String,
+    #[serde(flatten)]
+    record: RecordOutput
+}
+
+#[derive(Serialize)]
+struct RecordOutput {
+    /// Who held the record before, and with what. None if there were no scores to beat.
+    previous: Option<RecordHolderOutput>
+}
+
+#[derive(Serialize)]
+struct RecordHolderOutput {
+    username: String,
+    /// The value in the scorable's kind (durations are in seconds).
+    value: serde_json::Value,
+    /// The value formatted for display, including any unit.
+    display: String
 }
 
 #[post("/upsert_score", data = "<body>")]
@@ -226,8 +471,30 @@ async fn upsert_score(user: User, state: State<'_, state::State>, body: Json<Ups
     let date = score.date.unwrap_or_else(|| Utc::now());
     let username = score.username.unwrap_or_else(|| user.name.to_owned());
     let id = score.id.unwrap_or_else(ScoreId::new);
-    state.store.upsert_score(id, score.scorable_id, username, score.value, date).await?;
-    Ok(Json(UpsertScoreOutput { id }))
+    let value_kind = require_scorable_role(&state, &user, &score.scorable_id, Role::Editor).await?.value_kind;
+    require_not_deleted(&state, Item::Score(id)).await?;
+    // If the score already exists, it may be moving from another scorable:
+    match state.store.get_score(&id).await {
+        Ok(existing) => { require_scorable_role(&state, &user, &existing.scorable_id, Role::Editor).await?; },
+        Err(StoreError::ScoreNotFound(_)) => {},
+        Err(e) => return Err(e.into())
+    }
+    let value = value_kind.parse(&score.value).map_err(|e| HttpError::new(400, e))?;
+    let records = state.store.upsert_score(id, score.scorable_id, username, score.teammates, value, date, Some(&user.name)).await?;
+    let to_output = |record: Record| RecordOutput {
+        previous: record.previous.map(|holder| RecordHolderOutput {
+            username: holder.username,
+            value: value_kind.to_json(holder.value),
+            display: value_kind.format(holder.value)
+        })
+    };
+    Ok(Json(UpsertScoreOutput {
+        id,
+        top_score: records.top_score.map(to_output),
+        personal_bests: records.personal_bests.into_iter()
+            .map(|pb| PersonalBestOutput { username: pb.username, record: to_output(pb.record) })
+            .collect()
+    }))
 }
 
 
@@ -237,39 +504,859 @@ struct DeleteScoreInput {
 }
 
 #[post("/delete_score", data = "<body>")]
-async fn delete_score(_user: User, state: State<'_, state::State>, body: Json<DeleteScoreInput>) -> HttpResult<Json<Empty>> {
-    state.store.delete_score(&body.id).await?;
+async fn delete_score(user: User, state: State<'_, state::State>, body: Json<DeleteScoreInput>) -> HttpResult<Json<Empty>> {
+    let score = state.store.get_score(&body.id).await?;
+    require_scorable_role(&state, &user, &score.scorable_id, Role::Editor).await?;
+    state.store.delete_score(&body.id, Some(&user.name)).await?;
+    Ok(Json(Empty {}))
+}
+
+
+#[derive(Deserialize)]
+struct UpsertMatchInput {
+    id: Option<MatchId>,
+    scorable_id: ScorableId,
+    participants: Vec<String>,
+    /// Who won the match, or nothing if it was a draw.
+    winner: Option<String>,
+    date: Option<DateTime<Utc>>
+}
+
+#[derive(Serialize)]
+struct UpsertMatchOutput {
+    id: MatchId
+}
+
+#[post("/upsert_match", data = "<body>")]
+async fn upsert_match(user: User, state: State<'_, state::State>, body: Json<UpsertMatchInput>) -> HttpResult<Json<UpsertMatchOutput>> {
+    let m = body.into_inner();
+    let date = m.date.unwrap_or_else(|| Utc::now());
+    let id = m.id.unwrap_or_else(MatchId::new);
+    require_scorable_role(&state, &user, &m.scorable_id, Role::Editor).await?;
+    // If the match already exists, it may be moving from another scorable:
+    match state.store.get_match(&id).await {
+        Ok(existing) => { require_scorable_role(&state, &user, &existing.scorable_id, Role::Editor).await?; },
+        Err(StoreError::MatchNotFound(_)) => {},
+        Err(e) => return Err(e.into())
+    }
+    state.store.upsert_match(id, m.scorable_id, m.participants, m.winner, date, Some(&user.name)).await?;
+    Ok(Json(UpsertMatchOutput { id }))
+}
+
+
+#[derive(Deserialize)]
+struct DeleteMatchInput {
+    id: MatchId,
+}
+
+#[post("/delete_match", data = "<body>")]
+async fn delete_match(user: User, state: State<'_, state::State>, body: Json<DeleteMatchInput>) -> HttpResult<Json<Empty>> {
+    let m = state.store.get_match(&body.id).await?;
+    require_scorable_role(&state, &user, &m.scorable_id, Role::Editor).await?;
+    state.store.delete_match(&body.id, Some(&user.name)).await?;
     Ok(Json(Empty {}))
 }
 
 
-#[get("/groups")]
-async fn groups(_user: User, state: State<'_, state::State>) -> HttpResult<Json<Vec<Group>>> {
-    let groups = state.store.groups().await?;
-    Ok(Json(groups))
+#[derive(Deserialize)]
+struct MatchesInput {
+    scorable_id: ScorableId,
+    /// Only matches that this user took part in.
+    username: Option<String>,
+    as_of: Option<AsOf>
+}
+
+#[derive(Serialize)]
+struct MatchOutput {
+    id: MatchId,
+    participants: Vec<String>,
+    /// Who won the match, or nothing if it was a draw.
+    winner: Option<String>,
+    date: DateTime<Utc>
+}
+
+#[post("/matches", data = "<body>")]
+async fn matches(user: User, state: State<'_, state::State>, body: Json<MatchesInput>) -> HttpResult<Json<Vec<MatchOutput>>> {
+    let store = store_as_of(&state, body.as_of).await?;
+    let scorable = store.get_scorable(&body.scorable_id).await?;
+    require_role(&state, &user, &scorable.group_id, Role::Viewer).await?;
+    let matches = store.matches(&body.scorable_id, body.username.as_deref()).await?
+        .into_iter()
+        .map(|m| MatchOutput { id: m.id, participants: m.participants, winner: m.winner, date: m.date })
+        .collect();
+    Ok(Json(matches))
+}
+
+
+#[derive(Deserialize)]
+struct RatingsInput {
+    scorable_id: ScorableId,
+    as_of: Option<AsOf>
+}
+
+#[derive(Serialize)]
+struct RatingOutput {
+    username: String,
+    rating: f64,
+    wins: usize,
+    draws: usize,
+    losses: usize
+}
+
+// A leaderboard of everybody that has played matches against a scorable, highest rated first.
+#[post("/ratings", data = "<body>")]
+async fn ratings(user: User, state: State<'_, state::State>, body: Json<RatingsInput>) -> HttpResult<Json<Vec<RatingOutput>>> {
+    let store = store_as_of(&state, body.as_of).await?;
+    let scorable = store.get_scorable(&body.scorable_id).await?;
+    require_role(&state, &user, &scorable.group_id, Role::Viewer).await?;
+    let ratings = store.ratings(&body.scorable_id).await?
+        .into_iter()
+        .map(|r| RatingOutput { username: r.username, rating: r.rating, wins: r.wins, draws: r.draws, losses: r.losses })
+        .collect();
+    Ok(Json(ratings))
+}
+
+
+#[derive(Deserialize)]
+struct RatingHistoryInput {
+    scorable_id: ScorableId,
+    username: String,
+    as_of: Option<AsOf>
+}
+
+#[derive(Serialize)]
+struct RatingChangeOutput {
+    match_id: MatchId,
+    date: DateTime<Utc>,
+    /// The rating after the match.
+    rating: f64,
+    /// How much the rating went up (or down) by.
+    change: f64
+}
+
+#[post("/rating_history", data = "<body>")]
+async fn rating_history(user: User, state: State<'_, state::State>, body: Json<RatingHistoryInput>) -> HttpResult<Json<Vec<RatingChangeOutput>>> {
+    let store = store_as_of(&state, body.as_of).await?;
+    let scorable = store.get_scorable(&body.scorable_id).await?;
+    require_role(&state, &user, &scorable.group_id, Role::Viewer).await?;
+    let history = store.rating_history(&body.scorable_id, &body.username).await?
+        .into_iter()
+        .map(|c| RatingChangeOutput { match_id: c.match_id, date: c.date, rating: c.rating, change: c.change })
+        .collect();
+    Ok(Json(history))
+}
+
+
+#[derive(Deserialize)]
+struct SetSeasonsInput {
+    group_id: GroupId,
+    /// Nothing to stop splitting the group's scores up into seasons.
+    seasons: Option<Seasons>
+}
+
+#[post("/set_seasons", data = "<body>")]
+async fn set_seasons(user: User, state: State<'_, state::State>, body: Json<SetSeasonsInput>) -> HttpResult<Json<Empty>> {
+    require_role(&state, &user, &body.group_id, Role::Owner).await?;
+    let input = body.into_inner();
+    state.store.set_seasons(input.group_id, input.seasons, Some(&user.name)).await?;
+    Ok(Json(Empty {}))
+}
+
+
+#[derive(Deserialize)]
+struct SeasonsInput {
+    group_id: GroupId,
+    as_of: Option<AsOf>
+}
+
+#[derive(Serialize)]
+struct SeasonsOutput {
+    /// How the group's scores are split up into seasons, if they are.
+    definition: Option<Seasons>,
+    /// Each season, oldest first.
+    seasons: Vec<SeasonOutput>
+}
+
+#[derive(Serialize)]
+struct SeasonOutput {
+    name: String,
+    start: DateTime<Utc>,
+    /// Scores on or after this date are in the next season.
+    end: DateTime<Utc>,
+    /// Is this the season that's running now?
+    current: bool,
+    finished: bool
+}
+
+impl SeasonOutput {
+    fn new(season: Season, now: DateTime<Utc>) -> SeasonOutput {
+        SeasonOutput {
+            current: season.start <= now && now < season.end,
+            finished: season.end <= now,
+            name: season.name,
+            start: season.start,
+            end: season.end
+        }
+    }
+}
+
+#[post("/seasons", data = "<body>")]
+async fn seasons(user: User, state: State<'_, state::State>, body: Json<SeasonsInput>) -> HttpResult<Json<SeasonsOutput>> {
+    let store = store_as_of(&state, body.as_of).await?;
+    require_role(&state, &user, &body.group_id, Role::Viewer).await?;
+    let definition = store.seasons(&body.group_id).await?;
+    let now = Utc::now();
+    let seasons = definition.iter()
+        .flat_map(|d| d.list(now))
+        .map(|s| SeasonOutput::new(s, now))
+        .collect();
+    Ok(Json(SeasonsOutput { definition, seasons }))
+}
+
+
+#[derive(Deserialize)]
+struct SeasonLeaderboardInput {
+    scorable_id: ScorableId,
+    /// The name of the season (the one running now if not given).
+    season: Option<String>,
+    limit: Option<usize>,
+    /// Rank each member of a team separately, rather than teams as a whole.
+    #[serde(default)]
+    by_member: bool,
+    as_of: Option<AsOf>
+}
+
+#[derive(Serialize)]
+struct SeasonLeaderboardOutput {
+    season: SeasonOutput,
+    /// The best score that each user (or team) has in the season, best first.
+    scores: Vec<ScoreOutput>
+}
+
+#[post("/season_leaderboard", data = "<body>")]
+async fn season_leaderboard(user: User, state: State<'_, state::State>, body: Json<SeasonLeaderboardInput>) -> HttpResult<Json<SeasonLeaderboardOutput>> {
+    let store = store_as_of(&state, body.as_of).await?;
+    let scorable = store.get_scorable(&body.scorable_id).await?;
+    require_role(&state, &user, &scorable.group_id, Role::Viewer).await?;
+    let input = body.into_inner();
+    let definition = store.seasons(&scorable.group_id).await?
+        .ok_or_else(|| HttpError::new(400, "the group's scores aren't split up into seasons"))?;
+    let now = Utc::now();
+    let season = match input.season {
+        Some(name) => definition.list(now).into_iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| HttpError::new(400, format!("season '{}' not found", name)))?,
+        None => definition.season_of(now)
+            .ok_or_else(|| HttpError::new(400, "no season is running now"))?
+    };
+    let filter = ScoreFilter {
+        from: Some(season.start),
+        to: Some(season.end),
+        best_per_user: true,
+        by_member: input.by_member,
+        limit: input.limit,
+        ..ScoreFilter::default()
+    };
+    let value_kind = scorable.value_kind;
+    let scores = store.scores(&input.scorable_id, &filter).await?
+        .into_iter()
+        .map(|s| ScoreOutput {
+            id: s.id,
+            username: s.username,
+            teammates: s.teammates,
+            value: value_kind.to_json(s.value),
+            display: value_kind.format(s.value),
+            date: s.date
+        })
+        .collect();
+    Ok(Json(SeasonLeaderboardOutput { season: SeasonOutput::new(season, now), scores }))
+}
+
+
+#[derive(Deserialize)]
+struct SeasonArchiveInput {
+    group_id: GroupId,
+    as_of: Option<AsOf>
+}
+
+#[derive(Serialize)]
+struct ArchivedSeasonOutput {
+    name: String,
+    start: DateTime<Utc>,
+    end: DateTime<Utc>,
+    archived: DateTime<Utc>,
+    winners: Vec<SeasonWinnerOutput>
+}
+
+#[derive(Serialize)]
+struct SeasonWinnerOutput {
+    scorable_id: ScorableId,
+    username: String,
+    teammates: Vec<String>,
+    /// The value in the scorable's kind (durations are in seconds).
+    value: serde_json::Value,
+    /// The value formatted for display, including any unit.
+    display: String
+}
+
+// The winners of each season that has finished, most recent first. Winners against
+// scorables that have since been deleted are left out.
+#[post("/season_archive", data = "<body>")]
+async fn season_archive(user: User, state: State<'_, state::State>, body: Json<SeasonArchiveInput>) -> HttpResult<Json<Vec<ArchivedSeasonOutput>>> {
+    let store = store_as_of(&state, body.as_of).await?;
+    require_role(&state, &user, &body.group_id, Role::Viewer).await?;
+    let value_kinds: std::collections::HashMap<_,_> = store.scorables_in_group(&body.group_id).await?
+        .into_iter()
+        .map(|s| (s.id, s.value_kind))
+        .collect();
+    let archive = store.season_archive(&body.group_id).await?
+        .into_iter()
+        .map(|season| ArchivedSeasonOutput {
+            name: season.name,
+            start: season.start,
+            end: season.end,
+            archived: season.archived,
+            winners: season.winners.into_iter()
+                .filter_map(|w| {
+                    let value_kind = value_kinds.get(&w.scorable_id)?;
+                    Some(SeasonWinnerOutput {
+                        scorable_id: w.scorable_id,
+                        username: w.username,
+                        teammates: w.teammates,
+                        value: value_kind.to_json(w.value),
+                        display: value_kind.format(w.value)
+                    })
+                })
+                .collect()
+        })
+        .collect();
+    Ok(Json(archive))
+}
+
+
+#[get("/groups?<as_of>&<page>&<page_size>")]
+async fn groups(user: User, state: State<'_, state::State>, as_of: Option<String>, page: Option<String>, page_size: Option<usize>) -> HttpResult<Json<Listing<GroupOutput>>> {
+    let as_of: Option<AsOf> = as_of
+        .map(|a| a.parse())
+        .transpose()
+        .map_err(|e: anyhow::Error| HttpError::new(400, e.to_string()))?;
+    let page = page.map(|p| parse_query("page", &p)).transpose()?;
+    let page = page_request(page, page_size);
+    match (as_of, page) {
+        (None, Some(page)) => {
+            let Page { items, total, prev, next } = state.store.groups_page(Some(&user.name), &page).await?;
+            let items = with_roles(&state, &user, items).await?;
+            Ok(Json(Listing::Page(Page { items, total, prev, next })))
+        },
+        (None, None) => {
+            let groups = state.store.groups(Some(&user.name)).await?;
+            Ok(Json(Listing::All(with_roles(&state, &user, groups).await?)))
+        },
+        (Some(as_of), page) => {
+            // Who can see a group is decided by their current role in it, even when looking at
+            // the past, so look at every group back then rather than those they were a member of:
+            let groups = state.store.as_of(as_of).await?.groups(None).await?;
+            let groups = with_roles(&state, &user, groups).await?;
+            Ok(Json(match page {
+                Some(page) => Listing::Page(Page::from_all(groups, &page, |g| CursorKey::Named { name: g.name.clone(), id: g.id.to_string() })),
+                None => Listing::All(groups)
+            }))
+        }
+    }
+}
+
+/// Attach the current user's role to each group, leaving out any that they have no role in.
+async fn with_roles(state: &state::State, user: &User, groups: Vec<Group>) -> HttpResult<Vec<GroupOutput>> {
+    let mut outputs = Vec::with_capacity(groups.len());
+    for group in groups {
+        match state.store.role(&group.id, &user.name).await {
+            Ok(Some(role)) => outputs.push(GroupOutput { id: group.id, name: group.name, role }),
+            // Groups from the past may have been purged since:
+            Ok(None) | Err(StoreError::GroupNotFound(_)) => {},
+            Err(e) => return Err(e.into())
+        }
+    }
+    Ok(outputs)
+}
+
+/// Listings are handed back a page at a time if a page or page size is asked
+/// for, and are otherwise handed back in full (as they were before paging).
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Listing<T> {
+    All(Vec<T>),
+    Page(Page<T>)
+}
+
+/// How many results are in each page if a page is asked for without a size.
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+fn page_request(cursor: Option<Cursor>, size: Option<usize>) -> Option<PageRequest> {
+    if cursor.is_none() && size.is_none() {
+        return None
+    }
+    Some(PageRequest { cursor, size: size.unwrap_or(DEFAULT_PAGE_SIZE) })
 }
 
 
 #[derive(Deserialize)]
 struct ScorablesInGroupInput {
     group_id: GroupId,
+    as_of: Option<AsOf>,
+    /// A page token handed back with a previous page.
+    page: Option<Cursor>,
+    page_size: Option<usize>
 }
 
 #[post("/scorables_in_group", data = "<body>")]
-async fn scorables_in_group(_user: User, state: State<'_, state::State>, body: Json<ScorablesInGroupInput>) -> HttpResult<Json<Vec<Scorable>>> {
-    let scorables = state.store.scorables_in_group(&body.group_id).await?;
-    Ok(Json(scorables))
+async fn scorables_in_group(user: User, state: State<'_, state::State>, body: Json<ScorablesInGroupInput>) -> HttpResult<Json<Listing<Scorable>>> {
+    require_role(&state, &user, &body.group_id, Role::Viewer).await?;
+    let store = store_as_of(&state, body.as_of).await?;
+    let input = body.into_inner();
+    match page_request(input.page, input.page_size) {
+        Some(page) => Ok(Json(Listing::Page(store.scorables_in_group_page(&input.group_id, &page).await?))),
+        None => Ok(Json(Listing::All(store.scorables_in_group(&input.group_id).await?)))
+    }
 }
 
 
 #[derive(Deserialize)]
 struct ScoresInput {
     scorable_id: ScorableId,
-    limit: Option<usize>
+    limit: Option<usize>,
+    /// Only scores on or after this date.
+    from: Option<DateTime<Utc>>,
+    /// Only scores before this date.
+    to: Option<DateTime<Utc>>,
+    /// Only scores belonging to these users.
+    usernames: Option<Vec<String>>,
+    /// Only the best score that each user (or team) has.
+    #[serde(default)]
+    best_per_user: bool,
+    /// Rank each member of a team separately, rather than teams as a whole.
+    #[serde(default)]
+    by_member: bool,
+    as_of: Option<AsOf>,
+    /// A page token handed back with a previous page.
+    page: Option<Cursor>,
+    page_size: Option<usize>
+}
+
+#[derive(Serialize)]
+struct ScoreOutput {
+    id: ScoreId,
+    username: String,
+    teammates: Vec<String>,
+    /// The value in the scorable's kind (durations are in seconds).
+    value: serde_json::Value,
+    /// The value formatted for display, including any unit.
+    display: String,
+    date: DateTime<Utc>
 }
 
 #[post("/scores", data = "<body>")]
-async fn scores(_user: User, state: State<'_, state::State>, body: Json<ScoresInput>) -> HttpResult<Json<Vec<Score>>> {
-    let scores = state.store.scores(&body.scorable_id, body.limit.clone()).await?;
-    Ok(Json(scores))
-}
\ No newline at end of file
+async fn scores(user: User, state: State<'_, state::State>, body: Json<ScoresInput>) -> HttpResult<Json<Listing<ScoreOutput>>> {
+    let store = store_as_of(&state, body.as_of).await?;
+    let scorable = store.get_scorable(&body.scorable_id).await?;
+    require_role(&state, &user, &scorable.group_id, Role::Viewer).await?;
+    let value_kind = scorable.value_kind;
+    let input = body.into_inner();
+    let filter = ScoreFilter {
+        from: input.from,
+        to: input.to,
+        usernames: input.usernames,
+        best_per_user: input.best_per_user,
+        by_member: input.by_member,
+        limit: input.limit
+    };
+    let to_output = |s: Score| ScoreOutput {
+        id: s.id,
+        username: s.username,
+        teammates: s.teammates,
+        value: value_kind.to_json(s.value),
+        display: value_kind.format(s.value),
+        date: s.date
+    };
+    match page_request(input.page, input.page_size) {
+        Some(page) => {
+            let scores = store.scores_page(&input.scorable_id, &filter, &page).await?;
+            Ok(Json(Listing::Page(scores.map(to_output))))
+        },
+        None => {
+            let scores = store.scores(&input.scorable_id, &filter).await?;
+            Ok(Json(Listing::All(scores.into_iter().map(to_output).collect())))
+        }
+    }
+}
+
+
+#[derive(Deserialize)]
+struct StatsInput {
+    scorable_id: ScorableId,
+    /// Only scores on or after this date.
+    from: Option<DateTime<Utc>>,
+    /// Only scores before this date.
+    to: Option<DateTime<Utc>>,
+    /// Only scores belonging to these users.
+    usernames: Option<Vec<String>>,
+    /// Give stats for each member of a team separately, rather than teams as a whole.
+    #[serde(default)]
+    by_member: bool,
+    as_of: Option<AsOf>
+}
+
+#[derive(Serialize)]
+struct StatsOutput {
+    count: usize,
+    best: Option<StatOutput>,
+    worst: Option<StatOutput>,
+    mean: Option<StatOutput>,
+    median: Option<StatOutput>,
+    std_dev: Option<StatOutput>,
+    percentiles: Vec<PercentileOutput>,
+    /// How each user (or team) has done, best first.
+    users: Vec<UserStatsOutput>
+}
+
+#[derive(Serialize)]
+struct StatOutput {
+    /// The value in the scorable's kind (durations are in seconds).
+    value: f64,
+    /// The value (rounded) formatted for display, including any unit.
+    display: String
+}
+
+#[derive(Serialize)]
+struct PercentileOutput {
+    /// This percent of scores have a value below this one.
+    percentile: u8,
+    #[serde(flatten)]
+    value: StatOutput
+}
+
+#[derive(Serialize)]
+struct UserStatsOutput {
+    username: String,
+    /// The rest of the team, if these are a team's stats.
+    teammates: Vec<String>,
+    best: StatOutput,
+    mean: StatOutput,
+    attempts: usize
+}
+
+#[post("/stats", data = "<body>")]
+async fn stats(user: User, state: State<'_, state::State>, body: Json<StatsInput>) -> HttpResult<Json<StatsOutput>> {
+    let store = store_as_of(&state, body.as_of).await?;
+    let scorable = store.get_scorable(&body.scorable_id).await?;
+    require_role(&state, &user, &scorable.group_id, Role::Viewer).await?;
+    let input = body.into_inner();
+    let filter = ScoreFilter {
+        from: input.from,
+        to: input.to,
+        usernames: input.usernames,
+        by_member: input.by_member,
+        ..ScoreFilter::default()
+    };
+    let stats = store.stats(&input.scorable_id, &filter).await?;
+    let value_kind = scorable.value_kind;
+    let to_output = |raw: f64| StatOutput {
+        value: value_kind.to_number(raw),
+        display: value_kind.format(raw.round() as i64)
+    };
+    Ok(Json(StatsOutput {
+        count: stats.count,
+        best: stats.best.map(|v| to_output(v as f64)),
+        worst: stats.worst.map(|v| to_output(v as f64)),
+        mean: stats.mean.map(to_output),
+        median: stats.median.map(to_output),
+        std_dev: stats.std_dev.map(to_output),
+        percentiles: stats.percentiles.into_iter()
+            .map(|(percentile, v)| PercentileOutput { percentile, value: to_output(v) })
+            .collect(),
+        users: stats.users.into_iter()
+            .map(|u| UserStatsOutput {
+                username: u.username,
+                teammates: u.teammates,
+                best: to_output(u.best as f64),
+                mean: to_output(u.mean),
+                attempts: u.attempts
+            })
+            .collect()
+    }))
+}
+
+
+// Every score in a group (or just one scorable in it) as CSV, so that people
+// can do their own analysis. This is a GET so that it's easy to link to.
+#[get("/export/scores.csv?<group_id>&<scorable_id>")]
+async fn export_scores_csv(user: User, state: State<'_, state::State>, group_id: Option<String>, scorable_id: Option<String>) -> HttpResult<CsvStream> {
+    let scorables = match (group_id, scorable_id) {
+        (_, Some(scorable_id)) => {
+            let scorable_id = parse_query("scorable_id", &scorable_id)?;
+            vec![require_scorable_role(&state, &user, &scorable_id, Role::Viewer).await?]
+        },
+        (Some(group_id), None) => {
+            let group_id = parse_query("group_id", &group_id)?;
+            require_role(&state, &user, &group_id, Role::Viewer).await?;
+            state.store.scorables_in_group(&group_id).await?
+        },
+        (None, None) => return Err(HttpError::new(400, "Either group_id or scorable_id must be given"))
+    };
+    Ok(CsvStream::new(state.store.clone(), scorables))
+}
+
+
+#[derive(Deserialize)]
+struct HistoryInput {
+    /// Only changes made by this user.
+    by: Option<String>,
+    /// Only changes made in this group.
+    group_id: Option<GroupId>,
+    /// Only changes made to this scorable (or its scores).
+    scorable_id: Option<ScorableId>,
+    limit: Option<usize>
+}
+
+#[post("/history", data = "<body>")]
+async fn history(user: User, state: State<'_, state::State>, body: Json<HistoryInput>) -> HttpResult<Json<Vec<HistoryEntry>>> {
+    let input = body.into_inner();
+    // Anybody can see the history of a group (or a scorable in it) that they can view,
+    // but only admins can see the history of everything (including changes to users):
+    if let Some(scorable_id) = &input.scorable_id {
+        require_scorable_role(&state, &user, scorable_id, Role::Viewer).await?;
+    }
+    if let Some(group_id) = &input.group_id {
+        require_role(&state, &user, group_id, Role::Viewer).await?;
+    }
+    if input.scorable_id.is_none() && input.group_id.is_none() {
+        require_admin(&state, &user).await?;
+    }
+    let filter = HistoryFilter {
+        by: input.by,
+        group_id: input.group_id,
+        scorable_id: input.scorable_id,
+        limit: input.limit
+    };
+    let entries = state.store.history(&filter).await?;
+    Ok(Json(entries))
+}
+
+
+#[derive(Deserialize)]
+struct TrashInput {
+    /// Only things deleted from this group.
+    group_id: Option<GroupId>
+}
+
+#[post("/trash", data = "<body>")]
+async fn trash(user: User, state: State<'_, state::State>, body: Json<TrashInput>) -> HttpResult<Json<Vec<Deleted>>> {
+    if let Some(group_id) = &body.group_id {
+        require_role(&state, &user, group_id, Role::Viewer).await?;
+        let trash = state.store.trash(Some(group_id)).await?;
+        return Ok(Json(trash))
+    }
+    // Otherwise, hand back whatever's been deleted from groups that the user can view:
+    let mut roles = std::collections::HashMap::new();
+    let mut trash = Vec::new();
+    for deleted in state.store.trash(None).await? {
+        let role = match roles.get(&deleted.group_id) {
+            Some(role) => *role,
+            None => {
+                let role = state.store.role(&deleted.group_id, &user.name).await?;
+                roles.insert(deleted.group_id, role);
+                role
+            }
+        };
+        if role.is_some() {
+            trash.push(deleted);
+        }
+    }
+    Ok(Json(trash))
+}
+
+
+#[derive(Deserialize)]
+struct RestoreInput {
+    item: Item
+}
+
+#[post("/restore", data = "<body>")]
+async fn restore(user: User, state: State<'_, state::State>, body: Json<RestoreInput>) -> HttpResult<Json<Empty>> {
+    let item = body.item;
+    // Deleted things can't be looked up as usual, so find out which group it's in from the trash:
+    let deleted = state.store.trash(None).await?
+        .into_iter()
+        .find(|d| d.item == item)
+        .ok_or(StoreError::NotDeleted(item))?;
+    // Restoring something needs the same role as deleting it did:
+    match item {
+        Item::Group(id) => {
+            require_role(&state, &user, &deleted.group_id, Role::Owner).await?;
+            state.store.restore_group(&id, Some(&user.name)).await?;
+        },
+        Item::Scorable(id) => {
+            require_role(&state, &user, &deleted.group_id, Role::Editor).await?;
+            state.store.restore_scorable(&id, Some(&user.name)).await?;
+        },
+        Item::Score(id) => {
+            require_role(&state, &user, &deleted.group_id, Role::Editor).await?;
+            state.store.restore_score(&id, Some(&user.name)).await?;
+        }
+    }
+    Ok(Json(Empty {}))
+}
+
+
+/// The error to hand back when password attempts are being throttled.
+fn too_many_attempts(wait: std::time::Duration) -> HttpError {
+    let secs = (wait.as_millis() + 999) / 1000;
+    HttpError::new(429, format!("Too many failed attempts; try again in {} seconds", secs))
+}
+
+/// The store to answer a query from: the current one, or (if `as_of` is given) a copy of it
+/// as it was at some point in the past. Access is still checked against the current store.
+async fn store_as_of(state: &state::State, as_of: Option<AsOf>) -> HttpResult<Arc<dyn Store + Send + Sync>> {
+    match as_of {
+        Some(as_of) => Ok(state.store.as_of(as_of).await?),
+        None => Ok(state.store.clone())
+    }
+}
+
+/// Parse a query parameter, or complain about it.
+fn parse_query<T: std::str::FromStr>(name: &str, value: &str) -> HttpResult<T> {
+    value.parse().map_err(|_| HttpError::new(400, format!("'{}' is not a valid {}", value, name)))
+}
+
+/// Check that the user is a server-wide admin.
+async fn require_admin(state: &state::State, user: &User) -> HttpResult<()> {
+    if state.store.is_admin(&user.name).await? {
+        Ok(())
+    } else {
+        Err(HttpError::new(403, "Only admins can do that"))
+    }
+}
+
+/// Check that the user has at least the role given in a group, handing back the role they have.
+async fn require_role(state: &state::State, user: &User, group_id: &GroupId, role: Role) -> HttpResult<Role> {
+    match state.store.role(group_id, &user.name).await? {
+        Some(r) if r >= role => Ok(r),
+        _ => Err(HttpError::new(403, "You don't have permission to do that in this group"))
+    }
+}
+
+/// Upserting something in the trash would replace it (and anything in it) for good, without
+/// checking who could see it before, so it has to be restored first.
+async fn require_not_deleted(state: &state::State, item: Item) -> HttpResult<()> {
+    if state.store.trash(None).await?.iter().any(|d| d.item == item) {
+        Err(HttpError::new(409, format!("{} is in the trash, so must be restored via /api/restore first", item)))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check that the user has at least the role given in the group that a scorable lives in, handing back the scorable.
+async fn require_scorable_role(state: &state::State, user: &User, scorable_id: &ScorableId, role: Role) -> HttpResult<Scorable> {
+    let scorable = state.store.get_scorable(scorable_id).await?;
+    require_role(state, user, &scorable.group_id, role).await?;
+    Ok(scorable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocket::http::{ ContentType, Status };
+    use rocket::local::asynchronous::Client;
+    use serde_json::json;
+    use crate::backend::BackendStore;
+    use crate::backend::testing::{ paths, add_users };
+    use crate::events::Durability;
+    use crate::login_limiter::{ LoginLimiter, LoginLimits };
+
+    /// A fresh store with some users in it, each with the password "pw".
+    async fn store_with_users(usernames: &[&str]) -> Arc<dyn Store + Send + Sync> {
+        let (backend, path) = paths().remove(0);
+        let store = BackendStore::load(backend, path, Durability::Buffered).await.unwrap();
+        add_users(&store, usernames).await;
+        store.store()
+    }
+
+    /// A client for the API that has logged in as some user.
+    async fn logged_in(store: &Arc<dyn Store + Send + Sync>, username: &str) -> Client {
+        let (_, shutting_down) = tokio::sync::watch::channel(false);
+        let mut config = rocket::config::Config::default();
+        config.secret_key = rocket::config::SecretKey::generate().unwrap();
+        let rocket = rocket::custom(config)
+            .manage(state::State {
+                store: store.clone(),
+                static_files: None,
+                previous_secret_key: None,
+                login_limiter: LoginLimiter::new(LoginLimits {
+                    free_attempts: 5,
+                    lockout_attempts: 10,
+                    lockout: std::time::Duration::from_secs(60)
+                }),
+                trusted_proxy: None,
+                shutting_down
+            })
+            .mount("/api", routes());
+        let client = Client::tracked(rocket).await.unwrap();
+        let status = post(&client, "/api/login", json!({ "username": username, "password": "pw" })).await;
+        assert_eq!(status, Status::Ok);
+        client
+    }
+
+    async fn post(client: &Client, path: &str, body: serde_json::Value) -> Status {
+        client.post(path)
+            .remote("127.0.0.1:8000".parse().unwrap())
+            .header(ContentType::JSON)
+            .body(body.to_string())
+            .dispatch()
+            .await
+            .status()
+    }
+
+    #[rocket::async_test]
+    async fn users_that_no_longer_exist_are_logged_out() {
+        let store = store_with_users(&["alice"]).await;
+        let alice = logged_in(&store, "alice").await;
+        assert_eq!(post(&alice, "/api/add_token", json!({ "name": "Script" })).await, Status::Ok);
+
+        store.delete_user("alice", None).await.unwrap();
+        assert_eq!(post(&alice, "/api/add_token", json!({ "name": "Script" })).await, Status::Unauthorized);
+        let current_user = alice.get("/api/current_user").dispatch().await.into_string().await.unwrap();
+        assert_eq!(serde_json::from_str::<serde_json::Value>(&current_user).unwrap(), json!({ "username": null, "admin": false }));
+
+        // The cookie is gone, so a new user with the same name isn't logged in by it:
+        store.upsert_user("alice".to_owned(), HashedPassword::from_plain_password("new"), None, None).await.unwrap();
+        assert_eq!(post(&alice, "/api/add_token", json!({ "name": "Script" })).await, Status::Unauthorized);
+    }
+
+    #[rocket::async_test]
+    async fn things_in_the_trash_cant_be_upserted_over() {
+        let store = store_with_users(&["owner", "other"]).await;
+        let owner = logged_in(&store, "owner").await;
+        let other = logged_in(&store, "other").await;
+        let group_id = GroupId::new();
+        let other_group_id = GroupId::new();
+        let scorable_id = ScorableId::new();
+        assert_eq!(post(&owner, "/api/upsert_group", json!({ "id": group_id, "name": "Group" })).await, Status::Ok);
+        assert_eq!(post(&owner, "/api/upsert_scorable", json!({ "id": scorable_id, "group_id": group_id, "name": "Scorable" })).await, Status::Ok);
+        assert_eq!(post(&other, "/api/upsert_group", json!({ "id": other_group_id, "name": "Other" })).await, Status::Ok);
+
+        // Somebody who isn't a member can't take over (and so purge) a deleted scorable or group:
+        assert_eq!(post(&owner, "/api/delete_scorable", json!({ "id": scorable_id })).await, Status::Ok);
+        let status = post(&other, "/api/upsert_scorable", json!({ "id": scorable_id, "group_id": other_group_id, "name": "Mine" })).await;
+        assert_eq!(status, Status::Conflict);
+        assert_eq!(post(&owner, "/api/delete_group", json!({ "id": group_id })).await, Status::Ok);
+        assert_eq!(post(&other, "/api/upsert_group", json!({ "id": group_id, "name": "Mine" })).await, Status::Conflict);
+        assert_eq!(store.role(&group_id, "other").await.unwrap(), None);
+
+        // The owner can still restore them as they were:
+        let trash: Vec<Item> = store.trash(None).await.unwrap().into_iter().map(|d| d.item).collect();
+        assert!(trash.contains(&Item::Group(group_id)));
+        assert!(trash.contains(&Item::Scorable(scorable_id)));
+        assert_eq!(post(&owner, "/api/restore", json!({ "item": { "kind": "group", "id": group_id } })).await, Status::Ok);
+        assert_eq!(post(&owner, "/api/restore", json!({ "item": { "kind": "scorable", "id": scorable_id } })).await, Status::Ok);
+        assert_eq!(store.get_scorable(&scorable_id).await.unwrap().name, "Scorable");
+    }
+}
@@ -0,0 +1,297 @@
+//! Pick between the different [`crate::store_interface::Store`] implementations
+//! that a database can be stored with.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::str::FromStr;
+use crate::events::Durability;
+use crate::persisted_store::PersistedStore;
+use crate::sqlite_store::SqliteStore;
+use crate::store_interface::{ Store, StoreError, AsOf };
+
+/// The kinds of database that we know how to work with.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Backend {
+    /// An append-only event log, replayed into memory on startup.
+    EventLog,
+    /// An SQLite database.
+    Sqlite
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eventlog" => Ok(Backend::EventLog),
+            "sqlite" => Ok(Backend::Sqlite),
+            _ => anyhow::bail!("'{}' is not a valid backend; expected 'eventlog' or 'sqlite'", s)
+        }
+    }
+}
+
+/// A loaded database, whichever backend it happens to use.
+/// This is cheap to clone, and clones share the same store.
+#[derive(Clone)]
+pub enum BackendStore {
+    EventLog(Arc<PersistedStore>),
+    Sqlite(Arc<SqliteStore>)
+}
+
+impl BackendStore {
+    /// Load the database at some path using the backend given.
+    pub async fn load(backend: Backend, file_path: PathBuf, durability: Durability) -> anyhow::Result<BackendStore> {
+        match backend {
+            Backend::EventLog => Ok(BackendStore::EventLog(Arc::new(PersistedStore::load(file_path, durability).await?))),
+            Backend::Sqlite => Ok(BackendStore::Sqlite(Arc::new(SqliteStore::load(file_path, durability).await?)))
+        }
+    }
+
+    /// Make sure that everything written so far has made it to disk.
+    pub async fn flush_to_disk(&self) -> anyhow::Result<()> {
+        match self {
+            BackendStore::EventLog(store) => store.flush_to_disk().await,
+            // Every write to SQLite is committed as it happens:
+            BackendStore::Sqlite(_) => Ok(())
+        }
+    }
+
+    /// Shrink the database down, discarding anything no longer needed to
+    /// represent its current state (including anything that's been in the
+    /// trash for longer than `trash_retention`).
+    pub async fn compact(&self, trash_retention: chrono::Duration) -> anyhow::Result<()> {
+        match self {
+            BackendStore::EventLog(store) => store.compact(trash_retention).await,
+            BackendStore::Sqlite(store) => store.compact(trash_retention).await
+        }
+    }
+
+    /// Write a new event log database to `file_path` holding the state of this one as of
+    /// some point in the past. Returns the number of events written.
+    pub async fn snapshot(&self, as_of: AsOf, file_path: PathBuf) -> anyhow::Result<usize> {
+        match self {
+            BackendStore::EventLog(store) => store.snapshot(as_of, file_path).await,
+            BackendStore::Sqlite(_) => Err(StoreError::CannotReplay.into())
+        }
+    }
+
+    /// Hand back only the `Store` interface.
+    pub fn store(&self) -> Arc<dyn Store + Send + Sync + 'static> {
+        match self {
+            BackendStore::EventLog(store) => store.clone(),
+            BackendStore::Sqlite(store) => store.clone()
+        }
+    }
+}
+
+impl std::ops::Deref for BackendStore {
+    type Target = dyn Store + Send + Sync + 'static;
+    fn deref(&self) -> &Self::Target {
+        match self {
+            BackendStore::EventLog(store) => &**store,
+            BackendStore::Sqlite(store) => &**store
+        }
+    }
+}
+
+/// Databases of each kind for tests to run against, to check that they all behave the same.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::store_interface::HashedPassword;
+
+    /// Somewhere temporary to keep a new database of each kind.
+    pub fn paths() -> Vec<(Backend, PathBuf)> {
+        [(Backend::EventLog, "db"), (Backend::Sqlite, "sqlite")].iter()
+            .map(|(backend, ext)| (*backend, std::env::temp_dir().join(format!("highscore-{}.{}", uuid::Uuid::new_v4(), ext))))
+            .collect()
+    }
+
+    /// A new database of each kind.
+    pub async fn stores() -> Vec<BackendStore> {
+        let mut stores = Vec::new();
+        for (backend, path) in paths() {
+            stores.push(BackendStore::load(backend, path, Durability::Buffered).await.unwrap());
+        }
+        stores
+    }
+
+    /// Add some users, each with the password "pw".
+    pub async fn add_users(store: &BackendStore, usernames: &[&str]) {
+        for username in usernames {
+            store.upsert_user(username.to_string(), HashedPassword::from_plain_password("pw"), None, None).await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::{ paths, stores, add_users };
+    use chrono::{ TimeZone, Utc };
+    use crate::changes::Change;
+    use crate::ratings::Ratings;
+    use crate::store_interface::{ GroupId, ScorableId, ScoreId, MatchId, Match, Role, HistoryFilter, ScoreFilter, PageRequest, Cursor, CursorKey, StoreError };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn groups_always_keep_an_owner() {
+        let is_last_owner = |res: Result<(),StoreError>| matches!(res, Err(StoreError::LastOwner(..)));
+        for store in stores().await {
+            add_users(&store, &["alice", "bob"]).await;
+            let group_id = GroupId::new();
+            store.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+            store.upsert_member(group_id, "alice".to_owned(), Role::Owner, None).await.unwrap();
+            store.upsert_member(group_id, "bob".to_owned(), Role::Editor, None).await.unwrap();
+
+            assert!(is_last_owner(store.delete_member(&group_id, "alice", None).await));
+            assert!(is_last_owner(store.upsert_member(group_id, "alice".to_owned(), Role::Editor, None).await));
+            assert!(is_last_owner(store.delete_user("alice", None).await));
+            // Groups in the trash count too, else they'd be open to everybody once restored:
+            store.delete_group(&group_id, None).await.unwrap();
+            assert!(is_last_owner(store.delete_user("alice", None).await));
+            store.restore_group(&group_id, None).await.unwrap();
+            assert_eq!(store.role(&group_id, "bob").await.unwrap(), Some(Role::Editor));
+
+            // Once somebody else owns the group, the first owner can go:
+            store.upsert_member(group_id, "bob".to_owned(), Role::Owner, None).await.unwrap();
+            store.upsert_member(group_id, "alice".to_owned(), Role::Viewer, None).await.unwrap();
+            store.delete_user("alice", None).await.unwrap();
+            assert!(!store.user_exists("alice").await.unwrap());
+            assert!(store.user_exists("bob").await.unwrap());
+            assert!(is_last_owner(store.delete_member(&group_id, "bob", None).await));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn changes_are_sent_with_their_group() {
+        for store in stores().await {
+            let mut changes = store.subscribe();
+            add_users(&store, &["alice"]).await;
+            let group_id = GroupId::new();
+            let scorable_id = ScorableId::new();
+            let score_id = ScoreId::new();
+            store.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+            store.upsert_scorable(scorable_id, group_id, "Scorable".to_owned(), None, None, None).await.unwrap();
+            store.upsert_score(score_id, scorable_id, "alice".to_owned(), Vec::new(), 1, chrono::Utc::now(), None).await.unwrap();
+            store.delete_score(&score_id, None).await.unwrap();
+
+            let mut groups = Vec::new();
+            while let Ok(notification) = changes.try_recv() {
+                groups.push(notification.group_id);
+            }
+            // Users don't belong to any group, but everything else here does:
+            assert!(groups.len() >= 5);
+            assert_eq!(groups[0], None);
+            assert!(groups[1..].iter().all(|g| *g == Some(group_id)));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compacting_keeps_history() {
+        // History entries don't compare, but their JSON does:
+        async fn history(store: &BackendStore) -> String {
+            serde_json::to_string(&store.history(&HistoryFilter::default()).await.unwrap()).unwrap()
+        }
+        for (backend, path) in paths() {
+            let store = BackendStore::load(backend, path.clone(), Durability::Buffered).await.unwrap();
+            add_users(&store, &["alice"]).await;
+            let group_id = GroupId::new();
+            store.upsert_group(group_id, "Group".to_owned(), Some("alice")).await.unwrap();
+            store.upsert_group(group_id, "Renamed".to_owned(), Some("alice")).await.unwrap();
+            store.delete_group(&group_id, Some("alice")).await.unwrap();
+            let before = history(&store).await;
+            assert!(before.contains("alice"));
+
+            // Nothing is kept in the trash, so the group is purged too:
+            store.compact(chrono::Duration::zero()).await.unwrap();
+            assert_eq!(history(&store).await, before);
+            store.flush_to_disk().await.unwrap();
+            drop(store);
+            let store = BackendStore::load(backend, path, Durability::Buffered).await.unwrap();
+            assert_eq!(history(&store).await, before);
+
+            let newest = store.history(&HistoryFilter { limit: Some(1), ..HistoryFilter::default() }).await.unwrap();
+            assert!(matches!(newest[0].change, Change::DeleteGroup { .. }));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn keeping_the_trash_for_longer_than_dates_go_back() {
+        for store in stores().await {
+            let group_id = GroupId::new();
+            store.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+            store.delete_group(&group_id, None).await.unwrap();
+            store.compact(chrono::Duration::days(36_500)).await.unwrap();
+            store.compact(chrono::Duration::max_value()).await.unwrap();
+            assert_eq!(store.trash(None).await.unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn filtering_by_dates_too_far_away_to_store() {
+        let far_future = Utc.ymd(3000, 1, 1).and_hms(0, 0, 0);
+        let far_past = Utc.ymd(1000, 1, 1).and_hms(0, 0, 0);
+        for store in stores().await {
+            add_users(&store, &["alice"]).await;
+            let group_id = GroupId::new();
+            let scorable_id = ScorableId::new();
+            store.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+            store.upsert_scorable(scorable_id, group_id, "Scorable".to_owned(), None, None, None).await.unwrap();
+            store.upsert_score(ScoreId::new(), scorable_id, "alice".to_owned(), Vec::new(), 1, Utc::now(), None).await.unwrap();
+
+            let count = |from, to| {
+                let store = store.clone();
+                async move { store.scores(&scorable_id, &ScoreFilter { from, to, ..ScoreFilter::default() }).await.unwrap().len() }
+            };
+            assert_eq!(count(Some(far_future), None).await, 0);
+            assert_eq!(count(None, Some(far_future)).await, 1);
+            assert_eq!(count(Some(far_past), None).await, 1);
+            assert_eq!(count(None, Some(far_past)).await, 0);
+
+            let page = PageRequest {
+                cursor: Some(Cursor {
+                    backwards: false,
+                    key: CursorKey::Score { order: i64::MIN, date: far_past, id: String::new(), username: String::new() }
+                }),
+                size: 10
+            };
+            let page = store.scores_page(&scorable_id, &ScoreFilter::default(), &page).await.unwrap();
+            assert_eq!(page.items.len(), 1);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn matches_played_out_of_order_are_rated_in_order() {
+        let scorable_id = ScorableId::new();
+        let results = [(5, Some("alice")), (1, Some("bob")), (4, None), (2, Some("carol")), (3, Some("alice"))];
+        let matches: Vec<_> = results.iter()
+            .map(|(day, winner)| Match {
+                id: MatchId::new(),
+                scorable_id,
+                participants: vec!["alice".to_owned(), "bob".to_owned(), "carol".to_owned()],
+                winner: winner.map(|w| w.to_owned()),
+                date: Utc.ymd(2021, 1, *day).and_hms(0, 0, 0)
+            })
+            .collect();
+        for (backend, path) in paths() {
+            let store = BackendStore::load(backend, path.clone(), Durability::Buffered).await.unwrap();
+            add_users(&store, &["alice", "bob", "carol"]).await;
+            let group_id = GroupId::new();
+            store.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+            store.upsert_scorable(scorable_id, group_id, "Scorable".to_owned(), None, None, None).await.unwrap();
+            for m in &matches {
+                store.upsert_match(m.id, scorable_id, m.participants.clone(), m.winner.clone(), m.date, None).await.unwrap();
+            }
+            let in_order = Ratings::from_matches(&matches);
+            assert_eq!(store.ratings(&scorable_id).await.unwrap(), in_order.leaderboard());
+            store.flush_to_disk().await.unwrap();
+            drop(store);
+
+            // Replaying them out of order again ends up in the same place:
+            let store = BackendStore::load(backend, path, Durability::Buffered).await.unwrap();
+            assert_eq!(store.ratings(&scorable_id).await.unwrap(), in_order.leaderboard());
+            assert_eq!(store.rating_history(&scorable_id, "bob").await.unwrap(), in_order.history("bob"));
+            store.delete_match(&matches[1].id, None).await.unwrap();
+            assert_eq!(store.ratings(&scorable_id).await.unwrap(), Ratings::from_matches(matches.iter().skip(2).chain(&matches[..1])).leaderboard());
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Stream changes to clients as server-sent events.
+
+use std::sync::Arc;
+use std::time::Duration;
+use rocket::request::Request;
+use rocket::response::{self, Response, Responder};
+use rocket::http::ContentType;
+use tokio::io::{ AsyncWriteExt, DuplexStream };
+use tokio::sync::{ broadcast, watch };
+use crate::changes::{ Change, Notification };
+use crate::store_interface::{ Store, Role };
+
+/// How often to send something, so that we notice when clients go away.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A response which streams each change that a user can see as an event named
+/// `change`. If the client falls behind and misses some changes, it's sent a
+/// `lagged` event instead, and should refetch anything it cares about.
+pub struct ChangeStream {
+    body: DuplexStream
+}
+
+impl ChangeStream {
+    /// Stream the changes received that `username` can see until the client
+    /// disconnects or `shutdown` is set to true (otherwise graceful shutdown
+    /// would wait for the stream to end).
+    pub fn new(
+        store: Arc<dyn Store + Send + Sync + 'static>,
+        username: String,
+        mut changes: broadcast::Receiver<Notification>,
+        mut shutdown: watch::Receiver<bool>
+    ) -> ChangeStream {
+        let (mut writer, body) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            loop {
+                if *shutdown.borrow() { break }
+                let message = tokio::select! {
+                    change = changes.recv() => match change {
+                        Ok(notification) => {
+                            if !can_see(&*store, &username, &notification).await { continue }
+                            match serde_json::to_string(&notification.change) {
+                                Ok(json) => format!("event: change\ndata: {}\n\n", json),
+                                Err(e) => {
+                                    log::error!("Failed to serialize change: {}", e);
+                                    continue
+                                }
+                            }
+                        },
+                        Err(broadcast::error::RecvError::Lagged(n)) => format!("event: lagged\ndata: {}\n\n", n),
+                        Err(broadcast::error::RecvError::Closed) => break
+                    },
+                    _ = tokio::time::sleep(KEEPALIVE_INTERVAL) => ": keepalive\n\n".to_owned(),
+                    // This is only ever set to true:
+                    _ = shutdown.changed() => break
+                };
+                // This fails once the client has gone away:
+                if writer.write_all(message.as_bytes()).await.is_err() { break }
+                if writer.flush().await.is_err() { break }
+            }
+        });
+        ChangeStream { body }
+    }
+}
+
+/// Changes made outside of any group are seen by everybody. Otherwise, only viewers of
+/// the group see them, along with anybody being added to or removed from it.
+async fn can_see(store: &(dyn Store + Send + Sync), username: &str, notification: &Notification) -> bool {
+    let group_id = match &notification.group_id {
+        Some(group_id) => group_id,
+        None => return true
+    };
+    match &notification.change {
+        Change::UpsertMember { username: member, .. } | Change::DeleteMember { username: member, .. } if member == username => true,
+        _ => matches!(store.role(group_id, username).await, Ok(Some(role)) if role >= Role::Viewer)
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r, 'static> for ChangeStream {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(ContentType::new("text", "event-stream"))
+            .raw_header("Cache-Control", "no-cache")
+            .streamed_body(self.body)
+            .ok()
+    }
+}
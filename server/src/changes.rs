@@ -0,0 +1,117 @@
+//! Notifications describing changes made to a store, so that anything
+//! interested can find out about them as they happen rather than polling.
+
+use serde::{ Serialize, Deserialize };
+use tokio::sync::broadcast;
+use crate::events::Event;
+use crate::store_interface::{ GroupId, ScorableId, ScoreId, MatchId };
+
+/// How many changes can be buffered up for a slow subscriber before it
+/// starts missing them (and is told that it has).
+const CHANNEL_CAPACITY: usize = 256;
+
+/// A change that has been made to a store. These say what changed but not how; clients
+/// fetch anything they're interested in (and allowed to see) themselves.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "ty")]
+pub enum Change {
+    UpsertUser { username: String },
+    DeleteUser { username: String },
+    UpsertGroup { id: GroupId },
+    DeleteGroup { id: GroupId },
+    RestoreGroup { id: GroupId },
+    SetSeasons { group_id: GroupId },
+    ArchiveSeason { group_id: GroupId, name: String },
+    UpsertMember { group_id: GroupId, username: String },
+    DeleteMember { group_id: GroupId, username: String },
+    UpsertScorable { id: ScorableId, group_id: GroupId },
+    DeleteScorable { id: ScorableId },
+    RestoreScorable { id: ScorableId },
+    UpsertScore { id: ScoreId, scorable_id: ScorableId, username: String },
+    DeleteScore { id: ScoreId },
+    RestoreScore { id: ScoreId },
+    UpsertMatch { id: MatchId, scorable_id: ScorableId },
+    DeleteMatch { id: MatchId },
+    /// Not a change in itself, but sent after `UpsertScore` if the score set a record.
+    NewRecord { id: ScoreId, scorable_id: ScorableId, username: String, record: RecordKind }
+}
+
+/// The kind of record that a score has set. New top scores are always personal bests
+/// too, but subscribers are only told about the former.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordKind {
+    TopScore,
+    PersonalBest
+}
+
+impl Change {
+    /// The change that an event describes, if it's one that subscribers are told
+    /// about. API tokens aren't; they are only of interest to their owner.
+    pub fn from_event(event: &Event) -> Option<Change> {
+        let change = match event {
+            Event::UpsertUser { username, .. } => Change::UpsertUser { username: username.clone() },
+            Event::DeleteUser { username } => Change::DeleteUser { username: username.clone() },
+            Event::UpsertToken { .. } | Event::DeleteToken { .. } | Event::Compacted => return None,
+            Event::UpsertGroup { id, .. } => Change::UpsertGroup { id: *id },
+            Event::DeleteGroup { id } => Change::DeleteGroup { id: *id },
+            Event::RestoreGroup { id } => Change::RestoreGroup { id: *id },
+            Event::SetSeasons { group_id, .. } => Change::SetSeasons { group_id: *group_id },
+            Event::ArchiveSeason { group_id, season } => {
+                Change::ArchiveSeason { group_id: *group_id, name: season.name.clone() }
+            },
+            Event::UpsertMember { group_id, username, .. } => {
+                Change::UpsertMember { group_id: *group_id, username: username.clone() }
+            },
+            Event::DeleteMember { group_id, username } => {
+                Change::DeleteMember { group_id: *group_id, username: username.clone() }
+            },
+            Event::UpsertScorable { id, group_id, .. } => {
+                Change::UpsertScorable { id: *id, group_id: *group_id }
+            },
+            Event::DeleteScorable { id } => Change::DeleteScorable { id: *id },
+            Event::RestoreScorable { id } => Change::RestoreScorable { id: *id },
+            Event::UpsertScore { id, scorable_id, username, .. } => {
+                Change::UpsertScore { id: *id, scorable_id: *scorable_id, username: username.clone() }
+            },
+            Event::DeleteScore { id } => Change::DeleteScore { id: *id },
+            Event::RestoreScore { id } => Change::RestoreScore { id: *id },
+            Event::UpsertMatch { id, scorable_id, .. } => {
+                Change::UpsertMatch { id: *id, scorable_id: *scorable_id }
+            },
+            Event::DeleteMatch { id } => Change::DeleteMatch { id: *id }
+        };
+        Some(change)
+    }
+}
+
+/// A change, and the group that it was made in (if it was made in one), so that
+/// subscribers need only be told about changes in groups that they can see.
+#[derive(Debug, Clone)]
+pub struct Notification {
+    pub group_id: Option<GroupId>,
+    pub change: Change
+}
+
+/// Broadcast changes to anybody that has subscribed.
+pub struct ChangeNotifier {
+    sender: broadcast::Sender<Notification>
+}
+
+impl ChangeNotifier {
+    pub fn new() -> ChangeNotifier {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        ChangeNotifier { sender }
+    }
+
+    /// Tell subscribers about a change made in the group given.
+    pub fn notify(&self, group_id: Option<GroupId>, change: Change) {
+        // This only fails if nobody is subscribed, which is fine:
+        let _ = self.sender.send(Notification { group_id, change });
+    }
+
+    /// Receive any changes made from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.sender.subscribe()
+    }
+}
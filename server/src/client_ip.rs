@@ -0,0 +1,29 @@
+use std::net::IpAddr;
+use rocket::request::{self, Request, FromRequest};
+use rocket::http::Status;
+use rocket::State;
+use crate::state;
+
+/// The IP address that a request came from. The `X-Real-IP` header is respected
+/// on requests from the trusted reverse proxy (if we've been told about one), so
+/// that this is still useful behind it; anybody else could set it to anything.
+pub struct ClientIp(pub IpAddr);
+
+#[rocket::async_trait]
+impl<'a, 'r> FromRequest<'a, 'r> for ClientIp {
+    type Error = ();
+
+    async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let trusted_proxy = req.guard::<State<'_, state::State>>().await
+            .succeeded()
+            .and_then(|state| state.trusted_proxy);
+        let ip = match req.remote().map(|addr| addr.ip()) {
+            Some(remote) if Some(remote) == trusted_proxy => req.real_ip().or(Some(remote)),
+            remote => remote
+        };
+        match ip {
+            Some(ip) => request::Outcome::Success(ClientIp(ip)),
+            None => request::Outcome::Failure((Status::BadRequest,()))
+        }
+    }
+}
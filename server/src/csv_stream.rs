@@ -0,0 +1,39 @@
+//! Stream scores to clients as CSV.
+
+use std::sync::Arc;
+use rocket::request::Request;
+use rocket::response::{self, Response, Responder};
+use rocket::http::ContentType;
+use tokio::io::DuplexStream;
+use crate::store_interface::{ Store, Scorable };
+use crate::scores_csv;
+
+/// A response which streams the scores against some scorables as a CSV
+/// file, fetching the scores for each scorable as it goes.
+pub struct CsvStream {
+    body: DuplexStream
+}
+
+impl CsvStream {
+    pub fn new(store: Arc<dyn Store + Send + Sync>, scorables: Vec<Scorable>) -> CsvStream {
+        let (mut writer, body) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            // This also fails if the client goes away part way through:
+            if let Err(e) = scores_csv::export(&*store, &scorables, &mut writer).await {
+                log::warn!("Stopped streaming scores as CSV: {}", e);
+            }
+        });
+        CsvStream { body }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> Responder<'r, 'static> for CsvStream {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        Response::build()
+            .header(ContentType::new("text", "csv"))
+            .raw_header("Content-Disposition", "attachment; filename=\"scores.csv\"")
+            .streamed_body(self.body)
+            .ok()
+    }
+}
@@ -0,0 +1,220 @@
+//! A versioned JSON format holding everything in a database (bar API tokens,
+//! the history of changes and the trash), so that data can be moved between
+//! backends and machines, or handed to other tools. Version 1 looks like:
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "users": [
+//!     { "username": "jim", "admin": true, "hashed_password": "$argon2i$..." }
+//!   ],
+//!   "groups": [{
+//!     "id": "7d6e...",
+//!     "name": "Games",
+//!     "members": [{ "username": "jim", "role": "owner" }],
+//!     "seasons": { "kind": "recurring", "period": "monthly", "from": "2021-01-01T00:00:00Z" },
+//!     "season_archive": [{
+//!       "name": "2021-02",
+//!       "start": "2021-02-01T00:00:00Z",
+//!       "end": "2021-03-01T00:00:00Z",
+//!       "archived": "2021-03-01T00:10:00Z",
+//!       "winners": [{ "scorable_id": "0b1c...", "username": "bob", "value": 170 }]
+//!     }],
+//!     "scorables": [{
+//!       "id": "0b1c...",
+//!       "name": "Darts",
+//!       "ranking": "higher_is_better",
+//!       "value_kind": { "kind": "integer", "unit": "points" },
+//!       "scores": [
+//!         { "id": "94fa...", "username": "jim", "value": 180, "date": "2021-03-01T12:00:00Z" },
+//!         { "id": "2e81...", "username": "bob", "teammates": ["jim"], "value": 140, "date": "2021-03-01T13:00:00Z" }
+//!       ],
+//!       "matches": [
+//!         { "id": "c3d2...", "participants": ["jim", "bob"], "winner": "jim", "date": "2021-03-02T18:00:00Z" }
+//!       ]
+//!     }]
+//!   }]
+//! }
+//! ```
+//!
+//! Roles, rankings and value kinds are as in the API. Score values are the integers that
+//! we store: decimals are multiplied by 10^precision, and durations are in milliseconds.
+//! `hashed_password` is only present if asked for when exporting, and `teammates` only
+//! for scores credited to more than one user. Matches without a `winner`
+//! were drawn, and scorables without `matches` (as in dumps from before we had them) have none.
+//! Seasons are as in the API; groups without `seasons` don't split their scores up into seasons,
+//! and those without a `season_archive` have no past winners kept.
+
+use serde::{ Serialize, Deserialize };
+use chrono::prelude::{ DateTime, Utc };
+use crate::store_interface::{ Store, StoreError, GroupId, ScorableId, ScoreId, MatchId, HashedPassword, Member, Ranking, ValueKind, Role, ScoreFilter, Seasons, ArchivedSeason };
+
+/// The version of the format that we write, and the only one that we can read.
+pub const VERSION: u32 = 1;
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct Dump {
+    pub version: u32,
+    pub users: Vec<User>,
+    pub groups: Vec<Group>
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct User {
+    pub username: String,
+    pub admin: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hashed_password: Option<HashedPassword>
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct Group {
+    pub id: GroupId,
+    pub name: String,
+    pub members: Vec<GroupMember>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seasons: Option<Seasons>,
+    #[serde(default)]
+    pub season_archive: Vec<ArchivedSeason>,
+    pub scorables: Vec<Scorable>
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct GroupMember {
+    pub username: String,
+    pub role: Role
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct Scorable {
+    pub id: ScorableId,
+    pub name: String,
+    pub ranking: Ranking,
+    pub value_kind: ValueKind,
+    pub scores: Vec<Score>,
+    #[serde(default)]
+    pub matches: Vec<Match>
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct Score {
+    pub id: ScoreId,
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub teammates: Vec<String>,
+    pub value: i64,
+    pub date: DateTime<Utc>
+}
+
+#[derive(Serialize,Deserialize,Debug,Clone)]
+pub struct Match {
+    pub id: MatchId,
+    pub participants: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub winner: Option<String>,
+    pub date: DateTime<Utc>
+}
+
+impl Dump {
+    /// Parse a dump, checking that it's a version we understand first.
+    pub fn from_json(json: &str) -> anyhow::Result<Dump> {
+        #[derive(Deserialize)]
+        struct Versioned { version: u32 }
+        let Versioned { version } = serde_json::from_str(json)?;
+        if version != VERSION {
+            anyhow::bail!("Cannot read version {} of the export format; only version {} is supported", version, VERSION);
+        }
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+/// Gather up everything in a store, including password hashes if asked for.
+pub async fn export(store: &(dyn Store + Send + Sync), password_hashes: bool) -> Result<Dump,StoreError> {
+    let mut usernames = store.users().await?;
+    usernames.sort();
+    let mut users = Vec::with_capacity(usernames.len());
+    for username in usernames {
+        let admin = store.is_admin(&username).await?;
+        let hashed_password = if password_hashes {
+            Some(store.hashed_password(&username).await?)
+        } else {
+            None
+        };
+        users.push(User { username, admin, hashed_password });
+    }
+
+    let mut groups = Vec::new();
+    for group in store.groups(None).await? {
+        let members = store.members(&group.id).await?
+            .into_iter()
+            .map(|Member { username, role }| GroupMember { username, role })
+            .collect();
+        let mut scorables = Vec::new();
+        for scorable in store.scorables_in_group(&group.id).await? {
+            let scores = store.scores(&scorable.id, &ScoreFilter::default()).await?
+                .into_iter()
+                .map(|s| Score { id: s.id, username: s.username, teammates: s.teammates, value: s.value, date: s.date })
+                .collect();
+            // Oldest first, so that ratings can be worked out as each is imported:
+            let mut matches: Vec<_> = store.matches(&scorable.id, None).await?
+                .into_iter()
+                .map(|m| Match { id: m.id, participants: m.participants, winner: m.winner, date: m.date })
+                .collect();
+            matches.reverse();
+            scorables.push(Scorable {
+                id: scorable.id,
+                name: scorable.name,
+                ranking: scorable.ranking,
+                value_kind: scorable.value_kind,
+                scores,
+                matches
+            });
+        }
+        let seasons = store.seasons(&group.id).await?;
+        // Oldest first, as they were archived:
+        let mut season_archive = store.season_archive(&group.id).await?;
+        season_archive.reverse();
+        groups.push(Group { id: group.id, name: group.name, members, seasons, season_archive, scorables });
+    }
+
+    Ok(Dump { version: VERSION, users, groups })
+}
+
+/// Add everything in a dump to a store, replacing anything with the same username or ID.
+/// Users without a password hash are left as they are if they exist already, and are
+/// otherwise given a random password (which can be changed with `highscore users add`).
+pub async fn import(store: &(dyn Store + Send + Sync), dump: Dump) -> Result<(),StoreError> {
+    let existing_users = store.users().await?;
+    for user in dump.users {
+        let hashed_password = match user.hashed_password {
+            Some(hashed_password) => hashed_password,
+            None if existing_users.contains(&user.username) => continue,
+            None => HashedPassword::random()
+        };
+        store.upsert_user(user.username, hashed_password, Some(user.admin), None).await?;
+    }
+
+    for group in dump.groups {
+        store.upsert_group(group.id, group.name, None).await?;
+        for member in group.members {
+            store.upsert_member(group.id, member.username, member.role, None).await?;
+        }
+        if group.seasons.is_some() {
+            store.set_seasons(group.id, group.seasons, None).await?;
+        }
+        for season in group.season_archive {
+            store.archive_season(group.id, season, None).await?;
+        }
+        for scorable in group.scorables {
+            let id = scorable.id;
+            store.upsert_scorable(id, group.id, scorable.name, Some(scorable.ranking), Some(scorable.value_kind), None).await?;
+            for score in scorable.scores {
+                store.upsert_score(score.id, id, score.username, score.teammates, score.value, score.date, None).await?;
+            }
+            for m in scorable.matches {
+                store.upsert_match(m.id, id, m.participants, m.winner, m.date, None).await?;
+            }
+        }
+    }
+    Ok(())
+}
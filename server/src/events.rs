@@ -3,88 +3,200 @@
 //! way for write efficiency.
 
 use chrono::prelude::{ DateTime, Utc };
-use serde::{ Serialize, Deserialize };
+use serde::{ Serialize, Deserialize, de::DeserializeOwned };
 use std::sync::Arc;
 use std::time::Duration;
 use std::path::PathBuf;
 use tokio::{io::{AsyncReadExt, AsyncWriteExt}, sync::Mutex};
 use futures::stream::Stream;
 use std::marker::Unpin;
-use crate::store_interface::{ GroupId, ScorableId, ScoreId, HashedPassword };
+use crate::store_interface::{ GroupId, ScorableId, ScoreId, MatchId, TokenId, HashedPassword, HashedToken, Ranking, ValueKind, Role, Seasons, ArchivedSeason, HistoryEntry };
 
-const FILE_HEADER: &str = "__highscore_persisted_events__";
+/// Something that can be appended to a log on disk, one per line.
+pub trait Loggable: Serialize + DeserializeOwned + Send + Sync + 'static {
+    /// Logs start with this, so that we don't read from (or append to) the wrong kind of file.
+    const FILE_HEADER: &'static str;
+}
+
+impl Loggable for LoggedEvent {
+    const FILE_HEADER: &'static str = "__highscore_persisted_events__";
+}
+
+/// The history is logged apart from events, so that it isn't lost when they're compacted.
+impl Loggable for HistoryEntry {
+    const FILE_HEADER: &'static str = "__highscore_history__";
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "ty")]
 pub enum Event {
-    /// Add/update a user in the system
-    UpsertUser { username: String, hashed_password: HashedPassword },
+    /// Add/update a user in the system. Older events have no admin flag,
+    /// which leaves the user as they were (or not an admin if new).
+    UpsertUser {
+        username: String,
+        hashed_password: HashedPassword,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        admin: Option<bool>
+    },
     /// Delete user from the system
     DeleteUser { username: String },
 
+    /// Add/update an API token belonging to a user
+    UpsertToken { id: TokenId, username: String, name: String, hashed_secret: HashedToken, created: DateTime<Utc> },
+    /// Revoke an API token
+    DeleteToken { id: TokenId },
+
     /// Add/update a group for scores to live under
     UpsertGroup { id: GroupId, name: String },
-    /// Delete a group (and everything in it)
+    /// Delete a group (and everything in it). Deleted things can be restored until they
+    /// are purged, except for those deleted before we logged when events happened.
     DeleteGroup { id: GroupId },
-
-    /// Add thing to score (and all scores against it)
-    UpsertScorable { id: ScorableId, group_id: GroupId, name: String },
+    /// Restore a deleted group
+    RestoreGroup { id: GroupId },
+    /// Set how a group's scores are split up into seasons (or stop splitting them up)
+    SetSeasons { group_id: GroupId, seasons: Option<Seasons> },
+    /// Keep the winners of a finished season in a group, replacing any of the same name
+    ArchiveSeason { group_id: GroupId, season: ArchivedSeason },
+
+    /// Add a user to a group, or change their role in it
+    UpsertMember { group_id: GroupId, username: String, role: Role },
+    /// Remove a user from a group
+    DeleteMember { group_id: GroupId, username: String },
+
+    /// Add thing to score (and all scores against it). Older events have no ranking
+    /// or value kind, which leaves them as they were (or the defaults for new scorables).
+    UpsertScorable {
+        id: ScorableId,
+        group_id: GroupId,
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ranking: Option<Ranking>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        value_kind: Option<ValueKind>
+    },
     /// Remove a thing to score (and all scores against it)
     DeleteScorable { id: ScorableId },
-
-    /// Add a score to a group at a date
-    UpsertScore { id: ScoreId, scorable_id: ScorableId, username: String, value: i64, date: DateTime<Utc> },
+    /// Restore a deleted thing to score
+    RestoreScorable { id: ScorableId },
+
+    /// Add a score to a group at a date. Older events have no teammates, as scores
+    /// could only be credited to one user.
+    UpsertScore {
+        id: ScoreId,
+        scorable_id: ScorableId,
+        username: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        teammates: Vec<String>,
+        value: i64,
+        date: DateTime<Utc>
+    },
     /// Remove a score from a group
-    DeleteScore { id: ScoreId }
+    DeleteScore { id: ScoreId },
+    /// Restore a deleted score
+    RestoreScore { id: ScoreId },
+
+    /// Add/update a match played between users against something. Matches without a winner were drawn
+    UpsertMatch {
+        id: MatchId,
+        scorable_id: ScorableId,
+        participants: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        winner: Option<String>,
+        date: DateTime<Utc>
+    },
+    /// Remove a match for good
+    DeleteMatch { id: MatchId },
+
+    /// Logged at the end of a compacted log. The events before this describe how things
+    /// were when it was logged rather than how they got that way, so the log can't be
+    /// viewed as it was any earlier.
+    Compacted
+}
+
+/// Each event is logged along with when it was written and who by (nobody if it was
+/// made from the command line). These are flattened into the event, so that events
+/// logged before we kept track of them are still valid, just without either.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LoggedEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub at: Option<DateTime<Utc>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub by: Option<String>,
+    #[serde(flatten)]
+    pub event: Event
 }
 
-struct Events {
+/// How hard do we try to get events onto disk before a write is acknowledged?
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum Durability {
+    /// Events are buffered in memory and flushed to disk periodically.
+    Buffered,
+    /// Events are written to the file (and so handed to the OS) before a write returns.
+    Flush,
+    /// Events are written and fsynced before a write returns.
+    Fsync
+}
+
+impl std::str::FromStr for Durability {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "buffered" => Ok(Durability::Buffered),
+            "flush" => Ok(Durability::Flush),
+            "fsync" => Ok(Durability::Fsync),
+            _ => anyhow::bail!("'{}' is not a valid durability; expected 'buffered', 'flush' or 'fsync'", s)
+        }
+    }
+}
+
+struct Events<T> {
     file_path: PathBuf,
-    in_memory: Arc<Mutex<Vec<Event>>>
+    durability: Durability,
+    in_memory: Arc<Mutex<Vec<T>>>
 }
 
-impl Events {
+impl<T: Loggable> Events<T> {
 
-    fn new(file_path: PathBuf) -> Events {
+    fn new(file_path: PathBuf, durability: Durability) -> Events<T> {
         Events {
             file_path,
+            durability,
             in_memory: Arc::new(Mutex::new(Vec::new()))
         }
     }
 
-    #[must_use]
-    async fn push(&self, ev: Event) {
+    async fn push(&self, ev: T) {
         self.in_memory.lock().await.push(ev)
     }
 
     /// Check that the file we've pointed to has the header expected.
     async fn nonnempty_file_is_valid_db(file: &mut tokio::fs::File) -> bool {
-        let mut buf: [u8; FILE_HEADER.len()] = [0; FILE_HEADER.len()];
+        let mut buf = vec![0; T::FILE_HEADER.len()];
         if let Err(e) = file.read_exact(&mut buf).await {
             log::error!("Cannot read header from database: {}", e);
             return false
         }
-        buf == FILE_HEADER.as_bytes()
+        buf == T::FILE_HEADER.as_bytes()
     }
 
-    async fn read_from_disk(&self) -> anyhow::Result<impl Stream<Item = Result<Event,anyhow::Error>> + Unpin + Send + Sync + 'static> {
+    async fn read_from_disk(&self) -> anyhow::Result<impl Stream<Item = Result<T,anyhow::Error>> + Unpin + Send + Sync + 'static> {
         use tokio::io::AsyncBufReadExt;
 
         // We box our resulting stream into this, so that we can return an empty
         // stream if needbe, or return a stream from the file otherwise.
-        type BoxedStream = std::pin::Pin<Box<dyn Stream<Item = Result<Event,anyhow::Error>> + Send + Sync + 'static>>;
+        type BoxedStream<T> = std::pin::Pin<Box<dyn Stream<Item = Result<T,anyhow::Error>> + Send + Sync + 'static>>;
 
         let mut file = match tokio::fs::File::open(&self.file_path).await {
             Ok(file) => file,
             Err(e) => {
                 // Not an error if no file exists yet, but something you may want to know:
                 log::debug!("Cannot read from database: {}", e);
-                return Ok::<BoxedStream,_>(Box::pin(futures::stream::empty()))
+                return Ok::<BoxedStream<T>,_>(Box::pin(futures::stream::empty()))
             }
         };
 
         // Check that the file is a valid database before buffering up.
-        if !Events::nonnempty_file_is_valid_db(&mut file).await {
+        if !Events::<T>::nonnempty_file_is_valid_db(&mut file).await {
             anyhow::bail!(
                 "File {} does not appear to be a valid database",
                 self.file_path.to_string_lossy()
@@ -124,39 +236,73 @@ impl Events {
         // we can verify it's a valid database before writing to it.
         // else, read the header into memory and confirm it's valid.
         if file.metadata().await?.len() == 0 {
-            file.write_all(FILE_HEADER.as_bytes()).await?;
-        } else if !Events::nonnempty_file_is_valid_db(&mut file).await {
+            file.write_all(T::FILE_HEADER.as_bytes()).await?;
+        } else if !Events::<T>::nonnempty_file_is_valid_db(&mut file).await {
             anyhow::bail!(
                 "File {} does not appear to be a valid database",
                 self.file_path.to_string_lossy()
             )
         }
 
-        for event in &*events {
+        Events::<T>::write_events(&mut file, &events).await?;
+        file.flush().await?;
+        if self.durability == Durability::Fsync {
+            file.sync_data().await?;
+        }
+        *events = Vec::new();
+        Ok(())
+    }
+
+    /// Replace the events on disk with those handed back by `snapshot`. This is called
+    /// while holding the lock on in-memory events, which are then discarded, and so the
+    /// snapshot must already account for them. The new file is written alongside the old
+    /// one and then moved into place, leaving a copy of the old file as a backup.
+    async fn compact<F>(&self, snapshot: F) -> anyhow::Result<usize>
+    where F: FnOnce() -> Vec<T> {
+        let mut pending = self.in_memory.lock().await;
+        let events = snapshot();
+
+        let tmp_path = path_with_suffix(&self.file_path, ".tmp");
+        let mut file = tokio::fs::File::create(&tmp_path).await?;
+        file.write_all(T::FILE_HEADER.as_bytes()).await?;
+        Events::<T>::write_events(&mut file, &events).await?;
+        file.flush().await?;
+        file.sync_all().await?;
+
+        if tokio::fs::metadata(&self.file_path).await.is_ok() {
+            tokio::fs::copy(&self.file_path, path_with_suffix(&self.file_path, ".bak")).await?;
+        }
+        tokio::fs::rename(&tmp_path, &self.file_path).await?;
+
+        *pending = Vec::new();
+        Ok(events.len())
+    }
+
+    async fn write_events(file: &mut tokio::fs::File, events: &[T]) -> anyhow::Result<()> {
+        for event in events {
             let event_json = serde_json::to_vec(&event)?;
             // Newline first prevents accidental assumptions that
             // lead to 2 events ending up on the same line:
             file.write_all(b"\n").await?;
-            file.write(&event_json).await?;
+            file.write_all(&event_json).await?;
         }
-        file.flush().await?;
-        *events = Vec::new();
         Ok(())
     }
 
 }
 
-pub struct EventHandler {
-    in_memory_events: Arc<Events>
+/// Events are appended to a log on disk. The history of changes is kept in a log of its own.
+pub struct EventHandler<T = LoggedEvent> {
+    in_memory_events: Arc<Events<T>>
 }
 
-impl EventHandler {
+impl<T: Loggable> EventHandler<T> {
 
     /// Create a new event handler by providing a path on disk to where
     /// events will be persisted. This must run in a `tokio` context.
-    pub fn new(file_path: std::path::PathBuf) -> EventHandler {
+    pub fn new(file_path: std::path::PathBuf, durability: Durability) -> EventHandler<T> {
 
-        let in_memory_events = Arc::new(Events::new(file_path));
+        let in_memory_events = Arc::new(Events::new(file_path, durability));
         let in_memory_events2 = Arc::clone(&in_memory_events);
 
         // Periodically flush events to disk. This will bail early if
@@ -175,13 +321,20 @@ impl EventHandler {
         }
     }
 
-    /// Push a new event to the stream
-    pub async fn push(&self, ev: Event) {
-        self.in_memory_events.push(ev).await
+    /// Push a new event to the stream. Unless the durability is `Buffered`, this
+    /// waits for the event to be written to disk. Events pushed by concurrent callers
+    /// while a write is in progress are all written together by whichever flush
+    /// acquires the lock next, and the rest find nothing left to do.
+    pub async fn push(&self, ev: T) -> anyhow::Result<()> {
+        self.in_memory_events.push(ev).await;
+        match self.in_memory_events.durability {
+            Durability::Buffered => Ok(()),
+            Durability::Flush | Durability::Fsync => self.in_memory_events.flush_to_disk().await
+        }
     }
 
     /// Read events from disk
-    pub async fn read_from_disk(&self) -> anyhow::Result<impl Stream<Item = Result<Event,anyhow::Error>> + Unpin> {
+    pub async fn read_from_disk(&self) -> anyhow::Result<impl Stream<Item = Result<T,anyhow::Error>> + Unpin> {
         self.in_memory_events.read_from_disk().await
     }
 
@@ -190,4 +343,18 @@ impl EventHandler {
         self.in_memory_events.flush_to_disk().await
     }
 
+    /// Rewrite the events on disk to be those returned from the `snapshot` function,
+    /// which must describe everything pushed so far. Returns the number of events written.
+    pub async fn compact<F>(&self, snapshot: F) -> anyhow::Result<usize>
+    where F: FnOnce() -> Vec<T> {
+        self.in_memory_events.compact(snapshot).await
+    }
+
+}
+
+/// Append a suffix to a path, so `foo.db` becomes eg `foo.db.bak`.
+pub fn path_with_suffix(path: &std::path::Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
 }
\ No newline at end of file
@@ -0,0 +1,163 @@
+//! Throttle failed logins, so that passwords can't be guessed at will
+//! (and so that guessing doesn't burn through our CPU hashing them).
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+
+/// How long to wait after the first failure that we start backing off from.
+/// This doubles with each failure after that.
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug,Clone,Copy)]
+pub struct LoginLimits {
+    /// How many failures are allowed before we start making people wait.
+    pub free_attempts: u32,
+    /// After this many failures, lock out further attempts for `lockout`.
+    pub lockout_attempts: u32,
+    /// How long a lockout lasts. Failures are forgotten after this long, too.
+    pub lockout: Duration
+}
+
+/// Failures are counted separately for each IP address and each username,
+/// and attempts are throttled if either has failed too often recently.
+pub struct LoginLimiter {
+    limits: LoginLimits,
+    failures: Mutex<HashMap<Key, Failures>>
+}
+
+#[derive(Clone,PartialEq,Eq,Hash)]
+enum Key {
+    Ip(IpAddr),
+    Username(String)
+}
+
+struct Failures {
+    count: u32,
+    last: Instant
+}
+
+impl LoginLimiter {
+    pub fn new(limits: LoginLimits) -> LoginLimiter {
+        LoginLimiter { limits, failures: Mutex::new(HashMap::new()) }
+    }
+
+    /// Note an attempt to log in from this IP address as this user. If attempts are being
+    /// throttled, it isn't allowed, and we hand back how long it is until another one is.
+    /// Attempts count as failures from the start (so that concurrent guesses can't all get
+    /// in before any of them have failed), until `record_success` says otherwise.
+    pub fn attempt(&self, ip: IpAddr, username: &str) -> Result<(),Duration> {
+        let now = Instant::now();
+        let lockout = self.limits.lockout;
+        let mut failures = self.failures.lock().unwrap();
+        let keys = [Key::Ip(ip), Key::Username(username.to_owned())];
+        let wait = keys.iter()
+            .filter_map(|key| failures.get(key))
+            .filter_map(|f| self.allowed_from(f))
+            .filter(|allowed_from| *allowed_from > now)
+            .map(|allowed_from| allowed_from - now)
+            .max();
+        if let Some(wait) = wait {
+            return Err(wait)
+        }
+        // Forget about anything old enough not to matter any more:
+        failures.retain(|_,f| now.duration_since(f.last) < lockout);
+        for key in keys.iter() {
+            let f = failures.entry(key.clone()).or_insert(Failures { count: 0, last: now });
+            f.count += 1;
+            f.last = now;
+        }
+        Ok(())
+    }
+
+    /// Note that an attempt to log in succeeded, forgetting about past failures to log in
+    /// as the user. Past failures from the IP address still count, since they may be
+    /// guesses at other users; only the attempt that succeeded is taken back.
+    pub fn record_success(&self, ip: IpAddr, username: &str) {
+        let mut failures = self.failures.lock().unwrap();
+        failures.remove(&Key::Username(username.to_owned()));
+        if let Some(f) = failures.get_mut(&Key::Ip(ip)) {
+            f.count = f.count.saturating_sub(1);
+        }
+    }
+
+    /// When can another attempt be made given some failures, if it's being throttled at all?
+    fn allowed_from(&self, f: &Failures) -> Option<Instant> {
+        let LoginLimits { free_attempts, lockout_attempts, lockout } = self.limits;
+        if Instant::now().duration_since(f.last) >= lockout {
+            None
+        } else if f.count >= lockout_attempts {
+            Some(f.last + lockout)
+        } else if f.count >= free_attempts {
+            let doublings = (f.count - free_attempts).min(31);
+            let backoff = BASE_BACKOFF.checked_mul(1 << doublings).unwrap_or(lockout).min(lockout);
+            Some(f.last + backoff)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{ Arc, Barrier };
+
+    fn limiter(free_attempts: u32, lockout_attempts: u32, lockout: Duration) -> LoginLimiter {
+        LoginLimiter::new(LoginLimits { free_attempts, lockout_attempts, lockout })
+    }
+
+    #[test]
+    fn failures_are_throttled() {
+        let limiter = limiter(2, 4, Duration::from_secs(60));
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let other_ip: IpAddr = "5.6.7.8".parse().unwrap();
+        assert!(limiter.attempt(ip, "bob").is_ok());
+        assert!(limiter.attempt(ip, "bob").is_ok());
+        // Further attempts from the same IP address or as the same user have to wait:
+        assert!(limiter.attempt(ip, "bob").unwrap_err() <= BASE_BACKOFF);
+        assert!(limiter.attempt(ip, "alice").is_err());
+        assert!(limiter.attempt(other_ip, "bob").is_err());
+        assert!(limiter.attempt(other_ip, "alice").is_ok());
+    }
+
+    #[test]
+    fn concurrent_attempts_are_throttled() {
+        let limiter = Arc::new(limiter(3, 10, Duration::from_secs(60)));
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        let barrier = Arc::new(Barrier::new(20));
+        let threads: Vec<_> = (0..20).map(|_| {
+            let (limiter, barrier) = (limiter.clone(), barrier.clone());
+            std::thread::spawn(move || {
+                barrier.wait();
+                limiter.attempt(ip, "bob").is_ok()
+            })
+        }).collect();
+        let allowed = threads.into_iter().map(|t| t.join().unwrap()).filter(|ok| *ok).count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[test]
+    fn success_only_forgets_the_user() {
+        let limiter = limiter(2, 10, Duration::from_secs(60));
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        // A failed guess at one user's password, then another logs in from the same IP address:
+        limiter.attempt(ip, "alice").unwrap();
+        limiter.attempt(ip, "bob").unwrap();
+        limiter.record_success(ip, "bob");
+        // The success doesn't count against the IP address, but nor is the failure forgotten:
+        assert!(limiter.attempt(ip, "carol").is_ok());
+        assert!(limiter.attempt(ip, "dave").is_err());
+    }
+
+    #[test]
+    fn failures_are_forgotten() {
+        let limiter = limiter(0, 1, Duration::from_millis(50));
+        let ip: IpAddr = "1.2.3.4".parse().unwrap();
+        assert!(limiter.attempt(ip, "bob").is_ok());
+        assert!(limiter.attempt(ip, "bob").is_err());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(limiter.attempt(ip, "bob").is_ok());
+    }
+}
@@ -2,52 +2,329 @@
 
 mod store_interface;
 mod persisted_store;
+mod sqlite_store;
+mod backend;
 mod memory_store;
 mod events;
 mod password;
+mod tokens;
+mod secret_key;
+mod login_limiter;
+mod client_ip;
 mod user;
 mod http_result;
 mod api;
 mod state;
 mod static_files;
+mod score_values;
+mod ratings;
+mod seasons;
+mod changes;
+mod dump;
+mod scores_csv;
+mod change_stream;
+mod csv_stream;
 
 use anyhow::Context;
 use structopt::StructOpt;
 use std::{path::PathBuf};
-use persisted_store::{ PersistedStore };
-use store_interface::{HashedPassword, Store};
+use backend::{ Backend, BackendStore };
+use events::Durability;
+use store_interface::{HashedPassword, HashedToken, TokenId, GroupId, ScorableId, HistoryFilter, Item, AsOf, Ranking, ValueKind};
+use scores_csv::Column;
 
 #[derive(Debug,Clone,StructOpt)]
 enum Opts {
     /// Add a user to a highscore database
     Users(Users),
+    /// Manage API tokens that scripts and bots can use to act as a user
+    Tokens(Tokens),
+    /// Work with scores in bulk
+    Scores(Scores),
+    /// Maintain a highscore database
+    Db(Db),
+    /// Work with the secret keys used to encrypt session cookies
+    Secret(Secret),
     /// Run the highscore server
     Serve(ServeOpts)
 }
 
 #[derive(Debug,Clone,StructOpt)]
 enum Users {
-    /// Add a new user
-    Add(NamedUserOpts),
+    /// Add a new user (or update an existing user's password)
+    Add(AddUserOpts),
     /// List users
-    List(UserOpts),
+    List(DatabaseOpts),
     /// Remove a user
     Remove(NamedUserOpts)
 }
 
+#[derive(Debug,Clone,StructOpt)]
+enum Tokens {
+    /// Add a new token for a user, printing it out
+    Add(AddTokenOpts),
+    /// List the tokens belonging to a user
+    List(NamedUserOpts),
+    /// Revoke a token
+    Revoke(TokenOpts)
+}
+
+#[derive(Debug,Clone,StructOpt)]
+enum Scores {
+    /// Import scores from a CSV file (for instance, one exported from a spreadsheet)
+    ImportCsv(ImportCsvOpts),
+    /// Export the scores in a group (or just one scorable in it) as CSV
+    ExportCsv(ExportCsvOpts)
+}
+
+#[derive(Debug,Clone,StructOpt)]
+enum Db {
+    /// Rewrite the database to contain only what is needed to represent its
+    /// current state. A backup of an event log database is kept alongside it
+    Compact(CompactOpts),
+    /// Show who changed what, and when (newest first)
+    History(HistoryOpts),
+    /// List deleted groups, scorables and scores that can still be restored (newest first)
+    Trash(TrashOpts),
+    /// Restore a deleted group, scorable or score
+    Restore(RestoreOpts),
+    /// Write a new database containing the state of an event log database as of some
+    /// point in the past (without the history of how it got there)
+    Snapshot(SnapshotOpts),
+    /// Write out the users, groups, scorables and scores in a database as JSON
+    Export(ExportOpts),
+    /// Add the users, groups, scorables and scores from a JSON export to a database,
+    /// creating it if it doesn't exist
+    Import(ImportOpts)
+}
+
+#[derive(Debug,Clone,StructOpt)]
+enum Secret {
+    /// Print a new random secret key, for use with `serve --secret-key-file`
+    /// or the HIGHSCORE_SECRET_KEY environment variable
+    Generate
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct AddUserOpts {
+    /// The username
+    username: String,
+    /// Make the user a server-wide admin, who can manage other users
+    #[structopt(long)]
+    admin: bool,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
 #[derive(Debug,Clone,StructOpt)]
 struct NamedUserOpts {
     /// The username
     username: String,
     #[structopt(flatten)]
-    opts: UserOpts
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct AddTokenOpts {
+    /// The user that the token acts as
+    username: String,
+    /// A name to remember the token by
+    name: String,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct TokenOpts {
+    /// The ID of the token
+    id: TokenId,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct HistoryOpts {
+    /// Only show changes made by this user
+    #[structopt(long)]
+    by: Option<String>,
+    /// Only show changes made in this group
+    #[structopt(long)]
+    group_id: Option<GroupId>,
+    /// Only show changes made to this scorable (or its scores)
+    #[structopt(long)]
+    scorable_id: Option<ScorableId>,
+    /// Show at most this many changes
+    #[structopt(long)]
+    limit: Option<usize>,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct CompactOpts {
+    /// Purge anything that was deleted more than this many days ago
+    #[structopt(long,default_value="30",parse(try_from_str = parse_retention_days))]
+    trash_retention_days: i64,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct TrashOpts {
+    /// Only list things deleted from this group
+    #[structopt(long)]
+    group_id: Option<GroupId>,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct RestoreOpts {
+    /// What kind of thing to restore ('group', 'scorable' or 'score')
+    kind: ItemKind,
+    /// The ID of the thing to restore
+    id: String,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct SnapshotOpts {
+    /// An RFC 3339 date (eg 2021-03-01T00:00:00Z) to replay changes up to, or the
+    /// number of events to replay
+    #[structopt(long)]
+    as_of: AsOf,
+    /// Where to write the new database to. This must not already exist
+    #[structopt(long,short,parse(from_os_str))]
+    output: PathBuf,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct ExportOpts {
+    /// Where to write the export to. It's printed out if not given
+    #[structopt(long,short,parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Include the hashes of users' passwords, so that they can log in
+    /// with the same passwords wherever the export is imported
+    #[structopt(long)]
+    password_hashes: bool,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct ImportOpts {
+    /// The JSON export to import
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct ImportCsvOpts {
+    /// The CSV file to import
+    #[structopt(parse(from_os_str))]
+    file: PathBuf,
+    /// The name or ID of the group to import scores into
+    #[structopt(long)]
+    group: String,
+    /// The name or ID of the scorable that every score is for. If not given,
+    /// --scorable-column says which column names the scorable in each row
+    #[structopt(long)]
+    scorable: Option<String>,
+    /// The column naming the scorable that each row is for
+    #[structopt(long)]
+    scorable_column: Option<Column>,
+    /// The column holding usernames: either its name in the header row, or a number counting from 1
+    #[structopt(long,default_value="username")]
+    username_column: Column,
+    /// The column holding score values (a name or number, as above)
+    #[structopt(long,default_value="value")]
+    value_column: Column,
+    /// The column holding the date of each score (a name or number, as above)
+    #[structopt(long,default_value="date")]
+    date_column: Column,
+    /// The file has no header row, so columns must be given by number
+    #[structopt(long)]
+    no_headers: bool,
+    /// The strftime format that dates are in (eg "%d/%m/%Y"). If not given, RFC 3339,
+    /// "YYYY-MM-DD HH:MM:SS" and "YYYY-MM-DD" dates are understood. Dates without a
+    /// timezone are taken to be UTC
+    #[structopt(long)]
+    date_format: Option<String>,
+    /// Create users that don't exist yet, with random passwords
+    #[structopt(long)]
+    create_users: bool,
+    /// Create scorables that don't exist yet
+    #[structopt(long)]
+    create_scorables: bool,
+    /// How created scorables rank scores ('higher_is_better' or 'lower_is_better')
+    #[structopt(long,default_value="higher_is_better",parse(try_from_str = parse_ranking))]
+    ranking: Ranking,
+    /// What the values of created scorables are, as JSON (eg '{"kind":"duration","precision":2}')
+    #[structopt(long,default_value=r#"{"kind":"integer"}"#,parse(try_from_str = serde_json::from_str))]
+    value_kind: ValueKind,
+    /// Report what would be imported and which rows would fail, without changing anything
+    #[structopt(long)]
+    dry_run: bool,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+#[derive(Debug,Clone,StructOpt)]
+struct ExportCsvOpts {
+    /// The name or ID of the group to export scores from
+    #[structopt(long)]
+    group: String,
+    /// The name or ID of a scorable in the group, to export only its scores
+    #[structopt(long)]
+    scorable: Option<String>,
+    /// Where to write the CSV to. It's printed out if not given
+    #[structopt(long,short,parse(from_os_str))]
+    output: Option<PathBuf>,
+    #[structopt(flatten)]
+    opts: DatabaseOpts
+}
+
+fn parse_ranking(s: &str) -> Result<Ranking, serde_json::Error> {
+    serde_json::from_value(serde_json::Value::from(s))
+}
+
+/// Things can be kept in the trash for up to a hundred years or so.
+fn parse_retention_days(s: &str) -> anyhow::Result<i64> {
+    let days: i64 = s.parse()?;
+    if !(0..=36_500).contains(&days) {
+        anyhow::bail!("expected between 0 and 36500 days, but got {}", days)
+    }
+    Ok(days)
+}
+
+#[derive(Debug,Clone,Copy)]
+enum ItemKind { Group, Scorable, Score }
+
+impl std::str::FromStr for ItemKind {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "group" => Ok(ItemKind::Group),
+            "scorable" => Ok(ItemKind::Scorable),
+            "score" => Ok(ItemKind::Score),
+            _ => anyhow::bail!("'{}' is not a valid kind; expected 'group', 'scorable' or 'score'", s)
+        }
+    }
 }
 
 #[derive(Debug,Clone,StructOpt)]
-struct UserOpts {
+struct DatabaseOpts {
     /// Where does the database live
     #[structopt(long,short)]
-    database: PathBuf
+    database: PathBuf,
+    /// What kind of database is it ('eventlog' or 'sqlite')
+    #[structopt(long,default_value="eventlog")]
+    backend: Backend
 }
 
 #[derive(Debug,Clone,StructOpt)]
@@ -63,7 +340,50 @@ struct ServeOpts {
     static_files: Option<PathBuf>,
     /// Where does the database live
     #[structopt(long,short)]
-    database: PathBuf
+    database: PathBuf,
+    /// What kind of database is it ('eventlog' or 'sqlite')
+    #[structopt(long,default_value="eventlog")]
+    backend: Backend,
+    /// Compact the database every this many minutes while serving
+    #[structopt(long)]
+    compact_interval: Option<u64>,
+    /// When compacting, purge anything that was deleted more than this many days ago
+    #[structopt(long,default_value="30",parse(try_from_str = parse_retention_days))]
+    trash_retention_days: i64,
+    /// Archive the winners of any seasons that have finished every this many minutes while serving
+    #[structopt(long)]
+    season_archive_interval: Option<u64>,
+    /// When are writes acknowledged: 'buffered' (once in memory), 'flush' (once
+    /// written to the database file) or 'fsync' (once synced to disk)
+    #[structopt(long,default_value="buffered")]
+    durability: Durability,
+    /// A file containing the secret key used to encrypt session cookies. If
+    /// neither this nor HIGHSCORE_SECRET_KEY are given, a new key is generated
+    /// each time the server starts, which logs everybody out
+    #[structopt(long,parse(from_os_str))]
+    secret_key_file: Option<PathBuf>,
+    /// A file containing a previous secret key. Session cookies encrypted with
+    /// this are still accepted (and re-encrypted with the current key), so that
+    /// keys can be rotated without logging everybody out. Can also be given
+    /// via HIGHSCORE_PREVIOUS_SECRET_KEY
+    #[structopt(long,parse(from_os_str))]
+    previous_secret_key_file: Option<PathBuf>,
+    /// How many failed logins (from one IP address, or as one user) are allowed
+    /// before each further attempt has to wait for exponentially longer
+    #[structopt(long,default_value="3")]
+    login_free_attempts: u32,
+    /// After this many failed logins (from one IP address, or as one user),
+    /// further attempts are locked out for a while
+    #[structopt(long,default_value="10")]
+    login_lockout_attempts: u32,
+    /// How many minutes a lockout lasts. Failed logins are forgotten after this long, too
+    #[structopt(long,default_value="15")]
+    login_lockout_minutes: u64,
+    /// The IP address of a reverse proxy that the server sits behind. The X-Real-IP
+    /// header it sets is trusted to say who requests from it are from, so that
+    /// failed logins can be throttled per client
+    #[structopt(long)]
+    trusted_proxy: Option<std::net::IpAddr>
 }
 
 #[tokio::main]
@@ -75,19 +395,37 @@ async fn main() -> anyhow::Result<()> {
         Opts::Users(Users::Add(opts)) => add_user(opts).await,
         Opts::Users(Users::List(opts)) => list_users(opts).await,
         Opts::Users(Users::Remove(opts)) => remove_user(opts).await,
+        Opts::Tokens(Tokens::Add(opts)) => add_token(opts).await,
+        Opts::Tokens(Tokens::List(opts)) => list_tokens(opts).await,
+        Opts::Tokens(Tokens::Revoke(opts)) => revoke_token(opts).await,
+        Opts::Scores(Scores::ImportCsv(opts)) => import_csv(opts).await,
+        Opts::Scores(Scores::ExportCsv(opts)) => export_csv(opts).await,
+        Opts::Db(Db::Compact(opts)) => compact(opts).await,
+        Opts::Db(Db::History(opts)) => history(opts).await,
+        Opts::Db(Db::Trash(opts)) => trash(opts).await,
+        Opts::Db(Db::Restore(opts)) => restore(opts).await,
+        Opts::Db(Db::Snapshot(opts)) => snapshot(opts).await,
+        Opts::Db(Db::Export(opts)) => export(opts).await,
+        Opts::Db(Db::Import(opts)) => import(opts).await,
+        Opts::Secret(Secret::Generate) => {
+            println!("{}", secret_key::generate());
+            Ok(())
+        },
         Opts::Serve(opts) => serve(opts).await
     }
 }
 
 /// Add a new user to a database, creating the file if not exists.
-async fn add_user(opts: NamedUserOpts) -> anyhow::Result<()> {
+async fn add_user(opts: AddUserOpts) -> anyhow::Result<()> {
     let username = opts.username;
     let password = prompt_for_hidden_input("Password: ").await?;
     let password = password.trim_end_matches('\n');
 
     let hashed_password = HashedPassword::from_plain_password(&password);
-    let store = PersistedStore::load(opts.opts.database).await?;
-    store.upsert_user(username.clone(), hashed_password).await?;
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    // Leave existing users' admin status alone unless asked to make them admins:
+    let admin = if opts.admin { Some(true) } else { None };
+    store.upsert_user(username.clone(), hashed_password, admin, None).await?;
     store.flush_to_disk().await?;
 
     println!("User {} added.", username);
@@ -95,47 +433,299 @@ async fn add_user(opts: NamedUserOpts) -> anyhow::Result<()> {
 }
 
 /// List users in the database
-async fn list_users(opts: UserOpts) -> anyhow::Result<()> {
-    let store = PersistedStore::load(opts.database).await?;
+async fn list_users(opts: DatabaseOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.backend, opts.database, Durability::Buffered).await?;
     let mut users = store.users().await?;
     users.sort();
     for user in users {
-        println!("{}", user);
+        if store.is_admin(&user).await? {
+            println!("{} (admin)", user);
+        } else {
+            println!("{}", user);
+        }
     }
     Ok(())
 }
 
 /// Remove a user from the database.
 async fn remove_user(opts: NamedUserOpts) -> anyhow::Result<()> {
-    let store = PersistedStore::load(opts.opts.database).await?;
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
     let username = opts.username;
-    store.delete_user(&username).await?;
+    store.delete_user(&username, None).await?;
     store.flush_to_disk().await?;
 
     println!("User {} removed.", username);
     Ok(())
 }
 
+/// Add an API token for a user. The token itself is only ever shown here.
+async fn add_token(opts: AddTokenOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    let id = TokenId::new();
+    let secret = tokens::generate_secret();
+    let hashed_secret = HashedToken::from_plain_secret(&secret);
+    store.upsert_token(id, opts.username.clone(), opts.name, hashed_secret, chrono::Utc::now(), None).await?;
+    store.flush_to_disk().await?;
+
+    println!("Token {} added for user {}. Keep it somewhere safe; it won't be shown again:", id, opts.username);
+    println!("{}", tokens::to_token(&id, &secret));
+    Ok(())
+}
+
+/// List the API tokens belonging to a user.
+async fn list_tokens(opts: NamedUserOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    for token in store.tokens(&opts.username).await? {
+        println!("{}  {}  {}", token.id, token.created.format("%Y-%m-%d %H:%M"), token.name);
+    }
+    Ok(())
+}
+
+/// Revoke an API token.
+async fn revoke_token(opts: TokenOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    store.delete_token(&opts.id, None).await?;
+    store.flush_to_disk().await?;
+
+    println!("Token {} revoked.", opts.id);
+    Ok(())
+}
+
+/// Import scores from a CSV file.
+async fn import_csv(opts: ImportCsvOpts) -> anyhow::Result<()> {
+    opts.value_kind.validate().map_err(|e| anyhow::anyhow!("Invalid value kind: {}", e))?;
+    let csv = tokio::fs::read(&opts.file).await
+        .with_context(|| format!("Could not read {}", opts.file.display()))?;
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    let group_id = find_group(&store, &opts.group).await?;
+
+    let import_opts = scores_csv::ImportOptions {
+        group_id,
+        scorable: opts.scorable,
+        scorable_column: opts.scorable_column,
+        username_column: opts.username_column,
+        value_column: opts.value_column,
+        date_column: opts.date_column,
+        has_headers: !opts.no_headers,
+        date_format: opts.date_format,
+        create_users: opts.create_users,
+        create_scorables: if opts.create_scorables { Some((opts.ranking, opts.value_kind)) } else { None },
+        dry_run: opts.dry_run
+    };
+    let report = scores_csv::import(&*store, &csv, &import_opts).await?;
+    store.flush_to_disk().await?;
+
+    let (create, import) = if opts.dry_run { ("Would create", "Would import") } else { ("Created", "Imported") };
+    for (line, e) in &report.failures {
+        println!("Line {}: {}", line, e);
+    }
+    for username in &report.created_users {
+        println!("{} user {}", create, username);
+    }
+    for scorable in &report.created_scorables {
+        println!("{} scorable {}", create, scorable);
+    }
+    println!("{} {} scores ({} rows failed).", import, report.imported, report.failures.len());
+    Ok(())
+}
+
+/// Export scores as CSV.
+async fn export_csv(opts: ExportCsvOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    let group_id = find_group(&store, &opts.group).await?;
+    let mut scorables = store.scorables_in_group(&group_id).await?;
+    if let Some(scorable) = &opts.scorable {
+        scorables.retain(|s| &s.name == scorable || &s.id.to_string() == scorable);
+        if scorables.is_empty() {
+            anyhow::bail!("There is no scorable '{}' in group '{}'", scorable, opts.group);
+        }
+    }
+    match &opts.output {
+        Some(output) => {
+            let mut file = tokio::fs::File::create(output).await?;
+            scores_csv::export(&*store, &scorables, &mut file).await?;
+            println!("Scores exported to {}.", output.display());
+        },
+        None => scores_csv::export(&*store, &scorables, &mut tokio::io::stdout()).await?
+    }
+    Ok(())
+}
+
+/// Find a group by name or ID.
+async fn find_group(store: &BackendStore, name_or_id: &str) -> anyhow::Result<GroupId> {
+    store.groups(None).await?
+        .into_iter()
+        .find(|g| g.name == name_or_id || g.id.to_string() == name_or_id)
+        .map(|g| g.id)
+        .ok_or_else(|| anyhow::anyhow!("There is no group '{}'", name_or_id))
+}
+
+/// Compact the database.
+async fn compact(opts: CompactOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    store.compact(chrono::Duration::days(opts.trash_retention_days)).await?;
+
+    println!("Database compacted.");
+    Ok(())
+}
+
+/// Print out the history of changes made to the database.
+async fn history(opts: HistoryOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    let filter = HistoryFilter {
+        by: opts.by,
+        group_id: opts.group_id,
+        scorable_id: opts.scorable_id,
+        limit: opts.limit
+    };
+    for entry in store.history(&filter).await? {
+        // Changes from before history was recorded don't say when or who:
+        let at = entry.at
+            .map(|at| at.format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or_else(|| "-".to_owned());
+        let by = entry.by.as_deref().unwrap_or("-");
+        println!("{}  {}  {}", at, by, serde_json::to_string(&entry.change)?);
+    }
+    Ok(())
+}
+
+/// List everything in the trash.
+async fn trash(opts: TrashOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    for deleted in store.trash(opts.group_id.as_ref()).await? {
+        println!("{}  {}  {}", deleted.deleted_at.format("%Y-%m-%d %H:%M:%S"), deleted.item, deleted.name);
+    }
+    Ok(())
+}
+
+/// Restore something from the trash.
+async fn restore(opts: RestoreOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    let item = match opts.kind {
+        ItemKind::Group => Item::Group(opts.id.parse()?),
+        ItemKind::Scorable => Item::Scorable(opts.id.parse()?),
+        ItemKind::Score => Item::Score(opts.id.parse()?)
+    };
+    match item {
+        Item::Group(id) => store.restore_group(&id, None).await?,
+        Item::Scorable(id) => store.restore_scorable(&id, None).await?,
+        Item::Score(id) => store.restore_score(&id, None).await?
+    }
+    store.flush_to_disk().await?;
+
+    println!("Restored {}.", item);
+    Ok(())
+}
+
+/// Write out the state of the database as of some point in the past.
+async fn snapshot(opts: SnapshotOpts) -> anyhow::Result<()> {
+    if tokio::fs::metadata(&opts.output).await.is_ok() {
+        anyhow::bail!("{} already exists", opts.output.display());
+    }
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    let n = store.snapshot(opts.as_of, opts.output.clone()).await?;
+
+    println!("Snapshot written to {} ({} events).", opts.output.display(), n);
+    Ok(())
+}
+
+/// Export the database as JSON.
+async fn export(opts: ExportOpts) -> anyhow::Result<()> {
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    let dump = dump::export(&*store, opts.password_hashes).await?;
+    let json = serde_json::to_string_pretty(&dump)?;
+    match opts.output {
+        Some(output) => {
+            tokio::fs::write(&output, json).await?;
+            println!("Database exported to {}.", output.display());
+        },
+        None => println!("{}", json)
+    }
+    Ok(())
+}
+
+/// Import a JSON export into the database.
+async fn import(opts: ImportOpts) -> anyhow::Result<()> {
+    let json = tokio::fs::read_to_string(&opts.input).await
+        .with_context(|| format!("Could not read {}", opts.input.display()))?;
+    let dump = dump::Dump::from_json(&json)?;
+    let store = BackendStore::load(opts.opts.backend, opts.opts.database, Durability::Buffered).await?;
+    let users = dump.users.len();
+    let groups = dump.groups.len();
+    dump::import(&*store, dump).await?;
+    store.flush_to_disk().await?;
+
+    println!("Imported {} users and {} groups.", users, groups);
+    Ok(())
+}
+
 /// Serve the API somewhere.
 async fn serve(opts: ServeOpts) -> anyhow::Result<()> {
     println!("{:#?}", opts);
-    let store = PersistedStore::load(opts.database).await?;
+    let store = BackendStore::load(opts.backend, opts.database, opts.durability).await?;
+
+    if let Some(mins) = opts.compact_interval {
+        let store = store.clone();
+        let trash_retention = chrono::Duration::days(opts.trash_retention_days);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(std::time::Duration::from_secs(mins * 60)).await;
+                if let Err(e) = store.compact(trash_retention).await {
+                    log::error!("Error compacting database: {}", e);
+                }
+            }
+        });
+    }
+
+    // Seasons finish as time passes rather than when anything changes, so
+    // check for any that need archiving every so often (and on startup):
+    if let Some(mins) = opts.season_archive_interval {
+        let store = store.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = seasons::archive_finished(&*store, chrono::Utc::now(), Some(seasons::ARCHIVER)).await {
+                    log::error!("Error archiving finished seasons: {}", e);
+                }
+                tokio::time::sleep(std::time::Duration::from_secs(mins.max(1) * 60)).await;
+            }
+        });
+    }
 
     let mut rocket_config = rocket::config::Config::default();
     rocket_config.port = opts.port;
     rocket_config.address = opts.address;
-    // Generate a new key each time; this will invalidate existing sessions
-    // on a restart, but that isn't really a big deal for this app:
-    rocket_config.secret_key = rocket::config::SecretKey::generate()
-        .ok_or_else(|| anyhow::anyhow!("Failed to generate a secret key: not enough system randomness"))?;
+    // Use the secret key we've been given if possible. Otherwise, generate a new
+    // key each time; this will invalidate existing sessions on a restart:
+    rocket_config.secret_key = match secret_key::load(opts.secret_key_file.as_deref(), secret_key::ENV_VAR).await? {
+        Some(key) => rocket::config::SecretKey::from(&key),
+        None => {
+            log::warn!("No secret key given; generating one, so sessions won't survive a restart");
+            rocket::config::SecretKey::generate()
+                .ok_or_else(|| anyhow::anyhow!("Failed to generate a secret key: not enough system randomness"))?
+        }
+    };
+    let previous_secret_key = secret_key::load(opts.previous_secret_key_file.as_deref(), secret_key::PREVIOUS_ENV_VAR).await?
+        .map(|key| cookie::Key::from(&key));
+    // We handle shutdown signals ourselves (see below):
+    rocket_config.ctrlc = false;
+
+    let (shutting_down_tx, shutting_down) = tokio::sync::watch::channel(false);
 
     let mut rocket = rocket::custom(rocket_config)
         .manage(state::State {
             // Ensure that we don't need anything more than what
             // the `store_interface::Store` trait provides by
             // only providing that.
-            store: Box::new(store),
-            static_files: opts.static_files.clone()
+            store: store.store(),
+            static_files: opts.static_files.clone(),
+            previous_secret_key,
+            login_limiter: login_limiter::LoginLimiter::new(login_limiter::LoginLimits {
+                free_attempts: opts.login_free_attempts,
+                lockout_attempts: opts.login_lockout_attempts,
+                lockout: std::time::Duration::from_secs(opts.login_lockout_minutes * 60)
+            }),
+            trusted_proxy: opts.trusted_proxy,
+            shutting_down
         })
         .mount("/api", api::routes());
 
@@ -146,11 +736,42 @@ async fn serve(opts: ServeOpts) -> anyhow::Result<()> {
         rocket = rocket.mount("/", static_files::static_files_route());
     }
 
+    // On SIGINT/SIGTERM, stop accepting requests and let those in flight finish.
+    // Once Rocket has stopped, nothing else can be written, so flush anything
+    // still buffered to disk before exiting.
+    let shutdown = rocket.shutdown();
+    tokio::spawn(async move {
+        match wait_for_shutdown_signal().await {
+            Ok(()) => log::info!("Shutdown signal received; shutting down"),
+            Err(e) => log::error!("Cannot listen for shutdown signals, shutting down: {}", e)
+        }
+        // End any event streams, else we'd wait on them forever:
+        let _ = shutting_down_tx.send(true);
+        shutdown.notify();
+    });
+
     rocket.launch().await?;
+    store.flush_to_disk().await?;
 
     Ok(())
 }
 
+/// Wait for Ctrl-C/SIGINT, or SIGTERM on unix platforms.
+async fn wait_for_shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{ signal, SignalKind };
+        let mut sigterm = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = sigterm.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
 /// Prompt for password-like input (input is hidden)
 async fn prompt_for_hidden_input(msg: &str) -> anyhow::Result<String> {
     let msg = msg.to_owned();
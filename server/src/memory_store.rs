@@ -2,86 +2,226 @@
 //! implementation of [`crate::store_interface::Store`]. We prefer to use the
 //! persisted_store though, which builds persistence on top of this.
 
-use std::{collections::HashMap, sync::MutexGuard};
+use std::{collections::{ HashMap, HashSet }, sync::{ Arc, MutexGuard }};
 use std::sync::Mutex;
 use chrono::prelude::{ DateTime, Utc };
-use crate::events::{ Event, EventHandler };
-use crate::store_interface::{ self, Store, StoreError, GroupId, ScorableId, ScoreId, HashedPassword };
+use tokio::sync::broadcast;
+use crate::changes::{ Change, ChangeNotifier, Notification };
+use crate::events::{ Event, EventHandler, LoggedEvent };
+use crate::ratings::Ratings;
+use crate::store_interface::{ self, Store, StoreError, GroupId, ScorableId, ScoreId, MatchId, TokenId, HashedPassword, HashedToken, Ranking, ValueKind, Role, Member, Item, Deleted, HistoryEntry, HistoryFilter, ScoreFilter, Page, PageRequest, CursorKey, Stats, Records, RecordHolder, Rating, RatingChange, Seasons, ArchivedSeason, AsOf };
 
 pub struct MemoryStore {
-    inner: Mutex<MemoryStoreInner>
+    inner: Mutex<MemoryStoreInner>,
+    changes: ChangeNotifier
 }
 
 struct MemoryStoreInner {
     /// When was the last change made? This may update despite
     /// no changes being made, but must update if changes are made.
     last_changed: DateTime<Utc>,
-    /// Users (mapping of username to password)
-    users: HashMap<String, HashedPassword>,
+    /// Users (mapping of username to password and so on)
+    users: HashMap<String, User>,
+    /// API tokens belonging to users
+    tokens: HashMap<TokenId, Token>,
     /// Groups of scorables that themselves have scores on
     scores: HashMap<GroupId, Group>,
     // Indexes:
     scorable_to_group: HashMap<ScorableId, GroupId>,
-    score_to_scorable: HashMap<ScoreId, ScorableId>
+    score_to_scorable: HashMap<ScoreId, ScorableId>,
+    match_to_scorable: HashMap<MatchId, ScorableId>,
+    /// Every change made, oldest first (or since the history was last taken,
+    /// if it's being kept elsewhere)
+    history: Vec<HistoryEntry>
 }
 
 impl MemoryStore {
-    /// Load data in from persisted events.
-    pub async fn from_events(events: &EventHandler) -> anyhow::Result<MemoryStore> {
+    /// Load data in from persisted events, stopping at `as_of` if given to
+    /// see how things were at that point.
+    pub async fn from_events(events: &EventHandler, as_of: Option<AsOf>) -> Result<MemoryStore,StoreError> {
         use futures::stream::StreamExt;
         let mut data = MemoryStoreInner {
             last_changed: Utc::now(),
             users: HashMap::new(),
+            tokens: HashMap::new(),
             scores: HashMap::new(),
             // Indexes:
             scorable_to_group: HashMap::new(),
             score_to_scorable: HashMap::new(),
+            match_to_scorable: HashMap::new(),
+            history: Vec::new()
         };
-        let mut event_stream = events.read_from_disk().await?;
-        while let Some(event) = event_stream.next().await {
-            let event = event?;
+        let mut event_stream = events.read_from_disk().await?.enumerate();
+        while let Some((index, event)) = event_stream.next().await {
+            let LoggedEvent { at, by, event } = event?;
+            if let Event::Compacted = event {
+                // How things were before this is lost, so there's nothing to see there:
+                match as_of {
+                    Some(as_of) if !as_of.includes(index, at) => {
+                        return Err(StoreError::CompactedSince(at.unwrap_or_else(Utc::now)))
+                    },
+                    _ => continue
+                }
+            }
+            // Events are logged in order, but concurrent writes may disagree a little
+            // on when they were made, so skip anything unwanted rather than stopping:
+            if let Some(as_of) = as_of {
+                if !as_of.includes(index, at) { continue }
+            }
+            // Work out where the change was made before making it, so that we
+            // still know where anything being deleted lived:
+            let entry = Change::from_event(&event)
+                .map(|change| data.history_entry(at, by.as_deref(), change));
             match event {
-                Event::UpsertUser { username, hashed_password } => {
-                    data.users.insert(username, hashed_password);
+                Event::UpsertUser { username, hashed_password, admin } => {
+                    if let Err(e) = data.upsert_user(username, hashed_password, admin) {
+                        log::warn!("Ignoring event UpsertUser: {}", e);
+                        continue
+                    }
                 }
                 Event::DeleteUser { username } => {
                     if let Err(e) = data.delete_user(&username) {
                         log::warn!("Ignoring event DeleteUser: {}", e);
+                        continue
+                    }
+                }
+                Event::UpsertToken { id, username, name, hashed_secret, created } => {
+                    if let Err(e) = data.upsert_token(id, username, name, hashed_secret, created) {
+                        log::warn!("Ignoring event UpsertToken: {}", e);
+                        continue
+                    }
+                }
+                Event::DeleteToken { id } => {
+                    if let Err(e) = data.delete_token(&id) {
+                        log::warn!("Ignoring event DeleteToken: {}", e);
+                        continue
                     }
                 }
                 Event::UpsertGroup { id, name } => {
                     if let Err(e) = data.upsert_group(id, name) {
                         log::warn!("Ignoring event UpsertGroup: {}", e);
+                        continue
                     }
                 }
                 Event::DeleteGroup { id } => {
-                    if let Err(e) = data.delete_group(&id) {
+                    if let Err(e) = data.delete_group(&id, at) {
                         log::warn!("Ignoring event DeleteGroup: {}", e);
+                        continue
+                    }
+                }
+                Event::RestoreGroup { id } => {
+                    if let Err(e) = data.restore_group(&id) {
+                        log::warn!("Ignoring event RestoreGroup: {}", e);
+                        continue
+                    }
+                }
+                Event::SetSeasons { group_id, seasons } => {
+                    if let Err(e) = data.set_seasons(&group_id, seasons) {
+                        log::warn!("Ignoring event SetSeasons: {}", e);
+                        continue
+                    }
+                }
+                Event::ArchiveSeason { group_id, season } => {
+                    if let Err(e) = data.archive_season(&group_id, season) {
+                        log::warn!("Ignoring event ArchiveSeason: {}", e);
+                        continue
+                    }
+                }
+                Event::UpsertMember { group_id, username, role } => {
+                    if let Err(e) = data.upsert_member(group_id, username, role) {
+                        log::warn!("Ignoring event UpsertMember: {}", e);
+                        continue
+                    }
+                }
+                Event::DeleteMember { group_id, username } => {
+                    if let Err(e) = data.delete_member(&group_id, &username) {
+                        log::warn!("Ignoring event DeleteMember: {}", e);
+                        continue
                     }
                 }
-                Event::UpsertScorable { id, group_id, name } => {
-                    if let Err(e) = data.upsert_scorable(id, group_id, name) {
+                Event::UpsertScorable { id, group_id, name, ranking, value_kind } => {
+                    if let Err(e) = data.upsert_scorable(id, group_id, name, ranking, value_kind) {
                         log::warn!("Ignoring event AddScorable: {}", e);
+                        continue
                     }
                 }
                 Event::DeleteScorable { id } => {
-                    if let Err(e) = data.delete_scorable(&id) {
+                    if let Err(e) = data.delete_scorable(&id, at) {
                         log::warn!("Ignoring event DeleteScorable: {}", e);
+                        continue
                     }
                 }
-                Event::UpsertScore { id, scorable_id, username, value, date } => {
-                    if let Err(e) = data.upsert_score(id, scorable_id, username, value, date) {
+                Event::RestoreScorable { id } => {
+                    if let Err(e) = data.restore_scorable(&id) {
+                        log::warn!("Ignoring event RestoreScorable: {}", e);
+                        continue
+                    }
+                }
+                Event::UpsertScore { id, scorable_id, username, teammates, value, date } => {
+                    if let Err(e) = data.upsert_score(id, scorable_id, username, teammates, value, date) {
                         log::warn!("Ignoring event AddScore: {}", e);
+                        continue
                     }
                 }
                 Event::DeleteScore { id } => {
-                    if let Err(e) = data.delete_score(&id) {
+                    if let Err(e) = data.delete_score(&id, at) {
                         log::warn!("Ignoring event DeleteScore: {}", e);
+                        continue
                     }
                 }
+                Event::RestoreScore { id } => {
+                    if let Err(e) = data.restore_score(&id) {
+                        log::warn!("Ignoring event RestoreScore: {}", e);
+                        continue
+                    }
+                }
+                Event::UpsertMatch { id, scorable_id, participants, winner, date } => {
+                    if let Err(e) = data.upsert_match(id, scorable_id, participants, winner, date) {
+                        log::warn!("Ignoring event UpsertMatch: {}", e);
+                        continue
+                    }
+                }
+                Event::DeleteMatch { id } => {
+                    if let Err(e) = data.delete_match(&id) {
+                        log::warn!("Ignoring event DeleteMatch: {}", e);
+                        continue
+                    }
+                }
+                // Dealt with before anything else:
+                Event::Compacted => {}
+            }
+            if let Some(entry) = entry {
+                data.history.push(entry);
             }
         }
-        Ok(MemoryStore { inner: Mutex::new(data) })
+        Ok(MemoryStore { inner: Mutex::new(data), changes: ChangeNotifier::new() })
+    }
+    /// The smallest set of events that will recreate the current state of the store,
+    /// leaving out anything deleted before `purge_before` if it's given.
+    pub fn to_events(&self, purge_before: Option<DateTime<Utc>>) -> Vec<LoggedEvent> {
+        self.lock().to_events(purge_before)
+    }
+    /// Take the history of changes made so far, leaving it empty. This is for keeping
+    /// the history somewhere other than in memory, where it would grow without bound.
+    pub fn take_history(&self) -> Vec<HistoryEntry> {
+        std::mem::take(&mut self.lock().history)
+    }
+    /// Permanently remove anything that was deleted before the time given.
+    pub fn purge_deleted(&self, before: DateTime<Utc>) {
+        self.lock().purge_deleted(before)
+    }
+    // Make a change to the inner store. If it succeeds, record it in the
+    // history and tell subscribers about it.
+    fn change<T, F>(&self, by: Option<&str>, change: Change, f: F) -> Result<T,StoreError>
+    where F: FnOnce(&mut MemoryStoreInner) -> Result<T,StoreError> {
+        let mut inner = self.lock();
+        let entry = inner.history_entry(Some(Utc::now()), by, change.clone());
+        let res = f(&mut inner)?;
+        let group_id = entry.group_id;
+        inner.history.push(entry);
+        drop(inner);
+        self.changes.notify(group_id, change);
+        Ok(res)
     }
     // A convenience to lock the inner store briefly so that we can call things against it.
     fn lock(&self) -> MutexGuard<MemoryStoreInner> {
@@ -96,12 +236,19 @@ impl Store for MemoryStore {
     async fn last_changed(&self) -> DateTime<Utc> {
         self.lock().last_changed()
     }
+    fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.changes.subscribe()
+    }
 
     async fn users(&self) -> Result<Vec<String>,StoreError> {
         self.lock().users()
     }
-    async fn upsert_user(&self, username: String, password: HashedPassword) -> Result<(),StoreError> {
-        self.lock().upsert_user(username, password)
+    async fn user_exists(&self, username: &str) -> Result<bool,StoreError> {
+        Ok(self.lock().user_exists(username))
+    }
+    async fn upsert_user(&self, username: String, password: HashedPassword, admin: Option<bool>, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::UpsertUser { username: username.clone() };
+        self.change(by, change, |inner| inner.upsert_user(username, password, admin))
     }
     async fn check_user(&self, username: &str, password: &str) -> Result<bool,StoreError> {
         let hashed_password = self.lock()
@@ -112,45 +259,183 @@ impl Store for MemoryStore {
         );
         Ok(res)
     }
-    async fn delete_user(&self, username: &str) -> Result<(),StoreError> {
-        self.lock().delete_user(username)
+    async fn hashed_password(&self, username: &str) -> Result<HashedPassword,StoreError> {
+        self.lock()
+            .get_hashed_password(username)
+            .ok_or_else(|| StoreError::UserNotFound(username.to_owned()))
+    }
+    async fn is_admin(&self, username: &str) -> Result<bool,StoreError> {
+        self.lock().is_admin(username)
+    }
+    async fn delete_user(&self, username: &str, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::DeleteUser { username: username.to_owned() };
+        self.change(by, change, |inner| {
+            inner.check_owner_remains(None, username)?;
+            inner.delete_user(username)
+        })
     }
 
-    async fn upsert_group(&self, id: GroupId, name: String) -> Result<(),StoreError> {
-        self.lock().upsert_group(id, name)
+    // Nobody is told about changes to tokens; they are only of interest to their owner.
+    async fn upsert_token(&self, id: TokenId, username: String, name: String, hashed_secret: HashedToken, created: DateTime<Utc>, _by: Option<&str>) -> Result<(),StoreError> {
+        self.lock().upsert_token(id, username, name, hashed_secret, created)
+    }
+    async fn tokens(&self, username: &str) -> Result<Vec<store_interface::Token>,StoreError> {
+        self.lock().tokens(username)
+    }
+    async fn check_token(&self, id: &TokenId, secret: &str) -> Result<Option<store_interface::Token>,StoreError> {
+        Ok(self.lock().check_token(id, secret))
     }
-    async fn delete_group(&self, id: &GroupId) -> Result<(),StoreError> {
-        self.lock().delete_group(id)
+    async fn delete_token(&self, id: &TokenId, _by: Option<&str>) -> Result<(),StoreError> {
+        self.lock().delete_token(id)
+    }
+
+    async fn upsert_group(&self, id: GroupId, name: String, by: Option<&str>) -> Result<(),StoreError> {
+        self.change(by, Change::UpsertGroup { id }, |inner| inner.upsert_group(id, name))
+    }
+    async fn delete_group(&self, id: &GroupId, by: Option<&str>) -> Result<(),StoreError> {
+        self.change(by, Change::DeleteGroup { id: *id }, |inner| inner.delete_group(id, Some(Utc::now())))
     }
     async fn get_group(&self, id: &GroupId) -> Result<store_interface::Group,StoreError> {
         self.lock().get_group(id)
     }
 
-    async fn upsert_scorable(&self, id: ScorableId, group_id: GroupId, name: String) -> Result<(),StoreError> {
-        self.lock().upsert_scorable(id, group_id, name)
+    async fn upsert_member(&self, group_id: GroupId, username: String, role: Role, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::UpsertMember { group_id, username: username.clone() };
+        self.change(by, change, |inner| {
+            if role != Role::Owner {
+                inner.check_owner_remains(Some(&group_id), &username)?;
+            }
+            inner.upsert_member(group_id, username, role)
+        })
+    }
+    async fn delete_member(&self, group_id: &GroupId, username: &str, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::DeleteMember { group_id: *group_id, username: username.to_owned() };
+        self.change(by, change, |inner| {
+            inner.check_owner_remains(Some(group_id), username)?;
+            inner.delete_member(group_id, username)
+        })
+    }
+    async fn members(&self, group_id: &GroupId) -> Result<Vec<Member>,StoreError> {
+        self.lock().members(group_id)
+    }
+    async fn role(&self, group_id: &GroupId, username: &str) -> Result<Option<Role>,StoreError> {
+        self.lock().role(group_id, username)
+    }
+
+    async fn upsert_scorable(&self, id: ScorableId, group_id: GroupId, name: String, ranking: Option<Ranking>, value_kind: Option<ValueKind>, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::UpsertScorable { id, group_id };
+        self.change(by, change, |inner| inner.upsert_scorable(id, group_id, name, ranking, value_kind))
     }
-    async fn delete_scorable(&self, id: &ScorableId) -> Result<(),StoreError> {
-        self.lock().delete_scorable(id)
+    async fn delete_scorable(&self, id: &ScorableId, by: Option<&str>) -> Result<(),StoreError> {
+        self.change(by, Change::DeleteScorable { id: *id }, |inner| inner.delete_scorable(id, Some(Utc::now())))
     }
     async fn get_scorable(&self, id: &ScorableId) -> Result<store_interface::Scorable,StoreError> {
         self.lock().get_scorable(id)
     }
 
-    async fn upsert_score(&self, id: ScoreId, scorable_id: ScorableId, username: String, value: i64, date: DateTime<Utc>) -> Result<(),StoreError> {
-        self.lock().upsert_score(id, scorable_id, username, value, date)
+    async fn upsert_score(&self, id: ScoreId, scorable_id: ScorableId, username: String, teammates: Vec<String>, value: i64, date: DateTime<Utc>, by: Option<&str>) -> Result<Records,StoreError> {
+        let change = Change::UpsertScore { id, scorable_id, username: username.clone() };
+        let records = self.change(by, change, |inner| inner.upsert_score(id, scorable_id, username.clone(), teammates, value, date))?;
+        let group_id = self.lock().scorable_to_group.get(&scorable_id).copied();
+        for change in records.changes(id, scorable_id, &username) {
+            self.changes.notify(group_id, change);
+        }
+        Ok(records)
+    }
+    async fn delete_score(&self, id: &ScoreId, by: Option<&str>) -> Result<(),StoreError> {
+        self.change(by, Change::DeleteScore { id: *id }, |inner| inner.delete_score(id, Some(Utc::now())))
     }
-    async fn delete_score(&self, id: &ScoreId) -> Result<(),StoreError> {
-        self.lock().delete_score(id)
+    async fn get_score(&self, id: &ScoreId) -> Result<store_interface::Score,StoreError> {
+        self.lock().get_score(id)
     }
 
-    async fn groups(&self) -> Result<Vec<crate::store_interface::Group>,StoreError> {
-        self.lock().groups()
+    async fn upsert_match(&self, id: MatchId, scorable_id: ScorableId, participants: Vec<String>, winner: Option<String>, date: DateTime<Utc>, by: Option<&str>) -> Result<(),StoreError> {
+        self.change(by, Change::UpsertMatch { id, scorable_id }, |inner| inner.upsert_match(id, scorable_id, participants, winner, date))
+    }
+    async fn delete_match(&self, id: &MatchId, by: Option<&str>) -> Result<(),StoreError> {
+        self.change(by, Change::DeleteMatch { id: *id }, |inner| inner.delete_match(id))
+    }
+    async fn get_match(&self, id: &MatchId) -> Result<store_interface::Match,StoreError> {
+        self.lock().get_match(id)
+    }
+    async fn matches(&self, scorable_id: &ScorableId, username: Option<&str>) -> Result<Vec<store_interface::Match>,StoreError> {
+        self.lock().get_matches(scorable_id, username)
+    }
+    async fn ratings(&self, scorable_id: &ScorableId) -> Result<Vec<Rating>,StoreError> {
+        Ok(self.lock().scorable_mut(scorable_id)?.ratings(*scorable_id).leaderboard())
+    }
+    async fn rating_history(&self, scorable_id: &ScorableId, username: &str) -> Result<Vec<RatingChange>,StoreError> {
+        Ok(self.lock().scorable_mut(scorable_id)?.ratings(*scorable_id).history(username))
+    }
+
+    async fn set_seasons(&self, group_id: GroupId, seasons: Option<Seasons>, by: Option<&str>) -> Result<(),StoreError> {
+        // Checked here rather than on replay, so that seasons that were allowed before still load:
+        if let Some(seasons) = &seasons {
+            seasons.check(Utc::now())?;
+        }
+        self.change(by, Change::SetSeasons { group_id }, |inner| inner.set_seasons(&group_id, seasons))
+    }
+    async fn seasons(&self, group_id: &GroupId) -> Result<Option<Seasons>,StoreError> {
+        Ok(self.lock().group(group_id)?.seasons.clone())
+    }
+    async fn archive_season(&self, group_id: GroupId, season: ArchivedSeason, by: Option<&str>) -> Result<(),StoreError> {
+        // Like SQLite, refuse dates that can't be stored (again, not on replay):
+        for date in &[season.start, season.end, season.archived] {
+            store_interface::date_to_nanos(*date)?;
+        }
+        let change = Change::ArchiveSeason { group_id, name: season.name.clone() };
+        self.change(by, change, |inner| inner.archive_season(&group_id, season))
+    }
+    async fn season_archive(&self, group_id: &GroupId) -> Result<Vec<ArchivedSeason>,StoreError> {
+        self.lock().season_archive(group_id)
+    }
+
+    async fn restore_group(&self, id: &GroupId, by: Option<&str>) -> Result<(),StoreError> {
+        self.change(by, Change::RestoreGroup { id: *id }, |inner| inner.restore_group(id))
+    }
+    async fn restore_scorable(&self, id: &ScorableId, by: Option<&str>) -> Result<(),StoreError> {
+        self.change(by, Change::RestoreScorable { id: *id }, |inner| inner.restore_scorable(id))
+    }
+    async fn restore_score(&self, id: &ScoreId, by: Option<&str>) -> Result<(),StoreError> {
+        self.change(by, Change::RestoreScore { id: *id }, |inner| inner.restore_score(id))
+    }
+    async fn trash(&self, group_id: Option<&GroupId>) -> Result<Vec<Deleted>,StoreError> {
+        Ok(self.lock().trash(group_id))
+    }
+
+    async fn groups(&self, member: Option<&str>) -> Result<Vec<crate::store_interface::Group>,StoreError> {
+        self.lock().groups(member)
     }
     async fn scorables_in_group(&self, group_id: &GroupId) -> Result<Vec<store_interface::Scorable>,StoreError> {
         self.lock().scorables_in_group(group_id)
     }
-    async fn scores(&self, scorable_id: &ScorableId, limit: Option<usize>) -> Result<Vec<store_interface::Score>,StoreError> {
-        self.lock().get_scores(scorable_id, limit)
+    async fn scores(&self, scorable_id: &ScorableId, filter: &ScoreFilter) -> Result<Vec<store_interface::Score>,StoreError> {
+        self.lock().get_scores(scorable_id, filter)
+    }
+    async fn groups_page(&self, member: Option<&str>, page: &PageRequest) -> Result<Page<store_interface::Group>,StoreError> {
+        let groups = self.lock().groups(member)?;
+        Ok(Page::from_all(groups, page, CursorKey::of_group))
+    }
+    async fn scorables_in_group_page(&self, group_id: &GroupId, page: &PageRequest) -> Result<Page<store_interface::Scorable>,StoreError> {
+        let scorables = self.lock().scorables_in_group(group_id)?;
+        Ok(Page::from_all(scorables, page, CursorKey::of_scorable))
+    }
+    async fn scores_page(&self, scorable_id: &ScorableId, filter: &ScoreFilter, page: &PageRequest) -> Result<Page<store_interface::Score>,StoreError> {
+        let inner = self.lock();
+        let ranking = inner.scorable(scorable_id)?.1.ranking;
+        let scores = inner.get_scores(scorable_id, filter)?;
+        Ok(Page::from_all(scores, page, |score| CursorKey::of_score(ranking, score)))
+    }
+    async fn stats(&self, scorable_id: &ScorableId, filter: &ScoreFilter) -> Result<Stats,StoreError> {
+        let scores = self.lock().get_scores(scorable_id, filter)?;
+        Ok(Stats::from_scores(&scores, filter))
+    }
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>,StoreError> {
+        Ok(self.lock().history(filter))
+    }
+    async fn as_of(&self, _as_of: AsOf) -> Result<Arc<dyn Store + Send + Sync>,StoreError> {
+        // We only know how things are now, not how they got that way:
+        Err(StoreError::CannotReplay)
     }
 }
 
@@ -159,119 +444,390 @@ impl MemoryStoreInner {
     pub fn users(&self) -> Result<Vec<String>,StoreError> {
         Ok(self.users.keys().map(|u| u.to_owned()).collect())
     }
-    pub fn upsert_user(&mut self, username: String, hashed_password: HashedPassword) -> Result<(),StoreError> {
+    pub fn user_exists(&self, username: &str) -> bool {
+        self.users.contains_key(username)
+    }
+    pub fn upsert_user(&mut self, username: String, hashed_password: HashedPassword, admin: Option<bool>) -> Result<(),StoreError> {
         self.update_last_changed();
-        self.users.insert(username, hashed_password);
+        match self.users.get_mut(&username) {
+            Some(user) => {
+                user.hashed_password = hashed_password;
+                if let Some(admin) = admin {
+                    user.admin = admin;
+                }
+            },
+            None => {
+                self.users.insert(username, User { hashed_password, admin: admin.unwrap_or(false) });
+            }
+        }
         Ok(())
     }
     pub fn get_hashed_password(&self, username: &str) -> Option<HashedPassword> {
-        self.users.get(username).map(|u| u.clone())
+        self.users.get(username).map(|u| u.hashed_password.clone())
+    }
+    pub fn is_admin(&self, username: &str) -> Result<bool,StoreError> {
+        self.users.get(username)
+            .map(|u| u.admin)
+            .ok_or_else(|| StoreError::UserNotFound(username.to_owned()))
     }
     pub fn delete_user(&mut self, username: &str) -> Result<(),StoreError> {
         self.update_last_changed();
         self.users.remove(username)
             .ok_or_else(|| StoreError::UserNotFound(username.to_owned()))?;
-        // Remove all scores associated with this user, too:
+        // Remove all tokens, memberships, scores and matches associated with this user, too.
+        // Scores shared with teammates are kept for the rest of the team:
+        self.tokens.retain(|_,t| t.username != username);
         for group in self.scores.values_mut() {
-            for scores in group.scorables.values_mut() {
-                scores.scores.retain(|_,s| s.username != username);
+            group.members.remove(username);
+            for scorable in group.scorables.values_mut() {
+                scorable.scores.retain(|_,s| s.uncredit(username));
+                let played = scorable.matches.len();
+                scorable.matches.retain(|_,m| !m.participants.iter().any(|p| p == username));
+                if scorable.matches.len() != played {
+                    scorable.rerate_later();
+                }
             }
         }
         Ok(())
     }
 
+    // Working with API tokens
+    pub fn upsert_token(&mut self, id: TokenId, username: String, name: String, hashed_secret: HashedToken, created: DateTime<Utc>) -> Result<(),StoreError> {
+        store_interface::date_to_nanos(created)?;
+        if !self.users.contains_key(&username) {
+            return Err(StoreError::UserNotFound(username));
+        }
+        self.tokens.insert(id, Token { username, name, hashed_secret, created });
+        Ok(())
+    }
+    pub fn tokens(&self, username: &str) -> Result<Vec<store_interface::Token>,StoreError> {
+        if !self.users.contains_key(username) {
+            return Err(StoreError::UserNotFound(username.to_owned()));
+        }
+        let mut tokens: Vec<_> = self.tokens
+            .iter()
+            .filter(|(_,t)| t.username == username)
+            .map(|(id,t)| t.to_token(*id))
+            .collect();
+        tokens.sort_by(|a,b| a.created.cmp(&b.created).then_with(|| a.id.cmp(&b.id)));
+        Ok(tokens)
+    }
+    pub fn check_token(&self, id: &TokenId, secret: &str) -> Option<store_interface::Token> {
+        self.tokens.get(id)
+            .filter(|t| t.hashed_secret.verify_plain_secret(secret))
+            .map(|t| t.to_token(*id))
+    }
+    pub fn delete_token(&mut self, id: &TokenId) -> Result<(),StoreError> {
+        self.tokens.remove(id)
+            .ok_or(StoreError::TokenNotFound(*id))
+            .map(|_| ())
+    }
+
     // Editing Groups
     pub fn upsert_group(&mut self, id: GroupId, name: String) -> Result<(),StoreError> {
         self.update_last_changed();
-        self.scores
+        let group = self.scores
             .entry(id)
-            .or_insert_with(|| Group::empty())
-            .name = name;
+            .or_insert_with(|| Group::empty());
+        // Upserting a deleted group replaces it, as if it had been purged:
+        if group.deleted.is_some() {
+            *group = Group::empty();
+        }
+        group.name = name;
         Ok(())
     }
-    pub fn delete_group(&mut self, id: &GroupId) -> Result<(),StoreError> {
+    /// Deleted groups can be restored, unless we don't know when they were deleted (because
+    /// it was logged before we kept track of such things), in which case they're gone for good.
+    pub fn delete_group(&mut self, id: &GroupId, at: Option<DateTime<Utc>>) -> Result<(),StoreError> {
+        self.group(id)?;
         self.update_last_changed();
-        self.scores.remove(id)
-            .ok_or(StoreError::GroupNotFound(*id))
-            .map(|_| ())
+        match at {
+            Some(at) => { self.group_mut(id)?.deleted = Some(at); },
+            None => { self.scores.remove(id); }
+        }
+        Ok(())
+    }
+    pub fn restore_group(&mut self, id: &GroupId) -> Result<(),StoreError> {
+        let group = self.scores.get_mut(id)
+            .ok_or(StoreError::GroupNotFound(*id))?;
+        if group.deleted.take().is_none() {
+            return Err(StoreError::NotDeleted(Item::Group(*id)))
+        }
+        self.update_last_changed();
+        Ok(())
     }
     pub fn get_group(&self, id: &GroupId) -> Result<store_interface::Group,StoreError> {
-        self.scores.get(id)
+        self.group(id)
             .map(|g| store_interface::Group { id: *id, name: g.name.to_owned() })
-            .ok_or(StoreError::GroupNotFound(*id))
     }
 
-    // Editing Scorables
-    pub fn upsert_scorable(&mut self, id: ScorableId, group_id: GroupId, name: String) -> Result<(),StoreError> {
-        self.update_last_changed();
-        if let Some(group) = self.scores.get_mut(&group_id) {
-            group.scorables
-                .entry(id)
-                .or_insert_with(|| Scorable::empty())
-                .name = name;
-            self.scorable_to_group.insert(id, group_id);
-            Ok(())
-        } else {
-            Err(StoreError::GroupNotFound(group_id))
+    // Editing group members
+    pub fn upsert_member(&mut self, group_id: GroupId, username: String, role: Role) -> Result<(),StoreError> {
+        if !self.users.contains_key(&username) {
+            return Err(StoreError::UserNotFound(username));
         }
+        self.update_last_changed();
+        self.group_mut(&group_id)?
+            .members.insert(username, role);
+        Ok(())
     }
-    pub fn delete_scorable(&mut self, id: &ScorableId) -> Result<(),StoreError> {
-        let group_id = self.scorable_to_group
-        .remove(id)
-        .ok_or(StoreError::ScorableNotFound(*id))?;
+    pub fn delete_member(&mut self, group_id: &GroupId, username: &str) -> Result<(),StoreError> {
         self.update_last_changed();
-        self.scores.get_mut(&group_id)
-            .ok_or(StoreError::GroupNotFound(group_id))?
-            .scorables.remove(&id)
-            .ok_or(StoreError::ScorableNotFound(*id))
+        self.group_mut(group_id)?
+            .members.remove(username)
+            .ok_or_else(|| StoreError::MemberNotFound(username.to_owned(), *group_id))
             .map(|_| ())
     }
-    pub fn get_scorable(&self, id: &ScorableId) -> Result<store_interface::Scorable,StoreError> {
-        let group_id = self.scorable_to_group.get(id)
-            .ok_or(StoreError::ScorableNotFound(*id))?;
+    /// Groups that have members must always have an owner, else they'd be left with nobody
+    /// able to manage them. Fail if the user is the only owner of the group given, or of any
+    /// group (including those in the trash) if none is given. This isn't checked as events
+    /// are replayed, so that logs written before it was are still loaded as they were.
+    pub fn check_owner_remains(&self, group_id: Option<&GroupId>, username: &str) -> Result<(),StoreError> {
+        let groups = self.scores.iter().filter(|(id,_)| group_id.is_none() || group_id == Some(*id));
+        for (id, group) in groups {
+            let is_owner = group.members.get(username) == Some(&Role::Owner);
+            let other_owner = group.members.iter().any(|(u,r)| u != username && *r == Role::Owner);
+            if is_owner && !other_owner {
+                return Err(StoreError::LastOwner(username.to_owned(), *id))
+            }
+        }
+        Ok(())
+    }
+    pub fn members(&self, group_id: &GroupId) -> Result<Vec<Member>,StoreError> {
+        let mut members: Vec<_> = self.group(group_id)?
+            .members.iter()
+            .map(|(username,role)| Member { username: username.clone(), role: *role })
+            .collect();
+        members.sort_by(|a,b| a.username.cmp(&b.username));
+        Ok(members)
+    }
+    pub fn role(&self, group_id: &GroupId, username: &str) -> Result<Option<Role>,StoreError> {
+        // Deleted groups are included, so that we know who can restore them:
         let group = self.scores.get(group_id)
             .ok_or(StoreError::GroupNotFound(*group_id))?;
-        group.scorables.get(id)
-            .map(|s| store_interface::Scorable { id: *id, name: s.name.to_owned() })
-            .ok_or(StoreError::ScorableNotFound(*id))
+        Ok(group.role(username))
     }
 
-    // Editing Scores
-    pub fn upsert_score(&mut self, id: ScoreId, scorable_id: ScorableId, username: String, value: i64, date: DateTime<Utc>) -> Result<(),StoreError> {
-        if !self.users.contains_key(&username) {
-            return Err(StoreError::UserNotFound(username));
+    // Editing Scorables
+    pub fn upsert_scorable(&mut self, id: ScorableId, group_id: GroupId, name: String, ranking: Option<Ranking>, value_kind: Option<ValueKind>) -> Result<(),StoreError> {
+        self.update_last_changed();
+        let scorable = self.group_mut(&group_id)?
+            .scorables
+            .entry(id)
+            .or_insert_with(|| Scorable::empty());
+        // Upserting a deleted scorable replaces it, as if it had been purged:
+        if scorable.deleted.is_some() {
+            *scorable = Scorable::empty();
+        }
+        scorable.name = name;
+        if let Some(ranking) = ranking {
+            scorable.ranking = ranking;
         }
+        if let Some(value_kind) = value_kind {
+            scorable.value_kind = value_kind;
+        }
+        self.scorable_to_group.insert(id, group_id);
+        Ok(())
+    }
+    /// Like groups, scorables are gone for good if we don't know when they were deleted.
+    pub fn delete_scorable(&mut self, id: &ScorableId, at: Option<DateTime<Utc>>) -> Result<(),StoreError> {
+        self.scorable(id)?;
         self.update_last_changed();
-        let group_id = self.scorable_to_group.get(&scorable_id)
-            .ok_or(StoreError::ScorableNotFound(scorable_id))?;
-        let group = self.scores.get_mut(group_id)
-            .ok_or(StoreError::GroupNotFound(*group_id))?;
-        if let Some(scorable) = group.scorables.get_mut(&scorable_id) {
-            scorable.scores.insert(id, Score { username: username.clone(), value, date });
-            self.score_to_scorable.insert(id, scorable_id);
-            Ok(())
-        } else {
-            Err(StoreError::ScorableNotFound(scorable_id))
+        match at {
+            Some(at) => { self.scorable_mut(id)?.deleted = Some(at); },
+            None => {
+                if let Some(group_id) = self.scorable_to_group.remove(id) {
+                    self.group_mut(&group_id)?.scorables.remove(id);
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn restore_scorable(&mut self, id: &ScorableId) -> Result<(),StoreError> {
+        let group_id = *self.scorable_to_group.get(id)
+            .ok_or(StoreError::ScorableNotFound(*id))?;
+        let scorable = self.group_mut(&group_id)?
+            .scorables.get_mut(id)
+            .ok_or(StoreError::ScorableNotFound(*id))?;
+        if scorable.deleted.take().is_none() {
+            return Err(StoreError::NotDeleted(Item::Scorable(*id)))
         }
+        self.update_last_changed();
+        Ok(())
+    }
+    pub fn get_scorable(&self, id: &ScorableId) -> Result<store_interface::Scorable,StoreError> {
+        let (group_id, s) = self.scorable(id)?;
+        Ok(store_interface::Scorable {
+            id: *id,
+            group_id,
+            name: s.name.to_owned(),
+            ranking: s.ranking,
+            value_kind: s.value_kind.clone()
+        })
+    }
+
+    // Editing Scores
+    pub fn upsert_score(&mut self, id: ScoreId, scorable_id: ScorableId, username: String, teammates: Vec<String>, value: i64, date: DateTime<Utc>) -> Result<Records,StoreError> {
+        store_interface::Score::check_team(&username, &teammates)?;
+        // Keep to dates that every backend can store:
+        store_interface::date_to_nanos(date)?;
+        if let Some(username) = std::iter::once(&username).chain(&teammates).find(|u| !self.users.contains_key(*u)) {
+            return Err(StoreError::UserNotFound(username.clone()));
+        }
+        let credited: Vec<&str> = std::iter::once(&username).chain(&teammates).map(|u| u.as_str()).collect();
+        let records = self.records_set_by(&id, &scorable_id, &credited, value)?;
+        self.update_last_changed();
+        // This replaces any deleted score with the same ID:
+        self.scorable_mut(&scorable_id)?
+            .scores.insert(id, Score { username, teammates, value, date, deleted: None });
+        self.score_to_scorable.insert(id, scorable_id);
+        Ok(records)
     }
-    pub fn delete_score(&mut self, id: &ScoreId) -> Result<(),StoreError> {
+    /// Which records would a score set if it was upserted?
+    fn records_set_by(&self, id: &ScoreId, scorable_id: &ScorableId, credited: &[&str], value: i64) -> Result<Records,StoreError> {
+        let (_, scorable) = self.scorable(scorable_id)?;
+        let ranking = scorable.ranking;
+        // The best of the other scores, ordered as in get_scores if some are equal:
+        let best = |only_user: Option<&str>| scorable.scores.iter()
+            .filter(|(other_id,s)| *other_id != id && s.deleted.is_none())
+            .filter(|(_,s)| only_user.map(|u| s.is_credited_to(u)).unwrap_or(true))
+            .min_by(|(a_id,a),(b_id,b)| {
+                ranking.best_first(a.value, b.value)
+                    .then(a.date.cmp(&b.date))
+                    .then(a_id.cmp(b_id))
+            })
+            .map(|(_,s)| s);
+        let previous_best = best(None).map(|s| RecordHolder { username: s.username.clone(), value: s.value });
+        let previous_personal_bests = credited.iter()
+            .map(|username| (username.to_string(), best(Some(*username)).map(|s| s.value)))
+            .collect();
+        Ok(Records::set_by(ranking, value, previous_best, previous_personal_bests))
+    }
+    /// Like groups, scores are gone for good if we don't know when they were deleted.
+    pub fn delete_score(&mut self, id: &ScoreId, at: Option<DateTime<Utc>>) -> Result<(),StoreError> {
+        self.score(id)?;
         self.update_last_changed();
-        let scorable_id = self.score_to_scorable.remove(id)
+        match at {
+            Some(at) => { self.score_mut(id)?.deleted = Some(at); },
+            None => {
+                if let Some(scorable_id) = self.score_to_scorable.remove(id) {
+                    self.scorable_mut(&scorable_id)?.scores.remove(id);
+                }
+            }
+        }
+        Ok(())
+    }
+    pub fn restore_score(&mut self, id: &ScoreId) -> Result<(),StoreError> {
+        let scorable_id = *self.score_to_scorable.get(id)
             .ok_or(StoreError::ScoreNotFound(*id))?;
-        let group_id = self.scorable_to_group.get(&scorable_id)
-            .ok_or(StoreError::ScorableNotFound(scorable_id))?;
-        let group = self.scores.get_mut(group_id)
-            .ok_or(StoreError::GroupNotFound(*group_id))?;
-        group.scorables.get_mut(&scorable_id)
-            .ok_or(StoreError::ScorableNotFound(scorable_id))?
-            .scores.remove(id)
-            .ok_or(StoreError::ScoreNotFound(*id))
-            .map(|_| ())
+        let score = self.scorable_mut(&scorable_id)?
+            .scores.get_mut(id)
+            .ok_or(StoreError::ScoreNotFound(*id))?;
+        if score.deleted.take().is_none() {
+            return Err(StoreError::NotDeleted(Item::Score(*id)))
+        }
+        self.update_last_changed();
+        Ok(())
+    }
+    pub fn get_score(&self, id: &ScoreId) -> Result<store_interface::Score,StoreError> {
+        let (scorable_id, score) = self.score(id)?;
+        Ok(score.to_score(*id, scorable_id))
     }
 
-    pub fn groups(&self) -> Result<Vec<crate::store_interface::Group>,StoreError> {
+    // Editing Matches
+    pub fn upsert_match(&mut self, id: MatchId, scorable_id: ScorableId, participants: Vec<String>, winner: Option<String>, date: DateTime<Utc>) -> Result<(),StoreError> {
+        store_interface::Match::check(&participants, winner.as_deref())?;
+        store_interface::date_to_nanos(date)?;
+        if let Some(username) = participants.iter().find(|u| !self.users.contains_key(*u)) {
+            return Err(StoreError::UserNotFound(username.clone()));
+        }
+        self.scorable(&scorable_id)?;
+        self.update_last_changed();
+        // A match moving between scorables no longer counts towards the ratings of the old one:
+        if self.match_to_scorable.get(&id) != Some(&scorable_id) {
+            self.remove_match(&id);
+        }
+        let scorable = self.scorable_mut(&scorable_id)?;
+        let m = Match { participants, winner, date };
+        let new_match = m.to_match(id, scorable_id);
+        let replaced = scorable.matches.insert(id, m).is_some();
+        // Ratings only need working out again if the match isn't the latest:
+        match scorable.ratings.as_mut() {
+            Some(ratings) if !replaced && ratings.follows(&new_match) => ratings.apply(&new_match),
+            _ => scorable.rerate_later()
+        }
+        self.match_to_scorable.insert(id, scorable_id);
+        Ok(())
+    }
+    pub fn delete_match(&mut self, id: &MatchId) -> Result<(),StoreError> {
+        self.get_match(id)?;
+        self.update_last_changed();
+        self.remove_match(id);
+        Ok(())
+    }
+    /// Remove a match from wherever it lives (even if that's been deleted), reworking the ratings there.
+    fn remove_match(&mut self, id: &MatchId) {
+        let scorable_id = match self.match_to_scorable.remove(id) {
+            Some(scorable_id) => scorable_id,
+            None => return
+        };
+        let scores = &mut self.scores;
+        let scorable = self.scorable_to_group.get(&scorable_id)
+            .and_then(|group_id| scores.get_mut(group_id))
+            .and_then(|group| group.scorables.get_mut(&scorable_id));
+        if let Some(scorable) = scorable {
+            if scorable.matches.remove(id).is_some() {
+                scorable.rerate_later();
+            }
+        }
+    }
+    pub fn get_match(&self, id: &MatchId) -> Result<store_interface::Match,StoreError> {
+        let scorable_id = *self.match_to_scorable.get(id)
+            .ok_or(StoreError::MatchNotFound(*id))?;
+        self.scorable(&scorable_id)
+            .map_err(|_| StoreError::MatchNotFound(*id))?.1
+            .matches.get(id)
+            .map(|m| m.to_match(*id, scorable_id))
+            .ok_or(StoreError::MatchNotFound(*id))
+    }
+    pub fn get_matches(&self, scorable_id: &ScorableId, username: Option<&str>) -> Result<Vec<store_interface::Match>,StoreError> {
+        let (_, scorable) = self.scorable(scorable_id)?;
+        let mut matches: Vec<_> = scorable.matches.iter()
+            .filter(|(_,m)| username.map(|u| m.participants.iter().any(|p| p == u)).unwrap_or(true))
+            .map(|(id,m)| m.to_match(*id, *scorable_id))
+            .collect();
+        // newest first (and then by ID so that the order is stable):
+        matches.sort_by(|a,b| b.date.cmp(&a.date).then(b.id.cmp(&a.id)));
+        Ok(matches)
+    }
+
+    // Seasons
+    pub fn set_seasons(&mut self, group_id: &GroupId, seasons: Option<Seasons>) -> Result<(),StoreError> {
+        self.update_last_changed();
+        self.group_mut(group_id)?.seasons = seasons;
+        Ok(())
+    }
+    pub fn archive_season(&mut self, group_id: &GroupId, season: ArchivedSeason) -> Result<(),StoreError> {
+        self.update_last_changed();
+        self.group_mut(group_id)?
+            .season_archive.insert(season.name.clone(), season);
+        Ok(())
+    }
+    pub fn season_archive(&self, group_id: &GroupId) -> Result<Vec<ArchivedSeason>,StoreError> {
+        let mut archive: Vec<_> = self.group(group_id)?
+            .season_archive.values()
+            .cloned()
+            .collect();
+        // most recent first (and then by name so that the order is stable):
+        archive.sort_by(|a,b| b.start.cmp(&a.start).then_with(|| a.name.cmp(&b.name)));
+        Ok(archive)
+    }
+
+    pub fn groups(&self, member: Option<&str>) -> Result<Vec<crate::store_interface::Group>,StoreError> {
         let mut groups: Vec<_> = self.scores
             .iter()
+            .filter(|(_,group)| group.deleted.is_none())
+            .filter(|(_,group)| member.map(|m| group.role(m).is_some()).unwrap_or(true))
             .map(|(id,group)| store_interface::Group {
                 id: *id,
                 name: group.name.to_owned()
@@ -281,41 +837,260 @@ impl MemoryStoreInner {
         Ok(groups)
     }
     pub fn scorables_in_group(&self, group_id: &GroupId) -> Result<Vec<crate::store_interface::Scorable>,StoreError> {
-        let mut scorables: Vec<_> = self.scores.get(&group_id)
-            .ok_or(StoreError::GroupNotFound(*group_id))?
+        let mut scorables: Vec<_> = self.group(group_id)?
             .iter_scorables()
+            .filter(|(_,scorable)| scorable.deleted.is_none())
             .map(|(id,scorable)| store_interface::Scorable {
                 id: id,
-                name: scorable.name.to_owned()
+                group_id: *group_id,
+                name: scorable.name.to_owned(),
+                ranking: scorable.ranking,
+                value_kind: scorable.value_kind.clone()
             })
             .collect();
         scorables.sort();
         Ok(scorables)
     }
-    pub fn get_scores(&self, scorable_id: &ScorableId, limit: Option<usize>) -> Result<Vec<crate::store_interface::Score>,StoreError> {
-        let group_id = self.scorable_to_group.get(&scorable_id)
-            .ok_or(StoreError::ScorableNotFound(*scorable_id))?;
-        let group = self.scores.get(group_id)
-            .ok_or(StoreError::GroupNotFound(*group_id))?;
-        let mut scores: Vec<_> = group.scorables.get(scorable_id)
-            .ok_or(StoreError::ScorableNotFound(*scorable_id))?
-            .scores.iter()
-            .collect();
-        // highest score first:
-        scores.sort_by_key(|(_,s)| std::cmp::Reverse(s.value));
-        let limit = limit.unwrap_or(scores.len());
-        let scores = scores.into_iter()
-            .take(limit)
-            .map(|(id,s)| store_interface::Score {
-                id: *id,
-                date: s.date,
-                username: s.username.clone(),
-                value: s.value
-            })
+    pub fn get_scores(&self, scorable_id: &ScorableId, filter: &ScoreFilter) -> Result<Vec<crate::store_interface::Score>,StoreError> {
+        let (_, scorable) = self.scorable(scorable_id)?;
+        let mut scores: Vec<_> = scorable.scores.iter()
+            .filter(|(_,s)| s.deleted.is_none())
+            .map(|(id,s)| s.to_score(*id, *scorable_id))
+            .flat_map(|s| if filter.by_member { s.per_member() } else { vec![s] })
+            .filter(|s| filter.matches(s))
             .collect();
+        // best score first (and the earliest of equal scores, and then by ID and user so that the order is stable):
+        scores.sort_by(|a,b| {
+            scorable.ranking.best_first(a.value, b.value)
+                .then(a.date.cmp(&b.date))
+                .then(a.id.cmp(&b.id))
+                .then(a.username.cmp(&b.username))
+        });
+        if filter.best_per_user {
+            let mut seen = HashSet::new();
+            scores.retain(|s| seen.insert(filter.credited(s)));
+        }
+        scores.truncate(filter.limit.unwrap_or(scores.len()));
         Ok(scores)
     }
 
+    // The trash
+    pub fn trash(&self, group_id: Option<&GroupId>) -> Vec<Deleted> {
+        let mut trash = Vec::new();
+        let groups = self.scores.iter()
+            .filter(|(id,_)| group_id.map(|g| g == *id).unwrap_or(true));
+        for (group_id, group) in groups {
+            if let Some(at) = group.deleted {
+                trash.push(Deleted { item: Item::Group(*group_id), group_id: *group_id, name: group.name.clone(), deleted_at: at });
+            }
+            // As in to_events, ignore anything that isn't where the indexes say it lives:
+            let scorables = group.iter_scorables()
+                .filter(|(id,_)| self.scorable_to_group.get(id) == Some(group_id));
+            for (scorable_id, scorable) in scorables {
+                if let Some(at) = scorable.deleted {
+                    trash.push(Deleted { item: Item::Scorable(scorable_id), group_id: *group_id, name: scorable.name.clone(), deleted_at: at });
+                }
+                let scores = scorable.scores.iter()
+                    .filter(|(id,_)| self.score_to_scorable.get(id) == Some(&scorable_id));
+                for (score_id, score) in scores {
+                    if let Some(at) = score.deleted {
+                        trash.push(Deleted { item: Item::Score(*score_id), group_id: *group_id, name: score.username.clone(), deleted_at: at });
+                    }
+                }
+            }
+        }
+        trash.sort_by_key(|d| std::cmp::Reverse(d.deleted_at));
+        trash
+    }
+    pub fn purge_deleted(&mut self, before: DateTime<Utc>) {
+        let is_expired = |deleted| is_expired(deleted, Some(before));
+        self.scores.retain(|_,group| !is_expired(group.deleted));
+        for group in self.scores.values_mut() {
+            group.scorables.retain(|_,scorable| !is_expired(scorable.deleted));
+            for scorable in group.scorables.values_mut() {
+                scorable.scores.retain(|_,score| !is_expired(score.deleted));
+            }
+        }
+    }
+
+    // Looking things up. These only find things that haven't been deleted,
+    // and that don't live inside something that has been deleted.
+    fn group(&self, id: &GroupId) -> Result<&Group,StoreError> {
+        self.scores.get(id)
+            .filter(|g| g.deleted.is_none())
+            .ok_or(StoreError::GroupNotFound(*id))
+    }
+    fn group_mut(&mut self, id: &GroupId) -> Result<&mut Group,StoreError> {
+        self.scores.get_mut(id)
+            .filter(|g| g.deleted.is_none())
+            .ok_or(StoreError::GroupNotFound(*id))
+    }
+    fn scorable(&self, id: &ScorableId) -> Result<(GroupId,&Scorable),StoreError> {
+        let group_id = *self.scorable_to_group.get(id)
+            .ok_or(StoreError::ScorableNotFound(*id))?;
+        let scorable = self.group(&group_id)
+            .map_err(|_| StoreError::ScorableNotFound(*id))?
+            .scorables.get(id)
+            .filter(|s| s.deleted.is_none())
+            .ok_or(StoreError::ScorableNotFound(*id))?;
+        Ok((group_id, scorable))
+    }
+    fn scorable_mut(&mut self, id: &ScorableId) -> Result<&mut Scorable,StoreError> {
+        let group_id = *self.scorable_to_group.get(id)
+            .ok_or(StoreError::ScorableNotFound(*id))?;
+        self.group_mut(&group_id)
+            .map_err(|_| StoreError::ScorableNotFound(*id))?
+            .scorables.get_mut(id)
+            .filter(|s| s.deleted.is_none())
+            .ok_or(StoreError::ScorableNotFound(*id))
+    }
+    fn score(&self, id: &ScoreId) -> Result<(ScorableId,&Score),StoreError> {
+        let scorable_id = *self.score_to_scorable.get(id)
+            .ok_or(StoreError::ScoreNotFound(*id))?;
+        let score = self.scorable(&scorable_id)
+            .map_err(|_| StoreError::ScoreNotFound(*id))?.1
+            .scores.get(id)
+            .filter(|s| s.deleted.is_none())
+            .ok_or(StoreError::ScoreNotFound(*id))?;
+        Ok((scorable_id, score))
+    }
+    fn score_mut(&mut self, id: &ScoreId) -> Result<&mut Score,StoreError> {
+        let scorable_id = *self.score_to_scorable.get(id)
+            .ok_or(StoreError::ScoreNotFound(*id))?;
+        self.scorable_mut(&scorable_id)
+            .map_err(|_| StoreError::ScoreNotFound(*id))?
+            .scores.get_mut(id)
+            .filter(|s| s.deleted.is_none())
+            .ok_or(StoreError::ScoreNotFound(*id))
+    }
+
+    // History
+    pub fn history(&self, filter: &HistoryFilter) -> Vec<HistoryEntry> {
+        self.history.iter()
+            .rev()
+            .filter(|entry| filter.matches(entry))
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+    /// Describe a change for the history. This needs calling before the change is
+    /// made, so that we can still look up where anything being deleted lived.
+    pub fn history_entry(&self, at: Option<DateTime<Utc>>, by: Option<&str>, change: Change) -> HistoryEntry {
+        let group_of = |id: &ScorableId| self.scorable_to_group.get(id).copied();
+        let (group_id, scorable_id) = match &change {
+            Change::UpsertUser { .. } | Change::DeleteUser { .. } => (None, None),
+            Change::UpsertGroup { id } | Change::DeleteGroup { id } | Change::RestoreGroup { id } => (Some(*id), None),
+            Change::SetSeasons { group_id } | Change::ArchiveSeason { group_id, .. } => (Some(*group_id), None),
+            Change::UpsertMember { group_id, .. } | Change::DeleteMember { group_id, .. } => (Some(*group_id), None),
+            Change::UpsertScorable { id, group_id } => (Some(*group_id), Some(*id)),
+            Change::DeleteScorable { id } | Change::RestoreScorable { id } => (group_of(id), Some(*id)),
+            Change::UpsertScore { scorable_id, .. } | Change::NewRecord { scorable_id, .. } => (group_of(scorable_id), Some(*scorable_id)),
+            Change::DeleteScore { id } | Change::RestoreScore { id } => {
+                let scorable_id = self.score_to_scorable.get(id).copied();
+                (scorable_id.as_ref().and_then(group_of), scorable_id)
+            }
+            Change::UpsertMatch { scorable_id, .. } => (group_of(scorable_id), Some(*scorable_id)),
+            Change::DeleteMatch { id } => {
+                let scorable_id = self.match_to_scorable.get(id).copied();
+                (scorable_id.as_ref().and_then(group_of), scorable_id)
+            }
+        };
+        HistoryEntry { at, by: by.map(|b| b.to_owned()), group_id, scorable_id, change }
+    }
+
+    pub fn to_events(&self, purge_before: Option<DateTime<Utc>>) -> Vec<LoggedEvent> {
+        let is_expired = |deleted| is_expired(deleted, purge_before);
+        // These describe how things are rather than how they got that way, so
+        // there's nobody to say made them, and no time that they were made at.
+        // The exception is deleting things, which needs a time so that we know
+        // when they can be purged:
+        let logged = |event| LoggedEvent { at: None, by: None, event };
+        let deleted_at = |at, event| LoggedEvent { at: Some(at), by: None, event };
+        let mut events = Vec::new();
+        for (username, user) in &self.users {
+            events.push(logged(Event::UpsertUser {
+                username: username.clone(),
+                hashed_password: user.hashed_password.clone(),
+                admin: Some(user.admin)
+            }));
+        }
+        for (token_id, token) in &self.tokens {
+            events.push(logged(Event::UpsertToken {
+                id: *token_id,
+                username: token.username.clone(),
+                name: token.name.clone(),
+                hashed_secret: token.hashed_secret.clone(),
+                created: token.created
+            }));
+        }
+        for (group_id, group) in self.scores.iter().filter(|(_,g)| !is_expired(g.deleted)) {
+            events.push(logged(Event::UpsertGroup { id: *group_id, name: group.name.clone() }));
+            for (username, role) in &group.members {
+                events.push(logged(Event::UpsertMember { group_id: *group_id, username: username.clone(), role: *role }));
+            }
+            if group.seasons.is_some() {
+                events.push(logged(Event::SetSeasons { group_id: *group_id, seasons: group.seasons.clone() }));
+            }
+            let mut archive: Vec<_> = group.season_archive.values().collect();
+            archive.sort_by(|a,b| a.start.cmp(&b.start).then_with(|| a.name.cmp(&b.name)));
+            for season in archive {
+                events.push(logged(Event::ArchiveSeason { group_id: *group_id, season: season.clone() }));
+            }
+            // Only emit things found where the indexes say they live, so
+            // that we don't resurrect anything stale:
+            let scorables = group.iter_scorables()
+                .filter(|(id,_)| self.scorable_to_group.get(id) == Some(group_id))
+                .filter(|(_,scorable)| !is_expired(scorable.deleted));
+            for (scorable_id, scorable) in scorables {
+                events.push(logged(Event::UpsertScorable {
+                    id: scorable_id,
+                    group_id: *group_id,
+                    name: scorable.name.clone(),
+                    ranking: Some(scorable.ranking),
+                    value_kind: Some(scorable.value_kind.clone())
+                }));
+                let scores = scorable.scores.iter()
+                    .filter(|(id,_)| self.score_to_scorable.get(id) == Some(&scorable_id))
+                    .filter(|(_,score)| !is_expired(score.deleted));
+                for (score_id, score) in scores {
+                    events.push(logged(Event::UpsertScore {
+                        id: *score_id,
+                        scorable_id,
+                        username: score.username.clone(),
+                        teammates: score.teammates.clone(),
+                        value: score.value,
+                        date: score.date
+                    }));
+                    if let Some(at) = score.deleted {
+                        events.push(deleted_at(at, Event::DeleteScore { id: *score_id }));
+                    }
+                }
+                // Oldest first, so that ratings can be worked out as each is replayed:
+                let mut matches: Vec<_> = scorable.matches.iter()
+                    .filter(|(id,_)| self.match_to_scorable.get(id) == Some(&scorable_id))
+                    .collect();
+                matches.sort_by(|(a_id,a),(b_id,b)| a.date.cmp(&b.date).then(a_id.cmp(b_id)));
+                for (match_id, m) in matches {
+                    events.push(logged(Event::UpsertMatch {
+                        id: *match_id,
+                        scorable_id,
+                        participants: m.participants.clone(),
+                        winner: m.winner.clone(),
+                        date: m.date
+                    }));
+                }
+                if let Some(at) = scorable.deleted {
+                    events.push(deleted_at(at, Event::DeleteScorable { id: scorable_id }));
+                }
+            }
+            if let Some(at) = group.deleted {
+                events.push(deleted_at(at, Event::DeleteGroup { id: *group_id }));
+            }
+        }
+        events.push(LoggedEvent { at: Some(Utc::now()), by: None, event: Event::Compacted });
+        events
+    }
+
     fn last_changed(&self) -> DateTime<Utc> {
         self.last_changed
     }
@@ -324,14 +1099,46 @@ impl MemoryStoreInner {
     }
 }
 
+/// Has something been deleted since before some time, and so can be purged?
+fn is_expired(deleted: Option<DateTime<Utc>>, before: Option<DateTime<Utc>>) -> bool {
+    matches!((deleted, before), (Some(at), Some(before)) if at < before)
+}
+
+struct User {
+    hashed_password: HashedPassword,
+    admin: bool
+}
+
 struct Group {
     name: String,
-    scorables: HashMap<ScorableId, Scorable>
+    members: HashMap<String, Role>,
+    scorables: HashMap<ScorableId, Scorable>,
+    /// How scores are split up into seasons, if they are.
+    seasons: Option<Seasons>,
+    /// The winners of finished seasons, by season name.
+    season_archive: HashMap<String, ArchivedSeason>,
+    /// When was this deleted (if it has been)?
+    deleted: Option<DateTime<Utc>>
 }
 
 impl Group {
     fn empty() -> Group {
-        Group { name: String::new(), scorables: HashMap::new() }
+        Group {
+            name: String::new(),
+            members: HashMap::new(),
+            scorables: HashMap::new(),
+            seasons: None,
+            season_archive: HashMap::new(),
+            deleted: None
+        }
+    }
+    // Groups without any members are open to everybody:
+    fn role(&self, username: &str) -> Option<Role> {
+        if self.members.is_empty() {
+            Some(Role::Owner)
+        } else {
+            self.members.get(username).copied()
+        }
     }
     fn iter_scorables(&self) -> impl Iterator<Item=(ScorableId,&Scorable)> + '_ {
         self.scorables.iter().map(|(id,scorable)| (*id,scorable))
@@ -340,17 +1147,117 @@ impl Group {
 
 struct Scorable {
     name: String,
-    scores: HashMap<ScoreId, Score>
+    ranking: Ranking,
+    value_kind: ValueKind,
+    scores: HashMap<ScoreId, Score>,
+    matches: HashMap<MatchId, Match>,
+    /// Worked out from the matches, and kept up to date as later ones are played. This is
+    /// None if they need working out again, which waits until they're next asked for so
+    /// that a run of matches played out of order (say, when replaying) is only re-rated once.
+    ratings: Option<Ratings>,
+    /// When was this deleted (if it has been)?
+    deleted: Option<DateTime<Utc>>
 }
 
 impl Scorable {
     fn empty() -> Scorable {
-        Scorable { name: String::new(), scores: HashMap::new() }
+        Scorable {
+            name: String::new(),
+            ranking: Ranking::default(),
+            value_kind: ValueKind::default(),
+            scores: HashMap::new(),
+            matches: HashMap::new(),
+            ratings: Some(Ratings::default()),
+            deleted: None
+        }
+    }
+    /// Forget the ratings, for when matches change in a way that they can't just be
+    /// applied on top of the existing ones. They're worked out again when next needed.
+    fn rerate_later(&mut self) {
+        self.ratings = None;
+    }
+    /// The ratings given every match played, working them out from scratch if need be.
+    fn ratings(&mut self, id: ScorableId) -> &Ratings {
+        let matches = &self.matches;
+        self.ratings.get_or_insert_with(|| {
+            let matches: Vec<_> = matches.iter()
+                .map(|(match_id,m)| m.to_match(*match_id, id))
+                .collect();
+            Ratings::from_matches(&matches)
+        })
     }
 }
 
 struct Score {
     username: String,
+    teammates: Vec<String>,
     value: i64,
+    date: DateTime<Utc>,
+    /// When was this deleted (if it has been)?
+    deleted: Option<DateTime<Utc>>
+}
+
+impl Score {
+    fn to_score(&self, id: ScoreId, scorable_id: ScorableId) -> store_interface::Score {
+        store_interface::Score {
+            id,
+            scorable_id,
+            date: self.date,
+            username: self.username.clone(),
+            teammates: self.teammates.clone(),
+            value: self.value
+        }
+    }
+    fn is_credited_to(&self, username: &str) -> bool {
+        self.username == username || self.teammates.iter().any(|t| t == username)
+    }
+    /// Take a user off of the score, handing it to the next in the team if it was theirs.
+    /// Returns false if there's nobody left to credit the score to.
+    fn uncredit(&mut self, username: &str) -> bool {
+        if self.username != username {
+            self.teammates.retain(|t| t != username);
+            true
+        } else if self.teammates.is_empty() {
+            false
+        } else {
+            self.username = self.teammates.remove(0);
+            true
+        }
+    }
+}
+
+struct Match {
+    participants: Vec<String>,
+    winner: Option<String>,
     date: DateTime<Utc>
 }
+
+impl Match {
+    fn to_match(&self, id: MatchId, scorable_id: ScorableId) -> store_interface::Match {
+        store_interface::Match {
+            id,
+            scorable_id,
+            participants: self.participants.clone(),
+            winner: self.winner.clone(),
+            date: self.date
+        }
+    }
+}
+
+struct Token {
+    username: String,
+    name: String,
+    hashed_secret: HashedToken,
+    created: DateTime<Utc>
+}
+
+impl Token {
+    fn to_token(&self, id: TokenId) -> store_interface::Token {
+        store_interface::Token {
+            id,
+            username: self.username.clone(),
+            name: self.name.clone(),
+            created: self.created
+        }
+    }
+}
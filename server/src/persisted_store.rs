@@ -1,28 +1,111 @@
 use std::path::PathBuf;
-use crate::events::{ EventHandler, Event };
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use crate::changes::Notification;
+use futures::stream::StreamExt;
+use crate::events::{ self, EventHandler, Event, LoggedEvent, Durability };
 use crate::memory_store::{ MemoryStore };
-use crate::store_interface::{ Store, StoreError, GroupId, ScoreId, ScorableId, Group, Score, Scorable, HashedPassword };
+use crate::store_interface::{ Store, StoreError, GroupId, ScoreId, ScorableId, MatchId, TokenId, Group, Score, Match, Scorable, Token, Member, HashedPassword, HashedToken, Ranking, ValueKind, Role, Deleted, HistoryEntry, HistoryFilter, ScoreFilter, Page, PageRequest, Stats, Records, Rating, RatingChange, Seasons, ArchivedSeason, AsOf };
+
+/// How many replays of the event log (to view it as it was at some point) to keep around.
+const AS_OF_CACHE_SIZE: usize = 8;
 
 /// This combines an in-memory `Store` implementation with eventual
 /// persistence in the form of append-only event logs.
 pub struct PersistedStore {
     /// Read and write events to persist
     events: EventHandler,
-    /// In-memory data derived from events:
-    memory_store: MemoryStore
+    /// Who changed what and when. This is logged apart from events, so that it
+    /// survives them being compacted, and read from disk rather than kept in memory.
+    history: EventHandler<HistoryEntry>,
+    /// In-memory data derived from events. This also tells subscribers about
+    /// changes, since it knows which group each is made in:
+    memory_store: MemoryStore,
+    /// Replaying the event log is slow, so recent replays are kept in case the same
+    /// point is asked about again (as it usually is, by several queries in a row).
+    as_of_cache: tokio::sync::Mutex<Vec<Replay>>
+}
+
+struct Replay {
+    as_of: AsOf,
+    /// When the store was last changed when this was replayed. If it's been changed
+    /// since, the replay may be out of date.
+    last_changed: chrono::DateTime<chrono::Utc>,
+    store: Arc<MemoryStore>
 }
 
 impl PersistedStore {
     /// Load in our data from a file
-    pub async fn load(file_path: PathBuf) -> anyhow::Result<PersistedStore> {
-        let events = EventHandler::new(file_path);
-        let memory_store = MemoryStore::from_events(&events).await?;
-        Ok(PersistedStore { events, memory_store })
+    pub async fn load(file_path: PathBuf, durability: Durability) -> anyhow::Result<PersistedStore> {
+        let events = EventHandler::new(file_path.clone(), durability);
+        let memory_store = MemoryStore::from_events(&events, None).await?;
+        let history_path = events::path_with_suffix(&file_path, ".history");
+        let has_history = tokio::fs::metadata(&history_path).await.is_ok();
+        // The history is best-effort: it's flushed every second and on shutdown like any
+        // buffered log, so that writes only ever wait on the events reaching disk.
+        let history = EventHandler::new(history_path, Durability::Buffered);
+        // The history used to be worked out from the events, so for databases from
+        // before it was logged on its own, that's where it comes from (just this once):
+        let entries = memory_store.take_history();
+        if !has_history && !entries.is_empty() {
+            for entry in entries {
+                history.push(entry).await?;
+            }
+            history.flush_to_disk().await?;
+        }
+        Ok(PersistedStore { events, history, memory_store, as_of_cache: tokio::sync::Mutex::new(Vec::new()) })
     }
 
     /// Force anything in-memory to be flushed to disk immediately.
     pub async fn flush_to_disk(&self) -> anyhow::Result<()> {
-        self.events.flush_to_disk().await
+        self.events.flush_to_disk().await?;
+        self.history.flush_to_disk().await
+    }
+
+    /// Rewrite the event log as the smallest set of events needed to recreate the
+    /// current state, keeping a backup of the old log. Anything deleted longer ago
+    /// than `trash_retention` is purged. The history is left as it is.
+    pub async fn compact(&self, trash_retention: chrono::Duration) -> anyhow::Result<()> {
+        // If it's too long ago to represent, nothing's been in the trash that long:
+        let purge_before = chrono::Utc::now().checked_sub_signed(trash_retention).unwrap_or(chrono::MIN_DATETIME);
+        let n = self.events.compact(|| self.memory_store.to_events(Some(purge_before))).await?;
+        // Only purge things from memory once they're gone from disk too, so that the two
+        // still agree if compacting fails:
+        self.memory_store.purge_deleted(purge_before);
+        // The log that these were replayed from is gone:
+        self.as_of_cache.lock().await.clear();
+        log::info!("Compacted database down to {} events", n);
+        Ok(())
+    }
+
+    /// Write a new event log database to `file_path`, containing the state of this one as
+    /// of some point in the past (but not the history of how it got there, as with `compact`).
+    /// Returns the number of events written.
+    pub async fn snapshot(&self, as_of: AsOf, file_path: PathBuf) -> anyhow::Result<usize> {
+        self.events.flush_to_disk().await?;
+        let events = MemoryStore::from_events(&self.events, Some(as_of)).await?.to_events(None);
+        let n = events.len();
+        let snapshot = EventHandler::new(file_path, Durability::Buffered);
+        for event in events {
+            snapshot.push(event).await?;
+        }
+        snapshot.flush_to_disk().await?;
+        Ok(n)
+    }
+
+    /// Persist an event describing a change already made to the memory_store,
+    /// noting who made it and when, along with the history of the change.
+    async fn persist(&self, by: Option<&str>, event: Event) -> Result<(),StoreError> {
+        self.events.push(LoggedEvent {
+            at: Some(chrono::Utc::now()),
+            by: by.map(|b| b.to_owned()),
+            event
+        }).await?;
+        // The memory_store notes the history of each change as it's made:
+        for entry in self.memory_store.take_history() {
+            self.history.push(entry).await?;
+        }
+        Ok(())
     }
 }
 
@@ -31,100 +114,395 @@ impl PersistedStore {
 //
 // Writes to the event log only happen once the call to the memory_store has
 // succeeded, to avoid writing naff data to the event log and lean on memory_store
-// to check that inputs are sensible.
+// to check that inputs are sensible. Depending on the durability we were loaded
+// with, pushing an event may wait for it to be written to disk.
 #[async_trait::async_trait]
 impl Store for PersistedStore {
     async fn last_changed(&self) -> chrono::DateTime<chrono::Utc> {
         self.memory_store.last_changed().await
     }
+    fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.memory_store.subscribe()
+    }
 
     async fn users(&self) -> Result<Vec<String>,StoreError> {
         self.memory_store.users().await
     }
-    async fn upsert_user(&self, username: String, hashed_password: HashedPassword) -> Result<(),StoreError> {
-        let res = self.memory_store.upsert_user(username.clone(), hashed_password.clone()).await?;
-        self.events.push(Event::UpsertUser {
+    async fn user_exists(&self, username: &str) -> Result<bool,StoreError> {
+        self.memory_store.user_exists(username).await
+    }
+    async fn upsert_user(&self, username: String, hashed_password: HashedPassword, admin: Option<bool>, by: Option<&str>) -> Result<(),StoreError> {
+        let res = self.memory_store.upsert_user(username.clone(), hashed_password.clone(), admin, by).await?;
+        self.persist(by, Event::UpsertUser {
             username: username,
-            hashed_password: hashed_password
-        }).await;
+            hashed_password: hashed_password,
+            admin
+        }).await?;
         Ok(res)
     }
     async fn check_user(&self, username: &str, password: &str) -> Result<bool,StoreError> {
         self.memory_store.check_user(username, password).await
     }
-    async fn delete_user(&self, username: &str) -> Result<(),StoreError> {
-        let res = self.memory_store.delete_user(username).await?;
-        self.events.push(Event::DeleteUser {
+    async fn hashed_password(&self, username: &str) -> Result<HashedPassword,StoreError> {
+        self.memory_store.hashed_password(username).await
+    }
+    async fn is_admin(&self, username: &str) -> Result<bool,StoreError> {
+        self.memory_store.is_admin(username).await
+    }
+    async fn delete_user(&self, username: &str, by: Option<&str>) -> Result<(),StoreError> {
+        let res = self.memory_store.delete_user(username, by).await?;
+        self.persist(by, Event::DeleteUser {
             username: username.to_owned()
-        }).await;
+        }).await?;
         Ok(res)
     }
 
-    async fn upsert_group(&self, id: GroupId, name: String) -> Result<(),StoreError> {
-        self.memory_store.upsert_group(id, name.clone()).await?;
-        self.events.push(Event::UpsertGroup {
+    async fn upsert_token(&self, id: TokenId, username: String, name: String, hashed_secret: HashedToken, created: chrono::DateTime<chrono::Utc>, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.upsert_token(id, username.clone(), name.clone(), hashed_secret.clone(), created, by).await?;
+        self.persist(by, Event::UpsertToken {
+            id,
+            username,
+            name,
+            hashed_secret,
+            created
+        }).await?;
+        Ok(())
+    }
+    async fn tokens(&self, username: &str) -> Result<Vec<Token>,StoreError> {
+        self.memory_store.tokens(username).await
+    }
+    async fn check_token(&self, id: &TokenId, secret: &str) -> Result<Option<Token>,StoreError> {
+        self.memory_store.check_token(id, secret).await
+    }
+    async fn delete_token(&self, id: &TokenId, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.delete_token(id, by).await?;
+        self.persist(by, Event::DeleteToken {
+            id: *id
+        }).await?;
+        Ok(())
+    }
+
+    async fn upsert_group(&self, id: GroupId, name: String, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.upsert_group(id, name.clone(), by).await?;
+        self.persist(by, Event::UpsertGroup {
             id,
             name
-        }).await;
+        }).await?;
         Ok(())
     }
-    async fn delete_group(&self, id: &GroupId) -> Result<(),StoreError> {
-        let res = self.memory_store.delete_group(id).await?;
-        self.events.push(Event::DeleteGroup {
+    async fn delete_group(&self, id: &GroupId, by: Option<&str>) -> Result<(),StoreError> {
+        let res = self.memory_store.delete_group(id, by).await?;
+        self.persist(by, Event::DeleteGroup {
             id: *id,
-        }).await;
+        }).await?;
         Ok(res)
     }
     async fn get_group(&self, id: &GroupId) -> Result<Group,StoreError> {
         self.memory_store.get_group(id).await
     }
 
-    async fn upsert_scorable(&self, id: ScorableId, group_id: GroupId, name: String) -> Result<(),StoreError> {
-        self.memory_store.upsert_scorable(id, group_id, name.clone()).await?;
-        self.events.push(Event::UpsertScorable {
+    async fn upsert_member(&self, group_id: GroupId, username: String, role: Role, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.upsert_member(group_id, username.clone(), role, by).await?;
+        self.persist(by, Event::UpsertMember {
+            group_id,
+            username,
+            role
+        }).await?;
+        Ok(())
+    }
+    async fn delete_member(&self, group_id: &GroupId, username: &str, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.delete_member(group_id, username, by).await?;
+        self.persist(by, Event::DeleteMember {
+            group_id: *group_id,
+            username: username.to_owned()
+        }).await?;
+        Ok(())
+    }
+    async fn members(&self, group_id: &GroupId) -> Result<Vec<Member>,StoreError> {
+        self.memory_store.members(group_id).await
+    }
+    async fn role(&self, group_id: &GroupId, username: &str) -> Result<Option<Role>,StoreError> {
+        self.memory_store.role(group_id, username).await
+    }
+
+    async fn upsert_scorable(&self, id: ScorableId, group_id: GroupId, name: String, ranking: Option<Ranking>, value_kind: Option<ValueKind>, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.upsert_scorable(id, group_id, name.clone(), ranking, value_kind.clone(), by).await?;
+        self.persist(by, Event::UpsertScorable {
             id,
             group_id,
-            name
-        }).await;
+            name,
+            ranking,
+            value_kind
+        }).await?;
         Ok(())
     }
-    async fn delete_scorable(&self, id: &ScorableId) -> Result<(),StoreError> {
-        let res = self.memory_store.delete_scorable(id).await?;
-        self.events.push(Event::DeleteScorable {
+    async fn delete_scorable(&self, id: &ScorableId, by: Option<&str>) -> Result<(),StoreError> {
+        let res = self.memory_store.delete_scorable(id, by).await?;
+        self.persist(by, Event::DeleteScorable {
             id: *id
-        }).await;
+        }).await?;
         Ok(res)
     }
     async fn get_scorable(&self, id: &ScorableId) -> Result<Scorable,StoreError> {
         self.memory_store.get_scorable(id).await
     }
 
-    async fn upsert_score(&self, id: ScoreId, scorable_id: ScorableId, username: String, value: i64, date: chrono::DateTime<chrono::Utc>) -> Result<(),StoreError> {
-        self.memory_store.upsert_score(id, scorable_id, username.clone(), value, date).await?;
-        self.events.push(Event::UpsertScore {
+    async fn upsert_score(&self, id: ScoreId, scorable_id: ScorableId, username: String, teammates: Vec<String>, value: i64, date: chrono::DateTime<chrono::Utc>, by: Option<&str>) -> Result<Records,StoreError> {
+        let records = self.memory_store.upsert_score(id, scorable_id, username.clone(), teammates.clone(), value, date, by).await?;
+        self.persist(by, Event::UpsertScore {
             date,
             id,
             value,
             username: username,
+            teammates,
             scorable_id
-        }).await;
-        Ok(())
+        }).await?;
+        Ok(records)
     }
-    async fn delete_score(&self, id: &ScoreId) -> Result<(),StoreError> {
-        let res = self.memory_store.delete_score(id).await?;
-        self.events.push(Event::DeleteScore {
+    async fn delete_score(&self, id: &ScoreId, by: Option<&str>) -> Result<(),StoreError> {
+        let res = self.memory_store.delete_score(id, by).await?;
+        self.persist(by, Event::DeleteScore {
             id: *id
-        }).await;
+        }).await?;
         Ok(res)
     }
+    async fn get_score(&self, id: &ScoreId) -> Result<Score,StoreError> {
+        self.memory_store.get_score(id).await
+    }
+
+    async fn upsert_match(&self, id: MatchId, scorable_id: ScorableId, participants: Vec<String>, winner: Option<String>, date: chrono::DateTime<chrono::Utc>, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.upsert_match(id, scorable_id, participants.clone(), winner.clone(), date, by).await?;
+        self.persist(by, Event::UpsertMatch {
+            id,
+            scorable_id,
+            participants,
+            winner,
+            date
+        }).await?;
+        Ok(())
+    }
+    async fn delete_match(&self, id: &MatchId, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.delete_match(id, by).await?;
+        self.persist(by, Event::DeleteMatch {
+            id: *id
+        }).await?;
+        Ok(())
+    }
+    async fn get_match(&self, id: &MatchId) -> Result<Match,StoreError> {
+        self.memory_store.get_match(id).await
+    }
+    async fn matches(&self, scorable_id: &ScorableId, username: Option<&str>) -> Result<Vec<Match>,StoreError> {
+        self.memory_store.matches(scorable_id, username).await
+    }
+    async fn ratings(&self, scorable_id: &ScorableId) -> Result<Vec<Rating>,StoreError> {
+        self.memory_store.ratings(scorable_id).await
+    }
+    async fn rating_history(&self, scorable_id: &ScorableId, username: &str) -> Result<Vec<RatingChange>,StoreError> {
+        self.memory_store.rating_history(scorable_id, username).await
+    }
 
-    async fn groups(&self) -> Result<Vec<Group>,StoreError> {
-        self.memory_store.groups().await
+    async fn set_seasons(&self, group_id: GroupId, seasons: Option<Seasons>, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.set_seasons(group_id, seasons.clone(), by).await?;
+        self.persist(by, Event::SetSeasons {
+            group_id,
+            seasons
+        }).await?;
+        Ok(())
+    }
+    async fn seasons(&self, group_id: &GroupId) -> Result<Option<Seasons>,StoreError> {
+        self.memory_store.seasons(group_id).await
+    }
+    async fn archive_season(&self, group_id: GroupId, season: ArchivedSeason, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.archive_season(group_id, season.clone(), by).await?;
+        self.persist(by, Event::ArchiveSeason {
+            group_id,
+            season
+        }).await?;
+        Ok(())
+    }
+    async fn season_archive(&self, group_id: &GroupId) -> Result<Vec<ArchivedSeason>,StoreError> {
+        self.memory_store.season_archive(group_id).await
+    }
+
+    async fn restore_group(&self, id: &GroupId, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.restore_group(id, by).await?;
+        self.persist(by, Event::RestoreGroup {
+            id: *id
+        }).await?;
+        Ok(())
+    }
+    async fn restore_scorable(&self, id: &ScorableId, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.restore_scorable(id, by).await?;
+        self.persist(by, Event::RestoreScorable {
+            id: *id
+        }).await?;
+        Ok(())
+    }
+    async fn restore_score(&self, id: &ScoreId, by: Option<&str>) -> Result<(),StoreError> {
+        self.memory_store.restore_score(id, by).await?;
+        self.persist(by, Event::RestoreScore {
+            id: *id
+        }).await?;
+        Ok(())
+    }
+    async fn trash(&self, group_id: Option<&GroupId>) -> Result<Vec<Deleted>,StoreError> {
+        self.memory_store.trash(group_id).await
+    }
+
+    async fn groups(&self, member: Option<&str>) -> Result<Vec<Group>,StoreError> {
+        self.memory_store.groups(member).await
     }
     async fn scorables_in_group(&self, group_id: &GroupId) -> Result<Vec<Scorable>,StoreError> {
         self.memory_store.scorables_in_group(group_id).await
     }
-    async fn scores(&self, scorable_id: &ScorableId, limit: Option<usize>) -> Result<Vec<Score>,StoreError> {
-        self.memory_store.scores(scorable_id, limit).await
+    async fn scores(&self, scorable_id: &ScorableId, filter: &ScoreFilter) -> Result<Vec<Score>,StoreError> {
+        self.memory_store.scores(scorable_id, filter).await
+    }
+    async fn groups_page(&self, member: Option<&str>, page: &PageRequest) -> Result<Page<Group>,StoreError> {
+        self.memory_store.groups_page(member, page).await
+    }
+    async fn scorables_in_group_page(&self, group_id: &GroupId, page: &PageRequest) -> Result<Page<Scorable>,StoreError> {
+        self.memory_store.scorables_in_group_page(group_id, page).await
+    }
+    async fn scores_page(&self, scorable_id: &ScorableId, filter: &ScoreFilter, page: &PageRequest) -> Result<Page<Score>,StoreError> {
+        self.memory_store.scores_page(scorable_id, filter, page).await
+    }
+    async fn stats(&self, scorable_id: &ScorableId, filter: &ScoreFilter) -> Result<Stats,StoreError> {
+        self.memory_store.stats(scorable_id, filter).await
+    }
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>,StoreError> {
+        self.history.flush_to_disk().await?;
+        let mut entries = self.history.read_from_disk().await?;
+        // Keep hold of no more than the newest entries that we're handing back:
+        let limit = filter.limit.unwrap_or(usize::MAX);
+        let mut matching = std::collections::VecDeque::new();
+        while let Some(entry) = entries.next().await {
+            let entry = entry?;
+            if filter.matches(&entry) {
+                matching.push_back(entry);
+                if matching.len() > limit {
+                    matching.pop_front();
+                }
+            }
+        }
+        Ok(matching.into_iter().rev().collect())
+    }
+    async fn as_of(&self, as_of: AsOf) -> Result<Arc<dyn Store + Send + Sync>,StoreError> {
+        // Only one replay happens at a time, so that they can't hog every thread, and so
+        // that anybody waiting on the same point can use the replay once it's done:
+        let mut cache = self.as_of_cache.lock().await;
+        let last_changed = self.memory_store.last_changed().await;
+        cache.retain(|replay| replay.last_changed == last_changed);
+        if let Some(replay) = cache.iter().find(|replay| replay.as_of == as_of) {
+            return Ok(replay.store.clone())
+        }
+        // Replay everything that's been persisted up to the point asked for into a new
+        // memory store, flushing first so that nothing recent is missed:
+        self.events.flush_to_disk().await?;
+        let store = Arc::new(MemoryStore::from_events(&self.events, Some(as_of)).await?);
+        if cache.len() >= AS_OF_CACHE_SIZE {
+            cache.remove(0);
+        }
+        cache.push(Replay { as_of, last_changed, store: store.clone() });
+        Ok(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn history_is_recovered_from_older_databases() {
+        // Before the history was logged on its own, it only lived in the events:
+        let path = std::env::temp_dir().join(format!("highscore-{}.db", uuid::Uuid::new_v4()));
+        let events = EventHandler::new(path.clone(), Durability::Buffered);
+        let group_id = GroupId::new();
+        events.push(LoggedEvent {
+            at: Some(Utc::now()),
+            by: Some("alice".to_owned()),
+            event: Event::UpsertGroup { id: group_id, name: "Group".to_owned() }
+        }).await.unwrap();
+        events.flush_to_disk().await.unwrap();
+
+        let store = PersistedStore::load(path.clone(), Durability::Buffered).await.unwrap();
+        store.delete_group(&group_id, Some("bob")).await.unwrap();
+        let history = store.history(&HistoryFilter::default()).await.unwrap();
+        let by: Vec<_> = history.iter().map(|entry| entry.by.as_deref()).collect();
+        assert_eq!(by, vec![Some("bob"), Some("alice")]);
+        assert!(history.iter().all(|entry| entry.group_id == Some(group_id)));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn failing_to_compact_purges_nothing() {
+        let path = std::env::temp_dir().join(format!("highscore-{}.db", uuid::Uuid::new_v4()));
+        let store = PersistedStore::load(path.clone(), Durability::Buffered).await.unwrap();
+        let group_id = GroupId::new();
+        store.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+        store.delete_group(&group_id, None).await.unwrap();
+
+        // The new log is written alongside the old one first, so get in its way:
+        let tmp_path = events::path_with_suffix(&path, ".tmp");
+        tokio::fs::create_dir(&tmp_path).await.unwrap();
+        assert!(store.compact(chrono::Duration::zero()).await.is_err());
+        assert_eq!(store.trash(None).await.unwrap().len(), 1);
+
+        tokio::fs::remove_dir(&tmp_path).await.unwrap();
+        store.compact(chrono::Duration::zero()).await.unwrap();
+        assert_eq!(store.trash(None).await.unwrap().len(), 0);
+        drop(store);
+        let store = PersistedStore::load(path, Durability::Buffered).await.unwrap();
+        assert_eq!(store.trash(None).await.unwrap().len(), 0);
+    }
+
+    async fn name_as_of(store: &PersistedStore, group_id: &GroupId, as_of: AsOf) -> Result<String,StoreError> {
+        Ok(store.as_of(as_of).await?.get_group(group_id).await?.name)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn viewing_as_of_some_point() {
+        let path = std::env::temp_dir().join(format!("highscore-{}.db", uuid::Uuid::new_v4()));
+        let store = PersistedStore::load(path, Durability::Buffered).await.unwrap();
+        let group_id = GroupId::new();
+        store.upsert_group(group_id, "First".to_owned(), None).await.unwrap();
+        let first = Utc::now();
+        store.upsert_group(group_id, "Second".to_owned(), None).await.unwrap();
+        assert_eq!(name_as_of(&store, &group_id, AsOf::Time(first)).await.unwrap(), "First");
+        assert_eq!(name_as_of(&store, &group_id, AsOf::Index(1)).await.unwrap(), "First");
+        assert!(name_as_of(&store, &group_id, AsOf::Index(0)).await.is_err());
+
+        // Things in the trash are logged as deleted (and when) midway through a compacted log:
+        let scorable_id = ScorableId::new();
+        store.upsert_scorable(scorable_id, group_id, "Scorable".to_owned(), None, None, None).await.unwrap();
+        store.delete_scorable(&scorable_id, None).await.unwrap();
+        store.compact(chrono::Duration::days(30)).await.unwrap();
+        let compacted = Utc::now();
+        store.upsert_group(group_id, "Third".to_owned(), None).await.unwrap();
+
+        let is_compacted = |res: Result<String,StoreError>| matches!(res, Err(StoreError::CompactedSince(_)));
+        assert!(is_compacted(name_as_of(&store, &group_id, AsOf::Time(first)).await));
+        assert!(is_compacted(name_as_of(&store, &group_id, AsOf::Index(1)).await));
+        assert_eq!(name_as_of(&store, &group_id, AsOf::Time(compacted)).await.unwrap(), "Second");
+        assert_eq!(name_as_of(&store, &group_id, AsOf::Time(Utc::now())).await.unwrap(), "Third");
+        let as_of = store.as_of(AsOf::Time(compacted)).await.unwrap();
+        assert!(as_of.get_scorable(&scorable_id).await.is_err());
+        assert_eq!(as_of.trash(Some(&group_id)).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replays_are_cached_until_something_changes() {
+        let path = std::env::temp_dir().join(format!("highscore-{}.db", uuid::Uuid::new_v4()));
+        let store = PersistedStore::load(path, Durability::Buffered).await.unwrap();
+        store.upsert_group(GroupId::new(), "Group".to_owned(), None).await.unwrap();
+        let same = |a: &Arc<dyn Store + Send + Sync>, b: &Arc<dyn Store + Send + Sync>| {
+            Arc::as_ptr(a) as *const u8 == Arc::as_ptr(b) as *const u8
+        };
+        let as_of = AsOf::Time(Utc::now());
+        let first = store.as_of(as_of).await.unwrap();
+        assert!(same(&first, &store.as_of(as_of).await.unwrap()));
+        assert!(!same(&first, &store.as_of(AsOf::Index(1)).await.unwrap()));
+        store.upsert_group(GroupId::new(), "Another".to_owned(), None).await.unwrap();
+        assert!(!same(&first, &store.as_of(as_of).await.unwrap()));
     }
 }
@@ -0,0 +1,223 @@
+//! Elo ratings for scorables that are matches played between users (table tennis,
+//! chess and so on) rather than solo scores. Everybody starts at [`INITIAL_RATING`].
+//!
+//! In a match between two users, the winner takes points from the loser according to
+//! how likely they were to win. Matches with more participants are treated as the
+//! winner beating each of the others (or, if drawn, everybody drawing with everybody),
+//! with the points at stake shared out between the games that each user plays.
+
+use std::collections::HashMap;
+use chrono::prelude::{ DateTime, Utc };
+use crate::store_interface::{ Match, MatchId, Rating, RatingChange };
+
+/// The rating that users start with.
+pub const INITIAL_RATING: f64 = 1500.0;
+/// The most that a rating can change by in one match.
+const K_FACTOR: f64 = 32.0;
+
+/// The ratings of everybody that has played matches against a scorable.
+#[derive(Debug,Clone,Default)]
+pub struct Ratings {
+    players: HashMap<String, Player>,
+    /// The date and ID of the latest match applied. Matches are applied in this
+    /// order, so anything coming before it means working everything out again.
+    latest: Option<(DateTime<Utc>, MatchId)>
+}
+
+#[derive(Debug,Clone)]
+struct Player {
+    rating: f64,
+    wins: usize,
+    draws: usize,
+    losses: usize,
+    history: Vec<RatingChange>
+}
+
+impl Ratings {
+    /// Work out ratings from scratch, given every match played against a scorable (in any order).
+    pub fn from_matches<'a>(matches: impl IntoIterator<Item=&'a Match>) -> Ratings {
+        let mut matches: Vec<_> = matches.into_iter().collect();
+        matches.sort_by(|a,b| a.date.cmp(&b.date).then(a.id.cmp(&b.id)));
+        let mut ratings = Ratings::default();
+        for m in matches {
+            ratings.apply(m);
+        }
+        ratings
+    }
+    /// Can the match be applied on top of these ratings, or does it come before
+    /// matches that have already been applied?
+    pub fn follows(&self, m: &Match) -> bool {
+        self.latest.map(|latest| (m.date, m.id) > latest).unwrap_or(true)
+    }
+    /// Update ratings given the result of a match, which should follow everything applied so far.
+    pub fn apply(&mut self, m: &Match) {
+        let rating_of = |username: &String| self.players.get(username).map(|p| p.rating).unwrap_or(INITIAL_RATING);
+        let before: Vec<f64> = m.participants.iter().map(rating_of).collect();
+        let k = K_FACTOR / (m.participants.len() - 1).max(1) as f64;
+        for (idx, username) in m.participants.iter().enumerate() {
+            let won = m.winner.as_ref() == Some(username);
+            let mut change = 0.0;
+            for (other_idx, other) in m.participants.iter().enumerate() {
+                if other_idx == idx {
+                    continue
+                }
+                let score = match &m.winner {
+                    None => 0.5,
+                    Some(_) if won => 1.0,
+                    Some(winner) if winner == other => 0.0,
+                    // Neither won, so this pair didn't play each other:
+                    Some(_) => continue
+                };
+                change += k * (score - expected_score(before[idx], before[other_idx]));
+            }
+
+            let player = self.players.entry(username.clone()).or_insert_with(|| Player {
+                rating: INITIAL_RATING,
+                wins: 0,
+                draws: 0,
+                losses: 0,
+                history: Vec::new()
+            });
+            player.rating += change;
+            match &m.winner {
+                None => player.draws += 1,
+                Some(_) if won => player.wins += 1,
+                Some(_) => player.losses += 1
+            }
+            player.history.push(RatingChange { match_id: m.id, date: m.date, rating: player.rating, change });
+        }
+        self.latest = Some((m.date, m.id));
+    }
+    /// Everybody's rating, highest first.
+    pub fn leaderboard(&self) -> Vec<Rating> {
+        let mut ratings: Vec<_> = self.players.iter()
+            .map(|(username, p)| Rating {
+                username: username.clone(),
+                rating: p.rating,
+                wins: p.wins,
+                draws: p.draws,
+                losses: p.losses
+            })
+            .collect();
+        ratings.sort_by(|a,b| b.rating.partial_cmp(&a.rating).unwrap().then_with(|| a.username.cmp(&b.username)));
+        ratings
+    }
+    /// How a user's rating changed with each match they played, oldest first.
+    pub fn history(&self, username: &str) -> Vec<RatingChange> {
+        self.players.get(username)
+            .map(|p| p.history.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// How likely a player rated `rating` is to beat one rated `other` (counting draws as half a win).
+fn expected_score(rating: f64, other: f64) -> f64 {
+    1.0 / (1.0 + 10f64.powf((other - rating) / 400.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use crate::store_interface::ScorableId;
+
+    fn played(participants: &[&str], winner: Option<&str>, day: u32) -> Match {
+        Match {
+            id: MatchId::new(),
+            scorable_id: ScorableId::new(),
+            participants: participants.iter().map(|p| p.to_string()).collect(),
+            winner: winner.map(|w| w.to_owned()),
+            date: Utc.ymd(2021, 1, day).and_hms(0, 0, 0)
+        }
+    }
+
+    fn rating(ratings: &Ratings, username: &str) -> Rating {
+        ratings.leaderboard().into_iter().find(|r| r.username == username).unwrap()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} isn't {}", actual, expected);
+    }
+
+    #[test]
+    fn two_players_winning_and_drawing() {
+        let mut ratings = Ratings::default();
+        // Evenly matched players stake half of the K factor each:
+        ratings.apply(&played(&["alice", "bob"], Some("alice"), 1));
+        assert_close(rating(&ratings, "alice").rating, INITIAL_RATING + 16.0);
+        assert_close(rating(&ratings, "bob").rating, INITIAL_RATING - 16.0);
+        assert_eq!(ratings.leaderboard().iter().map(|r| (r.wins, r.draws, r.losses)).collect::<Vec<_>>(), vec![(1, 0, 0), (0, 0, 1)]);
+
+        // Drawing with somebody rated higher gains points, and they lose just as many:
+        ratings.apply(&played(&["alice", "bob"], None, 2));
+        let expected = 32.0 * (0.5 - 1.0 / (1.0 + 10f64.powf(32.0 / 400.0)));
+        let (alice, bob) = (rating(&ratings, "alice"), rating(&ratings, "bob"));
+        assert_close(bob.rating, INITIAL_RATING - 16.0 + expected);
+        assert_close(alice.rating, INITIAL_RATING + 16.0 - expected);
+        assert_eq!((alice.draws, bob.draws), (1, 1));
+
+        let history = ratings.history("bob");
+        assert_eq!(history.len(), 2);
+        assert_close(history[0].change, -16.0);
+        assert_close(history[1].rating, bob.rating);
+        assert!(ratings.history("carol").is_empty());
+    }
+
+    #[test]
+    fn points_are_shared_out_between_more_players() {
+        for n in 3..=5 {
+            let participants: Vec<String> = (0..n).map(|p| format!("player{}", p)).collect();
+            let participants: Vec<&str> = participants.iter().map(|p| p.as_str()).collect();
+            let mut ratings = Ratings::default();
+            // The winner beats each of the others, and the others don't play each other:
+            ratings.apply(&played(&participants, Some("player0"), 1));
+            let k = 32.0 / (n - 1) as f64;
+            assert_close(rating(&ratings, "player0").rating, INITIAL_RATING + k / 2.0 * (n - 1) as f64);
+            for loser in &participants[1..] {
+                assert_close(rating(&ratings, loser).rating, INITIAL_RATING - k / 2.0);
+            }
+
+            // In a draw, everybody draws with everybody:
+            ratings.apply(&played(&participants, None, 2));
+            let total: f64 = ratings.leaderboard().iter().map(|r| r.rating).sum();
+            assert_close(total, INITIAL_RATING * n as f64);
+            let winner = rating(&ratings, "player0");
+            assert!(winner.rating < INITIAL_RATING + 16.0);
+            assert_eq!((winner.wins, winner.draws, winner.losses), (1, 1, 0));
+        }
+    }
+
+    #[test]
+    fn matches_follow_the_latest_one_applied() {
+        let mut ids = [MatchId::new(), MatchId::new(), MatchId::new()];
+        ids.sort();
+        let mut ratings = Ratings::default();
+        let first = Match { id: ids[1], ..played(&["alice", "bob"], Some("alice"), 2) };
+        assert!(ratings.follows(&first));
+        ratings.apply(&first);
+        assert!(!ratings.follows(&first));
+        assert!(!ratings.follows(&played(&["alice", "bob"], None, 1)));
+        assert!(ratings.follows(&played(&["alice", "bob"], None, 3)));
+
+        // Matches on the same date are ordered by ID:
+        let same_day = played(&["alice", "bob"], None, 2);
+        assert!(!ratings.follows(&Match { id: ids[0], ..same_day.clone() }));
+        assert!(ratings.follows(&Match { id: ids[2], ..same_day }));
+    }
+
+    #[test]
+    fn matches_are_rated_in_the_order_they_were_played() {
+        let matches = vec![
+            played(&["alice", "bob"], Some("bob"), 3),
+            played(&["alice", "bob", "carol"], Some("alice"), 1),
+            played(&["bob", "carol"], None, 2)
+        ];
+        let mut in_order = Ratings::default();
+        for idx in &[1, 2, 0] {
+            in_order.apply(&matches[*idx]);
+        }
+        let from_matches = Ratings::from_matches(&matches);
+        assert_eq!(from_matches.leaderboard(), in_order.leaderboard());
+        assert_eq!(from_matches.history("bob"), in_order.history("bob"));
+    }
+}
@@ -0,0 +1,170 @@
+//! Scores are stored as plain integers. This converts between those and the
+//! typed values (decimals, durations and so on) that the API works with.
+
+use serde_json::Value;
+use crate::store_interface::ValueKind;
+
+/// Durations are stored as milliseconds.
+const DURATION_PRECISION: u8 = 3;
+/// Any more decimal places than this and we'd run out of room in an i64 fairly quickly.
+const MAX_DECIMAL_PRECISION: u8 = 9;
+
+impl ValueKind {
+    /// Check that the kind of value described is one we can work with.
+    pub fn validate(&self) -> Result<(),String> {
+        match self {
+            ValueKind::Decimal { precision, .. } if *precision > MAX_DECIMAL_PRECISION => {
+                Err(format!("decimal precision cannot be greater than {}", MAX_DECIMAL_PRECISION))
+            },
+            ValueKind::Duration { precision } if *precision > DURATION_PRECISION => {
+                Err(format!("duration precision cannot be greater than {}", DURATION_PRECISION))
+            },
+            _ => Ok(())
+        }
+    }
+
+    /// Parse a number or string handed to the API into the value that we store.
+    pub fn parse(&self, value: &Value) -> Result<i64,String> {
+        let text = match value {
+            Value::Number(n) => n.to_string(),
+            Value::String(s) => s.trim().to_owned(),
+            _ => return Err("score value must be a number or a string".to_owned())
+        };
+        match self {
+            ValueKind::Integer { .. } => {
+                text.parse().map_err(|_| format!("'{}' is not a whole number", text))
+            },
+            ValueKind::Decimal { precision, .. } => {
+                parse_decimal(&text, *precision).ok_or_else(|| {
+                    format!("'{}' is not a number with at most {} decimal places", text, precision)
+                })
+            },
+            ValueKind::Duration { .. } => {
+                parse_duration(&text).ok_or_else(|| {
+                    format!("'{}' is not a duration; expected something like '1:23.456', or a number of seconds", text)
+                })
+            }
+        }
+    }
+
+    /// The value that the API hands back for some stored value. Durations are given in seconds.
+    pub fn to_json(&self, raw: i64) -> Value {
+        match self {
+            ValueKind::Integer { .. } => Value::from(raw),
+            ValueKind::Decimal { precision, .. } => Value::from(raw as f64 / 10f64.powi(*precision as i32)),
+            ValueKind::Duration { .. } => Value::from(raw as f64 / 1000.0)
+        }
+    }
+
+    /// Some stored value, or an aggregate of them (like a mean), as a number in the
+    /// kind's terms. Durations are given in seconds.
+    pub fn to_number(&self, raw: f64) -> f64 {
+        match self {
+            ValueKind::Integer { .. } => raw,
+            ValueKind::Decimal { precision, .. } => raw / 10f64.powi(*precision as i32),
+            ValueKind::Duration { .. } => raw / 1000.0
+        }
+    }
+
+    /// Some stored value as plain text without any unit, which `parse` understands.
+    /// Durations are given in seconds.
+    pub fn to_plain(&self, raw: i64) -> String {
+        match self {
+            ValueKind::Integer { .. } => raw.to_string(),
+            ValueKind::Decimal { precision, .. } => format_decimal(raw, *precision),
+            ValueKind::Duration { .. } => format_decimal(raw, DURATION_PRECISION)
+        }
+    }
+
+    /// A human friendly representation of some stored value.
+    pub fn format(&self, raw: i64) -> String {
+        match self {
+            ValueKind::Integer { unit } => with_unit(raw.to_string(), unit),
+            ValueKind::Decimal { precision, unit } => with_unit(format_decimal(raw, *precision), unit),
+            ValueKind::Duration { precision } => format_duration(raw, *precision)
+        }
+    }
+}
+
+/// Parse eg "-12.3" into -1230 given a precision of 2.
+fn parse_decimal(text: &str, precision: u8) -> Option<i64> {
+    let (negative, unsigned) = match text.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, text)
+    };
+    let mut parts = unsigned.splitn(2, '.');
+    let whole = parts.next().unwrap_or("");
+    let frac = parts.next().unwrap_or("");
+
+    let all_digits = |s: &str| s.chars().all(|c| c.is_ascii_digit());
+    if (whole.is_empty() && frac.is_empty()) || !all_digits(whole) || !all_digits(frac) || frac.len() > precision as usize {
+        return None
+    }
+
+    let digits = format!("{}{:0<width$}", whole, frac, width = precision as usize);
+    let magnitude: i64 = digits.parse().ok()?;
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// Parse eg "1:02:03.4", "2:03.4" or "123.4" into milliseconds.
+fn parse_duration(text: &str) -> Option<i64> {
+    let mut parts: Vec<&str> = text.split(':').collect();
+    if parts.len() > 3 {
+        return None
+    }
+
+    let seconds = parts.pop()?;
+    let mut millis = parse_decimal(seconds, DURATION_PRECISION)?;
+    if millis < 0 || (!parts.is_empty() && millis >= 60_000) {
+        return None
+    }
+
+    // Minutes, then hours:
+    for (part, millis_per) in parts.iter().rev().zip(&[60_000, 3_600_000]) {
+        let n: i64 = part.parse().ok()?;
+        if n < 0 || (*millis_per == 60_000 && parts.len() == 2 && n >= 60) {
+            return None
+        }
+        millis = n.checked_mul(*millis_per)?.checked_add(millis)?;
+    }
+    Some(millis)
+}
+
+fn format_decimal(raw: i64, precision: u8) -> String {
+    let sign = if raw < 0 { "-" } else { "" };
+    let magnitude = (raw as i128).abs();
+    if precision == 0 {
+        return format!("{}{}", sign, magnitude)
+    }
+    let scale = 10i128.pow(precision as u32);
+    format!("{}{}.{:0width$}", sign, magnitude / scale, magnitude % scale, width = precision as usize)
+}
+
+fn format_duration(millis: i64, precision: u8) -> String {
+    let sign = if millis < 0 { "-" } else { "" };
+    let millis = (millis as i128).abs();
+    let hours = millis / 3_600_000;
+    let mins = millis / 60_000 % 60;
+    let secs = millis / 1000 % 60;
+
+    let mut s = if hours > 0 {
+        format!("{}{}:{:02}:{:02}", sign, hours, mins, secs)
+    } else if mins > 0 {
+        format!("{}{}:{:02}", sign, mins, secs)
+    } else {
+        format!("{}{}", sign, secs)
+    };
+    if precision > 0 {
+        let frac = format!("{:03}", millis % 1000);
+        s.push('.');
+        s.push_str(&frac[..precision as usize]);
+    }
+    s
+}
+
+fn with_unit(value: String, unit: &Option<String>) -> String {
+    match unit {
+        Some(unit) if !unit.is_empty() => format!("{} {}", value, unit),
+        _ => value
+    }
+}
@@ -0,0 +1,387 @@
+//! Import scores from CSV files (for instance, those exported from spreadsheets),
+//! and export them again for people to analyse however they like.
+
+use std::collections::{ HashMap, HashSet };
+use chrono::prelude::{ DateTime, Utc, NaiveDate, NaiveDateTime, TimeZone };
+use tokio::io::{ AsyncWrite, AsyncWriteExt };
+use crate::store_interface::{ Store, GroupId, ScorableId, ScoreId, Scorable, ScoreFilter, HashedPassword, Ranking, ValueKind };
+
+/// A column in a CSV file: either the name in its header, or a number counting from 1.
+#[derive(Debug,Clone)]
+pub enum Column {
+    Name(String),
+    Number(usize)
+}
+
+impl std::str::FromStr for Column {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(0) => anyhow::bail!("'0' is not a valid column; column numbers start from 1"),
+            Ok(n) => Ok(Column::Number(n)),
+            Err(_) => Ok(Column::Name(s.to_owned()))
+        }
+    }
+}
+
+impl Column {
+    /// Find the index of the column, given the headers of the file if it has any.
+    fn index(&self, headers: Option<&csv::StringRecord>) -> anyhow::Result<usize> {
+        match self {
+            Column::Number(n) => Ok(n - 1),
+            Column::Name(name) => headers
+                .and_then(|headers| headers.iter().position(|h| h.trim() == name))
+                .ok_or_else(|| anyhow::anyhow!("There is no '{}' column", name))
+        }
+    }
+}
+
+/// Where to put the scores being imported, and how to find them in each row.
+#[derive(Debug,Clone)]
+pub struct ImportOptions {
+    pub group_id: GroupId,
+    /// The name (or ID) of the scorable that every score is for. If not
+    /// given, each row names its scorable in `scorable_column`.
+    pub scorable: Option<String>,
+    pub scorable_column: Option<Column>,
+    pub username_column: Column,
+    pub value_column: Column,
+    pub date_column: Column,
+    /// Does the first row name the columns?
+    pub has_headers: bool,
+    /// The `strftime` format that dates are in. If not given, RFC 3339, "YYYY-MM-DD HH:MM:SS"
+    /// and "YYYY-MM-DD" dates are understood. Dates without a timezone are taken to be UTC.
+    pub date_format: Option<String>,
+    /// Create users that don't exist yet (with random passwords) rather than failing their rows.
+    pub create_users: bool,
+    /// Create scorables that don't exist yet, ranked and valued like this, rather than failing their rows.
+    pub create_scorables: Option<(Ranking, ValueKind)>,
+    /// Work out what would happen without changing anything.
+    pub dry_run: bool
+}
+
+/// What happened (or in a dry run, would happen) when importing.
+#[derive(Debug,Clone,Default)]
+pub struct ImportReport {
+    pub imported: usize,
+    pub created_users: Vec<String>,
+    pub created_scorables: Vec<String>,
+    /// Rows that couldn't be imported, and the lines that they're on.
+    pub failures: Vec<(u64, RowError)>
+}
+
+/// Why a row couldn't be imported.
+#[derive(thiserror::Error,Debug,Clone,PartialEq,Eq)]
+pub enum RowError {
+    #[error("there is no column {0}")]
+    MissingColumn(usize),
+    #[error("unknown user '{0}'")]
+    UnknownUser(String),
+    #[error("unknown scorable '{0}'")]
+    UnknownScorable(String),
+    #[error("bad date '{0}'")]
+    BadDate(String),
+    #[error("bad value: {0}")]
+    BadValue(String)
+}
+
+/// Import scores from some CSV into a group. Rows that can't be imported are reported
+/// and skipped, and the rest are added with `Store::upsert_score` (unless it's a dry
+/// run). Each row is added as a new score, so importing a file twice duplicates them.
+pub async fn import(store: &(dyn Store + Send + Sync), csv: &[u8], opts: &ImportOptions) -> anyhow::Result<ImportReport> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(opts.has_headers)
+        .flexible(true)
+        .from_reader(csv);
+    let headers = if opts.has_headers { Some(reader.headers()?.clone()) } else { None };
+    let columns = Columns {
+        username: opts.username_column.index(headers.as_ref())?,
+        value: opts.value_column.index(headers.as_ref())?,
+        date: opts.date_column.index(headers.as_ref())?,
+        scorable: match (&opts.scorable, &opts.scorable_column) {
+            (Some(_), _) => None,
+            (None, Some(column)) => Some(column.index(headers.as_ref())?),
+            (None, None) => anyhow::bail!("Either a scorable or a scorable column must be given")
+        }
+    };
+
+    let mut users: HashSet<String> = store.users().await?.into_iter().collect();
+    // Scorables can be referred to by name or by ID:
+    let mut scorables = HashMap::new();
+    for scorable in store.scorables_in_group(&opts.group_id).await? {
+        scorables.insert(scorable.id.to_string(), (scorable.id, scorable.value_kind.clone()));
+        scorables.insert(scorable.name, (scorable.id, scorable.value_kind));
+    }
+
+    let mut report = ImportReport::default();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map(|p| p.line()).unwrap_or(0);
+        // Check everything in the row before creating anything that it needs:
+        let row = match check_row(&record, &columns, opts, &users, &scorables) {
+            Ok(row) => row,
+            Err(e) => {
+                report.failures.push((line, e));
+                continue
+            }
+        };
+
+        if !users.contains(&row.username) {
+            if !opts.dry_run {
+                store.upsert_user(row.username.clone(), HashedPassword::random(), None, None).await?;
+            }
+            users.insert(row.username.clone());
+            report.created_users.push(row.username.clone());
+        }
+        let scorable_id = match row.scorable_id {
+            Some(id) => id,
+            None => {
+                let id = ScorableId::new();
+                let (ranking, value_kind) = opts.create_scorables.clone().expect("checked in check_row");
+                if !opts.dry_run {
+                    store.upsert_scorable(id, opts.group_id, row.scorable.clone(), Some(ranking), Some(value_kind.clone()), None).await?;
+                }
+                scorables.insert(row.scorable.clone(), (id, value_kind));
+                report.created_scorables.push(row.scorable);
+                id
+            }
+        };
+        if !opts.dry_run {
+            store.upsert_score(ScoreId::new(), scorable_id, row.username, Vec::new(), row.value, row.date, None).await?;
+        }
+        report.imported += 1;
+    }
+    Ok(report)
+}
+
+/// The indexes of the columns that we're interested in.
+struct Columns {
+    username: usize,
+    value: usize,
+    date: usize,
+    scorable: Option<usize>
+}
+
+/// A row that's ready to import (once any missing user or scorable is created).
+struct Row {
+    username: String,
+    scorable: String,
+    /// None if the scorable needs creating.
+    scorable_id: Option<ScorableId>,
+    value: i64,
+    date: DateTime<Utc>
+}
+
+fn check_row(
+    record: &csv::StringRecord,
+    columns: &Columns,
+    opts: &ImportOptions,
+    users: &HashSet<String>,
+    scorables: &HashMap<String, (ScorableId, ValueKind)>
+) -> Result<Row,RowError> {
+    let field = |idx: usize| record.get(idx).map(|f| f.trim()).ok_or(RowError::MissingColumn(idx + 1));
+
+    let username = field(columns.username)?;
+    if !users.contains(username) && !opts.create_users {
+        return Err(RowError::UnknownUser(username.to_owned()))
+    }
+
+    let scorable = match (&opts.scorable, columns.scorable) {
+        (Some(scorable), _) => scorable.as_str(),
+        (_, Some(idx)) => field(idx)?,
+        (None, None) => unreachable!("a scorable or scorable column is checked for up front")
+    };
+    let (scorable_id, value_kind) = match (scorables.get(scorable), &opts.create_scorables) {
+        (Some((id, value_kind)), _) => (Some(*id), value_kind),
+        (None, Some((_, value_kind))) => (None, value_kind),
+        (None, None) => return Err(RowError::UnknownScorable(scorable.to_owned()))
+    };
+
+    let date_text = field(columns.date)?;
+    let date = parse_date(date_text, opts.date_format.as_deref())
+        .ok_or_else(|| RowError::BadDate(date_text.to_owned()))?;
+
+    let value_text = field(columns.value)?;
+    let value = value_kind.parse(&serde_json::Value::from(value_text))
+        .map_err(RowError::BadValue)?;
+
+    Ok(Row { username: username.to_owned(), scorable: scorable.to_owned(), scorable_id, value, date })
+}
+
+/// Write out the scores against some scorables as CSV, one scorable at a time (best first).
+/// The username, value and date columns are what `import` expects by default, so exported
+/// scores can be imported elsewhere by giving it the scorable column too. Anybody else that
+/// a score is credited to is listed in the teammates column, separated by semicolons.
+pub async fn export<W: AsyncWrite + Unpin>(store: &(dyn Store + Send + Sync), scorables: &[Scorable], out: &mut W) -> anyhow::Result<()> {
+    out.write_all(&to_csv(&[vec!["scorable", "username", "value", "date", "rank", "teammates"]])?).await?;
+    for scorable in scorables {
+        let scores = store.scores(&scorable.id, &ScoreFilter::default()).await?;
+        let mut rows = Vec::with_capacity(scores.len());
+        let mut rank = 0;
+        for (idx, score) in scores.iter().enumerate() {
+            // Equal scores share a rank:
+            if idx == 0 || score.value != scores[idx - 1].value {
+                rank = idx + 1;
+            }
+            rows.push(vec![
+                scorable.name.clone(),
+                score.username.clone(),
+                scorable.value_kind.to_plain(score.value),
+                score.date.to_rfc3339(),
+                rank.to_string(),
+                score.teammates.join(";")
+            ]);
+        }
+        out.write_all(&to_csv(&rows)?).await?;
+    }
+    out.flush().await?;
+    Ok(())
+}
+
+fn to_csv<S: AsRef<str>>(rows: &[Vec<S>]) -> anyhow::Result<Vec<u8>> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer.write_record(row.iter().map(|field| field.as_ref()))?;
+    }
+    writer.into_inner().map_err(|e| e.into_error().into())
+}
+
+/// Parse a date in the format given, or in one of a few common formats if none is given.
+fn parse_date(text: &str, format: Option<&str>) -> Option<DateTime<Utc>> {
+    match format {
+        Some(format) => DateTime::parse_from_str(text, format).ok().map(|d| d.with_timezone(&Utc))
+            .or_else(|| NaiveDateTime::parse_from_str(text, format).ok().map(|d| Utc.from_utc_datetime(&d)))
+            .or_else(|| NaiveDate::parse_from_str(text, format).ok().map(|d| Utc.from_utc_datetime(&d.and_hms(0, 0, 0)))),
+        None => DateTime::parse_from_rfc3339(text).ok().map(|d| d.with_timezone(&Utc))
+            .or_else(|| parse_date(text, Some("%Y-%m-%d %H:%M:%S")))
+            .or_else(|| parse_date(text, Some("%Y-%m-%d")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BackendStore;
+    use crate::backend::testing::{ stores, add_users };
+
+    /// Import options for CSV with `username`, `value` and `date` headers.
+    fn options(group_id: GroupId) -> ImportOptions {
+        ImportOptions {
+            group_id,
+            scorable: None,
+            scorable_column: Some(Column::Name("scorable".to_owned())),
+            username_column: Column::Name("username".to_owned()),
+            value_column: Column::Name("value".to_owned()),
+            date_column: Column::Name("date".to_owned()),
+            has_headers: true,
+            date_format: None,
+            create_users: false,
+            create_scorables: None,
+            dry_run: false
+        }
+    }
+
+    async fn exported(store: &BackendStore, group_id: &GroupId) -> String {
+        let scorables = store.scorables_in_group(group_id).await.unwrap();
+        let mut out = Vec::new();
+        export(&**store, &scorables, &mut out).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn exported_scores_can_be_imported_elsewhere() {
+        let lap_time = ValueKind::Duration { precision: 2 };
+        for (from, to) in stores().await.into_iter().zip(stores().await) {
+            add_users(&from, &["alice", "bob"]).await;
+            let group_id = GroupId::new();
+            from.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+            let scores = [
+                ("Lap, short", "alice", 61_500, 1), ("Lap, short", "bob", 60_000, 2), ("Lap, short", "bob", 61_500, 3),
+                ("Lap, long", "alice", 120_250, 4)
+            ];
+            for (name, username, value, day) in scores.iter() {
+                let scorable_id = match from.scorables_in_group(&group_id).await.unwrap().into_iter().find(|s| s.name == *name) {
+                    Some(scorable) => scorable.id,
+                    None => {
+                        let id = ScorableId::new();
+                        from.upsert_scorable(id, group_id, name.to_string(), Some(Ranking::LowerIsBetter), Some(lap_time.clone()), None).await.unwrap();
+                        id
+                    }
+                };
+                let date = Utc.ymd(2021, 3, *day).and_hms(12, 30, 0);
+                from.upsert_score(ScoreId::new(), scorable_id, username.to_string(), Vec::new(), *value, date, None).await.unwrap();
+            }
+            let csv = exported(&from, &group_id).await;
+            assert_eq!(csv.lines().next(), Some("scorable,username,value,date,rank,teammates"));
+            assert_eq!(csv.lines().nth(2), Some("\"Lap, short\",bob,60.000,2021-03-02T12:30:00+00:00,1,"));
+
+            // Importing into a store with nothing in it yet, bar the group:
+            to.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+            let opts = ImportOptions {
+                create_users: true,
+                create_scorables: Some((Ranking::LowerIsBetter, lap_time.clone())),
+                ..options(group_id)
+            };
+            let report = import(&*to, csv.as_bytes(), &opts).await.unwrap();
+            assert_eq!(report.failures, Vec::new());
+            assert_eq!(report.imported, 4);
+            assert_eq!(report.created_users, vec!["alice".to_owned(), "bob".to_owned()]);
+            assert_eq!(report.created_scorables, vec!["Lap, long".to_owned(), "Lap, short".to_owned()]);
+            assert_eq!(exported(&to, &group_id).await, csv);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn dry_runs_report_the_rows_that_would_fail() {
+        for store in stores().await {
+            add_users(&store, &["bob"]).await;
+            let group_id = GroupId::new();
+            let scorable_id = ScorableId::new();
+            store.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+            let value_kind = ValueKind::Decimal { precision: 1, unit: None };
+            store.upsert_scorable(scorable_id, group_id, "Darts".to_owned(), None, Some(value_kind.clone()), None).await.unwrap();
+            let csv = [
+                "Player,When,Score",
+                "bob,01/02/2020,12.5",
+                "alice,01/02/2020,3",
+                "bob,yesterday,3",
+                "bob,02/02/2020,x",
+                "bob",
+                " bob , 03/02/2020 , 7"
+            ].join("\n");
+            let csv = csv.as_bytes();
+            let opts = ImportOptions {
+                scorable: Some("Darts".to_owned()),
+                username_column: "Player".parse().unwrap(),
+                value_column: "Score".parse().unwrap(),
+                date_column: "When".parse().unwrap(),
+                date_format: Some("%d/%m/%Y".to_owned()),
+                dry_run: true,
+                ..options(group_id)
+            };
+
+            let report = import(&*store, csv, &opts).await.unwrap();
+            assert_eq!(report.imported, 2);
+            assert_eq!(report.failures, vec![
+                (3, RowError::UnknownUser("alice".to_owned())),
+                (4, RowError::BadDate("yesterday".to_owned())),
+                (5, RowError::BadValue("'x' is not a number with at most 1 decimal places".to_owned())),
+                (6, RowError::MissingColumn(2))
+            ]);
+            assert!(store.scores(&scorable_id, &ScoreFilter::default()).await.unwrap().is_empty());
+
+            // Users and scorables that would be created are reported, but aren't created:
+            let opts = ImportOptions { create_users: true, scorable: Some("Pool".to_owned()), create_scorables: Some((Ranking::default(), value_kind)), ..opts };
+            let report = import(&*store, csv, &opts).await.unwrap();
+            assert_eq!((report.imported, report.failures.len()), (3, 3));
+            assert_eq!(report.created_users, vec!["alice".to_owned()]);
+            assert_eq!(report.created_scorables, vec!["Pool".to_owned()]);
+            assert_eq!(store.users().await.unwrap(), vec!["bob".to_owned()]);
+            assert_eq!(store.scorables_in_group(&group_id).await.unwrap().len(), 1);
+
+            // Columns that aren't there at all fail everything up front:
+            let opts = ImportOptions { date_column: "Date".parse().unwrap(), ..opts };
+            assert!(import(&*store, csv, &opts).await.is_err());
+        }
+    }
+}
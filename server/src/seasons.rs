@@ -0,0 +1,244 @@
+//! Seasons split a group's scores up by their date, for competitions that run every
+//! month (or quarter, and so on) or between dates of their own. Once a season has
+//! finished, the best score against each scorable in it is archived, so that past
+//! winners are kept even if scores are changed or deleted later.
+
+use std::collections::HashSet;
+use chrono::prelude::{ DateTime, Utc, Datelike, NaiveDate, TimeZone };
+use chrono::Duration;
+use crate::store_interface::{ Store, StoreError, Seasons, SeasonPeriod, Season, ArchivedSeason, SeasonWinner, ScoreFilter, MAX_SEASONS };
+
+impl Seasons {
+    /// The seasons, oldest first. Recurring seasons are listed up to the one that `now`
+    /// falls in (and no more than `MAX_SEASONS` of them, so the oldest are dropped),
+    /// whereas named seasons are all listed, even those yet to start.
+    pub fn list(&self, now: DateTime<Utc>) -> Vec<Season> {
+        match self {
+            Seasons::Named { seasons } => {
+                let mut seasons = seasons.clone();
+                seasons.sort_by_key(|s| s.start);
+                seasons
+            },
+            Seasons::Recurring { period, from } => {
+                let first_start = match period.season_of(*from) {
+                    Some(first) => first.start,
+                    None => return Vec::new()
+                };
+                // Work back from the latest season, so that we stop after as many as we want:
+                let mut seasons = Vec::new();
+                let mut season = period.season_of(now);
+                while let Some(s) = season.filter(|s| s.start >= first_start && seasons.len() < MAX_SEASONS) {
+                    season = s.start.checked_sub_signed(Duration::days(1)).and_then(|d| period.season_of(d));
+                    seasons.push(s);
+                }
+                seasons.reverse();
+                seasons
+            }
+        }
+    }
+    /// The season that a date falls in, if any.
+    pub fn season_of(&self, date: DateTime<Utc>) -> Option<Season> {
+        match self {
+            Seasons::Named { seasons } => {
+                seasons.iter().find(|s| s.start <= date && date < s.end).cloned()
+            },
+            Seasons::Recurring { period, from } => {
+                let first = period.season_of(*from)?;
+                period.season_of(date).filter(|s| s.start >= first.start)
+            }
+        }
+    }
+}
+
+impl SeasonPeriod {
+    /// The season of this period that a date falls in. There's none if the season
+    /// would start or end beyond the dates that can be represented at all.
+    pub fn season_of(self, date: DateTime<Utc>) -> Option<Season> {
+        let day = date.naive_utc().date();
+        let (start, end, name) = match self {
+            SeasonPeriod::Weekly => {
+                let start = day.checked_sub_signed(Duration::days(day.weekday().num_days_from_monday() as i64))?;
+                (start, start.checked_add_signed(Duration::weeks(1))?, start.format("%G-W%V").to_string())
+            },
+            SeasonPeriod::Monthly => {
+                let start = NaiveDate::from_ymd_opt(day.year(), day.month(), 1)?;
+                (start, add_months(start, 1)?, start.format("%Y-%m").to_string())
+            },
+            SeasonPeriod::Quarterly => {
+                let quarter = day.month0() / 3;
+                let start = NaiveDate::from_ymd_opt(day.year(), quarter * 3 + 1, 1)?;
+                (start, add_months(start, 3)?, format!("{}-Q{}", day.year(), quarter + 1))
+            },
+            SeasonPeriod::Yearly => {
+                let start = NaiveDate::from_ymd_opt(day.year(), 1, 1)?;
+                (start, NaiveDate::from_ymd_opt(day.year() + 1, 1, 1)?, day.year().to_string())
+            }
+        };
+        Some(Season { name, start: midnight(start), end: midnight(end) })
+    }
+}
+
+/// Who archiving is recorded as being done by when `serve` does it periodically,
+/// rather than anybody asking for it.
+pub const ARCHIVER: &str = "@season-archiver";
+
+/// Archive the winners of every season (in every group) that has finished by `now`
+/// and hasn't been archived already. Hands back how many seasons were archived.
+pub async fn archive_finished(store: &(dyn Store + Send + Sync), now: DateTime<Utc>, by: Option<&str>) -> Result<usize,StoreError> {
+    let mut archived = 0;
+    for group in store.groups(None).await? {
+        let seasons = match store.seasons(&group.id).await? {
+            Some(seasons) => seasons,
+            None => continue
+        };
+        let already_archived: HashSet<String> = store.season_archive(&group.id).await?
+            .into_iter()
+            .map(|s| s.name)
+            .collect();
+        let finished = seasons.list(now).into_iter()
+            .filter(|s| s.end <= now && !already_archived.contains(&s.name));
+        let scorables = store.scorables_in_group(&group.id).await?;
+        for season in finished {
+            let mut winners = Vec::new();
+            for scorable in &scorables {
+                let filter = ScoreFilter { from: Some(season.start), to: Some(season.end), limit: Some(1), ..ScoreFilter::default() };
+                if let Some(best) = store.scores(&scorable.id, &filter).await?.into_iter().next() {
+                    winners.push(SeasonWinner {
+                        scorable_id: scorable.id,
+                        username: best.username,
+                        teammates: best.teammates,
+                        value: best.value
+                    });
+                }
+            }
+            let season = ArchivedSeason {
+                name: season.name,
+                start: season.start,
+                end: season.end,
+                archived: now,
+                winners
+            };
+            store.archive_season(group.id, season, by).await?;
+            archived += 1;
+        }
+    }
+    Ok(archived)
+}
+
+/// The first of the month some months after a first of the month.
+fn add_months(date: NaiveDate, months: u32) -> Option<NaiveDate> {
+    let month0 = date.month0() + months;
+    NaiveDate::from_ymd_opt(date.year() + (month0 / 12) as i32, month0 % 12 + 1, 1)
+}
+
+fn midnight(date: NaiveDate) -> DateTime<Utc> {
+    Utc.from_utc_datetime(&date.and_hms(0, 0, 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::testing::{ stores, add_users };
+    use crate::changes::Change;
+    use crate::store_interface::{ GroupId, ScorableId, ScoreId, HistoryFilter };
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Utc.ymd(year, month, day).and_hms(0, 0, 0)
+    }
+
+    #[test]
+    fn seasons_of_each_period() {
+        let season = |period: SeasonPeriod, date| {
+            let s = period.season_of(date).unwrap();
+            (s.name, s.start, s.end)
+        };
+        assert_eq!(season(SeasonPeriod::Weekly, Utc.ymd(2021, 1, 1).and_hms(13, 0, 0)), ("2020-W53".to_owned(), date(2020, 12, 28), date(2021, 1, 4)));
+        assert_eq!(season(SeasonPeriod::Monthly, Utc.ymd(2021, 12, 31).and_hms(23, 59, 59)), ("2021-12".to_owned(), date(2021, 12, 1), date(2022, 1, 1)));
+        assert_eq!(season(SeasonPeriod::Quarterly, date(2021, 11, 5)), ("2021-Q4".to_owned(), date(2021, 10, 1), date(2022, 1, 1)));
+        assert_eq!(season(SeasonPeriod::Yearly, date(2021, 6, 5)), ("2021".to_owned(), date(2021, 1, 1), date(2022, 1, 1)));
+    }
+
+    #[test]
+    fn seasons_that_cant_end_dont_exist() {
+        for period in &[SeasonPeriod::Weekly, SeasonPeriod::Monthly, SeasonPeriod::Quarterly, SeasonPeriod::Yearly] {
+            assert_eq!(period.season_of(chrono::MAX_DATETIME), None);
+            let seasons = Seasons::Recurring { period: *period, from: chrono::MAX_DATETIME };
+            assert_eq!(seasons.list(chrono::MAX_DATETIME), Vec::new());
+        }
+    }
+
+    #[test]
+    fn recurring_seasons_are_listed_up_to_now() {
+        let seasons = Seasons::Recurring { period: SeasonPeriod::Monthly, from: date(2021, 1, 15) };
+        let names: Vec<String> = seasons.list(date(2021, 4, 2)).into_iter().map(|s| s.name).collect();
+        assert_eq!(names, vec!["2021-01", "2021-02", "2021-03", "2021-04"]);
+        assert_eq!(seasons.season_of(date(2020, 12, 31)), None);
+        assert_eq!(seasons.season_of(date(2021, 1, 2)).unwrap().name, "2021-01");
+    }
+
+    #[test]
+    fn only_the_latest_recurring_seasons_are_listed() {
+        let seasons = Seasons::Recurring { period: SeasonPeriod::Weekly, from: date(-262000, 1, 1) };
+        let listed = seasons.list(date(2021, 6, 1));
+        assert_eq!(listed.len(), MAX_SEASONS);
+        assert_eq!(listed.last().unwrap().name, "2021-W22");
+        assert!(listed.windows(2).all(|pair| pair[0].end == pair[1].start));
+    }
+
+    #[test]
+    fn seasons_must_have_dates_that_can_be_stored() {
+        let now = date(2021, 6, 1);
+        let recurring = |from| Seasons::Recurring { period: SeasonPeriod::Weekly, from };
+        assert!(recurring(date(2021, 1, 1)).check(now).is_ok());
+        assert!(matches!(recurring(date(2021, 7, 1)).check(now), Err(StoreError::InvalidSeasons(_))));
+        assert!(matches!(recurring(date(-262000, 1, 1)).check(now), Err(StoreError::DateOutOfRange(_))));
+
+        let named = |end| Seasons::Named { seasons: vec![Season { name: "Season".to_owned(), start: date(2021, 1, 1), end }] };
+        assert!(named(date(2021, 3, 1)).check(now).is_ok());
+        assert!(matches!(named(date(3000, 1, 1)).check(now), Err(StoreError::DateOutOfRange(_))));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn archiving_the_winners_of_finished_seasons() {
+        for store in stores().await {
+            add_users(&store, &["alice", "bob"]).await;
+            let group_id = GroupId::new();
+            let scorable_id = ScorableId::new();
+            store.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+            store.upsert_scorable(scorable_id, group_id, "Scorable".to_owned(), None, None, None).await.unwrap();
+            let season = |name: &str, start, end| Season { name: name.to_owned(), start, end };
+            let seasons = vec![season("Spring", date(2021, 3, 1), date(2021, 6, 1)), season("Winter", date(2021, 1, 1), date(2021, 3, 1))];
+            store.set_seasons(group_id, Some(Seasons::Named { seasons }), None).await.unwrap();
+            let scores = [("alice", vec![], 10, date(2021, 1, 5)), ("bob", vec!["alice".to_owned()], 20, date(2021, 2, 5)), ("alice", vec![], 5, date(2021, 3, 5))];
+            for (username, teammates, value, date) in scores.iter() {
+                store.upsert_score(ScoreId::new(), scorable_id, username.to_string(), teammates.clone(), *value, *date, None).await.unwrap();
+            }
+
+            // Seasons are archived once they've finished, and only once:
+            assert_eq!(archive_finished(&*store, date(2021, 4, 1), Some(ARCHIVER)).await.unwrap(), 1);
+            assert_eq!(archive_finished(&*store, date(2021, 4, 1), Some(ARCHIVER)).await.unwrap(), 0);
+            let winter = ArchivedSeason {
+                name: "Winter".to_owned(),
+                start: date(2021, 1, 1),
+                end: date(2021, 3, 1),
+                archived: date(2021, 4, 1),
+                winners: vec![SeasonWinner { scorable_id, username: "bob".to_owned(), teammates: vec!["alice".to_owned()], value: 20 }]
+            };
+            assert_eq!(store.season_archive(&group_id).await.unwrap(), vec![winter.clone()]);
+            let newest = store.history(&HistoryFilter { limit: Some(1), ..HistoryFilter::default() }).await.unwrap();
+            assert!(matches!(newest[0].change, Change::ArchiveSeason { .. }));
+            assert_eq!(newest[0].by.as_deref(), Some(ARCHIVER));
+
+            assert_eq!(archive_finished(&*store, date(2021, 7, 1), None).await.unwrap(), 1);
+            let archive = store.season_archive(&group_id).await.unwrap();
+            assert_eq!(archive.iter().map(|s| s.name.as_str()).collect::<Vec<_>>(), vec!["Spring", "Winter"]);
+            assert_eq!(archive[0].winners[0].value, 5);
+
+            // Seasons too far away for their dates to be stored are refused rather than panicking:
+            let far_future = date(3000, 1, 1);
+            let season = ArchivedSeason { name: "Far".to_owned(), start: far_future, end: far_future, archived: far_future, winners: Vec::new() };
+            assert!(matches!(store.archive_season(group_id, season, None).await, Err(StoreError::DateOutOfRange(_))));
+            assert_eq!(store.season_archive(&group_id).await.unwrap().len(), 2);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+//! Secret keys are used to encrypt session cookies. Loading one from a file or
+//! the environment (rather than generating a new one each time we start) means
+//! that restarting the server doesn't log everybody out.
+
+use std::path::Path;
+use anyhow::Context;
+
+/// Where to look for the secret key if no file is given.
+pub const ENV_VAR: &str = "HIGHSCORE_SECRET_KEY";
+/// Where to look for the previous secret key if no file is given.
+pub const PREVIOUS_ENV_VAR: &str = "HIGHSCORE_PREVIOUS_SECRET_KEY";
+
+/// How many bytes a key must be.
+const KEY_LEN: usize = 64;
+
+/// Generate a new random key, base64 encoded.
+pub fn generate() -> String {
+    let mut key = [0u8; KEY_LEN];
+    use rand::RngCore;
+    rand::thread_rng().fill_bytes(&mut key);
+    base64::encode(&key[..])
+}
+
+/// Load a base64 encoded key from the file given if there is one, or else
+/// from the environment variable given if it's set.
+pub async fn load(file: Option<&Path>, env_var: &str) -> anyhow::Result<Option<Vec<u8>>> {
+    let (encoded, source) = if let Some(file) = file {
+        let encoded = tokio::fs::read_to_string(file).await
+            .with_context(|| format!("Failed to read secret key from {}", file.display()))?;
+        (encoded, file.display().to_string())
+    } else if let Ok(encoded) = std::env::var(env_var) {
+        (encoded, env_var.to_owned())
+    } else {
+        return Ok(None)
+    };
+
+    let key = base64::decode(encoded.trim())
+        .with_context(|| format!("Secret key from {} is not valid base64", source))?;
+    if key.len() != KEY_LEN {
+        anyhow::bail!("Secret key from {} should be {} bytes but is {}; use `highscore secret generate` to make one", source, KEY_LEN, key.len());
+    }
+    Ok(Some(key))
+}
@@ -0,0 +1,1798 @@
+//! A [`crate::store_interface::Store`] implementation backed by SQLite. Unlike
+//! the persisted_store, nothing needs to be replayed into memory on startup, and
+//! queries are answered using indexes rather than by scanning everything.
+
+use std::path::PathBuf;
+use std::sync::{ Arc, Mutex, MutexGuard };
+use chrono::prelude::{ DateTime, Utc, TimeZone };
+use rusqlite::{ params, Connection, OptionalExtension };
+use tokio::sync::broadcast;
+use rusqlite::types::{ ToSql, ToSqlOutput, FromSql, FromSqlResult, FromSqlError, ValueRef };
+use crate::changes::{ Change, ChangeNotifier, Notification };
+use crate::events::Durability;
+use crate::ratings::Ratings;
+use crate::store_interface::{ Store, StoreError, GroupId, ScorableId, ScoreId, MatchId, TokenId, Group, Scorable, Score, Match, Token, Member, HashedPassword, HashedToken, Ranking, ValueKind, Role, Item, Deleted, HistoryEntry, HistoryFilter, ScoreFilter, Page, PageRequest, Cursor, CursorKey, Stats, Records, RecordHolder, Rating, RatingChange, Seasons, ArchivedSeason, AsOf, date_to_nanos };
+
+/// Run against every connection that we open.
+const SETUP: &str = "
+    PRAGMA foreign_keys = ON;
+    PRAGMA journal_mode = WAL;
+";
+
+/// Each migration moves the schema on by one version, and `PRAGMA user_version`
+/// records how many have been applied. Only ever append to this list.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial tables.
+    "
+    CREATE TABLE IF NOT EXISTS users (
+        username TEXT PRIMARY KEY NOT NULL,
+        hashed_password TEXT NOT NULL
+    );
+
+    CREATE TABLE IF NOT EXISTS groups (
+        id TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS groups_by_name ON groups(name, id);
+
+    CREATE TABLE IF NOT EXISTS scorables (
+        id TEXT PRIMARY KEY NOT NULL,
+        group_id TEXT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+        name TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS scorables_by_group ON scorables(group_id, name, id);
+
+    CREATE TABLE IF NOT EXISTS scores (
+        id TEXT PRIMARY KEY NOT NULL,
+        scorable_id TEXT NOT NULL REFERENCES scorables(id) ON DELETE CASCADE,
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+        value INTEGER NOT NULL,
+        -- nanoseconds since the unix epoch:
+        date INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS scores_by_scorable ON scores(scorable_id, value DESC);
+    CREATE INDEX IF NOT EXISTS scores_by_user ON scores(username);
+    ",
+    // 2: Scorables can rank lower scores first.
+    "
+    ALTER TABLE scorables ADD COLUMN ranking TEXT NOT NULL DEFAULT 'higher_is_better';
+    ",
+    // 3: Scorables say what kind of value their scores are (stored as JSON).
+    r#"
+    ALTER TABLE scorables ADD COLUMN value_kind TEXT NOT NULL DEFAULT '{"kind":"integer"}';
+    "#,
+    // 4: API tokens.
+    "
+    CREATE TABLE IF NOT EXISTS tokens (
+        id TEXT PRIMARY KEY NOT NULL,
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        hashed_secret TEXT NOT NULL,
+        -- nanoseconds since the unix epoch:
+        created INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS tokens_by_user ON tokens(username, created);
+    ",
+    // 5: Groups have members, each with some role.
+    "
+    CREATE TABLE IF NOT EXISTS members (
+        group_id TEXT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+        role TEXT NOT NULL,
+        PRIMARY KEY (group_id, username)
+    );
+    CREATE INDEX IF NOT EXISTS members_by_user ON members(username);
+    ",
+    // 6: Users can be server-wide admins.
+    "
+    ALTER TABLE users ADD COLUMN admin INTEGER NOT NULL DEFAULT 0;
+    ",
+    // 7: A history of the changes made, who by and when. This outlives whatever
+    // the changes were made to, so nothing here references other tables.
+    "
+    CREATE TABLE IF NOT EXISTS history (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        -- nanoseconds since the unix epoch:
+        at INTEGER NOT NULL,
+        -- NULL if the change was made from the command line:
+        by_user TEXT,
+        group_id TEXT,
+        scorable_id TEXT,
+        -- the change, as JSON:
+        change TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS history_by_user ON history(by_user, id);
+    CREATE INDEX IF NOT EXISTS history_by_group ON history(group_id, id);
+    CREATE INDEX IF NOT EXISTS history_by_scorable ON history(scorable_id, id);
+    ",
+    // 8: Groups, scorables and scores note when they were deleted (in nanoseconds
+    // since the unix epoch) rather than being removed, so that they can be restored.
+    "
+    ALTER TABLE groups ADD COLUMN deleted INTEGER;
+    ALTER TABLE scorables ADD COLUMN deleted INTEGER;
+    ALTER TABLE scores ADD COLUMN deleted INTEGER;
+    ",
+    // 9: Matches played between users against a scorable. Matches without a winner
+    // were drawn. Ratings are worked out from these whenever they're asked for.
+    "
+    CREATE TABLE IF NOT EXISTS matches (
+        id TEXT PRIMARY KEY NOT NULL,
+        scorable_id TEXT NOT NULL REFERENCES scorables(id) ON DELETE CASCADE,
+        winner TEXT,
+        -- nanoseconds since the unix epoch:
+        date INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS matches_by_scorable ON matches(scorable_id, date, id);
+
+    CREATE TABLE IF NOT EXISTS match_participants (
+        match_id TEXT NOT NULL REFERENCES matches(id) ON DELETE CASCADE,
+        -- the order that participants were given in, counting from 0:
+        position INTEGER NOT NULL,
+        username TEXT NOT NULL REFERENCES users(username) ON DELETE CASCADE,
+        PRIMARY KEY (match_id, position)
+    );
+    CREATE INDEX IF NOT EXISTS match_participants_by_user ON match_participants(username, match_id);
+    ",
+    // 10: Scores can be credited to teammates as well as to their user. Teammates are a JSON
+    // array in the order given, and the team is everybody credited (sorted, as a JSON array)
+    // so that scores by the same team can be grouped together.
+    "
+    ALTER TABLE scores ADD COLUMN teammates TEXT NOT NULL DEFAULT '[]';
+    ALTER TABLE scores ADD COLUMN team TEXT NOT NULL DEFAULT '[]';
+    UPDATE scores SET team = json_array(username);
+    ",
+    // 11: Groups can split their scores up into seasons (described in JSON), and
+    // keep the winners of seasons that have finished.
+    "
+    ALTER TABLE groups ADD COLUMN seasons TEXT;
+
+    CREATE TABLE IF NOT EXISTS season_archive (
+        group_id TEXT NOT NULL REFERENCES groups(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        start_date INTEGER NOT NULL,
+        end_date INTEGER NOT NULL,
+        archived INTEGER NOT NULL,
+        winners TEXT NOT NULL,
+        PRIMARY KEY (group_id, name)
+    );
+    ",
+];
+
+// Queries (taking an ID) which check that something exists, and that neither it
+// nor anything it lives in has been deleted.
+const GROUP_EXISTS: &str = "SELECT 1 FROM groups WHERE id = ?1 AND deleted IS NULL";
+const SCORABLE_EXISTS: &str = "SELECT 1 FROM scorables
+    JOIN groups ON groups.id = scorables.group_id
+    WHERE scorables.id = ?1 AND scorables.deleted IS NULL AND groups.deleted IS NULL";
+const SCORE_EXISTS: &str = "SELECT 1 FROM scores
+    JOIN scorables ON scorables.id = scores.scorable_id
+    JOIN groups ON groups.id = scorables.group_id
+    WHERE scores.id = ?1 AND scores.deleted IS NULL AND scorables.deleted IS NULL AND groups.deleted IS NULL";
+const MATCH_EXISTS: &str = "SELECT 1 FROM matches
+    JOIN scorables ON scorables.id = matches.scorable_id
+    JOIN groups ON groups.id = scorables.group_id
+    WHERE matches.id = ?1 AND scorables.deleted IS NULL AND groups.deleted IS NULL";
+
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+    /// When was the last change made? Like the memory_store, this
+    /// starts off as the time that the store was loaded.
+    last_changed: Mutex<DateTime<Utc>>,
+    changes: ChangeNotifier
+}
+
+impl SqliteStore {
+    /// Open (creating if necessary) an SQLite database at the path given.
+    pub async fn load(file_path: PathBuf, durability: Durability) -> anyhow::Result<SqliteStore> {
+        // Every write is committed before returning, so durability
+        // maps onto how often SQLite syncs to disk:
+        let synchronous = match durability {
+            Durability::Buffered => "OFF",
+            Durability::Flush => "NORMAL",
+            Durability::Fsync => "FULL"
+        };
+        let conn = tokio::task::spawn_blocking(move || -> anyhow::Result<Connection> {
+            let mut conn = Connection::open(&file_path)?;
+            conn.execute_batch(SETUP)?;
+            migrate(&mut conn)?;
+            conn.execute_batch(&format!("PRAGMA synchronous = {};", synchronous))?;
+            Ok(conn)
+        }).await??;
+        Ok(SqliteStore {
+            conn: Mutex::new(conn),
+            last_changed: Mutex::new(Utc::now()),
+            changes: ChangeNotifier::new()
+        })
+    }
+
+    /// Purge anything deleted longer ago than `trash_retention`, and then
+    /// rebuild the database file to reclaim unused space.
+    pub async fn compact(&self, trash_retention: chrono::Duration) -> anyhow::Result<()> {
+        // If it's too long ago to represent, nothing's been in the trash that long:
+        let purge_before = compared_nanos(Utc::now().checked_sub_signed(trash_retention).unwrap_or(chrono::MIN_DATETIME));
+        self.with_conn(|conn| {
+            // Anything living in what's purged goes too, via ON DELETE CASCADE:
+            for table in &["groups", "scorables", "scores"] {
+                conn.execute(&format!("DELETE FROM {} WHERE deleted < ?1", table), params![purge_before])?;
+            }
+            Ok(conn.execute_batch("VACUUM")?)
+        })?;
+        Ok(())
+    }
+
+    // SQLite calls block, so get hold of the connection and run them in a
+    // way that doesn't hold up other tasks on the runtime.
+    fn with_conn<T, F>(&self, f: F) -> Result<T,StoreError>
+    where F: FnOnce(&mut Connection) -> Result<T,StoreError> {
+        tokio::task::block_in_place(|| f(&mut self.lock()))
+    }
+    // As `with_conn`, but also record the change in the history, bump the last
+    // changed time and tell subscribers about the change once it's been made.
+    fn with_conn_mut<T, F>(&self, by: Option<&str>, change: Change, f: F) -> Result<T,StoreError>
+    where F: FnOnce(&mut Connection) -> Result<T,StoreError> {
+        let (res, group_id) = self.with_conn(|conn| {
+            // Work out where the change is being made before making it, so
+            // that we still know where anything being deleted lived:
+            let (group_id, scorable_id) = locate(conn, &change)?;
+            let res = f(conn)?;
+            conn.execute(
+                "INSERT INTO history (at, by_user, group_id, scorable_id, change) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    Utc::now().timestamp_nanos(), by,
+                    group_id.map(|id| id.to_string()), scorable_id.map(|id| id.to_string()),
+                    change
+                ]
+            )?;
+            Ok((res, group_id))
+        })?;
+        *self.last_changed.lock().unwrap() = Utc::now();
+        self.changes.notify(group_id, change);
+        Ok(res)
+    }
+    fn lock(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+}
+
+#[async_trait::async_trait]
+impl Store for SqliteStore {
+    async fn last_changed(&self) -> DateTime<Utc> {
+        *self.last_changed.lock().unwrap()
+    }
+    fn subscribe(&self) -> broadcast::Receiver<Notification> {
+        self.changes.subscribe()
+    }
+
+    async fn users(&self) -> Result<Vec<String>,StoreError> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare_cached("SELECT username FROM users")?;
+            let users = stmt.query_map(params![], |row| row.get(0))?
+                .collect::<Result<_,_>>()?;
+            Ok(users)
+        })
+    }
+    async fn user_exists(&self, username: &str) -> Result<bool,StoreError> {
+        self.with_conn(|conn| Ok(exists(conn, "SELECT 1 FROM users WHERE username = ?1", username)?))
+    }
+    async fn upsert_user(&self, username: String, password: HashedPassword, admin: Option<bool>, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::UpsertUser { username: username.clone() };
+        self.with_conn_mut(by, change, |conn| {
+            // ?4 is NULL if not given, leaving an existing user as they were:
+            conn.execute(
+                "INSERT INTO users (username, hashed_password, admin) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(username) DO UPDATE SET
+                    hashed_password = excluded.hashed_password,
+                    admin = COALESCE(?4, admin)",
+                params![username, password.as_hash(), admin.unwrap_or(false), admin]
+            )?;
+            Ok(())
+        })
+    }
+    async fn check_user(&self, username: &str, password: &str) -> Result<bool,StoreError> {
+        let hashed_password: Option<String> = self.with_conn(|conn| {
+            Ok(conn.query_row(
+                "SELECT hashed_password FROM users WHERE username = ?1",
+                params![username],
+                |row| row.get(0)
+            ).optional()?)
+        })?;
+        let hashed_password = hashed_password
+            .map(HashedPassword::from_hash)
+            .ok_or_else(|| StoreError::UserNotFound(username.to_owned()))?;
+        let res = tokio::task::block_in_place(||
+            hashed_password.verify_plain_password(password)
+        );
+        Ok(res)
+    }
+    async fn hashed_password(&self, username: &str) -> Result<HashedPassword,StoreError> {
+        self.with_conn(|conn| {
+            conn.query_row(
+                "SELECT hashed_password FROM users WHERE username = ?1",
+                params![username],
+                |row| row.get(0)
+            ).optional()?
+                .map(HashedPassword::from_hash)
+                .ok_or_else(|| StoreError::UserNotFound(username.to_owned()))
+        })
+    }
+    async fn is_admin(&self, username: &str) -> Result<bool,StoreError> {
+        self.with_conn(|conn| {
+            conn.query_row(
+                "SELECT admin FROM users WHERE username = ?1",
+                params![username],
+                |row| row.get(0)
+            ).optional()?.ok_or_else(|| StoreError::UserNotFound(username.to_owned()))
+        })
+    }
+    async fn delete_user(&self, username: &str, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::DeleteUser { username: username.to_owned() };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            check_owner_remains(&tx, None, username)?;
+            // Scores shared with teammates are kept for the rest of the team, handing
+            // them to the next in the team if they were the user's:
+            let shared = tx.prepare(
+                "SELECT id, username, teammates FROM scores
+                 WHERE teammates != '[]'
+                   AND (username = ?1 OR EXISTS (SELECT 1 FROM json_each(teammates) WHERE json_each.value = ?1))"
+            )?.query_map(params![username], |row| {
+                Ok((row.get::<_,String>(0)?, row.get::<_,String>(1)?, parse_json::<Vec<String>>(row.get(2)?)?))
+            })?.collect::<Result<Vec<_>,_>>()?;
+            for (id, owner, teammates) in shared {
+                let mut team: Vec<String> = std::iter::once(owner).chain(teammates).filter(|u| u != username).collect();
+                let owner = team.remove(0);
+                tx.execute(
+                    "UPDATE scores SET username = ?2, teammates = ?3, team = ?4 WHERE id = ?1",
+                    params![id, owner, to_json(&team)?, to_json(&Score::team_key(&owner, &team))?]
+                )?;
+            }
+            // Matches that the user took part in make no sense without them:
+            tx.execute(
+                "DELETE FROM matches WHERE id IN (SELECT match_id FROM match_participants WHERE username = ?1)",
+                params![username]
+            )?;
+            // Tokens, memberships and scores belonging to the user are removed via ON DELETE CASCADE:
+            let n = tx.execute("DELETE FROM users WHERE username = ?1", params![username])?;
+            if n == 0 { return Err(StoreError::UserNotFound(username.to_owned())) }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+
+    // Nobody is told about changes to tokens; they are only of interest to their owner.
+    async fn upsert_token(&self, id: TokenId, username: String, name: String, hashed_secret: HashedToken, created: DateTime<Utc>, _by: Option<&str>) -> Result<(),StoreError> {
+        let created = date_to_nanos(created)?;
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, "SELECT 1 FROM users WHERE username = ?1", &username)? {
+                return Err(StoreError::UserNotFound(username))
+            }
+            tx.execute(
+                "INSERT INTO tokens (id, username, name, hashed_secret, created) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(id) DO UPDATE SET
+                    username = excluded.username,
+                    name = excluded.name,
+                    hashed_secret = excluded.hashed_secret,
+                    created = excluded.created",
+                params![id.to_string(), username, name, hashed_secret.as_hash(), created]
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn tokens(&self, username: &str) -> Result<Vec<Token>,StoreError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, "SELECT 1 FROM users WHERE username = ?1", username)? {
+                return Err(StoreError::UserNotFound(username.to_owned()))
+            }
+            let mut stmt = tx.prepare_cached(
+                "SELECT id, name, created FROM tokens WHERE username = ?1 ORDER BY created, id"
+            )?;
+            let tokens = stmt.query_map(params![username], |row| {
+                Ok(Token {
+                    id: parse_id(row.get(0)?)?,
+                    username: username.to_owned(),
+                    name: row.get(1)?,
+                    created: Utc.timestamp_nanos(row.get(2)?)
+                })
+            })?.collect::<Result<_,_>>()?;
+            Ok(tokens)
+        })
+    }
+    async fn check_token(&self, id: &TokenId, secret: &str) -> Result<Option<Token>,StoreError> {
+        let token = self.with_conn(|conn| {
+            Ok(conn.query_row(
+                "SELECT username, name, hashed_secret, created FROM tokens WHERE id = ?1",
+                params![id.to_string()],
+                |row| {
+                    let token = Token {
+                        id: *id,
+                        username: row.get(0)?,
+                        name: row.get(1)?,
+                        created: Utc.timestamp_nanos(row.get(3)?)
+                    };
+                    Ok((token, HashedToken::from_hash(row.get(2)?)))
+                }
+            ).optional()?)
+        })?;
+        Ok(token
+            .filter(|(_,hashed_secret)| hashed_secret.verify_plain_secret(secret))
+            .map(|(token,_)| token))
+    }
+    async fn delete_token(&self, id: &TokenId, _by: Option<&str>) -> Result<(),StoreError> {
+        self.with_conn(|conn| {
+            let n = conn.execute("DELETE FROM tokens WHERE id = ?1", params![id.to_string()])?;
+            if n == 0 { return Err(StoreError::TokenNotFound(*id)) }
+            Ok(())
+        })
+    }
+
+    async fn upsert_group(&self, id: GroupId, name: String, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::UpsertGroup { id };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            // Upserting a deleted group replaces it, as if it had been purged:
+            tx.execute("DELETE FROM groups WHERE id = ?1 AND deleted IS NOT NULL", params![id.to_string()])?;
+            tx.execute(
+                "INSERT INTO groups (id, name) VALUES (?1, ?2)
+                 ON CONFLICT(id) DO UPDATE SET name = excluded.name",
+                params![id.to_string(), name]
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn delete_group(&self, id: &GroupId, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::DeleteGroup { id: *id };
+        self.with_conn_mut(by, change, |conn| {
+            let n = conn.execute(
+                "UPDATE groups SET deleted = ?2 WHERE id = ?1 AND deleted IS NULL",
+                params![id.to_string(), Utc::now().timestamp_nanos()]
+            )?;
+            if n == 0 { return Err(StoreError::GroupNotFound(*id)) }
+            Ok(())
+        })
+    }
+    async fn get_group(&self, id: &GroupId) -> Result<Group,StoreError> {
+        self.with_conn(|conn| {
+            conn.query_row(
+                "SELECT name FROM groups WHERE id = ?1 AND deleted IS NULL",
+                params![id.to_string()],
+                |row| Ok(Group { id: *id, name: row.get(0)? })
+            ).optional()?.ok_or(StoreError::GroupNotFound(*id))
+        })
+    }
+
+    async fn upsert_member(&self, group_id: GroupId, username: String, role: Role, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::UpsertMember { group_id, username: username.clone() };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, GROUP_EXISTS, &group_id.to_string())? {
+                return Err(StoreError::GroupNotFound(group_id))
+            }
+            if !exists(&tx, "SELECT 1 FROM users WHERE username = ?1", &username)? {
+                return Err(StoreError::UserNotFound(username))
+            }
+            if role != Role::Owner {
+                check_owner_remains(&tx, Some(&group_id), &username)?;
+            }
+            tx.execute(
+                "INSERT INTO members (group_id, username, role) VALUES (?1, ?2, ?3)
+                 ON CONFLICT(group_id, username) DO UPDATE SET role = excluded.role",
+                params![group_id.to_string(), username, role]
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn delete_member(&self, group_id: &GroupId, username: &str, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::DeleteMember { group_id: *group_id, username: username.to_owned() };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, GROUP_EXISTS, &group_id.to_string())? {
+                return Err(StoreError::GroupNotFound(*group_id))
+            }
+            check_owner_remains(&tx, Some(group_id), username)?;
+            let n = tx.execute(
+                "DELETE FROM members WHERE group_id = ?1 AND username = ?2",
+                params![group_id.to_string(), username]
+            )?;
+            if n == 0 { return Err(StoreError::MemberNotFound(username.to_owned(), *group_id)) }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn members(&self, group_id: &GroupId) -> Result<Vec<Member>,StoreError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, GROUP_EXISTS, &group_id.to_string())? {
+                return Err(StoreError::GroupNotFound(*group_id))
+            }
+            let mut stmt = tx.prepare_cached(
+                "SELECT username, role FROM members WHERE group_id = ?1 ORDER BY username"
+            )?;
+            let members = stmt.query_map(params![group_id.to_string()], |row| {
+                Ok(Member { username: row.get(0)?, role: row.get(1)? })
+            })?.collect::<Result<_,_>>()?;
+            Ok(members)
+        })
+    }
+    async fn role(&self, group_id: &GroupId, username: &str) -> Result<Option<Role>,StoreError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            // Deleted groups are included, so that we know who can restore them:
+            if !exists(&tx, "SELECT 1 FROM groups WHERE id = ?1", &group_id.to_string())? {
+                return Err(StoreError::GroupNotFound(*group_id))
+            }
+            let role = tx.query_row(
+                "SELECT role FROM members WHERE group_id = ?1 AND username = ?2",
+                params![group_id.to_string(), username],
+                |row| row.get(0)
+            ).optional()?;
+            // Groups without any members are open to everybody:
+            if role.is_none() && !exists(&tx, "SELECT 1 FROM members WHERE group_id = ?1", &group_id.to_string())? {
+                return Ok(Some(Role::Owner))
+            }
+            Ok(role)
+        })
+    }
+
+    async fn upsert_scorable(&self, id: ScorableId, group_id: GroupId, name: String, ranking: Option<Ranking>, value_kind: Option<ValueKind>, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::UpsertScorable { id, group_id };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, GROUP_EXISTS, &group_id.to_string())? {
+                return Err(StoreError::GroupNotFound(group_id))
+            }
+            // Upserting a deleted scorable replaces it, as if it had been purged:
+            tx.execute("DELETE FROM scorables WHERE id = ?1 AND deleted IS NOT NULL", params![id.to_string()])?;
+            // ?6 and ?7 are NULL if not given, leaving existing values as they are:
+            tx.execute(
+                "INSERT INTO scorables (id, group_id, name, ranking, value_kind) VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(id) DO UPDATE SET
+                    group_id = excluded.group_id,
+                    name = excluded.name,
+                    ranking = COALESCE(?6, ranking),
+                    value_kind = COALESCE(?7, value_kind)",
+                params![
+                    id.to_string(), group_id.to_string(), name,
+                    ranking.unwrap_or_default(), value_kind.clone().unwrap_or_default(),
+                    ranking, value_kind
+                ]
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn delete_scorable(&self, id: &ScorableId, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::DeleteScorable { id: *id };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, SCORABLE_EXISTS, &id.to_string())? {
+                return Err(StoreError::ScorableNotFound(*id))
+            }
+            tx.execute(
+                "UPDATE scorables SET deleted = ?2 WHERE id = ?1",
+                params![id.to_string(), Utc::now().timestamp_nanos()]
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn get_scorable(&self, id: &ScorableId) -> Result<Scorable,StoreError> {
+        self.with_conn(|conn| {
+            conn.query_row(
+                "SELECT scorables.group_id, scorables.name, scorables.ranking, scorables.value_kind FROM scorables
+                 JOIN groups ON groups.id = scorables.group_id
+                 WHERE scorables.id = ?1 AND scorables.deleted IS NULL AND groups.deleted IS NULL",
+                params![id.to_string()],
+                |row| Ok(Scorable {
+                    id: *id,
+                    group_id: parse_id(row.get(0)?)?,
+                    name: row.get(1)?,
+                    ranking: row.get(2)?,
+                    value_kind: row.get(3)?
+                })
+            ).optional()?.ok_or(StoreError::ScorableNotFound(*id))
+        })
+    }
+
+    async fn upsert_score(&self, id: ScoreId, scorable_id: ScorableId, username: String, teammates: Vec<String>, value: i64, date: DateTime<Utc>, by: Option<&str>) -> Result<Records,StoreError> {
+        Score::check_team(&username, &teammates)?;
+        // Checked before taking the connection, since it's given by whoever is adding the score:
+        let date = date_to_nanos(date)?;
+        let change = Change::UpsertScore { id, scorable_id, username: username.clone() };
+        let records = self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            for username in std::iter::once(&username).chain(&teammates) {
+                if !exists(&tx, "SELECT 1 FROM users WHERE username = ?1", username)? {
+                    return Err(StoreError::UserNotFound(username.clone()))
+                }
+            }
+            if !exists(&tx, SCORABLE_EXISTS, &scorable_id.to_string())? {
+                return Err(StoreError::ScorableNotFound(scorable_id))
+            }
+            let credited: Vec<&str> = std::iter::once(&username).chain(&teammates).map(|u| u.as_str()).collect();
+            let records = records_set_by(&tx, &id, &scorable_id, &credited, value)?;
+            tx.execute(
+                "INSERT INTO scores (id, scorable_id, username, teammates, team, value, date) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+                 ON CONFLICT(id) DO UPDATE SET
+                    scorable_id = excluded.scorable_id,
+                    username = excluded.username,
+                    teammates = excluded.teammates,
+                    team = excluded.team,
+                    value = excluded.value,
+                    date = excluded.date,
+                    deleted = NULL",
+                params![
+                    id.to_string(), scorable_id.to_string(), username,
+                    to_json(&teammates)?, to_json(&Score::team_key(&username, &teammates))?,
+                    value, date
+                ]
+            )?;
+            tx.commit()?;
+            Ok(records)
+        })?;
+        let changes = records.changes(id, scorable_id, &username);
+        if let Some(change) = changes.first() {
+            let (group_id, _) = self.with_conn(|conn| Ok(locate(conn, change)?))?;
+            for change in changes {
+                self.changes.notify(group_id, change);
+            }
+        }
+        Ok(records)
+    }
+    async fn delete_score(&self, id: &ScoreId, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::DeleteScore { id: *id };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, SCORE_EXISTS, &id.to_string())? {
+                return Err(StoreError::ScoreNotFound(*id))
+            }
+            tx.execute(
+                "UPDATE scores SET deleted = ?2 WHERE id = ?1",
+                params![id.to_string(), Utc::now().timestamp_nanos()]
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn get_score(&self, id: &ScoreId) -> Result<Score,StoreError> {
+        self.with_conn(|conn| {
+            conn.query_row(
+                "SELECT scores.scorable_id, scores.username, scores.teammates, scores.value, scores.date FROM scores
+                 JOIN scorables ON scorables.id = scores.scorable_id
+                 JOIN groups ON groups.id = scorables.group_id
+                 WHERE scores.id = ?1 AND scores.deleted IS NULL AND scorables.deleted IS NULL AND groups.deleted IS NULL",
+                params![id.to_string()],
+                |row| Ok(Score {
+                    id: *id,
+                    scorable_id: parse_id(row.get(0)?)?,
+                    username: row.get(1)?,
+                    teammates: parse_json(row.get(2)?)?,
+                    value: row.get(3)?,
+                    date: Utc.timestamp_nanos(row.get(4)?)
+                })
+            ).optional()?.ok_or(StoreError::ScoreNotFound(*id))
+        })
+    }
+
+    async fn upsert_match(&self, id: MatchId, scorable_id: ScorableId, participants: Vec<String>, winner: Option<String>, date: DateTime<Utc>, by: Option<&str>) -> Result<(),StoreError> {
+        Match::check(&participants, winner.as_deref())?;
+        let date = date_to_nanos(date)?;
+        let change = Change::UpsertMatch { id, scorable_id };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            for username in &participants {
+                if !exists(&tx, "SELECT 1 FROM users WHERE username = ?1", username)? {
+                    return Err(StoreError::UserNotFound(username.clone()))
+                }
+            }
+            if !exists(&tx, SCORABLE_EXISTS, &scorable_id.to_string())? {
+                return Err(StoreError::ScorableNotFound(scorable_id))
+            }
+            tx.execute(
+                "INSERT INTO matches (id, scorable_id, winner, date) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(id) DO UPDATE SET
+                    scorable_id = excluded.scorable_id,
+                    winner = excluded.winner,
+                    date = excluded.date",
+                params![id.to_string(), scorable_id.to_string(), winner, date]
+            )?;
+            tx.execute("DELETE FROM match_participants WHERE match_id = ?1", params![id.to_string()])?;
+            for (position, username) in participants.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO match_participants (match_id, position, username) VALUES (?1, ?2, ?3)",
+                    params![id.to_string(), position as i64, username]
+                )?;
+            }
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn delete_match(&self, id: &MatchId, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::DeleteMatch { id: *id };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, MATCH_EXISTS, &id.to_string())? {
+                return Err(StoreError::MatchNotFound(*id))
+            }
+            // Participants go too, via ON DELETE CASCADE:
+            tx.execute("DELETE FROM matches WHERE id = ?1", params![id.to_string()])?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn get_match(&self, id: &MatchId) -> Result<Match,StoreError> {
+        self.with_conn(|conn| {
+            let scorable_id: ScorableId = conn.query_row(
+                "SELECT matches.scorable_id FROM matches
+                 JOIN scorables ON scorables.id = matches.scorable_id
+                 JOIN groups ON groups.id = scorables.group_id
+                 WHERE matches.id = ?1 AND scorables.deleted IS NULL AND groups.deleted IS NULL",
+                params![id.to_string()],
+                |row| parse_id(row.get(0)?)
+            ).optional()?.ok_or(StoreError::MatchNotFound(*id))?;
+            query_matches(conn, &scorable_id, Some(id), None)?
+                .pop()
+                .ok_or(StoreError::MatchNotFound(*id))
+        })
+    }
+    async fn matches(&self, scorable_id: &ScorableId, username: Option<&str>) -> Result<Vec<Match>,StoreError> {
+        self.with_conn(|conn| {
+            if !exists(conn, SCORABLE_EXISTS, &scorable_id.to_string())? {
+                return Err(StoreError::ScorableNotFound(*scorable_id))
+            }
+            let mut matches = query_matches(conn, scorable_id, None, username)?;
+            matches.reverse();
+            Ok(matches)
+        })
+    }
+    async fn ratings(&self, scorable_id: &ScorableId) -> Result<Vec<Rating>,StoreError> {
+        self.with_conn(|conn| {
+            if !exists(conn, SCORABLE_EXISTS, &scorable_id.to_string())? {
+                return Err(StoreError::ScorableNotFound(*scorable_id))
+            }
+            let matches = query_matches(conn, scorable_id, None, None)?;
+            Ok(Ratings::from_matches(&matches).leaderboard())
+        })
+    }
+    async fn rating_history(&self, scorable_id: &ScorableId, username: &str) -> Result<Vec<RatingChange>,StoreError> {
+        self.with_conn(|conn| {
+            if !exists(conn, SCORABLE_EXISTS, &scorable_id.to_string())? {
+                return Err(StoreError::ScorableNotFound(*scorable_id))
+            }
+            // Everybody's matches count, since they change the ratings of this user's opponents:
+            let matches = query_matches(conn, scorable_id, None, None)?;
+            Ok(Ratings::from_matches(&matches).history(username))
+        })
+    }
+
+    async fn set_seasons(&self, group_id: GroupId, seasons: Option<Seasons>, by: Option<&str>) -> Result<(),StoreError> {
+        if let Some(seasons) = &seasons {
+            seasons.check(Utc::now())?;
+        }
+        let change = Change::SetSeasons { group_id };
+        self.with_conn_mut(by, change, |conn| {
+            let n = conn.execute(
+                "UPDATE groups SET seasons = ?2 WHERE id = ?1 AND deleted IS NULL",
+                params![group_id.to_string(), seasons.as_ref().map(to_json).transpose()?]
+            )?;
+            if n == 0 { return Err(StoreError::GroupNotFound(group_id)) }
+            Ok(())
+        })
+    }
+    async fn seasons(&self, group_id: &GroupId) -> Result<Option<Seasons>,StoreError> {
+        self.with_conn(|conn| {
+            let seasons: Option<String> = conn.query_row(
+                "SELECT seasons FROM groups WHERE id = ?1 AND deleted IS NULL",
+                params![group_id.to_string()],
+                |row| row.get(0)
+            ).optional()?.ok_or(StoreError::GroupNotFound(*group_id))?;
+            Ok(seasons.map(parse_json).transpose()?)
+        })
+    }
+    async fn archive_season(&self, group_id: GroupId, season: ArchivedSeason, by: Option<&str>) -> Result<(),StoreError> {
+        let (start, end, archived) = (date_to_nanos(season.start)?, date_to_nanos(season.end)?, date_to_nanos(season.archived)?);
+        let change = Change::ArchiveSeason { group_id, name: season.name.clone() };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, GROUP_EXISTS, &group_id.to_string())? {
+                return Err(StoreError::GroupNotFound(group_id))
+            }
+            tx.execute(
+                "INSERT INTO season_archive (group_id, name, start_date, end_date, archived, winners) VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+                 ON CONFLICT(group_id, name) DO UPDATE SET
+                    start_date = excluded.start_date,
+                    end_date = excluded.end_date,
+                    archived = excluded.archived,
+                    winners = excluded.winners",
+                params![
+                    group_id.to_string(), season.name, start, end, archived, to_json(&season.winners)?
+                ]
+            )?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn season_archive(&self, group_id: &GroupId) -> Result<Vec<ArchivedSeason>,StoreError> {
+        self.with_conn(|conn| {
+            if !exists(conn, GROUP_EXISTS, &group_id.to_string())? {
+                return Err(StoreError::GroupNotFound(*group_id))
+            }
+            // most recent first (and then by name so that the order is stable):
+            let mut stmt = conn.prepare_cached(
+                "SELECT name, start_date, end_date, archived, winners FROM season_archive
+                 WHERE group_id = ?1
+                 ORDER BY start_date DESC, name"
+            )?;
+            let archive = stmt.query_map(params![group_id.to_string()], |row| {
+                Ok(ArchivedSeason {
+                    name: row.get(0)?,
+                    start: Utc.timestamp_nanos(row.get(1)?),
+                    end: Utc.timestamp_nanos(row.get(2)?),
+                    archived: Utc.timestamp_nanos(row.get(3)?),
+                    winners: parse_json(row.get(4)?)?
+                })
+            })?.collect::<Result<Vec<_>,_>>()?;
+            Ok(archive)
+        })
+    }
+
+    async fn restore_group(&self, id: &GroupId, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::RestoreGroup { id: *id };
+        self.with_conn_mut(by, change, |conn| {
+            let deleted: Option<i64> = conn.query_row(
+                "SELECT deleted FROM groups WHERE id = ?1",
+                params![id.to_string()],
+                |row| row.get(0)
+            ).optional()?.ok_or(StoreError::GroupNotFound(*id))?;
+            if deleted.is_none() {
+                return Err(StoreError::NotDeleted(Item::Group(*id)))
+            }
+            conn.execute("UPDATE groups SET deleted = NULL WHERE id = ?1", params![id.to_string()])?;
+            Ok(())
+        })
+    }
+    async fn restore_scorable(&self, id: &ScorableId, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::RestoreScorable { id: *id };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            let (group_id, deleted): (GroupId, Option<i64>) = tx.query_row(
+                "SELECT group_id, deleted FROM scorables WHERE id = ?1",
+                params![id.to_string()],
+                |row| Ok((parse_id(row.get(0)?)?, row.get(1)?))
+            ).optional()?.ok_or(StoreError::ScorableNotFound(*id))?;
+            if !exists(&tx, GROUP_EXISTS, &group_id.to_string())? {
+                return Err(StoreError::GroupNotFound(group_id))
+            }
+            if deleted.is_none() {
+                return Err(StoreError::NotDeleted(Item::Scorable(*id)))
+            }
+            tx.execute("UPDATE scorables SET deleted = NULL WHERE id = ?1", params![id.to_string()])?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn restore_score(&self, id: &ScoreId, by: Option<&str>) -> Result<(),StoreError> {
+        let change = Change::RestoreScore { id: *id };
+        self.with_conn_mut(by, change, |conn| {
+            let tx = conn.transaction()?;
+            let (scorable_id, deleted): (ScorableId, Option<i64>) = tx.query_row(
+                "SELECT scorable_id, deleted FROM scores WHERE id = ?1",
+                params![id.to_string()],
+                |row| Ok((parse_id(row.get(0)?)?, row.get(1)?))
+            ).optional()?.ok_or(StoreError::ScoreNotFound(*id))?;
+            if !exists(&tx, SCORABLE_EXISTS, &scorable_id.to_string())? {
+                return Err(StoreError::ScorableNotFound(scorable_id))
+            }
+            if deleted.is_none() {
+                return Err(StoreError::NotDeleted(Item::Score(*id)))
+            }
+            tx.execute("UPDATE scores SET deleted = NULL WHERE id = ?1", params![id.to_string()])?;
+            tx.commit()?;
+            Ok(())
+        })
+    }
+    async fn trash(&self, group_id: Option<&GroupId>) -> Result<Vec<Deleted>,StoreError> {
+        self.with_conn(|conn| {
+            // ?1 is NULL if no group is given, returning everything deleted:
+            let mut stmt = conn.prepare_cached(
+                "SELECT 'group', id, id, name, deleted FROM groups
+                 WHERE deleted IS NOT NULL AND (?1 IS NULL OR id = ?1)
+                 UNION ALL
+                 SELECT 'scorable', id, group_id, name, deleted FROM scorables
+                 WHERE deleted IS NOT NULL AND (?1 IS NULL OR group_id = ?1)
+                 UNION ALL
+                 SELECT 'score', scores.id, scorables.group_id, scores.username, scores.deleted FROM scores
+                 JOIN scorables ON scorables.id = scores.scorable_id
+                 WHERE scores.deleted IS NOT NULL AND (?1 IS NULL OR scorables.group_id = ?1)
+                 ORDER BY 5 DESC"
+            )?;
+            let trash = stmt.query_map(params![group_id.map(|id| id.to_string())], |row| {
+                let kind: String = row.get(0)?;
+                let id: String = row.get(1)?;
+                let item = match &*kind {
+                    "group" => Item::Group(parse_id(id)?),
+                    "scorable" => Item::Scorable(parse_id(id)?),
+                    _ => Item::Score(parse_id(id)?)
+                };
+                Ok(Deleted {
+                    item,
+                    group_id: parse_id(row.get(2)?)?,
+                    name: row.get(3)?,
+                    deleted_at: Utc.timestamp_nanos(row.get(4)?)
+                })
+            })?.collect::<Result<_,_>>()?;
+            Ok(trash)
+        })
+    }
+
+    async fn groups(&self, member: Option<&str>) -> Result<Vec<Group>,StoreError> {
+        self.with_conn(|conn| {
+            // ?1 is NULL if no member is given, returning every group. Groups
+            // without any members are open to everybody, so they're included too:
+            let mut stmt = conn.prepare_cached(
+                "SELECT id, name FROM groups
+                 WHERE deleted IS NULL AND (
+                    ?1 IS NULL
+                    OR EXISTS (SELECT 1 FROM members WHERE group_id = groups.id AND username = ?1)
+                    OR NOT EXISTS (SELECT 1 FROM members WHERE group_id = groups.id)
+                 )
+                 ORDER BY name, id"
+            )?;
+            let groups = stmt.query_map(params![member], |row| {
+                Ok(Group { id: parse_id(row.get(0)?)?, name: row.get(1)? })
+            })?.collect::<Result<_,_>>()?;
+            Ok(groups)
+        })
+    }
+    async fn scorables_in_group(&self, group_id: &GroupId) -> Result<Vec<Scorable>,StoreError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            if !exists(&tx, GROUP_EXISTS, &group_id.to_string())? {
+                return Err(StoreError::GroupNotFound(*group_id))
+            }
+            let mut stmt = tx.prepare_cached(
+                "SELECT id, name, ranking, value_kind FROM scorables
+                 WHERE group_id = ?1 AND deleted IS NULL
+                 ORDER BY name, id"
+            )?;
+            let scorables = stmt.query_map(params![group_id.to_string()], |row| {
+                Ok(Scorable {
+                    id: parse_id(row.get(0)?)?,
+                    group_id: *group_id,
+                    name: row.get(1)?,
+                    ranking: row.get(2)?,
+                    value_kind: row.get(3)?
+                })
+            })?.collect::<Result<_,_>>()?;
+            Ok(scorables)
+        })
+    }
+    async fn scores(&self, scorable_id: &ScorableId, filter: &ScoreFilter) -> Result<Vec<Score>,StoreError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            Ok(query_scores(&tx, scorable_id, filter, None)?.items)
+        })
+    }
+    async fn groups_page(&self, member: Option<&str>, page: &PageRequest) -> Result<Page<Group>,StoreError> {
+        // There are rarely enough groups or scorables for it to be worth paging through them in SQL:
+        Ok(Page::from_all(self.groups(member).await?, page, CursorKey::of_group))
+    }
+    async fn scorables_in_group_page(&self, group_id: &GroupId, page: &PageRequest) -> Result<Page<Scorable>,StoreError> {
+        Ok(Page::from_all(self.scorables_in_group(group_id).await?, page, CursorKey::of_scorable))
+    }
+    async fn scores_page(&self, scorable_id: &ScorableId, filter: &ScoreFilter, page: &PageRequest) -> Result<Page<Score>,StoreError> {
+        self.with_conn(|conn| {
+            let tx = conn.transaction()?;
+            query_scores(&tx, scorable_id, filter, Some(page))
+        })
+    }
+    async fn stats(&self, scorable_id: &ScorableId, filter: &ScoreFilter) -> Result<Stats,StoreError> {
+        // Medians and percentiles need every value anyway, so work everything out from them:
+        let scores = self.scores(scorable_id, filter).await?;
+        Ok(Stats::from_scores(&scores, filter))
+    }
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>,StoreError> {
+        self.with_conn(|conn| {
+            // Each of ?1 to ?3 is NULL if not being filtered on:
+            let mut stmt = conn.prepare_cached(
+                "SELECT at, by_user, group_id, scorable_id, change FROM history
+                 WHERE (?1 IS NULL OR by_user = ?1)
+                   AND (?2 IS NULL OR group_id = ?2)
+                   AND (?3 IS NULL OR scorable_id = ?3)
+                 ORDER BY id DESC LIMIT ?4"
+            )?;
+            // A negative LIMIT means "no limit" to SQLite:
+            let limit = filter.limit.map(|l| l as i64).unwrap_or(-1);
+            let params = params![
+                filter.by,
+                filter.group_id.map(|id| id.to_string()),
+                filter.scorable_id.map(|id| id.to_string()),
+                limit
+            ];
+            let entries = stmt.query_map(params, |row| {
+                Ok(HistoryEntry {
+                    at: Some(Utc.timestamp_nanos(row.get(0)?)),
+                    by: row.get(1)?,
+                    group_id: row.get::<_,Option<String>>(2)?.map(parse_id).transpose()?,
+                    scorable_id: row.get::<_,Option<String>>(3)?.map(parse_id).transpose()?,
+                    change: row.get(4)?
+                })
+            })?.collect::<Result<_,_>>()?;
+            Ok(entries)
+        })
+    }
+    async fn as_of(&self, _as_of: AsOf) -> Result<Arc<dyn Store + Send + Sync>,StoreError> {
+        // The history table records what changed, but not enough to rebuild how things were:
+        Err(StoreError::CannotReplay)
+    }
+}
+
+/// Fetch the scores for a scorable that match the filter given, best first. Only the
+/// page asked for is fetched if there is one, and otherwise everything is.
+fn query_scores(conn: &Connection, scorable_id: &ScorableId, filter: &ScoreFilter, page: Option<&PageRequest>) -> Result<Page<Score>,StoreError> {
+    let ranking: Ranking = conn.query_row(
+        "SELECT scorables.ranking FROM scorables
+         JOIN groups ON groups.id = scorables.group_id
+         WHERE scorables.id = ?1 AND scorables.deleted IS NULL AND groups.deleted IS NULL",
+        params![scorable_id.to_string()],
+        |row| row.get(0)
+    ).optional()?.ok_or(StoreError::ScorableNotFound(*scorable_id))?;
+
+    // Scores are ordered as CursorKey::of_score orders them: best first, then earliest
+    // first, then by ID (and then by member, if ranking by member, in which case there's
+    // a row per member of each team). Each score is credited to its team, or to its member,
+    // as a JSON array. Each of ?2 to ?4 is NULL if not being filtered on. Usernames are
+    // given as a JSON array. The scores credited to each user (or team) are numbered so
+    // that we can keep just the best of them if asked, and then everything left is
+    // numbered and counted:
+    let order = match ranking {
+        Ranking::HigherIsBetter => "~value",
+        Ranking::LowerIsBetter => "value"
+    };
+    let ranked = format!(
+        "WITH credited AS (
+            SELECT id, username AS member, username AS owner, teammates, value, date,
+                CASE WHEN ?6 THEN json_array(username) ELSE team END AS credit
+            FROM scores
+            WHERE scorable_id = ?1 AND deleted IS NULL
+            UNION ALL
+            SELECT scores.id, teammate.value, scores.username, scores.teammates, scores.value, scores.date,
+                json_array(teammate.value)
+            FROM scores, json_each(scores.teammates) AS teammate
+            WHERE ?6 AND scores.scorable_id = ?1 AND scores.deleted IS NULL
+         ), matching AS (
+            SELECT id, member, owner, teammates, value, date, {order} AS ord,
+                ROW_NUMBER() OVER (PARTITION BY credit ORDER BY {order}, date, id, member) AS nth
+            FROM credited
+            WHERE (?2 IS NULL OR date >= ?2)
+              AND (?3 IS NULL OR date < ?3)
+              AND (?4 IS NULL OR EXISTS (
+                SELECT 1 FROM json_each(credit) AS c WHERE c.value IN (SELECT value FROM json_each(?4))
+              ))
+         ), ranked AS (
+            SELECT id, member, owner, teammates, value, date, ord,
+                ROW_NUMBER() OVER (ORDER BY ord, date, id, member) AS pos,
+                COUNT(*) OVER () AS total
+            FROM matching
+            WHERE NOT ?5 OR nth = 1
+         )",
+        order = order
+    );
+    let usernames = filter.usernames.as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    // Only the best `limit` scores (if given) are paged through:
+    let limit = filter.limit.map(|l| l as i64).unwrap_or(i64::MAX);
+    let (from, to) = (filter.from.map(compared_nanos), filter.to.map(compared_nanos));
+
+    // ?8 to ?11 are NULL if we're starting from the beginning:
+    let (backwards, after) = match page.and_then(|p| p.cursor.as_ref()) {
+        Some(Cursor { backwards, key: CursorKey::Score { order, date, id, username } }) => {
+            (*backwards, Some((*order, compared_nanos(*date), id.as_str(), username.as_str())))
+        },
+        _ => (false, None)
+    };
+    let (compare, direction) = if backwards { ("<", "DESC") } else { (">", "ASC") };
+    let query = format!(
+        "{ranked}
+         SELECT id, member, owner, teammates, value, date, pos, MIN(total, ?7) FROM ranked
+         WHERE pos <= ?7 AND (?8 IS NULL OR (ord, date, id, member) {compare} (?8, ?9, ?10, ?11))
+         ORDER BY pos {direction} LIMIT ?12",
+        ranked = ranked, compare = compare, direction = direction
+    );
+    // A negative LIMIT means "no limit" to SQLite:
+    let size = page.map(|p| p.size as i64).unwrap_or(-1);
+    let params = params![
+        scorable_id.to_string(), from, to, usernames, filter.best_per_user, filter.by_member, limit,
+        after.map(|a| a.0), after.map(|a| a.1), after.map(|a| a.2), after.map(|a| a.3), size
+    ];
+    let mut stmt = conn.prepare_cached(&query)?;
+    let mut rows = stmt.query_map(params, |row| {
+        // The member's teammates are everybody else credited with the score:
+        let username: String = row.get(1)?;
+        let owner: String = row.get(2)?;
+        let teammates = std::iter::once(owner)
+            .chain(parse_json::<Vec<String>>(row.get(3)?)?)
+            .filter(|u| *u != username)
+            .collect();
+        let score = Score {
+            id: parse_id(row.get(0)?)?,
+            scorable_id: *scorable_id,
+            username,
+            teammates,
+            value: row.get(4)?,
+            date: Utc.timestamp_nanos(row.get(5)?)
+        };
+        Ok((score, row.get::<_,i64>(6)?, row.get::<_,i64>(7)?))
+    })?.collect::<Result<Vec<_>,_>>()?;
+    if backwards {
+        rows.reverse();
+    }
+
+    // Each row knows the total, so we only need to count separately if there are none:
+    let total = match rows.first() {
+        Some((_, _, total)) => *total,
+        None => conn.query_row(
+            &format!("{ranked} SELECT COUNT(*) FROM ranked WHERE pos <= ?7", ranked = ranked),
+            params![scorable_id.to_string(), from, to, usernames, filter.best_per_user, filter.by_member, limit],
+            |row| row.get(0)
+        )?
+    };
+    let has_prev = rows.first().map(|(_, pos, _)| *pos > 1).unwrap_or(false);
+    let has_next = rows.last().map(|(_, pos, _)| *pos < total).unwrap_or(false);
+    let scores = rows.into_iter().map(|(score, _, _)| score).collect();
+    Ok(Page::new(scores, total as usize, has_prev, has_next, |score| CursorKey::of_score(ranking, score)))
+}
+
+/// Which records would a score set if it was upserted?
+fn records_set_by(conn: &Connection, id: &ScoreId, scorable_id: &ScorableId, credited: &[&str], value: i64) -> rusqlite::Result<Records> {
+    let ranking: Ranking = conn.query_row(
+        "SELECT ranking FROM scorables WHERE id = ?1",
+        params![scorable_id.to_string()],
+        |row| row.get(0)
+    )?;
+    let order = match ranking {
+        Ranking::HigherIsBetter => "value DESC",
+        Ranking::LowerIsBetter => "value ASC"
+    };
+    // The best of the other scores (only those credited to the user, if ?3 isn't NULL),
+    // ordered as in query_scores if some are equal:
+    let query = format!(
+        "SELECT username, value FROM scores
+         WHERE scorable_id = ?1 AND id != ?2 AND deleted IS NULL
+           AND (?3 IS NULL OR username = ?3 OR EXISTS (SELECT 1 FROM json_each(teammates) WHERE json_each.value = ?3))
+         ORDER BY {order}, date, id LIMIT 1",
+        order = order
+    );
+    let best = |only_user: Option<&str>| conn.query_row(
+        &query,
+        params![scorable_id.to_string(), id.to_string(), only_user],
+        |row| Ok(RecordHolder { username: row.get(0)?, value: row.get(1)? })
+    ).optional();
+    let previous_best = best(None)?;
+    let previous_personal_bests = credited.iter()
+        .map(|username| Ok((username.to_string(), best(Some(*username))?.map(|holder| holder.value))))
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(Records::set_by(ranking, value, previous_best, previous_personal_bests))
+}
+
+/// The matches played against a scorable (oldest first), narrowed down to the one match
+/// given, or to those that the user given took part in. The scorable isn't checked.
+fn query_matches(conn: &Connection, scorable_id: &ScorableId, id: Option<&MatchId>, username: Option<&str>) -> Result<Vec<Match>,StoreError> {
+    let mut stmt = conn.prepare(
+        "SELECT matches.id, matches.winner, matches.date, match_participants.username FROM matches
+         JOIN match_participants ON match_participants.match_id = matches.id
+         WHERE matches.scorable_id = ?1
+            AND (?2 IS NULL OR matches.id = ?2)
+            AND (?3 IS NULL OR matches.id IN (SELECT match_id FROM match_participants WHERE username = ?3))
+         ORDER BY matches.date, matches.id, match_participants.position"
+    )?;
+    let rows = stmt.query_map(
+        params![scorable_id.to_string(), id.map(|id| id.to_string()), username],
+        |row| Ok((parse_id::<MatchId>(row.get(0)?)?, row.get::<_,Option<String>>(1)?, row.get::<_,i64>(2)?, row.get::<_,String>(3)?))
+    )?;
+    // There's a row per participant, and they're ordered so that each match's rows are together:
+    let mut matches: Vec<Match> = Vec::new();
+    for row in rows {
+        let (id, winner, date, username) = row?;
+        match matches.last_mut() {
+            Some(m) if m.id == id => m.participants.push(username),
+            _ => matches.push(Match {
+                id,
+                scorable_id: *scorable_id,
+                participants: vec![username],
+                winner,
+                date: Utc.timestamp_nanos(date)
+            })
+        }
+    }
+    Ok(matches)
+}
+
+/// Bring the database schema up to date.
+fn migrate(conn: &mut Connection) -> anyhow::Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+    let version = version as usize;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!("Database was created by a newer version of highscore")
+    }
+    for (idx, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {};", idx + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Which group and scorable (if any) is a change being made in? This must be
+/// called before the change is made, so that anything being deleted is still there.
+fn locate(conn: &Connection, change: &Change) -> rusqlite::Result<(Option<GroupId>, Option<ScorableId>)> {
+    let group_of = |id: &ScorableId| -> rusqlite::Result<Option<GroupId>> {
+        conn.query_row(
+            "SELECT group_id FROM scorables WHERE id = ?1",
+            params![id.to_string()],
+            |row| parse_id(row.get(0)?)
+        ).optional()
+    };
+    let location = match change {
+        Change::UpsertUser { .. } | Change::DeleteUser { .. } => (None, None),
+        Change::UpsertGroup { id } | Change::DeleteGroup { id } | Change::RestoreGroup { id } => (Some(*id), None),
+        Change::SetSeasons { group_id } | Change::ArchiveSeason { group_id, .. } => (Some(*group_id), None),
+        Change::UpsertMember { group_id, .. } | Change::DeleteMember { group_id, .. } => (Some(*group_id), None),
+        Change::UpsertScorable { id, group_id } => (Some(*group_id), Some(*id)),
+        Change::DeleteScorable { id } | Change::RestoreScorable { id } => (group_of(id)?, Some(*id)),
+        Change::UpsertScore { scorable_id, .. } | Change::NewRecord { scorable_id, .. } => (group_of(scorable_id)?, Some(*scorable_id)),
+        Change::DeleteScore { id } | Change::RestoreScore { id } => {
+            let scorable_id: Option<ScorableId> = conn.query_row(
+                "SELECT scorable_id FROM scores WHERE id = ?1",
+                params![id.to_string()],
+                |row| parse_id(row.get(0)?)
+            ).optional()?;
+            let group_id = match &scorable_id {
+                Some(scorable_id) => group_of(scorable_id)?,
+                None => None
+            };
+            (group_id, scorable_id)
+        }
+        Change::UpsertMatch { scorable_id, .. } => (group_of(scorable_id)?, Some(*scorable_id)),
+        Change::DeleteMatch { id } => {
+            let scorable_id: Option<ScorableId> = conn.query_row(
+                "SELECT scorable_id FROM matches WHERE id = ?1",
+                params![id.to_string()],
+                |row| parse_id(row.get(0)?)
+            ).optional()?;
+            let group_id = match &scorable_id {
+                Some(scorable_id) => group_of(scorable_id)?,
+                None => None
+            };
+            (group_id, scorable_id)
+        }
+    };
+    Ok(location)
+}
+
+/// Does the query (taking a single parameter) return any rows?
+fn exists(conn: &Connection, query: &str, param: &str) -> rusqlite::Result<bool> {
+    conn.query_row(query, params![param], |_| Ok(()))
+        .optional()
+        .map(|r| r.is_some())
+}
+
+/// Groups that have members must always have an owner. Fail if the user is the only owner
+/// of the group given, or of any group (including those in the trash) if none is given.
+fn check_owner_remains(conn: &Connection, group_id: Option<&GroupId>, username: &str) -> Result<(),StoreError> {
+    let group_id: Option<String> = conn.query_row(
+        "SELECT group_id FROM members m
+         WHERE username = ?1 AND role = 'owner' AND (?2 IS NULL OR group_id = ?2)
+           AND NOT EXISTS (SELECT 1 FROM members WHERE group_id = m.group_id AND role = 'owner' AND username != ?1)
+         LIMIT 1",
+        params![username, group_id.map(|id| id.to_string())],
+        |row| row.get(0)
+    ).optional()?;
+    match group_id {
+        Some(id) => Err(StoreError::LastOwner(username.to_owned(), parse_id(id)?)),
+        None => Ok(())
+    }
+}
+
+/// A date to compare stored dates against, in nanoseconds. Dates that are too far away to
+/// store are clamped to the nearest that aren't, since they compare the same against any
+/// date that is stored.
+fn compared_nanos(date: DateTime<Utc>) -> i64 {
+    date_to_nanos(date).unwrap_or(if date.timestamp() < 0 { i64::MIN } else { i64::MAX })
+}
+
+/// Parse something that we've stored as JSON.
+fn parse_json<T: serde::de::DeserializeOwned>(s: String) -> rusqlite::Result<T> {
+    serde_json::from_str(&s).map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+/// Turn something into JSON to store it.
+fn to_json<T: serde::Serialize>(value: &T) -> rusqlite::Result<String> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+/// Parse one of our IDs from the text that it's stored as.
+fn parse_id<T: std::str::FromStr<Err = uuid::Error>>(s: String) -> rusqlite::Result<T> {
+    s.parse().map_err(|e| rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e)))
+}
+
+impl ToSql for Ranking {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let s = match self {
+            Ranking::HigherIsBetter => "higher_is_better",
+            Ranking::LowerIsBetter => "lower_is_better"
+        };
+        Ok(ToSqlOutput::from(s))
+    }
+}
+
+impl FromSql for Ranking {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "higher_is_better" => Ok(Ranking::HigherIsBetter),
+            "lower_is_better" => Ok(Ranking::LowerIsBetter),
+            _ => Err(FromSqlError::InvalidType)
+        }
+    }
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let s = match self {
+            Role::Viewer => "viewer",
+            Role::Editor => "editor",
+            Role::Owner => "owner"
+        };
+        Ok(ToSqlOutput::from(s))
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "viewer" => Ok(Role::Viewer),
+            "editor" => Ok(Role::Editor),
+            "owner" => Ok(Role::Owner),
+            _ => Err(FromSqlError::InvalidType)
+        }
+    }
+}
+
+impl ToSql for ValueKind {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let json = serde_json::to_string(self)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        Ok(ToSqlOutput::from(json))
+    }
+}
+
+impl FromSql for ValueKind {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        serde_json::from_str(value.as_str()?).map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+impl ToSql for Change {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        let json = serde_json::to_string(self)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        Ok(ToSqlOutput::from(json))
+    }
+}
+
+impl FromSql for Change {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        serde_json::from_str(value.as_str()?).map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(err: rusqlite::Error) -> StoreError {
+        StoreError::InternalError(err.into())
+    }
+}
+
+/// The same scenarios are run against the event log store too, to check that both behave the same.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::BackendStore;
+    use crate::backend::testing::{ paths, stores, add_users };
+    use crate::changes::RecordKind;
+    use crate::store_interface::{ TokenId, Item, PersonalBest, Record };
+
+    fn date(year: i32, month: u32, day: u32) -> DateTime<Utc> {
+        Utc.ymd(year, month, day).and_hms(0, 0, 0)
+    }
+
+    fn owned(scores: &[(&str, i64)]) -> Vec<(String,i64)> {
+        scores.iter().map(|(username, value)| (username.to_string(), *value)).collect()
+    }
+
+    /// A group with a single scorable in it, ranked as given.
+    async fn add_scorable(store: &BackendStore, scorable_id: ScorableId, ranking: Option<Ranking>) -> GroupId {
+        let group_id = GroupId::new();
+        store.upsert_group(group_id, "Group".to_owned(), None).await.unwrap();
+        store.upsert_scorable(scorable_id, group_id, "Scorable".to_owned(), ranking, None, None).await.unwrap();
+        group_id
+    }
+
+    /// Who each score matching the filter is credited to and what it was, best first.
+    async fn ranked(store: &BackendStore, scorable_id: &ScorableId, filter: ScoreFilter) -> Vec<(String,i64)> {
+        store.scores(scorable_id, &filter).await.unwrap().into_iter().map(|s| (s.username, s.value)).collect()
+    }
+
+    /// Every page of scores, following the cursors forwards from the start, and then
+    /// backwards again from the last page. Scores don't compare, but their JSON does.
+    async fn walk_pages(store: &BackendStore, scorable_id: &ScorableId, filter: &ScoreFilter, size: usize) -> (Vec<String>, Vec<String>) {
+        let mut forwards = Vec::new();
+        let mut page = store.scores_page(scorable_id, filter, &PageRequest { cursor: None, size }).await.unwrap();
+        loop {
+            assert!(page.items.len() <= size);
+            forwards.extend(page.items.iter().map(|s| serde_json::to_string(s).unwrap()));
+            match &page.next {
+                Some(next) => {
+                    // Cursors are handed out as tokens, so make sure they survive that:
+                    let cursor = next.to_string().parse().unwrap();
+                    page = store.scores_page(scorable_id, filter, &PageRequest { cursor: Some(cursor), size }).await.unwrap();
+                },
+                None => break
+            }
+        }
+        let mut backwards: Vec<_> = page.items.iter().rev().map(|s| serde_json::to_string(s).unwrap()).collect();
+        while let Some(prev) = page.prev.clone() {
+            page = store.scores_page(scorable_id, filter, &PageRequest { cursor: Some(prev), size }).await.unwrap();
+            assert!(page.items.len() <= size);
+            backwards.extend(page.items.iter().rev().map(|s| serde_json::to_string(s).unwrap()));
+        }
+        backwards.reverse();
+        (forwards, backwards)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn scores_are_ranked_in_the_direction_asked_for() {
+        let (highest, lowest) = (ScorableId::new(), ScorableId::new());
+        for (backend, path) in paths() {
+            let store = BackendStore::load(backend, path.clone(), Durability::Buffered).await.unwrap();
+            add_users(&store, &["alice"]).await;
+            let group_id = add_scorable(&store, highest, None).await;
+            store.upsert_scorable(lowest, group_id, "Golf".to_owned(), Some(Ranking::LowerIsBetter), None, None).await.unwrap();
+            // Not saying how to rank scores leaves them ranked as they were:
+            store.upsert_scorable(lowest, group_id, "Renamed".to_owned(), None, None, None).await.unwrap();
+            for value in &[5, 1, 9] {
+                for scorable_id in &[highest, lowest] {
+                    store.upsert_score(ScoreId::new(), *scorable_id, "alice".to_owned(), Vec::new(), *value, Utc::now(), None).await.unwrap();
+                }
+            }
+            store.flush_to_disk().await.unwrap();
+            drop(store);
+
+            let store = BackendStore::load(backend, path, Durability::Buffered).await.unwrap();
+            assert_eq!(store.get_scorable(&lowest).await.unwrap().ranking, Ranking::LowerIsBetter);
+            assert_eq!(store.get_scorable(&highest).await.unwrap().ranking, Ranking::HigherIsBetter);
+            assert_eq!(ranked(&store, &highest, ScoreFilter::default()).await, owned(&[("alice", 9), ("alice", 5), ("alice", 1)]));
+            assert_eq!(ranked(&store, &lowest, ScoreFilter::default()).await, owned(&[("alice", 1), ("alice", 5), ("alice", 9)]));
+            assert_eq!(ranked(&store, &lowest, ScoreFilter { limit: Some(1), ..ScoreFilter::default() }).await, owned(&[("alice", 1)]));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn team_scores_are_ranked_by_team_or_by_member() {
+        let team = |usernames: &[&str]| usernames.iter().map(|u| u.to_string()).collect::<Vec<_>>();
+        let scorable_id = ScorableId::new();
+        let scores = [
+            (ScoreId::new(), "alice", team(&["bob"]), 10),
+            (ScoreId::new(), "alice", team(&[]), 8),
+            (ScoreId::new(), "carol", team(&["dave", "bob"]), 12),
+            (ScoreId::new(), "bob", team(&["alice"]), 5)
+        ];
+        let mut by_member = Vec::new();
+        for store in stores().await {
+            add_users(&store, &["alice", "bob", "carol", "dave"]).await;
+            add_scorable(&store, scorable_id, None).await;
+            for (id, username, teammates, value) in scores.iter() {
+                store.upsert_score(*id, scorable_id, username.to_string(), teammates.clone(), *value, date(2021, 1, 1), None).await.unwrap();
+            }
+
+            assert_eq!(ranked(&store, &scorable_id, ScoreFilter::default()).await, owned(&[("carol", 12), ("alice", 10), ("alice", 8), ("bob", 5)]));
+            // alice and bob are the same team as bob and alice:
+            let best = ranked(&store, &scorable_id, ScoreFilter { best_per_user: true, ..ScoreFilter::default() }).await;
+            assert_eq!(best, owned(&[("carol", 12), ("alice", 10), ("alice", 8)]));
+            let with_bob = ranked(&store, &scorable_id, ScoreFilter { usernames: Some(team(&["bob"])), ..ScoreFilter::default() }).await;
+            assert_eq!(with_bob, owned(&[("carol", 12), ("alice", 10), ("bob", 5)]));
+
+            // Each member of a team is credited with its score:
+            let members = store.scores(&scorable_id, &ScoreFilter { by_member: true, ..ScoreFilter::default() }).await.unwrap();
+            assert_eq!(members.len(), 8);
+            assert_eq!(members[0].username, "bob");
+            assert_eq!(members[0].teammates, team(&["carol", "dave"]));
+            let best = ranked(&store, &scorable_id, ScoreFilter { by_member: true, best_per_user: true, ..ScoreFilter::default() }).await;
+            assert_eq!(best, owned(&[("bob", 12), ("carol", 12), ("dave", 12), ("alice", 10)]));
+            let alice = ranked(&store, &scorable_id, ScoreFilter { by_member: true, usernames: Some(team(&["alice"])), ..ScoreFilter::default() }).await;
+            assert_eq!(alice, owned(&[("alice", 10), ("alice", 8), ("alice", 5)]));
+            by_member.push(serde_json::to_string(&members).unwrap());
+        }
+        assert_eq!(by_member[0], by_member[1]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn team_scores_set_personal_bests_for_every_member() {
+        for store in stores().await {
+            add_users(&store, &["alice", "bob", "carol"]).await;
+            let scorable_id = ScorableId::new();
+            add_scorable(&store, scorable_id, None).await;
+            // The team's score is better than bob's best, but not carol's:
+            store.upsert_score(ScoreId::new(), scorable_id, "carol".to_owned(), Vec::new(), 20, Utc::now(), None).await.unwrap();
+            store.upsert_score(ScoreId::new(), scorable_id, "bob".to_owned(), Vec::new(), 5, Utc::now(), None).await.unwrap();
+
+            let mut changes = store.subscribe();
+            let team = vec!["bob".to_owned(), "carol".to_owned()];
+            let records = store.upsert_score(ScoreId::new(), scorable_id, "alice".to_owned(), team, 10, Utc::now(), None).await.unwrap();
+            assert_eq!(records.top_score, None);
+            assert_eq!(records.personal_bests, vec![
+                PersonalBest { username: "alice".to_owned(), record: Record { previous: None } },
+                PersonalBest {
+                    username: "bob".to_owned(),
+                    record: Record { previous: Some(RecordHolder { username: "bob".to_owned(), value: 5 }) }
+                }
+            ]);
+
+            let mut new_records = Vec::new();
+            while let Ok(notification) = changes.try_recv() {
+                if let Change::NewRecord { username, record, .. } = notification.change {
+                    new_records.push((username, record));
+                }
+            }
+            assert_eq!(new_records, vec![
+                ("alice".to_owned(), RecordKind::PersonalBest),
+                ("bob".to_owned(), RecordKind::PersonalBest)
+            ]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn best_scores_per_user_between_dates() {
+        let scorable_id = ScorableId::new();
+        for store in stores().await {
+            add_users(&store, &["alice", "bob", "carol"]).await;
+            add_scorable(&store, scorable_id, Some(Ranking::LowerIsBetter)).await;
+            let scores = [
+                ("bob", 70, date(2020, 6, 1)), ("bob", 72, date(2021, 2, 1)), ("bob", 68, date(2021, 5, 1)),
+                ("alice", 68, date(2021, 1, 1)), ("alice", 80, date(2021, 3, 1)),
+                ("carol", 60, date(2020, 1, 1)), ("carol", 90, date(2021, 7, 1))
+            ];
+            for (username, value, date) in scores.iter() {
+                store.upsert_score(ScoreId::new(), scorable_id, username.to_string(), Vec::new(), *value, *date, None).await.unwrap();
+            }
+
+            // Equal scores are ranked earliest first:
+            let all = ranked(&store, &scorable_id, ScoreFilter::default()).await;
+            assert_eq!(all[..3], owned(&[("carol", 60), ("alice", 68), ("bob", 68)])[..]);
+            let in_2021 = ScoreFilter { from: Some(date(2021, 1, 1)), to: Some(date(2022, 1, 1)), ..ScoreFilter::default() };
+            assert_eq!(ranked(&store, &scorable_id, in_2021.clone()).await.len(), 5);
+            let best = ranked(&store, &scorable_id, ScoreFilter { best_per_user: true, ..in_2021 }).await;
+            assert_eq!(best, owned(&[("alice", 68), ("bob", 68), ("carol", 90)]));
+            let best = ranked(&store, &scorable_id, ScoreFilter { best_per_user: true, limit: Some(2), ..ScoreFilter::default() }).await;
+            assert_eq!(best, owned(&[("carol", 60), ("alice", 68)]));
+            let some = ScoreFilter { best_per_user: true, usernames: Some(vec!["bob".to_owned(), "carol".to_owned()]), ..ScoreFilter::default() };
+            assert_eq!(ranked(&store, &scorable_id, some).await, owned(&[("carol", 60), ("bob", 68)]));
+            let before_2021 = ScoreFilter { to: Some(date(2021, 1, 1)), ..ScoreFilter::default() };
+            assert_eq!(ranked(&store, &scorable_id, before_2021).await, owned(&[("carol", 60), ("bob", 70)]));
+            assert!(ranked(&store, &scorable_id, ScoreFilter { usernames: Some(Vec::new()), ..ScoreFilter::default() }).await.is_empty());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paging_through_tied_scores() {
+        let scorable_id = ScorableId::new();
+        let users = ["alice", "bob", "carol"];
+        // Lots of equal values and dates, so that pages have to break ties:
+        let scores: Vec<_> = (0..23)
+            .map(|n| (ScoreId::new(), users[n % 3], n as i64 % 5, date(2021, 1, 1 + n as u32 % 2)))
+            .collect();
+        let mut pages = Vec::new();
+        for store in stores().await {
+            add_users(&store, &users).await;
+            add_scorable(&store, scorable_id, None).await;
+            for (id, username, value, date) in &scores {
+                store.upsert_score(*id, scorable_id, username.to_string(), Vec::new(), *value, *date, None).await.unwrap();
+            }
+
+            let filters = [
+                ScoreFilter::default(),
+                ScoreFilter { limit: Some(7), ..ScoreFilter::default() },
+                ScoreFilter { best_per_user: true, ..ScoreFilter::default() }
+            ];
+            for filter in filters.iter() {
+                let all: Vec<_> = store.scores(&scorable_id, filter).await.unwrap().iter()
+                    .map(|s| serde_json::to_string(s).unwrap())
+                    .collect();
+                for size in &[1, 4, 5, 23, 50] {
+                    let (forwards, backwards) = walk_pages(&store, &scorable_id, filter, *size).await;
+                    assert_eq!(forwards, all, "{:?} forwards in pages of {}", filter, size);
+                    assert_eq!(backwards, all, "{:?} backwards in pages of {}", filter, size);
+                    let page = store.scores_page(&scorable_id, filter, &PageRequest { cursor: None, size: *size }).await.unwrap();
+                    assert_eq!(page.total, all.len());
+                    pages.push(serde_json::to_string(&page).unwrap());
+                }
+            }
+        }
+        // Both stores hand out the same pages, cursors and all:
+        let (event_log, sqlite) = pages.split_at(pages.len() / 2);
+        assert_eq!(event_log, sqlite);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn paging_through_the_biggest_scores() {
+        let values = [i64::MIN, i64::MIN + 1, -1, 0, i64::MAX - 1, i64::MAX];
+        for ranking in &[Ranking::HigherIsBetter, Ranking::LowerIsBetter] {
+            let scorable_id = ScorableId::new();
+            // Scores are listed earliest first when tied, so these would be out of order if they were:
+            let scores: Vec<_> = values.iter().enumerate().map(|(n, value)| (ScoreId::new(), *value, date(2021, 1, 1 + n as u32))).collect();
+            let mut pages = Vec::new();
+            for store in stores().await {
+                add_users(&store, &["alice"]).await;
+                add_scorable(&store, scorable_id, Some(*ranking)).await;
+                for (id, value, date) in &scores {
+                    store.upsert_score(*id, scorable_id, "alice".to_owned(), Vec::new(), *value, *date, None).await.unwrap();
+                }
+                let mut best_first = values.to_vec();
+                best_first.sort_by(|a, b| ranking.best_first(*a, *b));
+                let listed: Vec<_> = ranked(&store, &scorable_id, ScoreFilter::default()).await.into_iter().map(|(_, value)| value).collect();
+                assert_eq!(listed, best_first, "{:?}", ranking);
+                for size in &[1, 2] {
+                    let (forwards, backwards) = walk_pages(&store, &scorable_id, &ScoreFilter::default(), *size).await;
+                    assert_eq!(forwards, backwards);
+                    assert_eq!(forwards.len(), values.len());
+                    pages.push(forwards);
+                }
+            }
+            let (event_log, sqlite) = pages.split_at(pages.len() / 2);
+            assert_eq!(event_log, sqlite);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn deleting_and_restoring() {
+        for (backend, path) in paths() {
+            let store = BackendStore::load(backend, path.clone(), Durability::Buffered).await.unwrap();
+            add_users(&store, &["alice", "bob"]).await;
+            let scorable_id = ScorableId::new();
+            let group_id = add_scorable(&store, scorable_id, None).await;
+            let (alices, bobs) = (ScoreId::new(), ScoreId::new());
+            store.upsert_score(alices, scorable_id, "alice".to_owned(), Vec::new(), 3, Utc::now(), None).await.unwrap();
+            store.upsert_score(bobs, scorable_id, "bob".to_owned(), Vec::new(), 4, Utc::now(), None).await.unwrap();
+            assert!(matches!(store.restore_score(&alices, None).await, Err(StoreError::NotDeleted(_))));
+
+            store.delete_score(&alices, None).await.unwrap();
+            assert!(store.get_score(&alices).await.is_err());
+            assert!(store.delete_score(&alices, None).await.is_err());
+            assert_eq!(store.scores(&scorable_id, &ScoreFilter::default()).await.unwrap().len(), 1);
+            let trash = store.trash(None).await.unwrap();
+            assert_eq!(trash.len(), 1);
+            assert_eq!((trash[0].item, trash[0].group_id, trash[0].name.as_str()), (Item::Score(alices), group_id, "alice"));
+
+            // Deleting something hides everything in it, but only it goes in the trash:
+            store.delete_scorable(&scorable_id, None).await.unwrap();
+            assert!(store.get_score(&bobs).await.is_err());
+            assert!(store.scores(&scorable_id, &ScoreFilter::default()).await.is_err());
+            assert!(store.restore_score(&alices, None).await.is_err());
+            store.delete_group(&group_id, None).await.unwrap();
+            assert!(store.groups(None).await.unwrap().is_empty());
+            assert!(store.restore_scorable(&scorable_id, None).await.is_err());
+            assert_eq!(store.trash(Some(&group_id)).await.unwrap().len(), 3);
+            assert!(store.trash(Some(&GroupId::new())).await.unwrap().is_empty());
+            store.flush_to_disk().await.unwrap();
+            drop(store);
+
+            let store = BackendStore::load(backend, path, Durability::Buffered).await.unwrap();
+            assert_eq!(store.trash(None).await.unwrap()[0].item, Item::Group(group_id));
+            store.restore_group(&group_id, None).await.unwrap();
+            assert!(store.scorables_in_group(&group_id).await.unwrap().is_empty());
+            store.restore_scorable(&scorable_id, None).await.unwrap();
+            assert_eq!(ranked(&store, &scorable_id, ScoreFilter::default()).await, owned(&[("bob", 4)]));
+            store.restore_score(&alices, None).await.unwrap();
+            assert_eq!(ranked(&store, &scorable_id, ScoreFilter::default()).await, owned(&[("bob", 4), ("alice", 3)]));
+            assert!(store.trash(None).await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn compacting_purges_the_old_trash() {
+        for (backend, path) in paths() {
+            let store = BackendStore::load(backend, path.clone(), Durability::Buffered).await.unwrap();
+            add_users(&store, &["alice"]).await;
+            let scorable_id = ScorableId::new();
+            add_scorable(&store, scorable_id, None).await;
+            let deleted = ScoreId::new();
+            for value in 0..100 {
+                store.upsert_score(ScoreId::new(), scorable_id, "alice".to_owned(), Vec::new(), value, Utc::now(), None).await.unwrap();
+            }
+            store.upsert_score(deleted, scorable_id, "alice".to_owned(), Vec::new(), 100, Utc::now(), None).await.unwrap();
+            store.delete_score(&deleted, None).await.unwrap();
+
+            // Things are kept in the trash until they've been there for long enough:
+            store.compact(chrono::Duration::days(30)).await.unwrap();
+            assert_eq!(store.trash(None).await.unwrap().len(), 1);
+            store.compact(chrono::Duration::zero()).await.unwrap();
+            assert!(store.trash(None).await.unwrap().is_empty());
+            drop(store);
+
+            let store = BackendStore::load(backend, path, Durability::Buffered).await.unwrap();
+            assert!(store.trash(None).await.unwrap().is_empty());
+            assert!(matches!(store.restore_score(&deleted, None).await, Err(StoreError::ScoreNotFound(_))));
+            assert_eq!(store.scores(&scorable_id, &ScoreFilter::default()).await.unwrap().len(), 100);
+            if let BackendStore::Sqlite(sqlite) = &store {
+                // The rows are gone, and so is the space that they took up:
+                let conn = sqlite.lock();
+                let rows: i64 = conn.query_row("SELECT COUNT(*) FROM scores", params![], |row| row.get(0)).unwrap();
+                assert_eq!(rows, 100);
+                let free_pages: i64 = conn.query_row("PRAGMA freelist_count", params![], |row| row.get(0)).unwrap();
+                assert_eq!(free_pages, 0);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn migrating_from_the_first_schema() {
+        let path = std::env::temp_dir().join(format!("highscore-{}.sqlite", uuid::Uuid::new_v4()));
+        let (group_id, scorable_id) = (GroupId::new(), ScorableId::new());
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(MIGRATIONS[0]).unwrap();
+            conn.execute(
+                "INSERT INTO users (username, hashed_password) VALUES ('alice', ?1)",
+                params![HashedPassword::from_plain_password("pw").as_hash()]
+            ).unwrap();
+            conn.execute("INSERT INTO groups (id, name) VALUES (?1, 'Group')", params![group_id.to_string()]).unwrap();
+            conn.execute(
+                "INSERT INTO scorables (id, group_id, name) VALUES (?1, ?2, 'Scorable')",
+                params![scorable_id.to_string(), group_id.to_string()]
+            ).unwrap();
+            for value in &[5, 9] {
+                conn.execute(
+                    "INSERT INTO scores (id, scorable_id, username, value, date) VALUES (?1, ?2, 'alice', ?3, ?4)",
+                    params![ScoreId::new().to_string(), scorable_id.to_string(), value, date(2020, 1, 1).timestamp_nanos()]
+                ).unwrap();
+            }
+        }
+
+        let store = SqliteStore::load(path.clone(), Durability::Buffered).await.unwrap();
+        let version: i64 = store.lock().query_row("PRAGMA user_version", params![], |row| row.get(0)).unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+        assert!(store.check_user("alice", "pw").await.unwrap());
+        assert!(!store.is_admin("alice").await.unwrap());
+        let scorable = store.get_scorable(&scorable_id).await.unwrap();
+        assert_eq!((scorable.ranking, scorable.value_kind), (Ranking::HigherIsBetter, ValueKind::default()));
+        assert_eq!(store.seasons(&group_id).await.unwrap(), None);
+        // Old scores were set by their user alone:
+        let scores = store.scores(&scorable_id, &ScoreFilter::default()).await.unwrap();
+        assert_eq!(scores.iter().map(|s| s.value).collect::<Vec<_>>(), vec![9, 5]);
+        assert!(scores.iter().all(|s| s.teammates.is_empty()));
+        let best = store.scores(&scorable_id, &ScoreFilter { best_per_user: true, ..ScoreFilter::default() }).await.unwrap();
+        assert_eq!(best.len(), 1);
+
+        // Databases from newer versions than this are refused:
+        store.lock().execute_batch(&format!("PRAGMA user_version = {};", MIGRATIONS.len() + 1)).unwrap();
+        drop(store);
+        assert!(SqliteStore::load(path, Durability::Buffered).await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tokens_created_too_far_away_to_store() {
+        for store in stores().await {
+            add_users(&store, &["alice"]).await;
+            let created = date(3000, 1, 1);
+            let res = store.upsert_token(TokenId::new(), "alice".to_owned(), "Token".to_owned(), HashedToken::from_plain_secret("secret"), created, None).await;
+            assert!(matches!(res, Err(StoreError::DateOutOfRange(_))));
+            assert!(store.tokens("alice").await.unwrap().is_empty());
+        }
+    }
+}
@@ -1,7 +1,22 @@
 use crate::store_interface;
+use std::net::IpAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use cookie::Key;
+use crate::login_limiter::LoginLimiter;
+use tokio::sync::watch;
 
 pub struct State {
-    pub store: Box<dyn store_interface::Store + Send + Sync + 'static>,
-    pub static_files: Option<PathBuf>
+    pub store: Arc<dyn store_interface::Store + Send + Sync + 'static>,
+    pub static_files: Option<PathBuf>,
+    /// Session cookies encrypted with this key are still accepted.
+    pub previous_secret_key: Option<Key>,
+    /// Throttles failed attempts to log in.
+    pub login_limiter: LoginLimiter,
+    /// Requests from this address come via a reverse proxy, so its
+    /// `X-Real-IP` header can be trusted to say who they're from.
+    pub trusted_proxy: Option<IpAddr>,
+    /// Becomes true once we start shutting down, so that
+    /// long-lived responses know to finish up.
+    pub shutting_down: watch::Receiver<bool>
 }
\ No newline at end of file
@@ -2,48 +2,136 @@
 //! This will roughly correspond to the JSON API.
 use serde::{ Serialize, Deserialize };
 use uuid::Uuid;
-use std::{fmt, hash::Hash};
+use std::{fmt, hash::Hash, cmp::Ordering, collections::HashMap, sync::Arc};
 use chrono::prelude::{ DateTime, Utc };
+use tokio::sync::broadcast;
+use crate::changes::{ Change, RecordKind, Notification };
 
 #[async_trait::async_trait]
 pub trait Store {
+    // Anything that changes the store is given `by`, the user making the change (or
+    // nothing if it's made from the command line), so that it can be recorded in the history.
+
     /// When was the last change made?
     async fn last_changed(&self) -> DateTime<Utc>;
+    /// Receive each change made to the store from now on.
+    fn subscribe(&self) -> broadcast::Receiver<Notification>;
 
     /// List users
     async fn users(&self) -> Result<Vec<String>,StoreError>;
-    /// Add/update a user
-    async fn upsert_user(&self, username: String, password: HashedPassword) -> Result<(),StoreError>;
+    /// Does a user exist?
+    async fn user_exists(&self, username: &str) -> Result<bool,StoreError>;
+    /// Add/update a user. If `admin` isn't given, an existing user
+    /// stays as they were, and a new user isn't an admin.
+    async fn upsert_user(&self, username: String, password: HashedPassword, admin: Option<bool>, by: Option<&str>) -> Result<(),StoreError>;
     /// Check that a user exists with the password provided
     async fn check_user(&self, username: &str, password: &str) -> Result<bool,StoreError>;
-    /// Delete a user
-    async fn delete_user(&self, username: &str) -> Result<(),StoreError>;
+    /// Get the hash of a user's password (for instance, to copy the user elsewhere)
+    async fn hashed_password(&self, username: &str) -> Result<HashedPassword,StoreError>;
+    /// Is the user a server-wide admin?
+    async fn is_admin(&self, username: &str) -> Result<bool,StoreError>;
+    /// Delete a user, along with their scores. Scores shared with teammates are kept, minus the user
+    async fn delete_user(&self, username: &str, by: Option<&str>) -> Result<(),StoreError>;
+
+    /// Add/update an API token for a user
+    async fn upsert_token(&self, id: TokenId, username: String, name: String, hashed_secret: HashedToken, created: DateTime<Utc>, by: Option<&str>) -> Result<(),StoreError>;
+    /// List the API tokens belonging to a user
+    async fn tokens(&self, username: &str) -> Result<Vec<Token>,StoreError>;
+    /// Hand back the token with the ID provided if the secret matches it
+    async fn check_token(&self, id: &TokenId, secret: &str) -> Result<Option<Token>,StoreError>;
+    /// Revoke (delete) an API token
+    async fn delete_token(&self, id: &TokenId, by: Option<&str>) -> Result<(),StoreError>;
 
     /// Add/update a group
-    async fn upsert_group(&self, id: GroupId, name: String) -> Result<(),StoreError>;
-    /// Delete a group
-    async fn delete_group(&self, id: &GroupId) -> Result<(),StoreError>;
+    async fn upsert_group(&self, id: GroupId, name: String, by: Option<&str>) -> Result<(),StoreError>;
+    /// Delete a group (and everything in it). It can be restored until it's purged
+    async fn delete_group(&self, id: &GroupId, by: Option<&str>) -> Result<(),StoreError>;
     /// Get a group
     async fn get_group(&self, id: &GroupId) -> Result<Group,StoreError>;
 
-    /// Add/update a thing to save scores against
-    async fn upsert_scorable(&self, id: ScorableId, group_id: GroupId, name: String) -> Result<(),StoreError>;
-    /// Delete a scorable
-    async fn delete_scorable(&self, id: &ScorableId) -> Result<(),StoreError>;
+    /// Add a user to a group, or change the role that they have in it
+    async fn upsert_member(&self, group_id: GroupId, username: String, role: Role, by: Option<&str>) -> Result<(),StoreError>;
+    /// Remove a user from a group
+    async fn delete_member(&self, group_id: &GroupId, username: &str, by: Option<&str>) -> Result<(),StoreError>;
+    /// List the members of a group
+    async fn members(&self, group_id: &GroupId) -> Result<Vec<Member>,StoreError>;
+    /// The role that a user has in a group, if any. Groups without any members (for
+    /// instance, those created before groups had members) are open to everybody as owners.
+    /// This works for deleted groups too, so that we know who can restore them.
+    async fn role(&self, group_id: &GroupId, username: &str) -> Result<Option<Role>,StoreError>;
+
+    /// Add/update a thing to save scores against. If no ranking or value kind is given, an
+    /// existing scorable keeps what it had, and a new one ranks higher integer scores first.
+    async fn upsert_scorable(&self, id: ScorableId, group_id: GroupId, name: String, ranking: Option<Ranking>, value_kind: Option<ValueKind>, by: Option<&str>) -> Result<(),StoreError>;
+    /// Delete a scorable (and all scores against it). It can be restored until it's purged
+    async fn delete_scorable(&self, id: &ScorableId, by: Option<&str>) -> Result<(),StoreError>;
     /// Get a scorable
     async fn get_scorable(&self, id: &ScorableId) -> Result<Scorable,StoreError>;
 
-    /// Add/update a score against something
-    async fn upsert_score(&self, id: ScoreId, scorable_id: ScorableId, username: String, value: i64, date: DateTime<Utc>) -> Result<(),StoreError>;
-    /// Delete a score against something
-    async fn delete_score(&self, id: &ScoreId) -> Result<(),StoreError>;
+    /// Add/update a score against something, credited to a user along with any teammates
+    async fn upsert_score(&self, id: ScoreId, scorable_id: ScorableId, username: String, teammates: Vec<String>, value: i64, date: DateTime<Utc>, by: Option<&str>) -> Result<Records,StoreError>;
+    /// Delete a score against something. It can be restored until it's purged
+    async fn delete_score(&self, id: &ScoreId, by: Option<&str>) -> Result<(),StoreError>;
+    /// Get a score
+    async fn get_score(&self, id: &ScoreId) -> Result<Score,StoreError>;
+
+    /// Add/update a match played between users against something. Matches without a
+    /// winner were drawn. Unlike scores, deleted matches are gone for good
+    async fn upsert_match(&self, id: MatchId, scorable_id: ScorableId, participants: Vec<String>, winner: Option<String>, date: DateTime<Utc>, by: Option<&str>) -> Result<(),StoreError>;
+    /// Delete a match
+    async fn delete_match(&self, id: &MatchId, by: Option<&str>) -> Result<(),StoreError>;
+    /// Get a match
+    async fn get_match(&self, id: &MatchId) -> Result<Match,StoreError>;
+    /// Return the matches played against a scorable (newest first), only those
+    /// that the user given took part in if one is given
+    async fn matches(&self, scorable_id: &ScorableId, username: Option<&str>) -> Result<Vec<Match>,StoreError>;
+    /// Return the rating of everybody that has played a match against a scorable (highest first)
+    async fn ratings(&self, scorable_id: &ScorableId) -> Result<Vec<Rating>,StoreError>;
+    /// Return how a user's rating against a scorable changed with each match they played (oldest first)
+    async fn rating_history(&self, scorable_id: &ScorableId, username: &str) -> Result<Vec<RatingChange>,StoreError>;
 
-    /// Return a list of groups that we know about
-    async fn groups(&self) -> Result<Vec<Group>,StoreError>;
+    /// Set how a group's scores are split up into seasons, or stop splitting them up if given nothing
+    async fn set_seasons(&self, group_id: GroupId, seasons: Option<Seasons>, by: Option<&str>) -> Result<(),StoreError>;
+    /// Return how a group's scores are split up into seasons, if they are
+    async fn seasons(&self, group_id: &GroupId) -> Result<Option<Seasons>,StoreError>;
+    /// Keep the winners of a season that has finished, replacing any season of the same name
+    /// that was archived before. These are kept even if the season's scores change afterwards
+    async fn archive_season(&self, group_id: GroupId, season: ArchivedSeason, by: Option<&str>) -> Result<(),StoreError>;
+    /// Return the seasons that have been archived in a group (most recent first)
+    async fn season_archive(&self, group_id: &GroupId) -> Result<Vec<ArchivedSeason>,StoreError>;
+
+    /// Restore a deleted group, along with everything in it that wasn't deleted separately
+    async fn restore_group(&self, id: &GroupId, by: Option<&str>) -> Result<(),StoreError>;
+    /// Restore a deleted scorable. Its group must not be deleted
+    async fn restore_scorable(&self, id: &ScorableId, by: Option<&str>) -> Result<(),StoreError>;
+    /// Restore a deleted score. Its scorable must not be deleted
+    async fn restore_score(&self, id: &ScoreId, by: Option<&str>) -> Result<(),StoreError>;
+    /// Return everything that has been deleted but not yet purged (most recently
+    /// deleted first), only from the group given if one is given
+    async fn trash(&self, group_id: Option<&GroupId>) -> Result<Vec<Deleted>,StoreError>;
+
+    /// Return a list of groups that we know about (only those that the member
+    /// given has a role in, if one is given)
+    async fn groups(&self, member: Option<&str>) -> Result<Vec<Group>,StoreError>;
     /// Return a list of scorable things in a group
     async fn scorables_in_group(&self, group_id: &GroupId) -> Result<Vec<Scorable>,StoreError>;
-    /// Return a list of scores for a scorable thing (highest first, up to some limit)
-    async fn scores(&self, scorable_id: &ScorableId, limit: Option<usize>) -> Result<Vec<Score>,StoreError>;
+    /// Return a list of scores for a scorable thing (best first, with the earliest of equal
+    /// scores first), narrowed down by the filter given
+    async fn scores(&self, scorable_id: &ScorableId, filter: &ScoreFilter) -> Result<Vec<Score>,StoreError>;
+    /// Return a page of the groups that `groups` would
+    async fn groups_page(&self, member: Option<&str>, page: &PageRequest) -> Result<Page<Group>,StoreError>;
+    /// Return a page of the scorables that `scorables_in_group` would
+    async fn scorables_in_group_page(&self, group_id: &GroupId, page: &PageRequest) -> Result<Page<Scorable>,StoreError>;
+    /// Return a page of the scores that `scores` would. If the filter has a limit, only
+    /// that many of the best scores are paged through
+    async fn scores_page(&self, scorable_id: &ScorableId, filter: &ScoreFilter, page: &PageRequest) -> Result<Page<Score>,StoreError>;
+    /// Return aggregates over the scores that `scores` would return
+    async fn stats(&self, scorable_id: &ScorableId, filter: &ScoreFilter) -> Result<Stats,StoreError>;
+    /// Return the changes that have been made (newest first), narrowed down by the filter given
+    async fn history(&self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>,StoreError>;
+    /// Hand back a copy of the store as it was at some point in the past, to be read from.
+    /// Only stores that keep every change that's been made (ie event logs) can do this
+    async fn as_of(&self, as_of: AsOf) -> Result<Arc<dyn Store + Send + Sync>,StoreError>;
 
 }
 
@@ -57,6 +145,28 @@ pub enum StoreError {
     ScorableNotFound(ScorableId),
     #[error("score '{0}' not found")]
     ScoreNotFound(ScoreId),
+    #[error("match '{0}' not found")]
+    MatchNotFound(MatchId),
+    #[error("token '{0}' not found")]
+    TokenNotFound(TokenId),
+    #[error("invalid match: {0}")]
+    InvalidMatch(String),
+    #[error("invalid team: {0}")]
+    InvalidTeam(String),
+    #[error("the date {0} is out of range; only dates between the years 1678 and 2261 can be stored")]
+    DateOutOfRange(DateTime<Utc>),
+    #[error("invalid seasons: {0}")]
+    InvalidSeasons(String),
+    #[error("user '{0}' is not a member of group '{1}'")]
+    MemberNotFound(String, GroupId),
+    #[error("user '{0}' is the only owner of group '{1}', which must have another owner first")]
+    LastOwner(String, GroupId),
+    #[error("{0} is not in the trash")]
+    NotDeleted(Item),
+    #[error("only event log databases can be viewed as they were in the past")]
+    CannotReplay,
+    #[error("the database was compacted at {0}, so can't be viewed as it was before then")]
+    CompactedSince(DateTime<Utc>),
     #[error("internal error: {0}")]
     InternalError(anyhow::Error)
 }
@@ -79,16 +189,702 @@ pub struct Scorable {
     // Name comes first for Ord impl:
     pub name: String,
     pub id: ScorableId,
+    pub group_id: GroupId,
+    pub ranking: Ranking,
+    pub value_kind: ValueKind,
+}
+
+#[derive(Debug,Serialize,Clone,PartialEq,Eq)]
+pub struct Member {
+    pub username: String,
+    pub role: Role
+}
+
+/// Something that can be deleted and later restored.
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+#[serde(tag = "kind", content = "id", rename_all = "snake_case")]
+pub enum Item {
+    Group(GroupId),
+    Scorable(ScorableId),
+    Score(ScoreId)
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Item::Group(id) => write!(f, "group '{}'", id),
+            Item::Scorable(id) => write!(f, "scorable '{}'", id),
+            Item::Score(id) => write!(f, "score '{}'", id)
+        }
+    }
+}
+
+/// Something in the trash, waiting to be restored or purged.
+#[derive(Debug,Serialize,Clone,PartialEq,Eq)]
+pub struct Deleted {
+    pub item: Item,
+    /// The group that the item is (or lives in).
+    pub group_id: GroupId,
+    /// The name of the group or scorable, or the user that the score belongs to.
+    pub name: String,
+    pub deleted_at: DateTime<Utc>
+}
+
+/// A change that was made to the store, and who made it when. Changes from
+/// before history was recorded have neither a time nor a user, and changes
+/// made from the command line have no user.
+#[derive(Debug,Serialize,Deserialize,Clone)]
+pub struct HistoryEntry {
+    pub at: Option<DateTime<Utc>>,
+    pub by: Option<String>,
+    /// The group that the change was made in, if any.
+    pub group_id: Option<GroupId>,
+    /// The scorable that the change was made to (or to a score against), if any.
+    pub scorable_id: Option<ScorableId>,
+    pub change: Change
+}
+
+/// Which history entries are we interested in? Entries must match everything given.
+#[derive(Debug,Clone,Default)]
+pub struct HistoryFilter {
+    pub by: Option<String>,
+    pub group_id: Option<GroupId>,
+    pub scorable_id: Option<ScorableId>,
+    /// Hand back at most this many (of the newest) entries.
+    pub limit: Option<usize>
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        (self.by.is_none() || self.by == entry.by)
+            && (self.group_id.is_none() || self.group_id == entry.group_id)
+            && (self.scorable_id.is_none() || self.scorable_id == entry.scorable_id)
+    }
+}
+
+/// Which scores are we interested in? Scores must match everything given.
+#[derive(Debug,Clone,Default)]
+pub struct ScoreFilter {
+    /// Only scores on or after this date.
+    pub from: Option<DateTime<Utc>>,
+    /// Only scores before this date.
+    pub to: Option<DateTime<Utc>>,
+    /// Only scores credited to (any of) these users.
+    pub usernames: Option<Vec<String>>,
+    /// Only the best matching score that each user (or team, unless `by_member`) has.
+    pub best_per_user: bool,
+    /// Rank each member of a team separately rather than teams as a whole. Scores are
+    /// handed back once per member, with that member as the username and the rest of
+    /// the team as teammates.
+    pub by_member: bool,
+    /// Hand back at most this many (of the best) scores.
+    pub limit: Option<usize>
+}
+
+impl ScoreFilter {
+    pub fn matches(&self, score: &Score) -> bool {
+        self.from.map(|from| score.date >= from).unwrap_or(true)
+            && self.to.map(|to| score.date < to).unwrap_or(true)
+            && self.usernames.as_ref().map(|us| self.credited(score).iter().any(|c| us.contains(c))).unwrap_or(true)
+    }
+    /// Who a score is credited to: just its user if ranking by member,
+    /// and otherwise its whole team (in a consistent order).
+    pub fn credited(&self, score: &Score) -> Vec<String> {
+        if self.by_member {
+            vec![score.username.clone()]
+        } else {
+            Score::team_key(&score.username, &score.teammates)
+        }
+    }
+}
+
+/// The records that a score set when it was upserted.
+#[derive(Debug,Clone,PartialEq,Eq,Default)]
+pub struct Records {
+    /// Set if the score is now the best against its scorable.
+    pub top_score: Option<Record>,
+    /// Each user that the score is credited to (its user first) for whom it's
+    /// now the best that they have against its scorable.
+    pub personal_bests: Vec<PersonalBest>
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct PersonalBest {
+    pub username: String,
+    pub record: Record
+}
+
+/// A record that has been set.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Record {
+    /// Who held the record before, and with what. None if there were no scores to beat.
+    pub previous: Option<RecordHolder>
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct RecordHolder {
+    pub username: String,
+    pub value: i64
+}
+
+impl Records {
+    /// Work out which records a score sets, given the best scores (bar it) from before it
+    /// was upserted: the best of all, and the best of each user that it's credited to.
+    /// Scores have to be better than the previous best to set a record.
+    pub fn set_by(ranking: Ranking, value: i64, previous_best: Option<RecordHolder>, previous_personal_bests: Vec<(String, Option<i64>)>) -> Records {
+        let beats = |previous: i64| ranking.best_first(value, previous) == Ordering::Less;
+        let top_score = match previous_best {
+            Some(holder) if !beats(holder.value) => None,
+            previous => Some(Record { previous })
+        };
+        let personal_bests = previous_personal_bests.into_iter()
+            .filter(|(_,previous)| previous.map(beats).unwrap_or(true))
+            .map(|(username, previous)| PersonalBest {
+                record: Record { previous: previous.map(|value| RecordHolder { username: username.clone(), value }) },
+                username
+            })
+            .collect();
+        Records { top_score, personal_bests }
+    }
+    /// The changes to tell subscribers about, if the score set any records: a new top
+    /// score, or else a personal best for each user that set one.
+    pub fn changes(&self, id: ScoreId, scorable_id: ScorableId, username: &str) -> Vec<Change> {
+        let new_record = |username: &str, record| Change::NewRecord { id, scorable_id, username: username.to_owned(), record };
+        if self.top_score.is_some() {
+            vec![new_record(username, RecordKind::TopScore)]
+        } else {
+            self.personal_bests.iter()
+                .map(|pb| new_record(&pb.username, RecordKind::PersonalBest))
+                .collect()
+        }
+    }
+}
+
+/// The percentiles that are worked out in [`Stats`].
+pub const PERCENTILES: [u8; 4] = [10, 25, 75, 90];
+
+/// Aggregates over some scores. Values are as stored (so decimals are multiplied by
+/// 10^precision, and durations are in milliseconds), and everything bar the count is
+/// None if there are no scores.
+#[derive(Debug,Clone,PartialEq)]
+pub struct Stats {
+    pub count: usize,
+    pub best: Option<i64>,
+    pub worst: Option<i64>,
+    pub mean: Option<f64>,
+    pub median: Option<f64>,
+    /// The population standard deviation.
+    pub std_dev: Option<f64>,
+    /// Each of [`PERCENTILES`], and the value that that percent of values are below.
+    pub percentiles: Vec<(u8, f64)>,
+    /// How each user has done, best first.
+    pub users: Vec<UserStats>
+}
+
+#[derive(Debug,Clone,PartialEq)]
+pub struct UserStats {
+    pub username: String,
+    /// The rest of the team, if these are a team's stats.
+    pub teammates: Vec<String>,
+    pub best: i64,
+    pub mean: f64,
+    pub attempts: usize
+}
+
+impl Stats {
+    /// Work out the stats for some scores, given best first (as `Store::scores` hands them back
+    /// for the filter given). Users are each team, or each member if ranking by member.
+    pub fn from_scores(scores: &[Score], filter: &ScoreFilter) -> Stats {
+        let count = scores.len();
+        let mut values: Vec<i64> = scores.iter().map(|s| s.value).collect();
+        values.sort_unstable();
+        let mean = average(&values);
+        let std_dev = mean.map(|mean| {
+            let variance = values.iter().map(|&v| (v as f64 - mean).powi(2)).sum::<f64>() / count as f64;
+            variance.sqrt()
+        });
+
+        // Users come in the order of their best score, since scores are best first:
+        let mut users: Vec<(&Score, Vec<i64>)> = Vec::new();
+        let mut user_idxs = HashMap::new();
+        for score in scores {
+            let idx = *user_idxs.entry(filter.credited(score)).or_insert_with(|| {
+                users.push((score, Vec::new()));
+                users.len() - 1
+            });
+            users[idx].1.push(score.value);
+        }
+        let users = users.into_iter()
+            .map(|(score, values)| UserStats {
+                username: score.username.clone(),
+                teammates: if filter.by_member { Vec::new() } else { score.teammates.clone() },
+                best: values[0],
+                mean: average(&values).unwrap_or(0.0),
+                attempts: values.len()
+            })
+            .collect();
+
+        Stats {
+            count,
+            best: scores.first().map(|s| s.value),
+            worst: scores.last().map(|s| s.value),
+            mean,
+            median: percentile(&values, 50),
+            std_dev,
+            percentiles: PERCENTILES.iter()
+                .filter_map(|&p| percentile(&values, p).map(|v| (p, v)))
+                .collect(),
+            users
+        }
+    }
+}
+
+fn average(values: &[i64]) -> Option<f64> {
+    if values.is_empty() {
+        return None
+    }
+    Some(values.iter().map(|&v| v as f64).sum::<f64>() / values.len() as f64)
+}
+
+/// The value that some percent of the (sorted) values given are below,
+/// interpolating between the values either side of it.
+fn percentile(sorted: &[i64], percent: u8) -> Option<f64> {
+    if sorted.is_empty() {
+        return None
+    }
+    let rank = percent as f64 / 100.0 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (sorted[rank.floor() as usize] as f64, sorted[rank.ceil() as usize] as f64);
+    Some(lower + (upper - lower) * rank.fract())
+}
+
+/// Which page of some results do we want?
+#[derive(Debug,Clone)]
+pub struct PageRequest {
+    /// Where to carry on from (the first page if not given).
+    pub cursor: Option<Cursor>,
+    /// How many results to hand back at most.
+    pub size: usize
+}
+
+/// A page of results, along with how many there are in total and cursors to the
+/// pages either side of it. Cursors are None if there's nothing more in that
+/// direction (or if the page is empty, in which case start from the beginning).
+#[derive(Debug,Clone,Serialize)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+    pub prev: Option<Cursor>,
+    pub next: Option<Cursor>
+}
+
+impl<T> Page<T> {
+    /// Build a page from the items in it, given whether there's anything before or after them.
+    pub fn new(items: Vec<T>, total: usize, has_prev: bool, has_next: bool, key: impl Fn(&T) -> CursorKey) -> Page<T> {
+        let prev = items.first()
+            .filter(|_| has_prev)
+            .map(|first| Cursor { backwards: true, key: key(first) });
+        let next = items.last()
+            .filter(|_| has_next)
+            .map(|last| Cursor { backwards: false, key: key(last) });
+        Page { items, total, prev, next }
+    }
+    /// Take a page from everything there is to page through, given in the order of their keys.
+    pub fn from_all(all: Vec<T>, request: &PageRequest, key: impl Fn(&T) -> CursorKey) -> Page<T> {
+        let total = all.len();
+        let (start, end) = match &request.cursor {
+            None => (0, request.size.min(total)),
+            Some(Cursor { backwards: false, key: after }) => {
+                let start = all.iter().position(|item| &key(item) > after).unwrap_or(total);
+                (start, (start + request.size).min(total))
+            },
+            Some(Cursor { backwards: true, key: before }) => {
+                let end = all.iter().position(|item| &key(item) >= before).unwrap_or(total);
+                (end.saturating_sub(request.size), end)
+            }
+        };
+        let items = all.into_iter().skip(start).take(end - start).collect();
+        Page::new(items, total, start > 0, end < total, key)
+    }
+    /// Convert the items in the page, keeping the cursors to either side of it.
+    pub fn map<U>(self, f: impl FnMut(T) -> U) -> Page<U> {
+        Page { items: self.items.into_iter().map(f).collect(), total: self.total, prev: self.prev, next: self.next }
+    }
+}
+
+/// A position to carry on from in some results, which doesn't move when results
+/// are added or removed. These are handed out as opaque tokens.
+#[derive(Debug,Clone,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Cursor {
+    /// Do we want the results before this position rather than after it?
+    pub backwards: bool,
+    pub key: CursorKey
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = serde_json::to_vec(&(self.backwards, &self.key)).map_err(|_| fmt::Error)?;
+        f.write_str(&base64::encode_config(&json, base64::URL_SAFE_NO_PAD))
+    }
+}
+impl std::str::FromStr for Cursor {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = || -> anyhow::Result<Cursor> {
+            let json = base64::decode_config(s, base64::URL_SAFE_NO_PAD)?;
+            let (backwards, key) = serde_json::from_slice(&json)?;
+            Ok(Cursor { backwards, key })
+        };
+        parse().map_err(|_| anyhow::anyhow!("'{}' is not a valid page token", s))
+    }
+}
+impl From<Cursor> for String {
+    fn from(cursor: Cursor) -> String {
+        cursor.to_string()
+    }
+}
+impl std::convert::TryFrom<String> for Cursor {
+    type Error = anyhow::Error;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Where something is in the order that it's listed in.
+#[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord,Serialize,Deserialize)]
+pub enum CursorKey {
+    /// Scores are ordered best first, then earliest first, then by ID (and then by
+    /// username, since scores ranked by member are listed once per member). The value's
+    /// bits are flipped if higher is better, so that lower always comes first (unlike
+    /// negating it, this can't overflow, so every value keeps its place).
+    Score { order: i64, date: DateTime<Utc>, id: String, username: String },
+    /// Groups and scorables are ordered by name, then by ID.
+    Named { name: String, id: String }
+}
+
+impl CursorKey {
+    pub fn of_score(ranking: Ranking, score: &Score) -> CursorKey {
+        let order = match ranking {
+            Ranking::HigherIsBetter => !score.value,
+            Ranking::LowerIsBetter => score.value
+        };
+        CursorKey::Score { order, date: score.date, id: score.id.to_string(), username: score.username.clone() }
+    }
+    pub fn of_group(group: &Group) -> CursorKey {
+        CursorKey::Named { name: group.name.clone(), id: group.id.to_string() }
+    }
+    pub fn of_scorable(scorable: &Scorable) -> CursorKey {
+        CursorKey::Named { name: scorable.name.clone(), id: scorable.id.to_string() }
+    }
+}
+
+/// A point in the past to look at the store as of. Changes are replayed up until either
+/// a time, or an index into the changes made (so `Index(0)` is an empty store). Neither
+/// can be from before the last time that the changes were compacted.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+#[serde(untagged)]
+pub enum AsOf {
+    Index(usize),
+    Time(DateTime<Utc>)
 }
 
+impl AsOf {
+    /// Does this point come after the change at `index`, made at `at`? Changes that don't
+    /// say when they were made come from before we kept track, so are assumed to.
+    pub fn includes(&self, index: usize, at: Option<DateTime<Utc>>) -> bool {
+        match (*self, at) {
+            (AsOf::Index(n), _) => index < n,
+            (AsOf::Time(t), Some(at)) => at <= t,
+            (AsOf::Time(_), None) => true
+        }
+    }
+}
+
+impl std::str::FromStr for AsOf {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(index) = s.parse() {
+            return Ok(AsOf::Index(index))
+        }
+        match DateTime::parse_from_rfc3339(s) {
+            Ok(time) => Ok(AsOf::Time(time.with_timezone(&Utc))),
+            Err(_) => anyhow::bail!("'{}' is not a valid point in time; expected an RFC 3339 date or an event index", s)
+        }
+    }
+}
+
+/// What can a member of a group do? Each role can do
+/// everything that the roles before it can.
+#[derive(Serialize,Deserialize,PartialEq,Eq,PartialOrd,Ord,Debug,Clone,Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// See the group and everything in it.
+    Viewer,
+    /// Add, edit and remove scorables and scores.
+    Editor,
+    /// Rename or delete the group, and manage its members.
+    Owner
+}
+
+/// Which scores are considered the best for a scorable?
+#[derive(Serialize,Deserialize,PartialEq,Eq,PartialOrd,Ord,Debug,Clone,Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Ranking {
+    HigherIsBetter,
+    LowerIsBetter
+}
+
+impl Default for Ranking {
+    fn default() -> Ranking {
+        Ranking::HigherIsBetter
+    }
+}
+
+impl Ranking {
+    /// Compare two score values such that the better one is ordered first.
+    pub fn best_first(&self, a: i64, b: i64) -> Ordering {
+        match self {
+            Ranking::HigherIsBetter => b.cmp(&a),
+            Ranking::LowerIsBetter => a.cmp(&b)
+        }
+    }
+}
+
+/// What do the (integer) values of scores against a scorable represent? Changing
+/// this doesn't convert any scores that have already been stored.
+#[derive(Serialize,Deserialize,PartialEq,Eq,PartialOrd,Ord,Debug,Clone)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ValueKind {
+    /// Whole numbers, optionally of some unit (eg "points").
+    Integer {
+        #[serde(default)]
+        unit: Option<String>
+    },
+    /// Fixed point decimals, stored multiplied by 10^precision.
+    Decimal {
+        precision: u8,
+        #[serde(default)]
+        unit: Option<String>
+    },
+    /// Durations, stored as milliseconds and displayed to
+    /// `precision` decimal places of seconds.
+    Duration {
+        precision: u8
+    }
+}
+
+impl Default for ValueKind {
+    fn default() -> ValueKind {
+        ValueKind::Integer { unit: None }
+    }
+}
+
+/// The most users that a score can be credited to.
+pub const MAX_TEAM_SIZE: usize = 4;
+
 #[derive(Debug,Serialize,Clone)]
 pub struct Score {
     pub id: ScoreId,
+    pub scorable_id: ScorableId,
     pub username: String,
+    /// Anybody else credited with the score, for team scores.
+    pub teammates: Vec<String>,
     pub value: i64,
     pub date: DateTime<Utc>
 }
 
+impl Score {
+    /// Check that a team makes sense: that it isn't too big, and that nobody is in it twice.
+    pub fn check_team(username: &str, teammates: &[String]) -> Result<(),StoreError> {
+        if teammates.len() + 1 > MAX_TEAM_SIZE {
+            return Err(StoreError::InvalidTeam(format!("a score can be credited to at most {} users", MAX_TEAM_SIZE)))
+        }
+        for (idx, teammate) in teammates.iter().enumerate() {
+            if teammate == username || teammates[..idx].contains(teammate) {
+                return Err(StoreError::InvalidTeam(format!("'{}' is in the team more than once", teammate)))
+            }
+        }
+        Ok(())
+    }
+    /// Everybody credited with a score, sorted so that the same team always looks the same.
+    pub fn team_key(username: &str, teammates: &[String]) -> Vec<String> {
+        let mut team: Vec<String> = std::iter::once(username.to_owned()).chain(teammates.iter().cloned()).collect();
+        team.sort();
+        team
+    }
+    /// The score once for each member of its team, as each member's own score.
+    pub fn per_member(self) -> Vec<Score> {
+        let team: Vec<String> = std::iter::once(self.username.clone()).chain(self.teammates.iter().cloned()).collect();
+        team.iter()
+            .map(|member| Score {
+                username: member.clone(),
+                teammates: team.iter().filter(|t| *t != member).cloned().collect(),
+                ..self.clone()
+            })
+            .collect()
+    }
+}
+
+/// A match played between two or more users. Matches without a winner were drawn.
+#[derive(Debug,Serialize,Clone,PartialEq,Eq)]
+pub struct Match {
+    pub id: MatchId,
+    pub scorable_id: ScorableId,
+    pub participants: Vec<String>,
+    pub winner: Option<String>,
+    pub date: DateTime<Utc>
+}
+
+impl Match {
+    /// Check that a match makes sense: that there are at least two distinct
+    /// participants, and that the winner (if any) is one of them.
+    pub fn check(participants: &[String], winner: Option<&str>) -> Result<(),StoreError> {
+        if participants.len() < 2 {
+            return Err(StoreError::InvalidMatch("a match needs at least two participants".to_owned()))
+        }
+        for (idx, username) in participants.iter().enumerate() {
+            if participants[..idx].contains(username) {
+                return Err(StoreError::InvalidMatch(format!("'{}' is in the match more than once", username)))
+            }
+        }
+        match winner {
+            Some(winner) if !participants.iter().any(|p| p == winner) => {
+                Err(StoreError::InvalidMatch(format!("the winner '{}' didn't take part in the match", winner)))
+            },
+            _ => Ok(())
+        }
+    }
+}
+
+/// A user's rating against a scorable, worked out from the matches that they've played.
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct Rating {
+    pub username: String,
+    pub rating: f64,
+    pub wins: usize,
+    pub draws: usize,
+    pub losses: usize
+}
+
+/// How a user's rating changed as a result of a match.
+#[derive(Debug,Serialize,Clone,PartialEq)]
+pub struct RatingChange {
+    pub match_id: MatchId,
+    pub date: DateTime<Utc>,
+    /// The rating after the match.
+    pub rating: f64,
+    /// How much the rating went up (or down) by.
+    pub change: f64
+}
+
+/// How a group's scores are split up into seasons, by their date.
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq,Eq)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Seasons {
+    /// A season every period (in UTC), starting with the one that `from` falls in.
+    Recurring { period: SeasonPeriod, from: DateTime<Utc> },
+    /// Seasons with names and dates of their own.
+    Named { seasons: Vec<Season> }
+}
+
+/// The most seasons that a group can have. Only this many of the latest recurring
+/// seasons are listed (and archived).
+pub const MAX_SEASONS: usize = 1000;
+
+impl Seasons {
+    /// Check that seasons make sense: that recurring seasons have already started, that
+    /// named seasons have names, end after they start, and don't overlap (so that every
+    /// score is in at most one of them), and that all of their dates can be stored.
+    pub fn check(&self, now: DateTime<Utc>) -> Result<(),StoreError> {
+        let seasons = match self {
+            Seasons::Recurring { period, from } => {
+                if *from > now {
+                    return Err(StoreError::InvalidSeasons("recurring seasons can't start in the future".to_owned()))
+                }
+                // The first season may start a little before `from`:
+                let first = period.season_of(*from).ok_or(StoreError::DateOutOfRange(*from))?;
+                date_to_nanos(first.start)?;
+                return Ok(())
+            },
+            Seasons::Named { seasons } => seasons
+        };
+        if seasons.len() > MAX_SEASONS {
+            return Err(StoreError::InvalidSeasons(format!("there can be at most {} seasons", MAX_SEASONS)))
+        }
+        for (idx, season) in seasons.iter().enumerate() {
+            if season.name.trim().is_empty() {
+                return Err(StoreError::InvalidSeasons("every season needs a name".to_owned()))
+            }
+            date_to_nanos(season.start)?;
+            date_to_nanos(season.end)?;
+            if season.end <= season.start {
+                return Err(StoreError::InvalidSeasons(format!("season '{}' ends before it starts", season.name)))
+            }
+            for other in &seasons[..idx] {
+                if other.name == season.name {
+                    return Err(StoreError::InvalidSeasons(format!("there is more than one season called '{}'", season.name)))
+                }
+                if other.start < season.end && season.start < other.end {
+                    return Err(StoreError::InvalidSeasons(format!("seasons '{}' and '{}' overlap", other.name, season.name)))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How often recurring seasons come around.
+#[derive(Debug,Serialize,Deserialize,Clone,Copy,PartialEq,Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SeasonPeriod {
+    /// Monday to Sunday, named like "2021-W09".
+    Weekly,
+    /// Named like "2021-03".
+    Monthly,
+    /// Named like "2021-Q1".
+    Quarterly,
+    /// Named like "2021".
+    Yearly
+}
+
+/// A season, which scores are in from `start` up to (but not including) `end`.
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq,Eq)]
+pub struct Season {
+    pub name: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>
+}
+
+/// The winners of a season that has finished, as they were when it was archived.
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq,Eq)]
+pub struct ArchivedSeason {
+    pub name: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub archived: DateTime<Utc>,
+    /// The best score against each scorable in the group that had any scores in the season.
+    pub winners: Vec<SeasonWinner>
+}
+
+#[derive(Debug,Serialize,Deserialize,Clone,PartialEq,Eq)]
+pub struct SeasonWinner {
+    pub scorable_id: ScorableId,
+    pub username: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub teammates: Vec<String>,
+    pub value: i64
+}
+
+/// An API token. The secret needed to use it is only
+/// known to whoever it was handed to when created.
+#[derive(Debug,Serialize,Clone)]
+pub struct Token {
+    pub id: TokenId,
+    pub username: String,
+    pub name: String,
+    pub created: DateTime<Utc>
+}
+
 #[derive(Serialize,Deserialize,Hash,PartialEq,Eq,PartialOrd,Ord,Debug,Clone,Copy)]
 pub struct GroupId(Uuid);
 
@@ -102,6 +898,12 @@ impl fmt::Display for GroupId {
         self.0.fmt(f)
     }
 }
+impl std::str::FromStr for GroupId {
+    type Err = uuid::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(GroupId(s.parse()?))
+    }
+}
 
 #[derive(Serialize,Deserialize,Hash,PartialEq,Eq,PartialOrd,Ord,Debug,Clone,Copy)]
 pub struct ScorableId(Uuid);
@@ -116,6 +918,12 @@ impl fmt::Display for ScorableId {
         self.0.fmt(f)
     }
 }
+impl std::str::FromStr for ScorableId {
+    type Err = uuid::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ScorableId(s.parse()?))
+    }
+}
 
 #[derive(Serialize,Deserialize,Hash,PartialEq,Eq,PartialOrd,Ord,Debug,Clone,Copy)]
 pub struct ScoreId(Uuid);
@@ -130,15 +938,361 @@ impl fmt::Display for ScoreId {
         self.0.fmt(f)
     }
 }
+impl std::str::FromStr for ScoreId {
+    type Err = uuid::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(ScoreId(s.parse()?))
+    }
+}
+
+#[derive(Serialize,Deserialize,Hash,PartialEq,Eq,PartialOrd,Ord,Debug,Clone,Copy)]
+pub struct MatchId(Uuid);
+
+impl MatchId {
+    pub fn new() -> MatchId {
+        MatchId(Uuid::new_v4())
+    }
+}
+impl fmt::Display for MatchId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl std::str::FromStr for MatchId {
+    type Err = uuid::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(MatchId(s.parse()?))
+    }
+}
+
+#[derive(Serialize,Deserialize,Hash,PartialEq,Eq,PartialOrd,Ord,Debug,Clone,Copy)]
+pub struct TokenId(Uuid);
+
+impl TokenId {
+    pub fn new() -> TokenId {
+        TokenId(Uuid::new_v4())
+    }
+}
+impl fmt::Display for TokenId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl std::str::FromStr for TokenId {
+    type Err = uuid::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(TokenId(s.parse()?))
+    }
+}
 
 #[derive(Serialize,Deserialize,PartialEq,Eq,Debug,Clone)]
 pub struct HashedPassword(String);
 
 impl HashedPassword {
+    /// Wrap a string that is already a password hash (for instance, one read back from a database).
+    pub fn from_hash(hash: String) -> HashedPassword {
+        HashedPassword(hash)
+    }
+    pub fn as_hash(&self) -> &str {
+        &self.0
+    }
     pub fn from_plain_password(plain: &str) -> HashedPassword {
         HashedPassword(crate::password::hash(plain))
     }
+    /// The hash of a random password that nobody knows, for users created on somebody's
+    /// behalf. They can't log in until a password is set with `highscore users add`.
+    pub fn random() -> HashedPassword {
+        HashedPassword::from_plain_password(&crate::tokens::generate_secret())
+    }
     pub fn verify_plain_password(&self, plain: &str) -> bool {
         crate::password::verify(&self.0, plain)
     }
-}
\ No newline at end of file
+}
+
+#[derive(Serialize,Deserialize,PartialEq,Eq,Debug,Clone)]
+pub struct HashedToken(String);
+
+impl HashedToken {
+    /// Wrap a string that is already a token hash (for instance, one read back from a database).
+    pub fn from_hash(hash: String) -> HashedToken {
+        HashedToken(hash)
+    }
+    pub fn as_hash(&self) -> &str {
+        &self.0
+    }
+    pub fn from_plain_secret(plain: &str) -> HashedToken {
+        HashedToken(crate::tokens::hash(plain))
+    }
+    pub fn verify_plain_secret(&self, plain: &str) -> bool {
+        crate::tokens::verify(&self.0, plain)
+    }
+}
+
+/// Dates are kept as nanoseconds since 1970, so only those between about 1677 and 2262 can
+/// be stored. Hand back the nanoseconds for a date, or complain if it's out of range.
+pub fn date_to_nanos(date: DateTime<Utc>) -> Result<i64,StoreError> {
+    date.timestamp()
+        .checked_mul(1_000_000_000)
+        .and_then(|nanos| nanos.checked_add(date.timestamp_subsec_nanos() as i64))
+        .ok_or(StoreError::DateOutOfRange(date))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn score(value: i64, day: u32, username: &str) -> Score {
+        Score {
+            id: ScoreId::new(),
+            scorable_id: ScorableId::new(),
+            username: username.to_owned(),
+            teammates: Vec::new(),
+            value,
+            date: Utc.ymd(2021, 1, day).and_hms(0, 0, 0)
+        }
+    }
+
+    /// Scores in the order that they're listed in.
+    fn ranked(ranking: Ranking, mut scores: Vec<Score>) -> Vec<Score> {
+        scores.sort_by_key(|s| CursorKey::of_score(ranking, s));
+        scores
+    }
+
+    fn page(scores: &[Score], cursor: Option<Cursor>, size: usize) -> Page<Score> {
+        Page::from_all(scores.to_vec(), &PageRequest { cursor, size }, |s| CursorKey::of_score(Ranking::HigherIsBetter, s))
+    }
+
+    fn ids(scores: &[Score]) -> Vec<ScoreId> {
+        scores.iter().map(|s| s.id).collect()
+    }
+
+    #[test]
+    fn paging_forwards_and_backwards_through_ties() {
+        // Ties on value, then on date too, then on ID too (when ranking by member):
+        let mut scores = Vec::new();
+        for n in 0..20 {
+            let s = score(n % 3, 1 + n as u32 % 2, "alice");
+            if n % 4 == 0 {
+                scores.push(Score { username: "bob".to_owned(), ..s.clone() });
+            }
+            scores.push(s);
+        }
+        let all = ranked(Ranking::HigherIsBetter, scores);
+
+        for size in 1..=all.len() + 1 {
+            let mut forwards = Vec::new();
+            let mut current = page(&all, None, size);
+            assert_eq!(current.prev, None);
+            loop {
+                assert_eq!(current.total, all.len());
+                forwards.extend(ids(&current.items));
+                match current.next.clone() {
+                    Some(next) => current = page(&all, Some(next.to_string().parse().unwrap()), size),
+                    None => break
+                }
+            }
+            assert_eq!(forwards, ids(&all), "forwards in pages of {}", size);
+
+            let mut backwards = ids(&current.items);
+            while let Some(prev) = current.prev.clone() {
+                current = page(&all, Some(prev), size);
+                assert_eq!(current.items.len(), size);
+                backwards.splice(0..0, ids(&current.items));
+            }
+            assert_eq!(backwards, ids(&all), "backwards in pages of {}", size);
+        }
+    }
+
+    #[test]
+    fn cursors_stay_put_when_scores_change() {
+        let mut all = ranked(Ranking::HigherIsBetter, (0..10).map(|n| score(n, 1, "alice")).collect());
+        let first = page(&all, None, 3);
+        let next = first.next.clone().unwrap();
+        let prev = page(&all, Some(next.clone()), 3).prev.unwrap();
+
+        // Remove the last score on the first page, and add a better score than any:
+        all.remove(2);
+        all.insert(0, score(100, 1, "bob"));
+        let second = page(&all, Some(next), 3);
+        assert_eq!(second.total, 10);
+        assert_eq!(ids(&second.items), ids(&all[3..6]));
+        assert!(second.prev.is_some());
+        // Going back again picks up whatever is now before the cursor:
+        let back = page(&all, Some(prev), 3);
+        assert_eq!(ids(&back.items), ids(&all[0..3]));
+        assert_eq!(back.prev, None);
+
+        // Cursors past the end find nothing there:
+        let last = Cursor { backwards: false, key: CursorKey::of_score(Ranking::HigherIsBetter, all.last().unwrap()) };
+        let end = page(&all, Some(last), 3);
+        assert!(end.items.is_empty());
+        assert_eq!((end.prev, end.next), (None, None));
+    }
+
+    #[test]
+    fn scores_are_ordered_right_up_to_the_biggest_values() {
+        let values = [i64::MIN, i64::MIN + 1, -1, 0, 1, i64::MAX - 1, i64::MAX];
+        for ranking in &[Ranking::HigherIsBetter, Ranking::LowerIsBetter] {
+            // If values ever compared equal, the earlier (and lower) of them would be listed first:
+            let scores: Vec<_> = values.iter().enumerate().map(|(n, v)| score(*v, 1 + n as u32, "alice")).collect();
+            let mut best_first: Vec<_> = scores.iter().map(|s| s.value).collect();
+            best_first.sort_by(|a, b| ranking.best_first(*a, *b));
+            let listed: Vec<_> = ranked(*ranking, scores).iter().map(|s| s.value).collect();
+            assert_eq!(listed, best_first, "{:?}", ranking);
+        }
+    }
+
+    #[test]
+    fn cursors_are_opaque_tokens() {
+        let cursor = Cursor { backwards: true, key: CursorKey::of_score(Ranking::LowerIsBetter, &score(i64::MIN, 1, "alice")) };
+        let token = cursor.to_string();
+        assert!(token.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+        assert_eq!(token.parse::<Cursor>().unwrap(), cursor);
+        assert!("nope".parse::<Cursor>().is_err());
+    }
+
+    /// Stats for some values, each scored by whoever is given, best first.
+    fn stats(ranking: Ranking, scores: &[(i64, &str, &[&str])], filter: &ScoreFilter) -> Stats {
+        let scores: Vec<_> = scores.iter()
+            .map(|(value, username, teammates)| Score {
+                teammates: teammates.iter().map(|t| t.to_string()).collect(),
+                ..score(*value, 1, username)
+            })
+            .flat_map(|s| if filter.by_member { s.per_member() } else { vec![s] })
+            .collect();
+        let scores = ranked(ranking, scores);
+        Stats::from_scores(&scores, filter)
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.unwrap();
+        assert!((actual - expected).abs() < 1e-9, "{} isn't {}", actual, expected);
+    }
+
+    #[test]
+    fn stats_of_no_scores() {
+        let stats = stats(Ranking::HigherIsBetter, &[], &ScoreFilter::default());
+        assert_eq!(stats, Stats {
+            count: 0, best: None, worst: None, mean: None, median: None, std_dev: None,
+            percentiles: Vec::new(), users: Vec::new()
+        });
+    }
+
+    #[test]
+    fn stats_of_a_single_score() {
+        let stats = stats(Ranking::HigherIsBetter, &[(7, "alice", &[])], &ScoreFilter::default());
+        assert_eq!((stats.count, stats.best, stats.worst), (1, Some(7), Some(7)));
+        assert_eq!((stats.mean, stats.median, stats.std_dev), (Some(7.0), Some(7.0), Some(0.0)));
+        assert_eq!(stats.percentiles, PERCENTILES.iter().map(|p| (*p, 7.0)).collect::<Vec<_>>());
+        assert_eq!(stats.users, vec![UserStats { username: "alice".to_owned(), teammates: Vec::new(), best: 7, mean: 7.0, attempts: 1 }]);
+    }
+
+    #[test]
+    fn stats_of_even_and_odd_numbers_of_scores() {
+        let even = stats(Ranking::HigherIsBetter, &[(1, "alice", &[]), (4, "alice", &[]), (2, "bob", &[]), (3, "bob", &[])], &ScoreFilter::default());
+        assert_eq!((even.count, even.best, even.worst), (4, Some(4), Some(1)));
+        assert_close(even.mean, 2.5);
+        assert_close(even.median, 2.5);
+        assert_close(even.std_dev, 1.25f64.sqrt());
+        let percentiles: Vec<_> = even.percentiles.iter().map(|(p, _)| *p).collect();
+        assert_eq!(percentiles, PERCENTILES.to_vec());
+        // Percentiles land between values, and are interpolated:
+        for ((_, actual), expected) in even.percentiles.iter().zip(&[1.3, 1.75, 3.25, 3.7]) {
+            assert_close(Some(*actual), *expected);
+        }
+
+        let odd = stats(Ranking::HigherIsBetter, &[(5, "alice", &[]), (1, "alice", &[]), (4, "bob", &[]), (2, "bob", &[]), (3, "bob", &[])], &ScoreFilter::default());
+        assert_eq!((odd.count, odd.best, odd.worst), (5, Some(5), Some(1)));
+        assert_close(odd.mean, 3.0);
+        assert_close(odd.median, 3.0);
+        assert_close(odd.std_dev, 2f64.sqrt());
+        assert_eq!(odd.percentiles[1..3], [(25, 2.0), (75, 4.0)]);
+        let users: Vec<_> = odd.users.iter().map(|u| (u.username.as_str(), u.best, u.mean, u.attempts)).collect();
+        assert_eq!(users, vec![("alice", 5, 3.0, 2), ("bob", 4, 3.0, 3)]);
+    }
+
+    #[test]
+    fn stats_where_lower_is_better() {
+        let stats = stats(Ranking::LowerIsBetter, &[(8, "alice", &[]), (3, "bob", &[]), (1, "alice", &[]), (9, "bob", &[])], &ScoreFilter::default());
+        assert_eq!((stats.best, stats.worst), (Some(1), Some(9)));
+        // Percentiles are still of the values, whichever way they're ranked:
+        assert_eq!(stats.percentiles[0].0, 10);
+        assert_close(Some(stats.percentiles[0].1), 1.6);
+        let users: Vec<_> = stats.users.iter().map(|u| (u.username.as_str(), u.best, u.attempts)).collect();
+        assert_eq!(users, vec![("alice", 1, 2), ("bob", 3, 2)]);
+    }
+
+    #[test]
+    fn stats_of_teams_and_of_their_members() {
+        let scores: &[(i64, &str, &[&str])] = &[(10, "alice", &["bob"]), (8, "bob", &["alice"]), (6, "alice", &[]), (4, "carol", &["bob"])];
+
+        // Teams are the same whoever in them scored, and each team is a user:
+        let by_team = stats(Ranking::HigherIsBetter, scores, &ScoreFilter::default());
+        assert_eq!(by_team.count, 4);
+        let users: Vec<_> = by_team.users.iter().map(|u| (u.username.as_str(), u.teammates.clone(), u.best, u.attempts)).collect();
+        assert_eq!(users, vec![
+            ("alice", vec!["bob".to_owned()], 10, 2),
+            ("alice", Vec::new(), 6, 1),
+            ("carol", vec!["bob".to_owned()], 4, 1)
+        ]);
+
+        // Each member is credited with every score their team made:
+        let by_member = stats(Ranking::HigherIsBetter, scores, &ScoreFilter { by_member: true, ..ScoreFilter::default() });
+        assert_eq!((by_member.count, by_member.best, by_member.worst), (7, Some(10), Some(4)));
+        let users: Vec<_> = by_member.users.iter().map(|u| (u.username.as_str(), u.best, u.mean, u.attempts)).collect();
+        assert_eq!(users, vec![("alice", 10, 8.0, 3), ("bob", 10, 22.0 / 3.0, 3), ("carol", 4, 4.0, 1)]);
+        assert!(by_member.users.iter().all(|u| u.teammates.is_empty()));
+    }
+
+    fn holder(username: &str, value: i64) -> Option<RecordHolder> {
+        Some(RecordHolder { username: username.to_owned(), value })
+    }
+
+    #[test]
+    fn records_need_beating() {
+        // The first score sets every record there is:
+        let records = Records::set_by(Ranking::HigherIsBetter, 10, None, vec![("alice".to_owned(), None)]);
+        assert_eq!(records, Records {
+            top_score: Some(Record { previous: None }),
+            personal_bests: vec![PersonalBest { username: "alice".to_owned(), record: Record { previous: None } }]
+        });
+
+        // Equalling a record doesn't set it:
+        let records = Records::set_by(Ranking::HigherIsBetter, 10, holder("bob", 10), vec![("alice".to_owned(), Some(10))]);
+        assert_eq!(records, Records::default());
+
+        // Which records are beaten depends on which way scores are ranked:
+        let records = Records::set_by(Ranking::LowerIsBetter, 9, holder("bob", 8), vec![("alice".to_owned(), Some(12))]);
+        assert_eq!(records.top_score, None);
+        assert_eq!(records.personal_bests, vec![PersonalBest { username: "alice".to_owned(), record: Record { previous: holder("alice", 12) } }]);
+        let records = Records::set_by(Ranking::LowerIsBetter, 7, holder("bob", 8), vec![("alice".to_owned(), Some(12))]);
+        assert_eq!(records.top_score, Some(Record { previous: holder("bob", 8) }));
+    }
+
+    #[test]
+    fn team_scores_can_be_personal_bests_for_some_of_the_team() {
+        let previous = vec![("alice".to_owned(), Some(12)), ("bob".to_owned(), None), ("carol".to_owned(), Some(5))];
+        let records = Records::set_by(Ranking::HigherIsBetter, 10, holder("alice", 12), previous);
+        assert_eq!(records.top_score, None);
+        assert_eq!(records.personal_bests, vec![
+            PersonalBest { username: "bob".to_owned(), record: Record { previous: None } },
+            PersonalBest { username: "carol".to_owned(), record: Record { previous: holder("carol", 5) } }
+        ]);
+
+        // Subscribers hear about each personal best, or just the top score if one was set:
+        let (id, scorable_id) = (ScoreId::new(), ScorableId::new());
+        let kinds = |records: &Records| -> Vec<(String, RecordKind)> {
+            records.changes(id, scorable_id, "alice").into_iter()
+                .map(|change| match change {
+                    Change::NewRecord { username, record, .. } => (username, record),
+                    other => panic!("unexpected change {:?}", other)
+                })
+                .collect()
+        };
+        assert_eq!(kinds(&records), vec![("bob".to_owned(), RecordKind::PersonalBest), ("carol".to_owned(), RecordKind::PersonalBest)]);
+        let records = Records { top_score: Some(Record { previous: holder("alice", 12) }), ..records };
+        assert_eq!(kinds(&records), vec![("alice".to_owned(), RecordKind::TopScore)]);
+        assert!(kinds(&Records::default()).is_empty());
+    }
+}
@@ -0,0 +1,45 @@
+//! A couple of helper functions to deal with API tokens. Tokens look like
+//! `<token id>.<secret>`; the ID lets us find the token, and the secret
+//! proves that whoever is using it was handed it.
+
+use sha2::{ Digest, Sha256 };
+use crate::store_interface::TokenId;
+
+/// Generate a new random secret for a token.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    use rand::RngCore;
+    rand::thread_rng().fill_bytes(&mut bytes);
+    to_hex(&bytes)
+}
+
+/// Put together the token that is handed to a user.
+pub fn to_token(id: &TokenId, secret: &str) -> String {
+    format!("{}.{}", id, secret)
+}
+
+/// Split a token back into its ID and secret.
+pub fn parse_token(token: &str) -> Option<(TokenId, &str)> {
+    let mut parts = token.splitn(2, '.');
+    let id = parts.next()?.parse().ok()?;
+    let secret = parts.next()?;
+    Some((id, secret))
+}
+
+/// Hash a token secret. Secrets are long and random, so unlike passwords they don't
+/// need a slow, salted hash, which keeps checking one on every request cheap.
+pub fn hash(secret: &str) -> String {
+    to_hex(&Sha256::digest(secret.as_bytes()))
+}
+
+/// Verify that a secret equals a hashed version. This takes the same
+/// time regardless of where the hashes differ.
+pub fn verify(hashed_secret: &str, secret: &str) -> bool {
+    let hashed = hash(secret);
+    hashed.len() == hashed_secret.len()
+        && hashed.bytes().zip(hashed_secret.bytes()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -1,5 +1,7 @@
 use rocket::request::{self, Request, FromRequest};
 use rocket::http::{CookieJar, Cookie, Status};
+use rocket::State;
+use crate::{ state, tokens };
 
 static COOKIE_NAME: &str = "username";
 
@@ -9,16 +11,31 @@ pub struct User {
     pub name: String
 }
 
-// This allows the thing to be asked for in a rocket request.
-// If the user is not loogged in, it'll return an Unauthorized status.
+// This allows the thing to be asked for in a rocket request. Users are either
+// logged in (and so have a cookie, possibly encrypted with a previous secret
+// key), or provide an API token in an `Authorization: Bearer <token>` header.
+// If neither (or the user no longer exists), it'll return an Unauthorized status.
 #[rocket::async_trait]
 impl<'a, 'r> FromRequest<'a, 'r> for User {
     type Error = ();
 
     async fn from_request(req: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
-        let username = req.cookies()
+        let mut username = req.cookies()
             .get_private(COOKIE_NAME)
             .map(|c| c.value().to_owned());
+        if username.is_none() {
+            username = username_from_previous_key(req).await;
+        }
+        // Cookies outlive the users that they were handed out to, so check that the user is still around:
+        if let Some(name) = &username {
+            if !user_exists(req, name).await {
+                remove_user_cookie(req.cookies());
+                username = None;
+            }
+        }
+        if username.is_none() {
+            username = username_from_token(req).await;
+        }
 
         match username {
             Some(name) => request::Outcome::Success(User { name }),
@@ -27,6 +44,53 @@ impl<'a, 'r> FromRequest<'a, 'r> for User {
     }
 }
 
+/// If we've been given the previous secret key, accept a cookie encrypted with it,
+/// and re-add the cookie so that it's encrypted with the current key from now on.
+async fn username_from_previous_key(req: &Request<'_>) -> Option<String> {
+    let state = req.guard::<State<'_, state::State>>().await.succeeded()?;
+    let key = state.previous_secret_key.as_ref()?;
+    let encrypted = req.cookies().get(COOKIE_NAME)?;
+    let username = cookie::CookieJar::new()
+        .private(key)
+        .decrypt(cookie::Cookie::new(COOKIE_NAME, encrypted.value().to_owned()))?
+        .value()
+        .to_owned();
+    add_user_cookie(req.cookies(), username.clone());
+    Some(username)
+}
+
+/// Does the user still exist? If we can't tell, assume not.
+async fn user_exists(req: &Request<'_>, username: &str) -> bool {
+    let state = match req.guard::<State<'_, state::State>>().await.succeeded() {
+        Some(state) => state,
+        None => return false
+    };
+    match state.store.user_exists(username).await {
+        Ok(exists) => exists,
+        Err(e) => {
+            log::error!("Failed to check that user '{}' exists: {}", username, e);
+            false
+        }
+    }
+}
+
+/// Find the user that a bearer token given in the request belongs to, if it's valid.
+async fn username_from_token(req: &Request<'_>) -> Option<String> {
+    let token = req.headers()
+        .get_one("Authorization")?
+        .strip_prefix("Bearer ")?
+        .trim();
+    let (id, secret) = tokens::parse_token(token)?;
+    let state = req.guard::<State<'_, state::State>>().await.succeeded()?;
+    match state.store.check_token(&id, secret).await {
+        Ok(token) => token.map(|t| t.username),
+        Err(e) => {
+            log::error!("Failed to check API token: {}", e);
+            None
+        }
+    }
+}
+
 /// Set a cookie for a user so that they are logged in
 pub fn add_user_cookie(cookies: &CookieJar, username: String) {
     cookies.add_private(build_cookie(username));